@@ -0,0 +1,262 @@
+use crate::file::FileInfo;
+use crate::filter::FilterSet;
+use crate::source::FileSource;
+use crate::warn;
+use crate::warnings::{self, Kind};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufReader, PipeReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+/// One device for every member, far from the local ones and from those of the remote sources
+const DEVICE: u64 = u64::MAX / 4;
+
+/// Members are numbered across archives, each is a file of its own
+static MEMBERS: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Zip,
+    Tar,
+    TarGz,
+}
+
+impl Format {
+    /// The format of an archive by the name of the file
+    pub fn detect(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        match name.rsplit_once('.')? {
+            (_, "zip") => Some(Format::Zip),
+            (_, "tar") => Some(Format::Tar),
+            (_, "tgz") => Some(Format::TarGz),
+            (stem, "gz") if stem.ends_with(".tar") => Some(Format::TarGz),
+            _ => None,
+        }
+    }
+}
+
+/// A zip or tar archive whose members are compared as files, named `archive.zip!/inner/file`.
+///
+/// Members are decompressed again each time they are read, nothing is extracted on disk.
+#[derive(Debug)]
+pub struct Archive {
+    /// the archive as it shows in the report
+    path: PathBuf,
+    /// where its content is read from
+    content: PathBuf,
+    format: Format,
+    /// the archive's own time, zip members only have a local time to two seconds
+    mtime: i64,
+}
+
+/// The file followed by the members it holds when it is an archive.
+///
+/// An archive which cannot be listed is still compared as a file, with a warning.
+pub fn with_members(fl: FileInfo, filters: &FilterSet) -> Vec<FileInfo> {
+    let Some(format) = Format::detect(&fl.path).filter(|_| !fl.is_link_target() && fl.origin.is_none()) else {
+        return vec![fl];
+    };
+
+    let archive = Arc::new(Archive {
+        path: fl.path.clone(),
+        content: fl.content_path().to_owned(),
+        format,
+        mtime: fl.mtime,
+    });
+    let members = match archive.list(filters) {
+        Ok(members) => members.collect(),
+        Err(err) => {
+            warn!("Could not list the archive {}, comparing it as a file: {}", fl.path.display(), err);
+            let msg = format!("could not list the archive: {}", err);
+            warnings::record(Kind::UnreadableArchive, Some(&fl.path), msg);
+            vec![]
+        }
+    };
+    std::iter::once(fl).chain(members).collect()
+}
+
+impl FileSource for Archive {
+    fn spec(&self) -> PathBuf {
+        self.path.clone()
+    }
+
+    fn list(self: Arc<Self>, filters: &FilterSet) -> io::Result<Box<dyn Iterator<Item = FileInfo>>> {
+        let root = self.member_path("");
+        let mut members = vec![];
+        let mut push = |name: &str, size: u64, mtime: i64| {
+            let path = self.member_path(name);
+            if !filters.accepts_listed(&root, &path, size, mtime) {
+                return;
+            }
+            members.push(FileInfo {
+                path,
+                size,
+                inode: MEMBERS.fetch_add(1, Ordering::Relaxed),
+                device: DEVICE,
+                links: 1,
+                mtime,
+                mode: 0o644,
+                origin: Some(Arc::clone(&self) as Arc<dyn FileSource>),
+                ..Default::default()
+            });
+        };
+
+        match self.format {
+            Format::Zip => {
+                let mut zip =
+                    zip::ZipArchive::new(BufReader::new(File::open(&self.content)?)).map_err(io::Error::other)?;
+                for idx in 0..zip.len() {
+                    let member = zip.by_index(idx).map_err(io::Error::other)?;
+                    if member.is_file() {
+                        push(member.name(), member.size(), self.mtime);
+                    }
+                }
+            }
+            Format::Tar | Format::TarGz => {
+                let mut tar = tar::Archive::new(reader(&self.content, self.format)?);
+                for entry in tar.entries()? {
+                    let entry = entry?;
+                    if entry.header().entry_type().is_file() {
+                        let name = entry.path()?.to_string_lossy().trim_start_matches("./").to_owned();
+                        let mtime = entry.header().mtime().unwrap_or(0) as i64;
+                        push(&name, entry.size(), mtime.saturating_mul(1_000_000_000));
+                    }
+                }
+            }
+        }
+        Ok(Box::new(members.into_iter()))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        let name = self.member_name(path)?;
+
+        // the members borrow the archive, a thread holds both while the content is read
+        let (reader, mut writer) = io::pipe()?;
+        let (content, format, name) = (self.content.clone(), self.format, name.to_owned());
+        let worker = std::thread::spawn(move || copy_member(&content, format, &name, &mut writer));
+        Ok(Box::new(Member { reader, worker: Some(worker), path: path.to_owned() }))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let name = self.member_name(path)?;
+        let size = match self.format {
+            Format::Zip => {
+                let mut zip =
+                    zip::ZipArchive::new(BufReader::new(File::open(&self.content)?)).map_err(io::Error::other)?;
+                let member = name.to_str().and_then(|name| zip.by_name(name).ok());
+                member.map(|member| member.size()).ok_or_else(not_found)?
+            }
+            Format::Tar | Format::TarGz => {
+                let mut tar = tar::Archive::new(reader(&self.content, self.format)?);
+                let mut entries = tar.entries()?;
+                loop {
+                    let entry = entries.next().ok_or_else(not_found)??;
+                    let member = entry.path()?;
+                    if member.strip_prefix(".").unwrap_or(&member) == name {
+                        break entry.size();
+                    }
+                }
+            }
+        };
+        Ok(FileInfo {
+            path: path.to_owned(),
+            size,
+            device: DEVICE,
+            links: 1,
+            mtime: self.mtime,
+            ..Default::default()
+        })
+    }
+}
+
+impl Archive {
+    /// The name in the archive of the member listed as `path`
+    fn member_name<'a>(&self, path: &'a Path) -> io::Result<&'a Path> {
+        path.strip_prefix(self.member_path("")).map_err(|_| {
+            let msg = format!("{} is not in {}", path.display(), self.path.display());
+            io::Error::new(io::ErrorKind::InvalidInput, msg)
+        })
+    }
+
+    /// `archive.zip!/name`, or `archive.zip!` for the archive itself
+    fn member_path(&self, name: &str) -> PathBuf {
+        let mut path = OsString::from(self.path.as_os_str());
+        path.push("!");
+        if !name.is_empty() {
+            path.push("/");
+            path.push(name.trim_start_matches('/'));
+        }
+        PathBuf::from(path)
+    }
+}
+
+/// The content of a member, an error rather than a short read when it could not be decompressed
+struct Member {
+    reader: PipeReader,
+    worker: Option<JoinHandle<io::Result<()>>>,
+    path: PathBuf,
+}
+
+impl Read for Member {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.reader.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            if let Some(worker) = self.worker.take() {
+                let done = worker
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("the reader panicked")));
+                done.map_err(|err| io::Error::new(err.kind(), format!("{}: {}", self.path.display(), err)))?;
+            }
+        }
+        Ok(n)
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// The content of the archive, decompressed when it is a gzipped tar
+fn reader(path: &Path, format: Format) -> io::Result<Box<dyn Read>> {
+    let file = BufReader::new(File::open(path)?);
+    match format {
+        Format::TarGz => Ok(Box::new(flate2::read::GzDecoder::new(file))),
+        _ => Ok(Box::new(file)),
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no such member in the archive")
+}
+
+/// Writes the content of the member `name` to `out`, until the reader hangs up
+fn copy_member<W: Write>(archive: &Path, format: Format, name: &Path, out: &mut W) -> io::Result<()> {
+    let copied = match format {
+        Format::Zip => {
+            let mut zip = zip::ZipArchive::new(BufReader::new(File::open(archive)?)).map_err(io::Error::other)?;
+            let name = name.to_str().ok_or_else(not_found)?;
+            let mut member = zip.by_name(name).map_err(|_| not_found())?;
+            io::copy(&mut member, out)
+        }
+        Format::Tar | Format::TarGz => {
+            let mut tar = tar::Archive::new(reader(archive, format)?);
+            let mut entries = tar.entries()?;
+            let member = loop {
+                let entry = entries.next().ok_or_else(not_found)??;
+                let path = entry.path()?;
+                if path.strip_prefix(".").unwrap_or(&path) == name {
+                    break entry;
+                }
+            };
+            io::copy(&mut { member }, out)
+        }
+    };
+    match copied {
+        // the reader only wanted the start
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        other => other.map(|_| ()),
+    }
+}
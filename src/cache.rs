@@ -0,0 +1,282 @@
+use crate::file::FileInfo;
+use crate::warn;
+use crate::warnings::{self, Kind};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use std::cell::Cell;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Somewhere to keep the hashes of files between runs
+pub trait HashStore {
+    /// The digest recorded for this `kind` of hash, e.g. `prefix-metro-4096`
+    fn get(&self, fl: &FileInfo, kind: &str) -> Option<Vec<u8>>;
+
+    fn put(&self, fl: &FileInfo, kind: &str, digest: &[u8]);
+}
+
+/// A store the workers of the comparison share, one at a time
+pub struct Shared(Mutex<Box<dyn HashStore + Send>>);
+
+impl Shared {
+    pub fn new(store: Box<dyn HashStore + Send>) -> Self {
+        Self(Mutex::new(store))
+    }
+}
+
+impl HashStore for Shared {
+    fn get(&self, fl: &FileInfo, kind: &str) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().get(fl, kind)
+    }
+
+    fn put(&self, fl: &FileInfo, kind: &str, digest: &[u8]) {
+        self.0.lock().unwrap().put(fl, kind, digest)
+    }
+}
+
+/// Hashes computed by earlier runs, stored in SQLite.
+///
+/// Entries are keyed by device and inode and only trusted while the size and modification time
+/// still match, so an edited file is hashed again. Writes are batched in short transactions, so
+/// other runs sharing the cache only wait for one batch.
+pub struct HashCache {
+    conn: Connection,
+    read_only: bool,
+    /// writes in the open transaction, and when the first of them was made
+    pending: Cell<(usize, Option<Instant>)>,
+}
+
+impl HashCache {
+    pub fn open<P: AsRef<Path>>(path: &P) -> io::Result<Self> {
+        if let Some(dir) = path.as_ref().parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let conn = Connection::open(path).map_err(to_io)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS hashes (
+                 device INTEGER NOT NULL,
+                 inode INTEGER NOT NULL,
+                 kind TEXT NOT NULL,
+                 size INTEGER NOT NULL,
+                 mtime INTEGER NOT NULL,
+                 digest BLOB NOT NULL,
+                 PRIMARY KEY (device, inode, kind)
+             );",
+        )
+        .map_err(to_io)?;
+        // another run may be saving a batch, it is done well within this
+        conn.busy_timeout(BUSY_TIMEOUT).map_err(to_io)?;
+        Ok(Self { conn, read_only: false, pending: Cell::new((0, None)) })
+    }
+
+    /// Kinds of hashes recorded for an inode, whatever its size and modification time
+    pub fn kinds(&self, device: u64, inode: u64) -> Vec<String> {
+        let kinds = self
+            .conn
+            .prepare_cached("SELECT kind FROM hashes WHERE device = ?1 AND inode = ?2")
+            .and_then(|mut stmt| {
+                stmt.query_map(params![device as i64, inode as i64], |row| row.get(0))?
+                    .collect()
+            });
+        kinds.unwrap_or_else(|err| {
+            warn!("Could not read the hash cache: {}", err);
+            vec![]
+        })
+    }
+
+    pub fn forget(&self, device: u64, inode: u64, kind: &str) {
+        let res = self.write(|conn| {
+            conn.execute(
+                "DELETE FROM hashes WHERE device = ?1 AND inode = ?2 AND kind = ?3",
+                params![device as i64, inode as i64, kind],
+            )
+        });
+        if let Err(err) = res {
+            warn!("Could not update the hash cache: {}", err);
+        }
+    }
+
+    /// Saves the writes so far, the next one starts a new transaction
+    pub fn commit(&self) {
+        if self.pending.get().1.is_none() {
+            return;
+        }
+        if let Err(err) = self.conn.execute_batch("COMMIT;") {
+            warn!("Could not save the hash cache: {}", err);
+        }
+        // a batch which could not be saved yet is tried again with the next write
+        if self.conn.is_autocommit() {
+            self.pending.set((0, None));
+        }
+    }
+
+    /// Opens an existing cache without ever writing to it, for dry runs
+    pub fn open_read_only<P: AsRef<Path>>(path: &P) -> io::Result<Self> {
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(to_io)?;
+        Ok(Self { conn, read_only: true, pending: Cell::new((0, None)) })
+    }
+
+    /// Runs a write in the open transaction, starting one if needed, and saves the batch once it
+    /// holds `COMMIT_EVERY` writes or is `COMMIT_AFTER` old
+    fn write<T>(&self, f: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> rusqlite::Result<T> {
+        let (count, started) = self.pending.get();
+        let started = match started {
+            Some(started) => started,
+            None => {
+                self.conn.execute_batch("BEGIN;")?;
+                Instant::now()
+            }
+        };
+        self.pending.set((count + 1, Some(started)));
+
+        let res = f(&self.conn);
+        if count + 1 >= COMMIT_EVERY || started.elapsed() >= COMMIT_AFTER {
+            self.commit();
+        }
+        res
+    }
+}
+
+impl HashStore for HashCache {
+    fn get(&self, fl: &FileInfo, kind: &str) -> Option<Vec<u8>> {
+        let mut stmt = self
+            .conn
+            .prepare_cached(
+                "SELECT digest FROM hashes WHERE device = ?1 AND inode = ?2 AND kind = ?3 AND size = ?4 AND mtime = ?5",
+            )
+            .ok()?;
+        stmt.query_row(params![fl.device as i64, fl.inode as i64, kind, fl.size as i64, fl.mtime], |row| {
+            row.get(0)
+        })
+        .optional()
+        .unwrap_or_else(|err| {
+            cache_warning("Could not read the hash cache", err);
+            None
+        })
+    }
+
+    fn put(&self, fl: &FileInfo, kind: &str, digest: &[u8]) {
+        if self.read_only {
+            return;
+        }
+
+        let res = self.write(|conn| {
+            conn.prepare_cached("INSERT OR REPLACE INTO hashes (device, inode, kind, size, mtime, digest) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")
+                .and_then(|mut stmt| stmt.execute(params![fl.device as i64, fl.inode as i64, kind, fl.size as i64, fl.mtime, digest]))
+        });
+        if let Err(err) = res {
+            cache_warning("Could not update the hash cache", err);
+        }
+    }
+}
+
+impl Drop for HashCache {
+    fn drop(&mut self) {
+        self.commit();
+    }
+}
+
+/// Hashes stored next to the content, in the `user.rcompare.hash` extended attribute.
+///
+/// The attribute holds one `kind size mtime digest` line per kind of hash, the way git-annex and
+/// rmlint users expect. Files which cannot hold attributes are simply hashed every time.
+pub struct XattrCache {
+    failed: Cell<bool>,
+    read_only: bool,
+}
+
+impl XattrCache {
+    const NAME: &'static str = "user.rcompare.hash";
+
+    pub fn new(read_only: bool) -> Self {
+        Self { failed: Cell::new(false), read_only }
+    }
+
+    fn read(&self, fl: &FileInfo) -> Vec<String> {
+        match xattr::get(fl.content_path(), Self::NAME) {
+            Ok(Some(value)) => String::from_utf8_lossy(&value).lines().map(|l| l.to_owned()).collect(),
+            _ => vec![],
+        }
+    }
+}
+
+impl HashStore for XattrCache {
+    fn get(&self, fl: &FileInfo, kind: &str) -> Option<Vec<u8>> {
+        let fresh = format!("{} {} {} ", kind, fl.size, fl.mtime);
+        let line = self.read(fl).into_iter().find(|line| line.starts_with(&fresh))?;
+        from_hex(&line[fresh.len()..])
+    }
+
+    fn put(&self, fl: &FileInfo, kind: &str, digest: &[u8]) {
+        if self.read_only {
+            return;
+        }
+
+        let mut lines: Vec<String> = self
+            .read(fl)
+            .into_iter()
+            .filter(|line| !line.starts_with(&format!("{} ", kind)))
+            .collect();
+        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+        lines.push(format!("{} {} {} {}", kind, fl.size, fl.mtime, hex));
+
+        if let Err(err) = xattr::set(fl.content_path(), Self::NAME, lines.join("\n").as_bytes()) {
+            // read-only media or file systems without user attributes, say it once
+            if !self.failed.replace(true) {
+                warn!(
+                    "Could not store hashes in extended attributes, e.g. for {}: {}",
+                    &fl.path.display(),
+                    err
+                );
+                let msg = format!("could not store hashes in extended attributes: {}", err);
+                warnings::record(Kind::Cache, Some(&fl.path), msg);
+            }
+        }
+    }
+}
+
+/// `$XDG_CACHE_HOME/rcompare/hashes.sqlite`, or `~/.cache/rcompare/hashes.sqlite`
+pub fn default_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CACHE_HOME").filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => Path::new(&std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(dir.join("rcompare").join("hashes.sqlite"))
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// Writes saved together, much faster than one transaction per file
+const COMMIT_EVERY: usize = 1000;
+
+/// How long a batch may keep other runs from writing to the cache
+const COMMIT_AFTER: Duration = Duration::from_secs(2);
+
+/// How long to wait for the batch of another run before failing with `SQLITE_BUSY`
+const BUSY_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Warns about a cache failing during a scan, the same failure is listed once in the report
+fn cache_warning<E: std::fmt::Display>(what: &str, err: E) {
+    warn!("{}: {}", what, err);
+    warnings::record(Kind::Cache, None, format!("{}: {}", what.to_lowercase(), err));
+}
+
+fn to_io(err: rusqlite::Error) -> io::Error {
+    io::Error::other(err)
+}
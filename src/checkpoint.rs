@@ -0,0 +1,200 @@
+use crate::common::{Confidence, FileSeparation, Preprocessed, VecIdx};
+use crate::file::{FileInfo, SymlinkMode};
+use crate::filter::FilterSet;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+/// Where a scan stands, saved by `--checkpoint` for `--resume`: the walked files with the hashes
+/// computed so far, the groups confirmed and the groups of candidates still to compare
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    version: u32,
+    roots: Vec<RawPath>,
+    files: Vec<Entry>,
+    zero: VecIdx,
+    unique: VecIdx,
+    same: Vec<VecIdx>,
+    confidence: Vec<Confidence>,
+    pending: Vec<VecIdx>,
+}
+
+/// Saves the files and groups of `prep` with those `found` since, `pending` being left to
+/// compare, to `path`.
+///
+/// The state is written aside and renamed over `path`, an interruption while saving leaves the
+/// previous one whole.
+pub fn save(path: &Path, prep: &Preprocessed, found: &FileSeparation, pending: &[VecIdx]) -> io::Result<()> {
+    let hashes: HashMap<usize, &String> = found.hashes.iter().map(|(idx, hash)| (*idx, hash)).collect();
+    let files = prep
+        .info
+        .iter()
+        .enumerate()
+        .map(|(idx, fl)| Entry::new(fl, fl.hash.as_ref().or(hashes.get(&idx).copied())))
+        .collect();
+    let state = Checkpoint {
+        version: CHECKPOINT_VERSION,
+        roots: prep.roots.iter().map(|root| RawPath::new(root)).collect(),
+        files,
+        zero: prep.zero.clone(),
+        unique: [&prep.unique[..], &found.unique].concat(),
+        same: [&prep.same[..], &found.same].concat(),
+        confidence: [&prep.confidence[..], &found.confidence].concat(),
+        pending: pending.to_vec(),
+    };
+
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    let tmp = PathBuf::from(name);
+    fs::write(&tmp, serde_json::to_vec(&state)?)?;
+    fs::rename(&tmp, path)
+}
+
+/// Reads the state saved in `path`, which must be a scan of `lhs` and `rhs`, back into the
+/// files and groups the comparison goes on with
+pub fn load(path: &Path, lhs: &Path, rhs: &Path, filters: &FilterSet) -> io::Result<Preprocessed> {
+    let state: Checkpoint = serde_json::from_slice(&fs::read(path)?)?;
+    if state.version != CHECKPOINT_VERSION {
+        let msg = format!(
+            "checkpoint version {} is not supported, expected {}",
+            state.version, CHECKPOINT_VERSION
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+
+    let roots: Vec<PathBuf> = state
+        .roots
+        .into_iter()
+        .map(RawPath::into_path)
+        .collect::<io::Result<_>>()?;
+    if roots.first().map(PathBuf::as_path) != Some(lhs) || roots.last().map(PathBuf::as_path) != Some(rhs) {
+        let msg = format!("the checkpoint is a scan of other paths: {}", display_roots(&roots));
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+
+    let info: Vec<FileInfo> = state
+        .files
+        .into_iter()
+        .map(Entry::into_info)
+        .collect::<io::Result<_>>()?;
+    let in_range = |group: &VecIdx| group.iter().all(|idx| *idx < info.len());
+    let listed = [&state.zero, &state.unique];
+    let mut groups = state.same.iter().chain(&state.pending).chain(listed.iter().copied());
+    if !groups.all(in_range) || state.same.len() != state.confidence.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "the checkpoint is corrupt"));
+    }
+
+    Ok(Preprocessed {
+        roots,
+        filters: filters.clone(),
+        info,
+        zero: state.zero,
+        unique: state.unique,
+        same: state.same,
+        confidence: state.confidence,
+        to_process: state.pending,
+    })
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// A path as a string when it is valid UTF-8, as its bytes in base64 otherwise
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawPath {
+    Utf8(String),
+    Bytes { base64: String },
+}
+
+impl RawPath {
+    fn new(path: &Path) -> Self {
+        match path.to_str() {
+            Some(s) => RawPath::Utf8(s.to_owned()),
+            None => RawPath::Bytes {
+                base64: base64::engine::general_purpose::STANDARD.encode(path.as_os_str().as_bytes()),
+            },
+        }
+    }
+
+    fn into_path(self) -> io::Result<PathBuf> {
+        match self {
+            RawPath::Utf8(s) => Ok(PathBuf::from(s)),
+            RawPath::Bytes { base64 } => {
+                let bytes = base64::engine::general_purpose::STANDARD
+                    .decode(base64)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                Ok(PathBuf::from(std::ffi::OsString::from_vec(bytes)))
+            }
+        }
+    }
+}
+
+/// A walked file, with what `FileInfo` keeps of it but the source it is streamed from
+#[derive(Debug, Serialize, Deserialize)]
+struct Entry {
+    path: RawPath,
+    size: u64,
+    inode: u64,
+    device: u64,
+    links: u64,
+    mtime: i64,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symlink: Option<SymlinkMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<RawPath>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+}
+
+impl Entry {
+    fn new(fl: &FileInfo, hash: Option<&String>) -> Self {
+        Self {
+            path: RawPath::new(&fl.path),
+            size: fl.size,
+            inode: fl.inode,
+            device: fl.device,
+            links: fl.links,
+            mtime: fl.mtime,
+            mode: fl.mode,
+            uid: fl.uid,
+            gid: fl.gid,
+            symlink: fl.symlink,
+            source: fl.source.as_deref().map(RawPath::new),
+            hash: hash.cloned(),
+        }
+    }
+
+    fn into_info(self) -> io::Result<FileInfo> {
+        Ok(FileInfo {
+            path: self.path.into_path()?,
+            size: self.size,
+            inode: self.inode,
+            device: self.device,
+            links: self.links,
+            mtime: self.mtime,
+            mode: self.mode,
+            uid: self.uid,
+            gid: self.gid,
+            symlink: self.symlink,
+            source: self.source.map(RawPath::into_path).transpose()?,
+            hash: self.hash,
+            origin: None,
+        })
+    }
+}
+
+fn display_roots(roots: &[PathBuf]) -> String {
+    let roots: Vec<String> = roots.iter().map(|root| root.display().to_string()).collect();
+    roots.join(", ")
+}
@@ -0,0 +1,138 @@
+use crate::config::CHUNK_SAMPLE_FILES;
+use crate::file::FileInfo;
+use crate::hashing::{BuildMetro, Metro64};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::hash::Hasher;
+use std::io::{self, BufReader, Read};
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct ChunkEstimate {
+    pub average_chunk_size: usize,
+    pub sampled_files: usize,
+    pub sampled_bytes: u64,
+    pub sampled_unique_bytes: u64,
+    pub total_bytes: u64,
+    pub estimated_savings: u64,
+}
+
+/// Estimates how much a chunk-deduplicating backup tool would save on the scanned files.
+///
+/// At most `CHUNK_SAMPLE_FILES` files are read, evenly spread over the list. The ratio of
+/// unique chunk bytes found in the sample is then extrapolated to the total size.
+pub fn estimate_chunk_dedup(info: &[FileInfo], average_chunk_size: usize, read_size: usize) -> ChunkEstimate {
+    let candidates: Vec<&FileInfo> = info.iter().filter(|fl| fl.size > 0 && !fl.is_link_target()).collect();
+    let total_bytes: u64 = candidates.iter().map(|fl| fl.size).sum();
+    let step = candidates.len().div_ceil(CHUNK_SAMPLE_FILES).max(1);
+
+    let mut chunker = Chunker::new(average_chunk_size);
+    let mut seen: HashSet<u64, BuildMetro> = HashSet::default();
+    let mut estimate = ChunkEstimate { average_chunk_size, total_bytes, ..Default::default() };
+
+    for fl in candidates.iter().step_by(step) {
+        let chunks = fl.open().and_then(|file| chunker.chunk_reader(file, read_size));
+        if let Err(err) = chunks {
+            eprintln!("Unable to read file {} for the chunk estimate", &fl.path.display());
+            eprintln!("Error: {:?}", err);
+            continue;
+        }
+
+        estimate.sampled_files += 1;
+        for (key, len) in chunks.unwrap() {
+            estimate.sampled_bytes += len;
+            if seen.insert(key) {
+                estimate.sampled_unique_bytes += len;
+            }
+        }
+    }
+
+    if estimate.sampled_bytes > 0 {
+        let ratio = estimate.sampled_unique_bytes as f64 / estimate.sampled_bytes as f64;
+        estimate.estimated_savings = (total_bytes as f64 * (1.0 - ratio)) as u64;
+    }
+
+    estimate
+}
+
+// ----------
+//  Internal
+// ----------
+
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, so the table is fixed across builds
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Gear-based content defined chunking, in the spirit of FastCDC
+pub(crate) struct Chunker {
+    mask: u64,
+    min_size: usize,
+    max_size: usize,
+    buf: Vec<u8>,
+}
+
+impl Chunker {
+    pub(crate) fn new(average: usize) -> Self {
+        let average = average.max(64).next_power_of_two();
+        let bits = average.trailing_zeros();
+        // use the top bits of the gear hash, the lower ones only depend on the last few bytes
+        let mask = ((1u64 << bits) - 1) << (64 - bits);
+        Self { mask, min_size: average / 4, max_size: average * 8, buf: vec![] }
+    }
+
+    /// The key and the length of every chunk of the content
+    pub(crate) fn chunk_reader<R: Read>(&mut self, file: R, read_size: usize) -> io::Result<Vec<(u64, u64)>> {
+        let mut reader = BufReader::with_capacity(read_size, file);
+        self.buf.resize(read_size.max(1), 0);
+
+        let mut chunks: Vec<(u64, u64)> = vec![];
+        let mut hasher = Metro64::default();
+        let mut gear: u64 = 0;
+        let mut len: usize = 0;
+        loop {
+            let n = reader.read(&mut self.buf)?;
+            if n == 0 {
+                break;
+            }
+
+            let mut start = 0;
+            for i in 0..n {
+                gear = (gear << 1).wrapping_add(GEAR[self.buf[i] as usize]);
+                len += 1;
+                let boundary = (len >= self.min_size && gear & self.mask == 0) || len >= self.max_size;
+                if !boundary {
+                    continue;
+                }
+
+                hasher.write(&self.buf[start..=i]);
+                hasher.write_usize(len);
+                chunks.push((hasher.finish(), len as u64));
+                hasher = Metro64::default();
+                gear = 0;
+                len = 0;
+                start = i + 1;
+            }
+            hasher.write(&self.buf[start..n]);
+        }
+
+        if len > 0 {
+            hasher.write_usize(len);
+            chunks.push((hasher.finish(), len as u64));
+        }
+
+        Ok(chunks)
+    }
+}
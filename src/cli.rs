@@ -1,85 +1,1032 @@
-use crate::common::stringify_bytes;
-use crate::config::{Config, HASH_BUF_SIZE, MAX_FILE_SIZE, READ_SIZE};
-use clap::Parser;
+use crate::common::{
+    is_same_path, parse_bytes, parse_duration, stringify_bytes, HardlinkMode, ScanMode, SortKey, SortOrder, ZeroMode,
+};
+use crate::config::{Config, HASH_BUF_SIZE, MIN_READ_SIZE, READ_SIZE};
+use crate::diff::{Direction, NameKey, PathMatch, UnicodeForm};
+use crate::export::DedupeAction;
+use crate::file::SymlinkMode;
+use crate::filter::FilterSet;
+use crate::hashing::HashAlgorithm;
+use crate::i18n::{text, tr, Lang, Msg};
+use crate::keep::{KeepPolicy, KeepRule};
+use crate::policy::{PolicyTable, TextNormalize};
+use crate::regions::IgnoreRanges;
+use crate::say;
+use crate::schedule::Timer;
+use crate::term::Color;
+use clap::{Args, Parser, Subcommand};
 use std::convert::TryFrom;
 
 #[derive(Debug, Parser)]
-#[command(name = "rcompare")]
+#[command(name = "rcompare", args_conflicts_with_subcommands = true)]
 pub(crate) struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    #[arg(long, value_enum, global = true, help = "language of the messages - default: from LANG")]
+    pub lang: Option<Lang>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "line oriented output without ANSI sequences, for screen readers and dumb terminals"
+    )]
+    pub plain: bool,
+
+    #[arg(
+        long,
+        global = true,
+        help = "print what would be written or removed, without touching any file, and exit with 0"
+    )]
+    pub dry_run: bool,
+
+    #[arg(
+        short,
+        long,
+        global = true,
+        help = "only print the report, warnings and errors, without banners or progress"
+    )]
+    pub quiet: bool,
+
+    #[arg(
+        long,
+        value_enum,
+        global = true,
+        default_value_t = Color::Auto,
+        help = "when to draw the progress with ANSI sequences, auto only does on terminals"
+    )]
+    pub color: Color,
+
+    #[arg(long, global = true, help = "do not show the progress, for output redirected to files or scripts")]
+    pub no_progress: bool,
+
+    #[arg(long, global = true, help = "show a single progress bar instead of the dashboard on terminals")]
+    pub no_dashboard: bool,
+
+    #[arg(
+        long,
+        value_name = "path",
+        global = true,
+        help = "settings file, its defaults overridden by RCOMPARE_* variables and then by flags - \
+                default: ~/.config/rcompare/config.toml when it exists"
+    )]
+    pub config: Option<String>,
+
+    #[command(flatten)]
+    pub scan: ScanArgs,
+
+    #[arg(
+        long,
+        value_name = "report",
+        help = "check that a report was written with the schema of this version, then exit"
+    )]
+    pub check_compat: Option<String>,
+}
+
+// what to scan and how, shared by the commands running a scan
+#[derive(Debug, Args)]
+pub(crate) struct ScanArgs {
+    /// the settings file, from the global `--config`
+    #[arg(skip)]
+    pub settings: Option<String>,
+
     #[arg(help = "the first path - default is current directory")]
     pub lhs: Option<String>,
-    #[arg(help = "the second path - default is the first path")]
+    #[arg(
+        help = "the second path, [user@]host:/path for a tree read over ssh or s3://bucket/prefix - default is the first path"
+    )]
     pub rhs: Option<String>,
-    #[arg(short, help = "output path")]
-    pub output: Option<String>,
-    #[arg(short, long, help = "print information as the program runs")]
+    #[arg(
+        long = "self",
+        help = "look for duplicates inside a single path, even when the second path is given and is the same"
+    )]
+    pub self_scan: bool,
+    #[arg(
+        long,
+        value_name = "list",
+        conflicts_with = "rhs",
+        help = "compare the files listed in this file, or on stdin for -, instead of walking the paths, \
+                e.g. from find"
+    )]
+    pub files_from: Option<String>,
+    #[arg(short = '0', long = "null", requires = "files_from", help = "the paths of --files-from end with NUL bytes")]
+    pub null: bool,
+    #[arg(
+        long,
+        value_enum,
+        conflicts_with = "self_scan",
+        help = "how the paths are compared, mirror pairs the files of two copies of a tree by relative path - \
+                default: from the paths"
+    )]
+    pub mode: Option<ScanMode>,
+    #[arg(
+        short,
+        help = "output path, repeatable: .md gets a summary, .db a SQLite database, anything else the JSON report, \
+                - the JSON report on stdout with messages on stderr; reports get a timestamped name in a directory"
+    )]
+    pub output: Vec<String>,
+    #[arg(short, long, conflicts_with = "quiet", help = "print information as the program runs")]
     pub verbose: bool,
+    #[arg(
+        long,
+        help = "tell the time, files and bytes read of the walk, the grouping, the hashing and the comparison, to see \
+                whether a run waits on metadata or on reads"
+    )]
+    pub stats: bool,
 
-    #[arg(long, value_name = "max_file_size", help = &format!("maximum file size allowed to read it entirely in memory - default: {}", stringify_bytes(MAX_FILE_SIZE as usize)))]
+    /// ignored, kept so older command lines and profiles still parse
+    #[arg(long, value_name = "max_file_size", hide = true)]
     pub max_file_size: Option<u64>,
 
-    #[arg(long, value_name = "read_size", help = &format!("read block size - default: {}", stringify_bytes(READ_SIZE)))]
+    #[arg(long, value_name = "read_size", help = &format!("read block size, comparisons hold two blocks in memory - default: {}", stringify_bytes(READ_SIZE)))]
     pub read_size: Option<u64>,
 
     #[arg(long, value_name = "hash_size", help = &format!("how many bytes to read for hash calculation - default: {}", stringify_bytes(HASH_BUF_SIZE)))]
     pub hash_size: Option<u64>,
 
-    #[arg(long, value_name = "chunks_only", help = "disable reading the entire file into memory")]
+    /// ignored, kept so older command lines and profiles still parse
+    #[arg(long, value_name = "chunks_only", hide = true)]
     pub chunks_only: bool,
+
+    #[arg(long, value_name = "avg_chunk_size", value_parser = parse_bytes, help = "estimate the savings of a chunk-deduplicating backup tool, e.g. 4M")]
+    pub estimate_chunk_dedup: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "ext",
+        value_delimiter = ',',
+        help = "only compare files with these extensions, e.g. jpg,png,raw"
+    )]
+    pub ext: Option<Vec<String>>,
+
+    #[arg(long, value_name = "percent", value_parser = clap::value_parser!(u8).range(1..=100), help = "report pairs of directories sharing at least this percentage of their bytes")]
+    pub similar_dirs: Option<u8>,
+
+    #[arg(long, help = "report directories whose whole content is a copy of another one")]
+    pub duplicate_dirs: bool,
+
+    #[arg(long, value_name = "percent", value_parser = clap::value_parser!(u8).range(1..=100), help = "report groups of files sharing at least this percentage of their content, like truncated or re-saved copies")]
+    pub similar: Option<u8>,
+
+    #[arg(long, help = "skip hidden files and directories")]
+    pub skip_hidden: bool,
+
+    #[arg(long, help = "report files moved between lhs and rhs as renames")]
+    pub detect_renames: bool,
+
+    #[arg(
+        long,
+        value_name = "key",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "name",
+        help = "report files sharing a name, or with path the same path below the roots, with different contents"
+    )]
+    pub same_name: Option<NameKey>,
+
+    #[arg(
+        long,
+        value_name = "form",
+        help = "match relative paths in this Unicode normalization form, in mirror mode and for --same-name"
+    )]
+    pub path_normalize: Option<UnicodeForm>,
+
+    #[arg(long, help = "match relative paths regardless of case, in mirror mode and for --same-name")]
+    pub ignore_case: bool,
+
+    #[arg(
+        long,
+        value_name = "bytes",
+        value_parser = clap::value_parser!(u16).range(1..=4096),
+        help = "when lhs and rhs are two files, report this many bytes around their first difference in hex"
+    )]
+    pub context: Option<u16>,
+
+    #[arg(long, value_name = "depth", value_parser = clap::value_parser!(u64).range(1..), help = "only descend this many levels below each path")]
+    pub max_depth: Option<u64>,
+
+    #[arg(long, value_enum, help = "check that one path contains every file of the other, exits with 1 otherwise")]
+    pub direction: Option<Direction>,
+
+    #[arg(long, value_name = "glob", help = "skip files and directories matching this glob, can be repeated")]
+    pub exclude: Vec<String>,
+
+    #[arg(long, value_name = "size", value_parser = parse_bytes, help = "skip files smaller than this size")]
+    pub min_size: Option<u64>,
+
+    #[arg(long, value_name = "size", value_parser = parse_bytes, help = "skip files larger than this size")]
+    pub max_size: Option<u64>,
+
+    #[arg(long, value_name = "age", value_parser = parse_duration, help = "only compare files modified within this duration, e.g. 7d")]
+    pub newer_than: Option<u64>,
+
+    #[arg(long, value_name = "age", value_parser = parse_duration, help = "only compare files modified before this duration, e.g. 1y")]
+    pub older_than: Option<u64>,
+
+    #[arg(long, help = "skip entries ignored by .gitignore files")]
+    pub gitignore: bool,
+
+    #[arg(long, help = "do not cross into other file systems, like /proc or network mounts")]
+    pub one_file_system: bool,
+
+    #[arg(long, value_enum, default_value_t = SymlinkMode::Follow, help = "how to treat symbolic links")]
+    pub symlinks: SymlinkMode,
+
+    #[arg(long, help = "also compare the files inside zip and tar archives, named like archive.zip!/inner/file")]
+    pub scan_archives: bool,
+
+    #[arg(long, help = "report directories without any file below them, and empty layouts found more than once")]
+    pub empty_dirs: bool,
+
+    #[arg(long, help = "keep the paths as given instead of resolving symbolic links and relative parts")]
+    pub no_canonicalize: bool,
+
+    #[arg(
+        long,
+        value_name = "dir",
+        help = "treat this directory as / when resolving absolute links and displaying paths"
+    )]
+    pub sysroot: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "index",
+        help = "add the scanned files to this index and report duplicates across every volume in it - files are hashed \
+                with BLAKE3 unless --hash is a cryptographic algorithm"
+    )]
+    pub append_index: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "label",
+        requires = "append_index",
+        help = "name of the scanned volume in the index - default: the first path"
+    )]
+    pub volume_label: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "dir",
+        help = "hard link every duplicate group into its own directory under this empty one, e.g. group-000001/"
+    )]
+    pub link_farm: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "command",
+        help = "run this shell command for every duplicate group, {paths} stands for its quoted paths, without it they come NUL separated on stdin"
+    )]
+    pub exec_per_group: Option<String>,
+
+    #[arg(long, value_enum, help = "hash algorithm used to group files - default: metro")]
+    pub hash: Option<HashAlgorithm>,
+
+    #[arg(long, help = "skip the prefix hash and read each candidate file once, for slow network file systems")]
+    pub full_hash: bool,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["full_hash", "paranoid"],
+        help = "trust the hash of the first bytes without reading the files whole, the report tags these groups \
+                and the actions on duplicates leave them alone"
+    )]
+    pub quick: bool,
+
+    #[arg(
+        long,
+        value_name = "spec",
+        help = "treat these byte ranges as equal in every file, e.g. 0..512, or only for an extension, e.g. bin=0..16,32..48"
+    )]
+    pub ignore_ranges: Vec<String>,
+
+    #[arg(
+        long,
+        value_name = "what",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "line-endings",
+        help = "compare text files regardless of CRLF or LF line endings and a UTF-8 BOM, whitespace also ignores trailing blanks"
+    )]
+    pub text_normalize: Option<TextNormalize>,
+
+    #[arg(long, help = "compare files byte by byte when their digests match, instead of trusting them")]
+    pub paranoid: bool,
+
+    #[arg(long, help = "read large files into buffers instead of mapping them in memory")]
+    pub no_mmap: bool,
+
+    #[arg(
+        long,
+        help = "expert: read files with O_DIRECT, around the page cache, in aligned blocks of up to 1M, without memory \
+                maps, on Linux - file systems without direct reads still get the usual hints"
+    )]
+    pub direct_io: bool,
+
+    #[arg(long, value_name = "size", value_parser = parse_bytes, help = "cap the memory of the read buffers, shrinking the read size and disabling memory maps, e.g. 64K")]
+    pub max_memory: Option<u64>,
+
+    #[arg(
+        long,
+        value_name = "count",
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "list this many folders and compare this many groups of files at once, a spinning disk is still read \
+                by one at a time - default: the number of processors"
+    )]
+    pub threads: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "count",
+        value_parser = clap::value_parser!(u32).range(2..),
+        help = "keep at most this many files open at once, raising the limit of open files when needed - default: the \
+                limit of open files, less a margin for the rest of the run"
+    )]
+    pub max_open_files: Option<u32>,
+
+    #[arg(
+        long,
+        value_name = "count",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+        help = "walk the trees this many times, each pass only grouping a share of the file sizes, to bound the memory on huge file systems"
+    )]
+    pub passes: u32,
+
+    #[arg(
+        long,
+        value_name = "path",
+        help = "where to keep hashes between runs - default: ~/.cache/rcompare/hashes.sqlite"
+    )]
+    pub cache: Option<String>,
+
+    #[arg(long, conflicts_with = "cache", help = "hash every file again instead of reusing earlier runs")]
+    pub no_cache: bool,
+
+    #[arg(
+        long,
+        value_name = "path",
+        help = "save the walked files, the hashes and the groups confirmed to this file every minute, for --resume"
+    )]
+    pub checkpoint: Option<String>,
+
+    #[arg(
+        long,
+        value_name = "path",
+        help = "go on with a scan saved by --checkpoint, given the same paths, without walking or hashing the files \
+                already done; the progress keeps being saved there unless --checkpoint says otherwise"
+    )]
+    pub resume: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["cache", "no_cache"],
+        help = "keep hashes in the user.rcompare.hash extended attribute of each file instead of a cache file"
+    )]
+    pub xattr_cache: bool,
+
+    #[arg(
+        long,
+        value_name = "count",
+        requires = "output",
+        help = "when the output is a directory, only keep this many reports in it"
+    )]
+    pub keep_reports: Option<usize>,
+
+    #[arg(long, help = "exit with an error when any duplicate is found, for build pipelines")]
+    pub fail_if_duplicates: bool,
+
+    #[arg(
+        long,
+        value_name = "size",
+        value_parser = parse_bytes,
+        conflicts_with = "fail_if_duplicates",
+        help = "exit with an error when the duplicates waste more than this size, e.g. 100M"
+    )]
+    pub fail_if_wasted_bytes: Option<u64>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["link_farm", "exec_per_group", "xattr_cache"],
+        help = "have the kernel deny every change to files but the reports, the checkpoint and the hash cache, which \
+                must be outside the scanned folders, and to the rights, owners, times and extended attributes of any \
+                file - needs Landlock, Linux 5.13"
+    )]
+    pub hardened: bool,
+
+    #[arg(
+        long,
+        value_name = "count",
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "only report the groups wasting the most bytes, the summaries still count every group"
+    )]
+    pub top: Option<u64>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "mode",
+        default_value_t = HardlinkMode::Duplicates,
+        help = "whether links to the same inode count as duplicates, are left out of the groups or reported apart"
+    )]
+    pub hardlinks: HardlinkMode,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "mode",
+        default_value_t = ZeroMode::List,
+        help = "whether empty files are listed apart, left out, or grouped as duplicates of each other"
+    )]
+    pub zero_size: ZeroMode,
+
+    #[arg(long, value_enum, value_name = "key", help = "order the duplicate groups of the report by this key")]
+    pub sort: Option<SortKey>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "order",
+        requires = "sort",
+        help = "ascending or descending order of --sort, paths default to ascending and the rest to descending"
+    )]
+    pub order: Option<SortOrder>,
+
+    #[arg(
+        long,
+        value_name = "rules",
+        value_parser = KeepPolicy::parse,
+        help = "mark the copy to keep in every duplicate group, by the first of these rules to tell them apart: \
+                oldest, newest, prefer-lhs, shortest-path or prefer:<dir>, e.g. prefer:/srv/master,newest"
+    )]
+    pub keep: Option<KeepPolicy>,
+
+    #[arg(
+        long,
+        value_name = "path",
+        help = "write every file with its size and a strong hash, to check the trees later with verify --manifest"
+    )]
+    pub manifest: Option<String>,
+
+    #[arg(
+        long,
+        help = "print the redundant copies of every duplicate group to stdout, ended by NUL bytes, \
+                for xargs -0; the report is only written to --output"
+    )]
+    pub print0: bool,
+    #[arg(
+        long,
+        value_name = "path",
+        help = "write a shell script cleaning up the redundant copies of every group, to review and run yourself"
+    )]
+    pub emit_script: Option<String>,
+
+    #[arg(
+        long,
+        value_enum,
+        value_name = "action",
+        requires = "emit_script",
+        help = "what the script does with the redundant copies"
+    )]
+    pub script_action: Option<DedupeAction>,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum Command {
+    /// Look for duplicates, the same as giving the paths without a command
+    Scan(ScanArgs),
+    /// Compare two trees, grouping the files found in both
+    Compare(ScanArgs),
+    /// Scan, then remove, link or share the extents of the redundant copies of every duplicate group in place
+    Dedupe {
+        #[arg(long, value_enum, default_value_t = DedupeAction::Remove, help = "what to do with the redundant copies")]
+        action: DedupeAction,
+        #[arg(
+            long,
+            conflicts_with = "force",
+            help = "move the removed copies to the trash, as file managers do, where they can be restored from"
+        )]
+        trash: bool,
+        #[arg(long, help = "delete the removed copies for good")]
+        force: bool,
+        #[command(flatten)]
+        scan: ScanArgs,
+    },
+    /// Scan, then watch the paths and write the reports again as files change
+    Watch {
+        #[arg(
+            long,
+            value_name = "seconds",
+            default_value_t = 10,
+            value_parser = clap::value_parser!(u64).range(1..),
+            help = "how long changes are gathered before the next scan"
+        )]
+        interval: u64,
+        #[command(flatten)]
+        scan: ScanArgs,
+    },
+    /// Run a scan regularly
+    #[command(subcommand)]
+    Schedule(ScheduleCommand),
+    /// Work on existing reports
+    #[command(subcommand)]
+    Report(ReportCommand),
+    /// Print the JSON Schema of the reports
+    Schema {
+        #[arg(short, help = "output path, - for standard output - default: standard output")]
+        output: Option<String>,
+    },
+    /// Work on the hash cache
+    #[command(subcommand)]
+    Cache(CacheCommand),
+    /// Run scans and dedupes requested over an HTTP JSON API, one at a time
+    Serve {
+        #[arg(
+            long,
+            value_name = "address",
+            default_value = "127.0.0.1:8710",
+            help = "address to listen on, requests need the token written at startup"
+        )]
+        listen: String,
+    },
+    /// Show the space taken by each directory, counting every duplicated content once
+    Du {
+        #[arg(help = "the directory to measure - default is current directory")]
+        dir: Option<String>,
+        #[arg(short = 'd', long, value_name = "depth", help = "only show directories this deep below the root")]
+        max_depth: Option<usize>,
+    },
+    /// Read the files of chosen groups of a report again and write the updated report, or check
+    /// trees against a manifest
+    #[command(alias = "reverify")]
+    Verify {
+        #[arg(
+            value_name = "REPORT|DIR",
+            required_unless_present = "manifest",
+            help = "the report to update, or with --manifest the directory to check - default: the manifest paths"
+        )]
+        target: Option<String>,
+        #[arg(
+            long,
+            value_name = "list",
+            value_delimiter = ',',
+            required_unless_present = "manifest",
+            conflicts_with = "manifest",
+            help = "positions of the groups in the `same` list, from 0, e.g. 12,45"
+        )]
+        groups: Vec<usize>,
+        #[arg(
+            long,
+            value_name = "path",
+            help = "list the files added, removed, modified and corrupted since the manifest was written"
+        )]
+        manifest: Option<String>,
+        #[arg(long, help = "compare the files byte by byte as well as hashing them whole")]
+        paranoid: bool,
+        #[arg(short, help = "output path, - for standard output - default: standard output")]
+        output: Option<String>,
+    },
 }
 
-impl TryFrom<Cli> for Config {
+#[derive(Debug, Subcommand)]
+pub(crate) enum CacheCommand {
+    /// Hash files again as they change, so the next scan finds their hashes in the cache
+    Watch {
+        #[arg(help = "the directory to watch")]
+        dir: String,
+        #[arg(long, value_name = "path", help = "the hash cache to update - default: ~/.cache/rcompare/hashes.sqlite")]
+        cache: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum ReportCommand {
+    /// Convert a report written by an earlier version to the current schema
+    Upgrade {
+        #[arg(help = "the report to convert")]
+        report: String,
+        #[arg(short, help = "output path, - for standard output - default: standard output")]
+        output: Option<String>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum ScheduleCommand {
+    /// Save the scan given after `--` as a profile and run it on a schedule
+    Install {
+        #[arg(long, value_name = "expr", help = "when to run, as a cron expression, e.g. '0 3 * * 0'")]
+        cron: String,
+        #[arg(long, value_name = "name", help = "name of the profile")]
+        profile: String,
+        #[arg(long, value_enum, default_value_t = Timer::Cron, help = "what runs the profile")]
+        timer: Timer,
+        #[arg(long, value_name = "count", default_value_t = 10, help = "how many reports to keep")]
+        keep: usize,
+        #[arg(long, value_name = "dir", help = "where to write the reports - default: ~/.local/state/rcompare/<name>")]
+        reports: Option<String>,
+        #[arg(last = true, required = true, help = "the scan arguments, e.g. -- /mnt/nas /mnt/backup")]
+        args: Vec<String>,
+    },
+    /// Run a saved profile, keeping its latest reports
+    Run {
+        #[arg(long, value_name = "name", help = "name of the profile")]
+        profile: String,
+    },
+}
+
+/// Parses the command line, the options of a command being allowed before its name too.
+///
+/// The scan arguments of the top level conflict with the commands, so clap would take the name
+/// of a command following an option, like `rcompare -q du`, for the path to scan. The options
+/// given before the name of a command which takes them all are moved after it.
+pub(crate) fn parse() -> Cli {
+    Cli::parse_from(options_after_command(std::env::args_os().collect()))
+}
+
+/// Parses the arguments of a scan requested through `rcompare serve`, as given after `rcompare scan`
+pub(crate) fn parse_scan(args: &[String]) -> Result<ScanArgs, clap::Error> {
+    let args = ["rcompare", "scan"]
+        .iter()
+        .copied()
+        .chain(args.iter().map(String::as_str));
+    let cli = Cli::try_parse_from(args)?;
+    match cli.command {
+        Some(Command::Scan(mut scan)) => {
+            scan.settings = cli.config;
+            Ok(scan)
+        }
+        _ => unreachable!("the scan command is given"),
+    }
+}
+
+impl TryFrom<ScanArgs> for Config {
     type Error = std::io::Error;
-    fn try_from(value: Cli) -> std::io::Result<Self> {
+    fn try_from(mut value: ScanArgs) -> std::io::Result<Self> {
+        let settings = crate::settings::load(value.settings.as_deref().map(std::path::Path::new))
+            .inspect_err(|e| eprintln!("There was an error when reading the settings. Error: {}", e))?;
+        if value.output.is_empty() {
+            value.output = settings.output;
+        }
+
+        // the report or the paths on stdout, for another program to read
+        let report_to_stdout = value.output.iter().any(|path| path == "-");
+        if value.print0 && report_to_stdout {
+            eprintln!("{}", text(Msg::StdoutTwice));
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, text(Msg::StdoutTwice)));
+        }
+        if value.print0 || report_to_stdout {
+            crate::term::take_stdout();
+        }
+
         let lhs = match value.lhs {
             Some(s) => std::path::Path::new(s.as_str()).to_path_buf(),
             None => std::env::current_dir().expect("Cannot get current directory"),
         };
+        let lhs_given = lhs.clone();
 
         let verbose = value.verbose;
-        let path = lhs.canonicalize();
+        let canonicalize = !value.no_canonicalize;
+        let path = standardize(&lhs, canonicalize);
         if let Err(e) = path {
-            eprintln!("There was an error when standardizing the path '{}'. Error: {}", lhs.display(), &e);
+            eprintln!("{}", tr(Msg::StandardizeError, &[&lhs.display(), &e]));
             return Err(e);
         }
 
         let lhs = path.unwrap();
         if verbose {
-            println!("The standardized lhs path is {}", lhs.display());
+            say!("The standardized lhs path is {}", lhs.display());
         }
 
+        let two_paths = value.rhs.is_some();
         let rhs = match value.rhs {
             Some(s) => std::path::Path::new(s.as_str()).to_path_buf(),
-            None => lhs.clone(),
+            None => lhs_given.clone(),
         };
+        let rhs_given = rhs.clone();
 
-        let path = rhs.canonicalize();
+        // a remote tree is taken as given, it is only reached during the scan
+        let path = match crate::source::is_remote(&rhs) {
+            true => Ok(rhs.clone()),
+            false => standardize(&rhs, canonicalize),
+        };
         if let Err(e) = path {
-            eprintln!("There was an error when standardizing the path '{}'. Error: {}", rhs.display(), &e);
+            eprintln!("{}", tr(Msg::StandardizeError, &[&rhs.display(), &e]));
             return Err(e);
         }
 
         let rhs = path.unwrap();
         if verbose {
-            println!("The standardized rhs path is {}", rhs.display());
+            say!("The standardized rhs path is {}", rhs.display());
         }
 
-        let output = value.output.map(|s| std::path::Path::new(s.as_str()).to_path_buf());
-        let chunks_only = value.chunks_only;
+        let same_roots = is_same_path(&lhs, &rhs);
+        let self_scan = value.self_scan || value.mode == Some(ScanMode::SelfScan);
+        let mode = match (two_paths, same_roots, self_scan) {
+            (_, false, true) => {
+                let msg = tr(Msg::SelfNeedsOnePath, &[&lhs.display(), &rhs.display()]);
+                eprintln!("{}", msg);
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+            }
+            (_, false, false) => ScanMode::TwoRoots,
+            (true, true, false) => {
+                eprintln!("{}", tr(Msg::SameRoots, &[&lhs_given.display(), &rhs_given.display()]));
+                ScanMode::SameRoots
+            }
+            (false, true, _) | (true, true, true) => ScanMode::SelfScan,
+        };
+        let mode = match (value.mode, mode) {
+            (Some(ScanMode::TwoRoots | ScanMode::Mirror), ScanMode::SelfScan | ScanMode::SameRoots) => {
+                let msg = text(Msg::ModeNeedsTwoPaths);
+                eprintln!("{}", msg);
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+            }
+            (Some(ScanMode::Mirror), _) => ScanMode::Mirror,
+            _ => mode,
+        };
+
+        // the paths of the files are resolved, the preferred directories must be too
+        let keep = value.keep.map(|mut keep| {
+            for rule in keep.rules.iter_mut() {
+                if let KeepRule::Prefer(dir) = rule {
+                    *dir = standardize(dir, canonicalize).unwrap_or_else(|_| dir.clone());
+                }
+            }
+            keep
+        });
+        let script_action = value.script_action;
+        let files_from = match &value.files_from {
+            Some(source) => Some(
+                crate::file::read_list(source, value.null, canonicalize)
+                    .inspect_err(|e| eprintln!("Could not read the list of files {}: {}", source, e))?,
+            ),
+            None => None,
+        };
+        let output: Vec<std::path::PathBuf> = value.output.iter().map(std::path::PathBuf::from).collect();
+        let output_dir = output.iter().find(|path| path.is_dir()).cloned();
+        if value.keep_reports.is_some() && output_dir.is_none() {
+            let msg = "--keep-reports needs the output to be a directory";
+            eprintln!("{}", msg);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+        }
+        let output = output
+            .into_iter()
+            .map(|path| match path.is_dir() {
+                true => crate::report::new_report_path(&path),
+                false => path,
+            })
+            .collect();
+        // files streamed from elsewhere cannot be reopened from the saved state
+        let checkpointed = value.checkpoint.is_some() || value.resume.is_some();
+        if checkpointed && (value.passes > 1 || value.scan_archives || crate::source::is_remote(&rhs)) {
+            let msg = "--checkpoint and --resume need a single pass over local files, without --scan-archives";
+            eprintln!("{}", msg);
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+        }
+        if value.max_file_size.is_some() || value.chunks_only {
+            eprintln!("--max-file-size and --chunks-only are ignored, files are always compared in fixed-size blocks");
+        }
 
-        let read_size = value.read_size.map(|u| u as usize).unwrap_or(READ_SIZE);
+        let mut read_size = value
+            .read_size
+            .or(settings.read_size)
+            .map(|u| u as usize)
+            .unwrap_or(READ_SIZE);
+        let mut threads = match value.threads.or(settings.threads) {
+            Some(threads) => threads as usize,
+            None => std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+        };
+        let max_memory = value.max_memory.map(|u| u as usize);
+        if let Some(budget) = max_memory {
+            if budget < 2 * MIN_READ_SIZE {
+                let msg = format!("--max-memory must be at least {}", stringify_bytes(2 * MIN_READ_SIZE));
+                eprintln!("{}", msg);
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, msg));
+            }
+            // every worker holds two blocks at once, the first one those of the main comparator, fewer
+            // workers get to when they would not fit
+            threads = threads.min(budget / (2 * MIN_READ_SIZE));
+            read_size = read_size.min(budget / (2 * threads));
+        }
         let hash_size = value.hash_size.map(|u| u as usize).unwrap_or(HASH_BUF_SIZE);
-        let max_file_size = value.max_file_size.unwrap_or(MAX_FILE_SIZE);
+        let ignore_ranges = IgnoreRanges::parse(&value.ignore_ranges).map_err(|e| {
+            eprintln!("There was an error when parsing the ignored ranges. Error: {}", e);
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+        })?;
+        let rules = settings
+            .policy
+            .into_iter()
+            .chain(value.text_normalize.map(|t| t.rule()))
+            .collect();
+        let policies = PolicyTable::new(rules)
+            .inspect_err(|e| eprintln!("There was an error when reading the settings. Error: {}", e))?;
+        let chunk_dedup = value.estimate_chunk_dedup.map(|u| u as usize);
+        let extensions = value.ext.map(|v| {
+            v.iter()
+                .map(|e| e.trim().trim_start_matches('.').to_lowercase())
+                .filter(|e| !e.is_empty())
+                .collect()
+        });
+
+        let sysroot = match value.sysroot {
+            Some(s) => Some(standardize(std::path::Path::new(s.as_str()), canonicalize).inspect_err(|e| {
+                eprintln!("There was an error when standardizing the sysroot '{}'. Error: {}", s, e);
+            })?),
+            None => None,
+        };
+
+        let filters = FilterSet {
+            extensions,
+            min_size: value.min_size,
+            max_size: value.max_size,
+            newer_than: value.newer_than,
+            older_than: value.older_than,
+            skip_hidden: value.skip_hidden,
+            gitignore: value.gitignore,
+            max_depth: value.max_depth.map(|u| u as usize),
+            one_file_system: value.one_file_system,
+            symlinks: value.symlinks,
+            scan_archives: value.scan_archives,
+            empty_dirs: value.empty_dirs,
+            sysroot,
+            ..Default::default()
+        };
+        let exclude = settings.exclude.into_iter().chain(value.exclude).collect();
+        let filters = filters.with_exclude(exclude).inspect_err(|e| {
+            eprintln!("There was an error when parsing the exclude globs. Error: {}", e);
+        })?;
 
         Ok(Config {
             lhs,
             rhs,
             verbose,
+            stats: value.stats,
             read_size,
             hash_size,
-            chunks_only,
-            max_file_size,
             output,
+            chunk_dedup,
+            similar_dirs: value.similar_dirs.map(f64::from),
+            duplicate_dirs: value.duplicate_dirs,
+            similar: value.similar.map(f64::from),
+            detect_renames: value.detect_renames,
+            same_name: value.same_name,
+            hardlinks: value.hardlinks,
+            zero: value.zero_size,
+            path_match: PathMatch { normalize: value.path_normalize, ignore_case: value.ignore_case },
+            direction: value.direction,
+            filters,
+            canonicalize,
+            given: [lhs_given, rhs_given],
+            mode,
+            append_index: value
+                .append_index
+                .map(|s| std::path::Path::new(s.as_str()).to_path_buf()),
+            volume_label: value.volume_label,
+            link_farm: value.link_farm.map(std::path::PathBuf::from),
+            exec_per_group: value.exec_per_group,
+            hash: value.hash.or(settings.hash).unwrap_or_default(),
+            full_hash: value.full_hash,
+            quick: value.quick,
+            ignore_ranges,
+            policies,
+            paranoid: value.paranoid,
+            // mapped pages count against the resident memory too
+            mmap: !value.no_mmap && !value.direct_io && max_memory.is_none(),
+            direct_io: value.direct_io,
+            max_memory,
+            passes: value.passes as usize,
+            xattr_cache: value.xattr_cache,
+            // from the global `--dry-run`
+            dry_run: false,
+            hardened: value.hardened,
+            cache: match value.no_cache || value.xattr_cache {
+                true => None,
+                false => value
+                    .cache
+                    .map(std::path::PathBuf::from)
+                    .or_else(crate::cache::default_path),
+            },
+            keep_reports: output_dir.zip(value.keep_reports),
+            fail_over: value.fail_if_wasted_bytes.or(value.fail_if_duplicates.then_some(0)),
+            keep,
+            dedupe: None,
+            files_from,
+            print0: value.print0,
+            context: value.context.map(usize::from),
+            manifest: value.manifest.map(std::path::PathBuf::from),
+            threads,
+            max_open_files: value.max_open_files.map(|n| n as usize),
+            checkpoint: value.checkpoint.or(value.resume.clone()).map(std::path::PathBuf::from),
+            resume: value.resume.map(std::path::PathBuf::from),
+            emit_script: value
+                .emit_script
+                .map(|path| (std::path::PathBuf::from(path), script_action.unwrap_or_default())),
+            top: value.top.map(|n| n as usize),
+            sort: value.sort.unwrap_or_default(),
+            descending: match (value.sort, value.order) {
+                (_, Some(order)) => order == SortOrder::Desc,
+                (None | Some(SortKey::Path), None) => false,
+                (Some(_), None) => true,
+            },
         })
     }
 }
+
+/// Canonicalizes `path` as the scan does, the messages of the caller name the path already
+fn standardize(path: &std::path::Path, canonicalize: bool) -> std::io::Result<std::path::PathBuf> {
+    crate::common::resolve_path(&Some(&path), canonicalize).map_err(|err| match err {
+        crate::error::Error::Walk { source, .. } => source,
+        err => err.into(),
+    })
+}
+
+/// `args` with the options before the name of a command moved after it, when it takes them all
+fn options_after_command(mut args: Vec<std::ffi::OsString>) -> Vec<std::ffi::OsString> {
+    let mut cli = <Cli as clap::CommandFactory>::command();
+    cli.build();
+    let mut options = vec![];
+    let mut idx = 1;
+    while let Some(token) = args.get(idx).and_then(|arg| arg.to_str()) {
+        let Some((arg, next)) = option(&cli, token) else {
+            break;
+        };
+        options.push(arg.get_id().clone());
+        idx += 1 + next as usize;
+    }
+
+    let command = args
+        .get(idx)
+        .and_then(|arg| arg.to_str())
+        .and_then(|name| cli.find_subcommand(name));
+    if let Some(command) = command.filter(|_| idx > 1) {
+        let takes = |id: &clap::Id| command.get_arguments().any(|arg| arg.get_id() == id);
+        if options.iter().all(takes) {
+            args[1..=idx].rotate_right(1);
+        }
+    }
+    args
+}
+
+/// The option of `cmd` spelled by `token`, like `--lang=es`, `--lang` or `-q`, and whether its
+/// value is the next argument
+fn option<'c>(cmd: &'c clap::Command, token: &str) -> Option<(&'c clap::Arg, bool)> {
+    let (arg, inline) = match token.strip_prefix("--") {
+        Some("") => return None,
+        Some(long) => {
+            let (name, value) = match long.split_once('=') {
+                Some((name, _)) => (name, true),
+                None => (long, false),
+            };
+            (cmd.get_arguments().find(|arg| arg.get_long() == Some(name))?, value)
+        }
+        None => {
+            let mut shorts = token.strip_prefix('-')?.chars();
+            let short = shorts.next()?;
+            let arg = cmd.get_arguments().find(|arg| arg.get_short() == Some(short))?;
+            (arg, shorts.next().is_some())
+        }
+    };
+    let takes_value = arg.get_action().takes_values() && !arg.is_require_equals_set();
+    Some((arg, takes_value && !inline))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Cli {
+        let args = args.split(' ').map(std::ffi::OsString::from).collect();
+        Cli::try_parse_from(options_after_command(args)).unwrap()
+    }
+
+    #[test]
+    fn global_options_before_the_command() {
+        let cli = parse("rcompare -q du l");
+        assert!(cli.quiet);
+        assert!(matches!(cli.command, Some(Command::Du { .. })));
+
+        let cli = parse("rcompare --plain --lang es schema");
+        assert!(cli.plain);
+        assert!(matches!(cli.command, Some(Command::Schema { .. })));
+
+        let cli = parse("rcompare --dry-run dedupe --force l");
+        assert!(cli.dry_run);
+        assert!(matches!(cli.command, Some(Command::Dedupe { force: true, .. })));
+    }
+
+    #[test]
+    fn scan_options_before_the_command() {
+        let cli = parse("rcompare --no-cache -o report.json scan l");
+        let Some(Command::Scan(scan)) = cli.command else {
+            panic!("expected the scan command");
+        };
+        assert!(scan.no_cache);
+        assert_eq!(scan.output, ["report.json"]);
+        assert_eq!(scan.lhs.as_deref(), Some("l"));
+    }
+
+    #[test]
+    fn paths_after_options_stay_paths() {
+        let cli = parse("rcompare -q l r");
+        assert!(cli.command.is_none());
+        assert_eq!((cli.scan.lhs.as_deref(), cli.scan.rhs.as_deref()), (Some("l"), Some("r")));
+    }
+}
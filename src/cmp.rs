@@ -1,17 +1,64 @@
-use crate::common::{stringify_bytes, FileSeparation, Preprocessed, Processed};
-use crate::config::{Config, Key, HASH_BUF_SIZE};
+use crate::cache::{HashCache, HashStore, Shared, XattrCache};
+use crate::common::{stringify_bytes, Confidence, FileSeparation, Preprocessed, Processed};
+use crate::config::{Config, Key, CHECKPOINT_INTERVAL};
+use crate::content::ContentType;
+use crate::error::Error;
+use crate::fds;
 use crate::file::FileInfo;
-use fasthash::MetroHasher;
+use crate::hashing::{FileHasher, HashAlgorithm};
+use crate::i18n::{tr, Msg};
+use crate::interrupt;
+use crate::pagecache::Sequential;
+use crate::policy::{Policy, PolicyTable};
+use crate::readers::Readers;
+use crate::regions::IgnoreRanges;
+use crate::skipped::{self, Stage};
+use crate::sparse;
+use crate::stats::{self, Phase};
+use crate::term;
+use crate::throughput::{self, Usage};
+use crate::warnings::{self, Kind};
+use crate::{say, warn};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryInto;
 use std::fs::File;
-use std::hash::{Hash, Hasher};
-use std::io::{self, BufReader, Read, Write};
-use std::path::Path;
-
-fn hash_file<P: AsRef<Path>>(path: &P, buf_size: Option<usize>) -> io::Result<Key> {
-    let file = File::open(path)?;
-    let size = buf_size.unwrap_or(HASH_BUF_SIZE);
-    let mut reader = std::io::BufReader::with_capacity(size, file);
-    let mut hasher = MetroHasher::default();
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Instant;
+
+/// The policies which make sense for each type of content, files of other types are read exactly
+const STRATEGIES: [(ContentType, &[Policy]); 4] = [
+    (
+        ContentType::Text,
+        &[
+            Policy::NormalizedText,
+            Policy::NormalizedLineEndings,
+            Policy::IgnoreRanges,
+        ],
+    ),
+    (ContentType::Image, &[Policy::IgnoreMetadataImage, Policy::IgnoreRanges]),
+    (ContentType::Archive, &[Policy::IgnoreRanges]),
+    (ContentType::Binary, &[Policy::IgnoreRanges]),
+];
+
+/// How content of type `content` is read when the rule for its path asks for `policy`
+pub fn strategy(content: ContentType, policy: Policy) -> Policy {
+    let fits = STRATEGIES
+        .iter()
+        .any(|(ty, policies)| *ty == content && policies.contains(&policy));
+    match fits {
+        true => policy,
+        false => Policy::Exact,
+    }
+}
+
+/// Hashes the first `size` bytes given by `reader`
+fn hash_prefix<R: Read>(mut reader: R, size: usize, algorithm: HashAlgorithm) -> io::Result<Key> {
+    let mut hasher = algorithm.hasher();
     let mut buf = [0; 1024];
     let mut count = 0;
     while count < size {
@@ -21,302 +68,1220 @@ fn hash_file<P: AsRef<Path>>(path: &P, buf_size: Option<usize>) -> io::Result<Ke
         }
 
         n = n.min(size - count);
-        buf[..n].hash(&mut hasher);
+        hasher.update(&buf[..n]);
         count += n;
     }
 
-    let hash = hasher.finish();
-    Ok(hash)
+    Ok(hasher.key())
+}
+
+/// Hashes the first `size` bytes of the file, as the first stage of the comparison does
+pub fn hash_file_prefix<P: AsRef<Path>>(path: &P, size: usize, algorithm: HashAlgorithm) -> io::Result<Key> {
+    let _open = fds::acquire(1);
+    hash_prefix(BufReader::with_capacity(size, Sequential::open(path.as_ref())?), size, algorithm)
 }
 
-fn get_readers<P: AsRef<Path>, Q: AsRef<Path>>(
-    a: &P,
-    b: &Q,
+/// Hashes the whole content of the file, reading `read_size` bytes at a time
+pub fn hash_full_file<P: AsRef<Path>>(
+    path: &P,
     read_size: usize,
-) -> io::Result<(BufReader<File>, BufReader<File>)> {
-    let file_a = File::open(a);
-    let file_b = File::open(b);
+    algorithm: HashAlgorithm,
+) -> io::Result<Box<dyn FileHasher>> {
+    let mut buf = vec![0; read_size.max(1)];
+    let _open = fds::acquire(1);
+    hash_with_buffer(Sequential::open(path.as_ref())?, &mut buf, algorithm)
+}
 
-    if let Err(err) = file_a {
-        eprintln!("File {} raised an error", a.as_ref().to_str().unwrap());
-        eprintln!("Error: {:?}", &err);
-        return Err(err);
+fn hash_with_buffer<R: Read>(mut file: R, buf: &mut [u8], algorithm: HashAlgorithm) -> io::Result<Box<dyn FileHasher>> {
+    let mut hasher = algorithm.hasher();
+    loop {
+        let n = file.read(buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
     }
 
-    if let Err(err) = file_b {
-        eprintln!("File {} raised an error\n", b.as_ref().to_str().unwrap());
-        eprintln!("Error: {:?}", &err);
-        return Err(err);
+    Ok(hasher)
+}
+
+fn hash_link<P: AsRef<Path>>(path: &P, algorithm: HashAlgorithm) -> io::Result<Key> {
+    use std::os::unix::ffi::OsStrExt;
+    let target = std::fs::read_link(path)?;
+    let mut hasher = algorithm.hasher();
+    // keeps links apart from regular files holding the same bytes as the target path
+    hasher.update(b"symlink\0");
+    hasher.update(target.as_os_str().as_bytes());
+    Ok(hasher.key())
+}
+
+fn compare_link_targets(lhs: &FileInfo, rhs: &FileInfo) -> io::Result<bool> {
+    if !(lhs.is_link_target() && rhs.is_link_target()) {
+        return Ok(false);
+    }
+
+    Ok(std::fs::read_link(&lhs.path)? == std::fs::read_link(&rhs.path)?)
+}
+
+fn open_files<P: AsRef<Path>, Q: AsRef<Path>>(a: &P, b: &Q) -> io::Result<(Sequential, Sequential)> {
+    let file_a = Sequential::open(a.as_ref()).inspect_err(|err| {
+        warn!("File {} raised an error", a.as_ref().display());
+        warn!("Error: {:?}", err);
+    })?;
+    let file_b = Sequential::open(b.as_ref()).inspect_err(|err| {
+        warn!("File {} raised an error", b.as_ref().display());
+        warn!("Error: {:?}", err);
+    })?;
+    Ok((file_a, file_b))
+}
+
+/// Offset of the first byte where `a` and `b` differ, the end of the shorter one when it is a prefix
+/// of the other, and `None` when they are the same
+fn first_difference<A: Read, B: Read>(mut a: A, mut b: B, bufa: &mut [u8], bufb: &mut [u8]) -> io::Result<Option<u64>> {
+    let mut offset = 0;
+    loop {
+        let (na, nb) = (fill(&mut a, bufa)?, fill(&mut b, bufb)?);
+        let n = na.min(nb);
+        if let Some(pos) = bufa[..n].iter().zip(&bufb[..n]).position(|(x, y)| x != y) {
+            return Ok(Some(offset + pos as u64));
+        }
+        if na != nb {
+            return Ok(Some(offset + n as u64));
+        }
+        if n == 0 {
+            return Ok(None);
+        }
+        offset += n as u64;
+    }
+}
+
+/// `len` bytes of the file from `start` in hex, fewer at the end of the file
+fn hex_window(fl: &FileInfo, start: u64, len: usize) -> io::Result<String> {
+    let _open = fds::acquire(1);
+    let mut file = fl.open()?;
+    io::copy(&mut file.by_ref().take(start), &mut io::sink())?;
+    let mut bytes = vec![];
+    file.take(len as u64).read_to_end(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "))
+}
+
+/// Reads until `buf` is full or the reader is exhausted, so a short read never passes for the end
+fn fill<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
     }
+    Ok(filled)
+}
+
+/// Compares two readers one buffer at a time, never holding more than the two buffers
+fn compare_readers<A: Read, B: Read>(mut a: A, mut b: B, bufa: &mut [u8], bufb: &mut [u8]) -> io::Result<bool> {
+    debug_assert_eq!(bufa.len(), bufb.len());
+    loop {
+        let bts_a = fill(&mut a, bufa)?;
+        let bts_b = fill(&mut b, bufb)?;
+        if bts_a != bts_b || bufa[..bts_a] != bufb[..bts_b] {
+            return Ok(false);
+        }
 
-    let file_a = file_a.unwrap();
-    let file_b = file_b.unwrap();
-    let reader_a = BufReader::with_capacity(read_size, file_a);
-    let reader_b = BufReader::with_capacity(read_size, file_b);
+        // a buffer left partly empty means both readers are exhausted
+        if bts_a < bufa.len() {
+            return Ok(true);
+        }
+    }
+}
 
-    Ok((reader_a, reader_b))
+/// Where two files given on their own part ways, like `cmp` tells
+#[derive(Debug, Clone, Serialize)]
+pub struct Difference {
+    /// the first byte which differs, counted from 0, or the size of the shorter file
+    pub offset: u64,
+    /// where the context windows start, under `--context`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_start: Option<u64>,
+    /// the bytes of lhs around the offset, in hex
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lhs: Option<String>,
+    /// the bytes of rhs around the offset, in hex
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rhs: Option<String>,
 }
+
+/// Compares files in two buffers of `read_size` bytes allocated once, the whole memory it needs.
+///
+/// Large files go through memory maps, which the kernel pages in and out instead of the heap.
 pub struct Comparator {
     read_size: usize,
     hash_size: usize,
-    max_file_size: u64,
-    bufa: Vec<u8>,
-    bufb: Vec<u8>,
+    algorithm: HashAlgorithm,
+    /// skip the prefix hash and go straight to the digest of the whole content
+    full_hash: bool,
+    /// stop at the prefix hash, without reading files whole
+    quick: bool,
+    /// compare files byte by byte when their digests match
+    paranoid: bool,
+    /// compare large files through memory maps
+    mmap: bool,
+    /// shared by the workers, they take turns
+    cache: Option<Arc<Shared>>,
+    /// groups of candidates left after the current one, for the progress
+    queued: usize,
+    /// byte ranges read as zeros, in every stage
+    ignore: IgnoreRanges,
+    /// how files are read by glob, before the byte ranges
+    policies: PolicyTable,
+    /// where the progress of `process_files` is saved now and then
+    checkpoint: Option<PathBuf>,
+    /// workers comparing groups of candidates at once
+    threads: usize,
+    bufa: Box<[u8]>,
+    bufb: Box<[u8]>,
 }
 
 impl Comparator {
-    pub fn new(read_size: usize, hash_size: usize, max_file_size: u64) -> Self {
-        let bufa = Vec::with_capacity(read_size);
-        let bufb = Vec::with_capacity(read_size);
-        Self { read_size, hash_size, bufa, bufb, max_file_size }
+    pub fn new(read_size: usize, hash_size: usize, algorithm: HashAlgorithm) -> Self {
+        let read_size = read_size.max(1);
+        Self {
+            bufa: vec![0; read_size].into_boxed_slice(),
+            bufb: vec![0; read_size].into_boxed_slice(),
+            ..Self::without_buffers(read_size, hash_size, algorithm)
+        }
+    }
+
+    /// A comparator whose buffers are yet to be given
+    fn without_buffers(read_size: usize, hash_size: usize, algorithm: HashAlgorithm) -> Self {
+        Self {
+            read_size,
+            hash_size,
+            bufa: Box::default(),
+            bufb: Box::default(),
+            algorithm,
+            full_hash: false,
+            quick: false,
+            paranoid: false,
+            mmap: true,
+            cache: None,
+            queued: 0,
+            ignore: IgnoreRanges::default(),
+            policies: PolicyTable::default(),
+            checkpoint: None,
+            threads: 1,
+        }
     }
 
     pub fn from_config(config: &Config) -> Self {
-        Comparator::new(config.read_size, config.hash_size, config.max_file_size)
+        let comparator = Comparator::new(config.read_size, config.hash_size, config.hash);
+        let cache: Option<Box<dyn HashStore + Send>> = match &config.cache {
+            _ if config.xattr_cache => Some(Box::new(XattrCache::new(config.dry_run))),
+            // a dry run only reads an existing cache, without creating one
+            Some(path) if config.dry_run => HashCache::open_read_only(path)
+                .ok()
+                .map(|cache| Box::new(cache) as Box<dyn HashStore + Send>),
+            Some(path) => match HashCache::open(path) {
+                Ok(cache) => Some(Box::new(cache)),
+                Err(e) => {
+                    warn!("Could not open the hash cache {}, running without it: {}", path.display(), e);
+                    let msg = format!("could not open the hash cache, running without it: {}", e);
+                    warnings::record(Kind::Cache, Some(path), msg);
+                    None
+                }
+            },
+            None => None,
+        };
+        Self {
+            full_hash: config.full_hash,
+            quick: config.quick,
+            paranoid: config.paranoid,
+            // maps are compared as they are, without masks
+            mmap: config.mmap && config.ignore_ranges.is_empty(),
+            cache: cache.map(|cache| Arc::new(Shared::new(cache))),
+            ignore: config.ignore_ranges.clone(),
+            policies: config.policies.clone(),
+            checkpoint: config.checkpoint.clone().filter(|_| !config.dry_run),
+            threads: config.threads,
+            ..comparator
+        }
     }
 
-    fn compare_file_seq<P, Q>(&mut self, lhs: &P, rhs: &Q) -> io::Result<bool>
-    where
-        P: AsRef<Path> + ?Sized,
-        Q: AsRef<Path> + ?Sized,
-    {
-        let (mut reader_lhs, mut reader_rhs) = get_readers(&lhs, &rhs, self.read_size)?;
-        let mut bts_lhs: usize;
-        let mut bts_rhs: usize;
+    /// Reads files through `policies` and with the bytes of `ignore` left out
+    pub fn with_rules(self, ignore: IgnoreRanges, policies: PolicyTable) -> Self {
+        Self { mmap: self.mmap && ignore.is_empty(), ignore, policies, ..self }
+    }
 
-        loop {
-            bts_lhs = reader_lhs.read(self.bufa.as_mut_slice())?;
-            bts_rhs = reader_rhs.read(self.bufb.as_mut_slice())?;
+    /// Checks again which of `files` are the same, reading them whole
+    pub fn verify(&mut self, files: &[FileInfo], paranoid: bool) -> FileSeparation {
+        self.full_hash = true;
+        self.quick = false;
+        self.paranoid = paranoid;
+        let all: Vec<usize> = (0..files.len()).collect();
+        self.separate_files(&all, files)
+    }
 
-            if (bts_lhs != bts_rhs) || (self.bufa[..bts_lhs] != self.bufb[..bts_rhs]) {
-                return Ok(false);
+    fn compare_file_seq(&mut self, lhs: &FileInfo, rhs: &FileInfo) -> io::Result<bool> {
+        if lhs.origin.is_some() || rhs.origin.is_some() {
+            return self.compare_streams(lhs, rhs);
+        }
+
+        let (lhs, rhs) = (lhs.content_path(), rhs.content_path());
+        let _open = fds::acquire(2);
+        let (file_lhs, file_rhs) = open_files(&lhs, &rhs)?;
+        // the sizes matched during the scan, but either file may have changed since
+        let resizes = self.policies.policy_for(lhs).resizes() || self.policies.policy_for(rhs).resizes();
+        let (meta_lhs, meta_rhs) = (file_lhs.metadata()?, file_rhs.metadata()?);
+        if !resizes && meta_lhs.len() != meta_rhs.len() {
+            return Ok(false);
+        }
+
+        let (mask_lhs, mask_rhs) = (self.ignore.for_path(lhs), self.ignore.for_path(rhs));
+        compare_readers(
+            self.policies
+                .reader(lhs, throughput::timed(file_lhs, meta_lhs.dev()), mask_lhs),
+            self.policies
+                .reader(rhs, throughput::timed(file_rhs, meta_rhs.dev()), mask_rhs),
+            &mut self.bufa,
+            &mut self.bufb,
+        )
+    }
+
+    /// Compares mapped files `read_size` bytes at a time, falling back to the buffers when a file
+    /// cannot be mapped.
+    fn compare_file_mmap(&mut self, lhs_info: &FileInfo, rhs_info: &FileInfo) -> io::Result<bool> {
+        let (lhs, rhs) = (lhs_info.content_path(), rhs_info.content_path());
+        let open = fds::acquire(2);
+        let (file_lhs, file_rhs) = (Sequential::open(lhs)?, Sequential::open(rhs)?);
+        // the maps are only read while the files are open, a file truncated meanwhile is the one
+        // thing a buffered read would survive
+        let maps = unsafe { (memmap2::Mmap::map(file_lhs.file()), memmap2::Mmap::map(file_rhs.file())) };
+        let (map_lhs, map_rhs) = match maps {
+            (Ok(l), Ok(r)) => (l, r),
+            _ => {
+                // the buffers open the files again
+                drop((file_lhs, file_rhs, open));
+                return self.compare_file_seq(lhs_info, rhs_info);
             }
+        };
 
-            if (bts_rhs == 0) | (bts_lhs == 0) {
+        if map_lhs.len() != map_rhs.len() {
+            return Ok(false);
+        }
+        // pages are read ahead of the comparison, like with the buffers
+        _ = (
+            map_lhs.advise(memmap2::Advice::Sequential),
+            map_rhs.advise(memmap2::Advice::Sequential),
+        );
+
+        // the pages are read as they are touched, the whole comparison counts as waiting for them
+        let start = Instant::now();
+        let mut compared = 0;
+        let mut same = true;
+        for (a, b) in map_lhs.chunks(self.read_size).zip(map_rhs.chunks(self.read_size)) {
+            compared += a.len() as u64;
+            if a != b {
+                same = false;
                 break;
             }
         }
-
-        Ok((bts_rhs == 0) & (bts_lhs == 0))
+        throughput::read(2 * compared);
+        if throughput::is_enabled() {
+            let usage = Usage { bytes: compared, wait: start.elapsed() / 2 };
+            throughput::record(file_lhs.metadata()?.dev(), usage);
+            throughput::record(file_rhs.metadata()?.dev(), usage);
+        }
+        Ok(same)
     }
 
-    fn compare_file_full<P, Q>(&mut self, lhs: &P, rhs: &Q) -> io::Result<bool>
-    where
-        P: AsRef<Path> + ?Sized,
-        Q: AsRef<Path> + ?Sized,
-    {
-        self.bufa.clear();
-        self.bufb.clear();
+    /// Compares sparse files over the extents allocated in either of them, where both have a hole
+    /// they read as zeros and nothing needs reading.
+    ///
+    /// The extents are read at their offsets, past the page cache hints and direct reads of
+    /// `Sequential`.
+    fn compare_file_sparse(&mut self, lhs: &FileInfo, rhs: &FileInfo) -> io::Result<bool> {
+        let _open = fds::acquire(2);
+        let (file_lhs, file_rhs) = (File::open(lhs.content_path())?, File::open(rhs.content_path())?);
+        let (size, size_rhs) = (file_lhs.metadata()?.len(), file_rhs.metadata()?.len());
+        if size != size_rhs {
+            return Ok(false);
+        }
 
-        let (mut reader_lhs, mut reader_rhs) = get_readers(&lhs, &rhs, self.read_size)?;
-        let bts_lhs = reader_lhs.read_to_end(&mut self.bufa)?;
-        let bts_rhs = reader_rhs.read_to_end(&mut self.bufb)?;
+        let extents = sparse::union(&sparse::data_extents(&file_lhs, size)?, &sparse::data_extents(&file_rhs, size)?);
+        let mut compared = 0;
+        let mut same = true;
+        'extents: for (start, len) in extents {
+            let mut offset = start;
+            while offset < start + len {
+                let n = (start + len - offset).min(self.read_size as u64) as usize;
+                file_lhs.read_exact_at(&mut self.bufa[..n], offset)?;
+                file_rhs.read_exact_at(&mut self.bufb[..n], offset)?;
+                compared += n as u64;
+                if self.bufa[..n] != self.bufb[..n] {
+                    same = false;
+                    break 'extents;
+                }
+                offset += n as u64;
+            }
+        }
+        throughput::read(2 * compared);
+        Ok(same)
+    }
 
-        if (bts_lhs != bts_rhs) || (self.bufa[..bts_lhs] != self.bufb[..bts_rhs]) {
+    /// Compares files which can only be streamed, like the files of a remote tree, through their
+    /// origin
+    fn compare_streams(&mut self, lhs: &FileInfo, rhs: &FileInfo) -> io::Result<bool> {
+        // the sizes matched when listed, but either file may have changed since
+        let resizes = self.policies.policy_for(&lhs.path).resizes() || self.policies.policy_for(&rhs.path).resizes();
+        if !resizes && lhs.metadata()?.size != rhs.metadata()?.size {
             return Ok(false);
         }
 
-        Ok(true)
+        let (mask_lhs, mask_rhs) = (self.ignore.for_path(&lhs.path), self.ignore.for_path(&rhs.path));
+        let _open = fds::acquire(2);
+        compare_readers(
+            self.policies
+                .reader(&lhs.path, throughput::timed(lhs.open()?, lhs.device), mask_lhs),
+            self.policies
+                .reader(&rhs.path, throughput::timed(rhs.open()?, rhs.device), mask_rhs),
+            &mut self.bufa,
+            &mut self.bufb,
+        )
+    }
+
+    fn hash_entry(&self, fl: &FileInfo) -> Result<Key, Error> {
+        if fl.is_link_target() {
+            return hash_link(&fl.path, self.algorithm).map_err(hash_error(fl));
+        }
+
+        let ignore = self.ignore.for_path(&fl.path);
+        let kind = format!(
+            "prefix-{}-{}{}",
+            self.algorithm.name(),
+            self.hash_size,
+            self.policies.kind(&fl.path, ignore)
+        );
+        if let Some(key) = self.cached(fl, &kind).and_then(|d| d.try_into().ok()) {
+            return Ok(Key::from_be_bytes(key));
+        }
+
+        let _open = fds::acquire(1);
+        let file = fl.open().map_err(hash_error(fl))?;
+        let file = BufReader::with_capacity(self.hash_size, throughput::timed(file, fl.device));
+        let file = self.policies.reader(&fl.path, file, ignore);
+        let key = hash_prefix(file, self.hash_size, self.algorithm).map_err(hash_error(fl))?;
+        if let Some(cache) = self.cache_for(fl) {
+            cache.put(fl, &kind, &key.to_be_bytes());
+        }
+        Ok(key)
     }
 
-    pub fn hash_file<P: AsRef<Path>>(&self, path: &P) -> io::Result<Key> {
-        hash_file(&path, Some(self.hash_size))
+    fn cached(&self, fl: &FileInfo, kind: &str) -> Option<Vec<u8>> {
+        self.cache_for(fl)?.get(fl, kind)
     }
 
-    fn separate_files(
-        &mut self,
-        dupes: &[usize],
-        list: &[FileInfo],
-        compare: fn(&mut Self, &Path, &Path) -> io::Result<bool>,
-        _verbose: bool,
-        total: usize,
-        progress: &mut usize,
-    ) -> FileSeparation {
-        let mut map: Vec<(Key, Vec<Vec<usize>>)> = Vec::with_capacity(dupes.len() / 2 + 1);
-        let mut errors: Vec<usize> = vec![];
+    /// The cache, unless the file is streamed, its inode tells nothing about the local ones
+    fn cache_for(&self, fl: &FileInfo) -> Option<&dyn HashStore> {
+        self.cache
+            .as_deref()
+            .map(|cache| cache as &dyn HashStore)
+            .filter(|_| fl.origin.is_none())
+    }
 
-        for idx in dupes.iter() {
-            *progress += 1;
-            let fl = list.get(*idx);
-            if fl.is_none() {
-                eprintln!("Could not find file at position {}", &idx);
-                errors.push(*idx);
+    /// Saves where the comparison of `prep` stands, `pending` being the groups not compared yet
+    fn save_checkpoint(&self, prep: &Preprocessed, found: &FileSeparation, pending: &[Vec<usize>]) {
+        let Some(path) = &self.checkpoint else {
+            return;
+        };
+        if let Err(err) = crate::checkpoint::save(path, prep, found, pending) {
+            warn!("Could not save the checkpoint {}: {}", path.display(), err);
+        }
+    }
+
+    /// Compares the groups of `to_process` one after the other, until Ctrl-C.
+    ///
+    /// Returns what was found and how many groups it covers, the progress being saved now and
+    /// then on top of `prep`.
+    fn compare_groups(&mut self, prep: &Preprocessed, to_process: &[Vec<usize>]) -> (FileSeparation, usize) {
+        let mut found = FileSeparation::default();
+        let mut saved = Instant::now();
+        for (n, dupes) in to_process.iter().enumerate() {
+            if interrupt::interrupted() {
+                return (found, n);
             }
+            if saved.elapsed() >= CHECKPOINT_INTERVAL {
+                self.save_checkpoint(prep, &found, &to_process[n..]);
+                saved = Instant::now();
+            }
+            self.queued = to_process.len() - n - 1;
+            let _work = throughput::work();
+            found.append(self.separate_files(dupes, &prep.info));
+        }
+        (found, to_process.len())
+    }
+
+    /// Compares the groups of `to_process` like `compare_groups`, on `threads` workers.
+    ///
+    /// Every worker takes the next group once the devices holding its files have room for one
+    /// more reader. What they find is put together in the order of the groups, so the result does
+    /// not depend on which worker was faster.
+    fn compare_groups_parallel(&mut self, prep: &Preprocessed, to_process: &[Vec<usize>]) -> (FileSeparation, usize) {
+        let readers = Readers::new(self.threads);
+        let next = AtomicUsize::new(0);
+        let (tx, rx) = mpsc::channel::<(usize, FileSeparation)>();
+        // the first worker reads into the buffers of this comparator, the threads hold no more than one
+        // comparator per thread would
+        let mut workers: Vec<Comparator> = vec![];
+        for n in 0..self.threads.min(to_process.len()) {
+            let buffers = match n {
+                0 => (std::mem::take(&mut self.bufa), std::mem::take(&mut self.bufb)),
+                _ => (
+                    vec![0; self.read_size].into_boxed_slice(),
+                    vec![0; self.read_size].into_boxed_slice(),
+                ),
+            };
+            workers.push(self.worker(buffers));
+        }
 
-            let fl = fl.unwrap();
-            let hash = self.hash_file(&fl.path);
-            if let Err(err) = hash {
-                eprintln!("Unable to hash file {}", &fl.path.display());
-                eprintln!("Error: {:?}", err);
+        let mut found = FileSeparation::default();
+        let mut done = 0;
+        std::thread::scope(|scope| {
+            for (n, worker) in workers.iter_mut().enumerate() {
+                let (tx, next, readers, info) = (tx.clone(), &next, &readers, &prep.info);
+                let run = move || loop {
+                    let n = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(dupes) = to_process.get(n).filter(|_| !interrupt::interrupted()) else {
+                        break;
+                    };
+                    let devices: Vec<u64> = dupes
+                        .iter()
+                        .filter_map(|idx| info.get(*idx))
+                        .map(|fl| fl.device)
+                        .collect();
+                    let _permit = readers.acquire(&devices);
+                    worker.queued = to_process.len().saturating_sub(n + 1);
+                    let _work = throughput::work();
+                    if tx.send((n, worker.separate_files(dupes, info))).is_err() {
+                        break;
+                    }
+                };
+                std::thread::Builder::new()
+                    .name(format!("worker-{}", n + 1))
+                    .spawn_scoped(scope, run)
+                    .expect("cannot start a worker thread");
+            }
+            drop(tx);
+
+            let mut waiting: BTreeMap<usize, FileSeparation> = BTreeMap::new();
+            let mut saved = Instant::now();
+            for (n, sep) in rx {
+                waiting.insert(n, sep);
+                while let Some(sep) = waiting.remove(&done) {
+                    found.append(sep);
+                    done += 1;
+                }
+                if saved.elapsed() >= CHECKPOINT_INTERVAL {
+                    self.save_checkpoint(prep, &found, &to_process[done..]);
+                    saved = Instant::now();
+                }
+            }
+        });
+        if let Some(first) = workers.first_mut() {
+            (self.bufa, self.bufb) = (std::mem::take(&mut first.bufa), std::mem::take(&mut first.bufb));
+        }
+        (found, done)
+    }
+
+    /// A comparator like this one reading into `buffers`, for another thread
+    fn worker(&self, (bufa, bufb): (Box<[u8]>, Box<[u8]>)) -> Comparator {
+        Comparator {
+            full_hash: self.full_hash,
+            quick: self.quick,
+            paranoid: self.paranoid,
+            mmap: self.mmap,
+            cache: self.cache.clone(),
+            ignore: self.ignore.clone(),
+            policies: self.policies.clone(),
+            bufa,
+            bufb,
+            ..Comparator::without_buffers(self.read_size, self.hash_size, self.algorithm)
+        }
+    }
+
+    /// Runs a group of files of the same size through the stages of the pipeline.
+    ///
+    /// Files are first split by a hash of their first bytes, then by a digest of their whole
+    /// content, and finally compared byte by byte when `paranoid` is set. Each stage only reads
+    /// the files which still collide, and none compares every pair of a group.
+    ///
+    /// Every group found is tagged with the last stage it went through.
+    fn separate_files(&mut self, dupes: &[usize], list: &[FileInfo]) -> FileSeparation {
+        let mut sep = FileSeparation::default();
+        let buckets = if self.full_hash {
+            vec![dupes.to_vec()]
+        } else {
+            term::phase(Msg::StagePrefix, self.queued);
+            self.split_by_prefix(dupes, list, &mut sep.errors)
+        };
+
+        for bucket in buckets {
+            if bucket.len() == 1 {
+                term::settle(list[bucket[0]].size);
+                sep.unique.push(bucket[0]);
+                continue;
+            }
+
+            // only reported, the actions leave sampled groups alone, see `Processed::is_exact`
+            if self.quick {
+                bucket.iter().for_each(|idx| term::settle(list[*idx].size));
+                found(&bucket, list);
+                sep.confidence.push(confidence(&bucket, list, Confidence::SampledHash));
+                sep.same.push(bucket);
+                continue;
+            }
+
+            // hashing sparse files would read every hole, comparing them skips the holes of both
+            if self.all_sparse(&bucket, list) {
+                term::phase(Msg::StageContent, self.queued);
+                let groups = self.split_by_content(&bucket, list, &mut sep.errors);
+                bucket.iter().for_each(|idx| term::settle(list[*idx].size));
+                keep_groups(&mut sep, groups, list, Confidence::ByteCompare);
+                continue;
+            }
+
+            term::phase(Msg::StageDigest, self.queued);
+            for group in self.split_by_digest(&bucket, list, &mut sep) {
+                let (groups, stage) = match self.paranoid && group.len() > 1 {
+                    true => {
+                        term::phase(Msg::StageContent, self.queued);
+                        (self.split_by_content(&group, list, &mut sep.errors), Confidence::ByteCompare)
+                    }
+                    false => (vec![group], Confidence::FullHash),
+                };
+                keep_groups(&mut sep, groups, list, stage);
+            }
+        }
+        sep
+    }
+
+    /// Whether the files of `group` are all local sparse files read exactly, which
+    /// `compare_file_sparse` compares without reading their holes
+    fn all_sparse(&self, group: &[usize], list: &[FileInfo]) -> bool {
+        group.iter().all(|idx| {
+            list.get(*idx).is_some_and(|fl| {
+                fl.origin.is_none()
+                    && !fl.is_link_target()
+                    && self.policies.kind(&fl.path, self.ignore.for_path(&fl.path)).is_empty()
+                    && std::fs::metadata(fl.content_path()).is_ok_and(|meta| sparse::is_sparse(&meta))
+            })
+        })
+    }
+
+    fn split_by_prefix(&mut self, dupes: &[usize], list: &[FileInfo], errors: &mut Vec<usize>) -> Vec<Vec<usize>> {
+        let _phase = stats::phase(Phase::Hash, dupes.len());
+        let mut buckets: HashMap<Key, Vec<usize>> = HashMap::new();
+        for idx in dupes.iter() {
+            let Some(fl) = list.get(*idx) else {
+                warn!("Could not find file at position {}", &idx);
                 errors.push(*idx);
                 continue;
+            };
+
+            match self.hash_entry(fl) {
+                Ok(key) => buckets.entry(key).or_default().push(*idx),
+                Err(err) => {
+                    if skipped::record(&fl.path, Stage::Compare, &err) {
+                        warn!("{}", tr(Msg::UnableToHash, &[&fl.path.display()]));
+                        warn!("Error: {}", err);
+                    }
+                    term::settle(fl.size);
+                    errors.push(*idx);
+                }
             }
+        }
+        sorted(buckets.into_values().collect(), list)
+    }
 
-            let key = hash.unwrap();
-            let pos = map.iter().position(|(k, _)| k == &key);
+    /// Splits files by the digest of their whole content, reading each file once.
+    ///
+    /// The progress counts a file as done here, comparing contents is a second pass over them.
+    /// A 64 bits hash is too weak to stand in for a comparison, so BLAKE3 is used unless the
+    /// chosen algorithm is cryptographic.
+    fn split_by_digest(&mut self, bucket: &[usize], list: &[FileInfo], sep: &mut FileSeparation) -> Vec<Vec<usize>> {
+        let algorithm = if self.algorithm.is_cryptographic() {
+            self.algorithm
+        } else {
+            HashAlgorithm::Blake3
+        };
+        let _phase = stats::phase(Phase::Hash, bucket.len());
+        let mut groups: HashMap<Vec<u8>, Vec<usize>> = HashMap::new();
+        let mut inodes: HashMap<(u64, u64), Vec<u8>> = HashMap::new();
 
-            // if there are no groups, we just insert one
-            if pos.is_none() {
-                map.push((key, vec![vec![*idx]]));
+        for idx in bucket.iter() {
+            let Some(fl) = list.get(*idx) else {
+                warn!("Could not find file at position {}", &idx);
+                sep.errors.push(*idx);
                 continue;
+            };
+
+            // hard links share their content, no need to read it again
+            let digest = match inodes.get(&(fl.device, fl.inode)) {
+                Some(digest) if !fl.is_link_target() => Ok(digest.clone()),
+                _ => {
+                    term::working_on(tr(Msg::Hashing, &[&fl.path.display()]));
+                    self.digest_entry(fl, algorithm)
+                }
+            };
+            term::settle(fl.size);
+
+            match digest {
+                Ok(digest) => {
+                    if !fl.is_link_target() {
+                        inodes.insert((fl.device, fl.inode), digest.clone());
+                        let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+                        sep.hashes.push((*idx, format!("{}:{}", algorithm.name(), hex)));
+                    }
+                    groups.entry(digest).or_default().push(*idx);
+                }
+                Err(err) => {
+                    if skipped::record(&fl.path, Stage::Compare, &err) {
+                        warn!("{}", tr(Msg::UnableToHash, &[&fl.path.display()]));
+                        warn!("Error: {}", err);
+                    }
+                    sep.errors.push(*idx);
+                }
             }
+        }
+        sorted(groups.into_values().collect(), list)
+    }
+
+    fn digest_entry(&mut self, fl: &FileInfo, algorithm: HashAlgorithm) -> Result<Vec<u8>, Error> {
+        if fl.is_link_target() {
+            let key = hash_link(&fl.path, algorithm).map_err(hash_error(fl))?;
+            return Ok(key.to_be_bytes().to_vec());
+        }
+
+        let ignore = self.ignore.for_path(&fl.path);
+        let kind = format!("full-{}{}", algorithm.name(), self.policies.kind(&fl.path, ignore));
+        if let Some(digest) = self.cached(fl, &kind) {
+            return Ok(digest);
+        }
 
-            // if a group exists we check if the file actually belongs to any of them
-            let (_, groups) = map.get_mut(pos.unwrap()).unwrap();
-            let mut matched: bool = false;
+        // the comparison buffer is free at this stage, no need for another one
+        let _open = fds::acquire(1);
+        let file = fl.open().map_err(hash_error(fl))?;
+        let file = self
+            .policies
+            .reader(&fl.path, throughput::timed(file, fl.device), ignore);
+        let digest = hash_with_buffer(file, &mut self.bufa, algorithm)
+            .map_err(hash_error(fl))?
+            .digest();
+        if let Some(cache) = self.cache_for(fl) {
+            cache.put(fl, &kind, &digest);
+        }
+        Ok(digest)
+    }
+
+    /// Compares files byte by byte against the first file of every group found so far
+    fn split_by_content(&mut self, group: &[usize], list: &[FileInfo], errors: &mut Vec<usize>) -> Vec<Vec<usize>> {
+        let _phase = stats::phase(Phase::Compare, group.len());
+        let size = list.get(group[0]).map(|fl| fl.size).unwrap_or(0);
+        // maps are compared as they are, streamed files and files read through a policy or a mask go
+        // through the buffers
+        let exact = group
+            .iter()
+            .filter_map(|idx| list.get(*idx))
+            .all(|fl| fl.origin.is_none() && self.policies.kind(&fl.path, self.ignore.for_path(&fl.path)).is_empty());
+        let compare = match exact {
+            true if self.all_sparse(group, list) => Self::compare_file_sparse,
+            true => self.pick_compare(size),
+            false => Self::compare_file_seq,
+        };
+        let mut groups: Vec<Vec<usize>> = vec![];
+
+        for idx in group.iter() {
+            let Some(fl) = list.get(*idx) else {
+                warn!("Could not find file at position {}", &idx);
+                errors.push(*idx);
+                continue;
+            };
+
+            let mut matched = false;
             for group in groups.iter_mut() {
                 // just needs to check the first entry of the group
-                let found = list.get(group[0]);
-                if found.is_none() {
-                    eprintln!(
-                        "There was an error when getting FileInfo: index {} was used but it is not in the vector",
-                        group[0]
-                    );
-                    continue;
+                let found = &list[group[0]];
+                // if the inode is the same, the files must be equal
+                if found.device == fl.device && found.inode == fl.inode && !fl.is_link_target() {
+                    group.push(*idx);
+                    matched = true;
+                    break;
                 }
 
-                let found = found.unwrap();
-                if cfg!(target_family = "unix") {
-                    // if the inode is the same, the files must be equal
-                    if found.inode == fl.inode {
+                term::working_on(tr(Msg::Comparing, &[&fl.path.display(), &found.path.display()]));
+                let check = if fl.is_link_target() || found.is_link_target() {
+                    compare_link_targets(fl, found)
+                } else {
+                    compare(self, fl, found)
+                };
+                let check =
+                    check.map_err(|source| Error::Compare { lhs: fl.path.clone(), rhs: found.path.clone(), source });
+
+                match check {
+                    Ok(true) => {
                         group.push(*idx);
                         matched = true;
                         break;
                     }
-                }
-
-                // if the inode is not the same we compare the whole file
-                let pct = (*progress * 100) / total;
-                let msg = format!(
-                    "Progress: {}% --- Comparing {} vs {}",
-                    pct,
-                    &fl.path.display(),
-                    &found.path.display()
-                );
-                print_same_line(&msg, pct < 100);
-                let check = compare(self, &fl.path, &found.path);
-                if let Err(err) = check {
-                    eprintln!(
-                        "There was an error when checking file {} vs {}",
-                        &fl.path.display(),
-                        found.path.display()
-                    );
-                    eprintln!("Error: {}", err);
-                    eprintln!("Skipping file {}", &fl.path.display());
-                    errors.push(*idx);
-                    continue;
-                }
-
-                if let Ok(ck) = check {
-                    if ck {
-                        group.push(*idx);
+                    Ok(false) => {}
+                    Err(err) => {
+                        if skipped::record(&fl.path, Stage::Compare, &err) {
+                            warn!(
+                                "There was an error when checking file {} vs {}",
+                                &fl.path.display(),
+                                found.path.display()
+                            );
+                            warn!("Error: {}", err);
+                            warn!("Skipping file {}", &fl.path.display());
+                        }
+                        errors.push(*idx);
                         matched = true;
                         break;
                     }
                 }
             }
 
-            // at this stage there was a hash collision but it did not match any of the groups
-            // we then create a new group under the same hash
-
+            // the digests matched but the content did not, a genuine collision
             if !matched {
                 groups.push(vec![*idx]);
             }
         }
+        groups
+    }
 
-        let mut same: Vec<Vec<usize>> = vec![];
-        let mut unique: Vec<usize> = vec![];
-        for (_, mut value) in map.drain(..) {
-            for group in value.drain(..) {
-                match group.len() {
-                    0 => panic!("Vector cannot be empty here"),
-                    1 => unique.push(group[0]),
-                    _ => same.push(group),
-                }
-            }
+    fn pick_compare(&self, size: u64) -> fn(&mut Self, &FileInfo, &FileInfo) -> io::Result<bool> {
+        if self.mmap && size > 2 * self.read_size as u64 {
+            return Self::compare_file_mmap;
         }
-        FileSeparation { same, unique, errors }
-    }
-
-    pub fn process_files(&mut self, mut prep: Preprocessed, chunks_only: bool, verbose: bool) -> Processed {
-        let mut capa = self.bufa.capacity();
-        let mut capb = self.bufb.capacity();
-        let mut cmp: fn(&mut Self, &Path, &Path) -> io::Result<bool>;
-        let info = prep.info;
-
-        let mut progress = 0;
-        let total = prep.to_process.iter().map(|v| v.len()).sum::<usize>();
-        for dupes in prep.to_process.iter() {
-            let size = dupes
-                .first()
-                .map(|&idx| info.get(idx).map(|i| i.size).unwrap_or(0))
-                .unwrap_or(0);
-
-            let full = (!chunks_only) && (size > 2 * self.read_size as u64) && (size < self.max_file_size);
-            cmp = if full {
-                Self::compare_file_full
-            } else {
-                // - We need to check if buffers have enough size to read sequentially, since
-                // - we clear the vector when we run the full comparison
-                while self.bufa.len() < self.read_size {
-                    self.bufa.push(0);
-                }
+        Self::compare_file_seq
+    }
 
-                while self.bufb.len() < self.read_size {
-                    self.bufb.push(0);
-                }
-                Self::compare_file_seq
-            };
+    /// Compares the two files given on their own byte by byte, without hashing them, and tells
+    /// where they differ.
+    ///
+    /// With `context`, that many bytes on both sides of the difference are kept in hex.
+    pub fn process_pair(&mut self, prep: Preprocessed, context: Option<usize>) -> io::Result<Processed> {
+        let (lhs, rhs) = match &prep.info[..] {
+            [lhs, rhs] => (lhs, rhs),
+            _ => return Ok(self.process_files(prep, false)),
+        };
 
-            let mut sep = self.separate_files(dupes, &info, cmp, verbose, total, &mut progress);
-            if verbose {
-                if capa < self.bufa.capacity() {
-                    println!(
-                        "We needed to grow buffer A, additional {}",
-                        stringify_bytes(self.bufa.capacity() - capa)
-                    );
-                    capa = self.bufa.capacity();
-                    println!("Buffer A size is: {}", stringify_bytes(self.bufa.len()));
-                }
+        term::start_compare(lhs.size.max(rhs.size));
+        let open = fds::acquire(2);
+        let (file_lhs, file_rhs) = (lhs.open()?, rhs.open()?);
+        let offset = first_difference(
+            throughput::timed(file_lhs, lhs.device),
+            throughput::timed(file_rhs, rhs.device),
+            &mut self.bufa,
+            &mut self.bufb,
+        );
+        drop(open);
+        term::finish();
+        let difference = match offset? {
+            None => None,
+            Some(offset) => {
+                let start = offset.saturating_sub(context.unwrap_or(0) as u64);
+                let len = (offset - start) as usize + context.unwrap_or(0);
+                let window = |fl: &FileInfo| context.map(|_| hex_window(fl, start, len)).transpose();
+                let (lhs, rhs) = (window(lhs)?, window(rhs)?);
+                Some(Difference { offset, context_start: context.map(|_| start), lhs, rhs })
+            }
+        };
 
-                if capb < self.bufb.capacity() {
-                    println!(
-                        "We needed to grow buffer B, additional {}",
-                        stringify_bytes(self.bufb.capacity() - capb)
-                    );
-                    capb = self.bufb.capacity();
-                    println!("Buffer B size is: {}", stringify_bytes(self.bufb.len()));
+        let mut res = Processed {
+            roots: prep.roots,
+            filters: prep.filters,
+            zero: prep.zero,
+            unique: prep.unique,
+            same: prep.same,
+            ..Default::default()
+        };
+        for group in prep.to_process {
+            match difference {
+                Some(_) => res.unique.extend(group),
+                None => {
+                    res.same.push(group);
+                    res.confidence.push(Confidence::ByteCompare);
                 }
             }
+        }
+        res.info = prep.info;
+        res.difference = difference;
+        Ok(res)
+    }
 
-            prep.same.append(&mut sep.same);
-            prep.unique.append(&mut sep.unique);
-            for idx in sep.errors.drain(..) {
-                let fl = info.get(idx);
-                if fl.is_none() {
-                    eprintln!("Unable to get information for index {}", idx);
-                    continue;
-                }
+    pub fn process_files(&mut self, mut prep: Preprocessed, verbose: bool) -> Processed {
+        if verbose {
+            say!(
+                "Comparing with two buffers of {} on {} threads",
+                stringify_bytes(self.read_size),
+                self.threads
+            );
+        }
 
-                let fl = fl.unwrap();
-                eprintln!("There was an error when processing file {}", &fl.path.display());
+        let to_process = std::mem::take(&mut prep.to_process);
+        let total = to_process
+            .iter()
+            .flatten()
+            .filter_map(|idx| prep.info.get(*idx))
+            .map(|fl| fl.size)
+            .sum();
+        // a walk cut short lists too few files to be picked up again
+        let walked = !interrupt::interrupted();
+        if walked {
+            self.save_checkpoint(&prep, &FileSeparation::default(), &to_process);
+        }
+        term::start_compare(total);
+        let (found, done) = match self.threads {
+            0 | 1 => self.compare_groups(&prep, &to_process),
+            _ => self.compare_groups_parallel(&prep, &to_process),
+        };
+        term::finish();
+        prep.same.extend(found.same);
+        prep.confidence.extend(found.confidence);
+        prep.unique.extend(found.unique);
+        for (idx, hash) in found.hashes {
+            prep.info[idx].hash = Some(hash);
+        }
+        if walked {
+            self.save_checkpoint(&prep, &FileSeparation::default(), &to_process[done..]);
+        }
+        if let Some(dupes) = to_process.get(done) {
+            let size = dupes.first().and_then(|idx| prep.info.get(*idx)).map(|fl| fl.size);
+            let (total, size) = (to_process.len(), stringify_bytes(size.unwrap_or(0) as usize));
+            warn!("{}", tr(Msg::StoppedComparing, &[&done, &total, &size]));
+            let msg = format!(
+                "interrupted after comparing {} of {} groups of candidates, at files of {}",
+                done, total, size
+            );
+            warnings::record(Kind::Interrupted, None, msg);
+            if let Some(path) = &self.checkpoint {
+                eprintln!("{}", tr(Msg::ResumeWith, &[&path.display()]));
             }
         }
 
-        Processed { info, same: prep.same, zero: prep.zero, unique: prep.unique }
+        Processed {
+            roots: prep.roots,
+            filters: prep.filters,
+            info: prep.info,
+            same: prep.same,
+            confidence: prep.confidence,
+            zero: prep.zero,
+            unique: prep.unique,
+            ..Default::default()
+        }
+    }
+}
+
+fn found(group: &[usize], list: &[FileInfo]) {
+    let size = group
+        .first()
+        .and_then(|idx| list.get(*idx))
+        .map(|fl| fl.size)
+        .unwrap_or(0);
+    term::found(group.len(), size * (group.len() as u64 - 1));
+}
+
+/// `stage` unless every file of the group is the same inode, which needs no reading at all
+fn confidence(group: &[usize], list: &[FileInfo], stage: Confidence) -> Confidence {
+    let mut files = group.iter().filter_map(|idx| list.get(*idx));
+    let Some(first) = files.next() else {
+        return stage;
+    };
+
+    let same_inode = files.all(|fl| fl.device == first.device && fl.inode == first.inode);
+    match same_inode && !first.is_link_target() {
+        true => Confidence::Inode,
+        false => stage,
     }
 }
 
-fn print_same_line(s: &str, clear_line: bool) {
-    print!("{}", s);
-    let res = std::io::stdout().flush();
-    if res.is_err() {
-        panic!("Unable to print to stdout");
-    }
-    if clear_line {
-        print!("\x1B[2K");
-        print!("\r");
-    } else {
-        println!();
+/// Adds the `groups` split by the last stage, `stage`, to what `sep` found
+fn keep_groups(sep: &mut FileSeparation, groups: Vec<Vec<usize>>, list: &[FileInfo], stage: Confidence) {
+    for group in groups {
+        match group[..] {
+            [] => {}
+            [single] => sep.unique.push(single),
+            _ => {
+                found(&group, list);
+                sep.confidence.push(confidence(&group, list, stage));
+                sep.same.push(group);
+            }
+        }
+    }
+}
+
+/// Groups in the path order of their first file, members keep the order they came in
+fn sorted(mut groups: Vec<Vec<usize>>, list: &[FileInfo]) -> Vec<Vec<usize>> {
+    groups.sort_by(|a, b| list[a[0]].path.cmp(&list[b[0]].path));
+    groups
+}
+
+fn hash_error(fl: &FileInfo) -> impl FnOnce(io::Error) -> Error + '_ {
+    move |source| Error::Hash { path: fl.path.clone(), source }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::FilterSet;
+    use crate::policy::{PolicyRule, TextNormalize};
+    use crate::source::{FileSource, Memory};
+    use std::io::Cursor;
+    use std::sync::Arc;
+
+    const BUF: usize = 8;
+    const SIZES: [usize; 9] = [0, 1, BUF - 1, BUF, BUF + 1, 2 * BUF - 1, 2 * BUF, 2 * BUF + 1, 5 * BUF];
+
+    /// Hands out at most `max` bytes per read, and fails once with `Interrupted` first
+    struct ShortReader {
+        inner: Cursor<Vec<u8>>,
+        max: usize,
+        interrupted: bool,
+    }
+
+    impl ShortReader {
+        fn new(data: Vec<u8>, max: usize) -> Self {
+            Self { inner: Cursor::new(data), max, interrupted: false }
+        }
+    }
+
+    impl Read for ShortReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if !self.interrupted {
+                self.interrupted = true;
+                return Err(io::Error::from(io::ErrorKind::Interrupted));
+            }
+            let n = buf.len().min(self.max);
+            self.inner.read(&mut buf[..n])
+        }
+    }
+
+    fn data(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn listed(source: Memory) -> Vec<FileInfo> {
+        Arc::new(source).list(&FilterSet::default()).unwrap().collect()
+    }
+
+    fn same(a: impl Read, b: impl Read) -> bool {
+        let (mut bufa, mut bufb) = ([0; BUF], [0; BUF]);
+        compare_readers(a, b, &mut bufa, &mut bufb).unwrap()
+    }
+
+    #[test]
+    fn equal_content_around_the_buffer_size() {
+        for size in SIZES {
+            assert!(same(Cursor::new(data(size)), Cursor::new(data(size))), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn last_byte_differs_around_the_buffer_size() {
+        for size in SIZES.iter().copied().filter(|s| *s > 0) {
+            let mut other = data(size);
+            other[size - 1] ^= 1;
+            assert!(!same(Cursor::new(data(size)), Cursor::new(other)), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn sizes_differ_by_one_byte() {
+        for size in SIZES {
+            assert!(!same(Cursor::new(data(size)), Cursor::new(data(size + 1))), "size {}", size);
+            assert!(!same(Cursor::new(data(size + 1)), Cursor::new(data(size))), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn short_reads_are_not_mismatches() {
+        for size in SIZES {
+            for max in [1, 3, BUF - 1] {
+                let short = ShortReader::new(data(size), max);
+                assert!(same(short, Cursor::new(data(size))), "size {} max {}", size, max);
+            }
+        }
+    }
+
+    #[test]
+    fn short_reads_still_catch_a_longer_file() {
+        for size in SIZES {
+            let short = ShortReader::new(data(size + 1), 3);
+            assert!(!same(short, ShortReader::new(data(size), 5)), "size {}", size);
+        }
+    }
+
+    #[test]
+    fn files_around_the_buffer_size() {
+        let dir = std::env::temp_dir().join(format!("rcompare-cmp-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let info = |name: &str| FileInfo { path: dir.join(name), ..Default::default() };
+        let (lhs, rhs) = (info("lhs"), info("rhs"));
+
+        for mmap in [false, true] {
+            let mut cmp = Comparator::new(BUF, BUF, HashAlgorithm::Blake3);
+            cmp.mmap = mmap;
+            for size in SIZES {
+                std::fs::write(&lhs.path, data(size)).unwrap();
+                std::fs::write(&rhs.path, data(size)).unwrap();
+                let compare = cmp.pick_compare(size as u64);
+                assert!(compare(&mut cmp, &lhs, &rhs).unwrap(), "size {} mmap {}", size, mmap);
+
+                // as if the file grew after the scan
+                std::fs::write(&rhs.path, data(size + 1)).unwrap();
+                assert!(!compare(&mut cmp, &lhs, &rhs).unwrap(), "size {} mmap {}", size, mmap);
+            }
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sparse_files_compare_their_extents() {
+        let dir = std::env::temp_dir().join(format!("rcompare-sparse-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let sparse = |name: &str, writes: &[(u64, &[u8])]| {
+            let path = dir.join(name);
+            let file = File::create(&path).unwrap();
+            file.set_len(1 << 20).unwrap();
+            writes
+                .iter()
+                .for_each(|(offset, bytes)| file.write_all_at(bytes, *offset).unwrap());
+            FileInfo::from_metadata(path, &file.metadata().unwrap())
+        };
+        let lhs = sparse("lhs", &[(0, b"head"), (600_000, b"middle")]);
+        // zeros written where the other has a hole are the same content
+        let zeros = sparse("zeros", &[(0, b"head"), (300_000, &[0; 4096]), (600_000, b"middle")]);
+        let other = sparse("other", &[(0, b"head"), (600_000, b"middlf")]);
+        let tail = sparse("tail", &[(0, b"head"), (600_000, b"middle"), ((1 << 20) - 1, b"!")]);
+
+        let mut cmp = Comparator::new(BUF, BUF, HashAlgorithm::Blake3);
+        assert!(cmp.compare_file_sparse(&lhs, &zeros).unwrap());
+        assert!(!cmp.compare_file_sparse(&lhs, &other).unwrap());
+        assert!(!cmp.compare_file_sparse(&tail, &lhs).unwrap());
+
+        let sep = cmp.verify(&[lhs, zeros, other, tail], false);
+        assert_eq!(sep.same, vec![vec![0, 1]]);
+        assert_eq!(sep.unique, vec![2, 3]);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_held_in_memory() {
+        let source = Memory::new("/mem")
+            .with("a", &data(3 * BUF))
+            .with("b", &data(3 * BUF))
+            .with("c", &data(3 * BUF + 1))
+            .with("d", b"other");
+        let files = listed(source);
+        for paranoid in [false, true] {
+            let sep = Comparator::new(BUF, BUF, HashAlgorithm::Blake3).verify(&files, paranoid);
+            assert_eq!(sep.same, vec![vec![0, 1]], "paranoid {}", paranoid);
+            assert_eq!(sep.unique, vec![2, 3], "paranoid {}", paranoid);
+        }
+    }
+
+    #[test]
+    fn workers_find_the_groups_in_order() {
+        let mut source = Memory::new("/mem");
+        for n in 0..24 {
+            // four pairs of every size, the odd ones differing in the last byte
+            let mut content = data(BUF + n / 4);
+            content[0] = n as u8;
+            source = source.with(&format!("{:02}a", n), &content);
+            content[BUF + n / 4 - 1] ^= (n % 2) as u8;
+            source = source.with(&format!("{:02}b", n), &content);
+        }
+        let info = listed(source);
+        let mut groups: BTreeMap<u64, Vec<usize>> = BTreeMap::new();
+        info.iter()
+            .enumerate()
+            .for_each(|(idx, fl)| groups.entry(fl.size).or_default().push(idx));
+        let prep = Preprocessed { info, to_process: groups.into_values().collect(), ..Default::default() };
+
+        let run = |threads: usize| {
+            let mut cmp = Comparator { threads, ..Comparator::new(BUF, BUF, HashAlgorithm::Blake3) };
+            let res = cmp.process_files(prep.clone(), false);
+            (res.same, res.unique, res.confidence)
+        };
+        let sequential = run(1);
+        assert!(!sequential.0.is_empty() && !sequential.1.is_empty());
+        assert_eq!(run(4), sequential);
+    }
+
+    #[test]
+    fn files_gone_since_listed_are_errors() {
+        let mut files = listed(Memory::new("/mem").with("a", b"same").with("b", b"same"));
+        files[1].path = "/mem/gone".into();
+        let sep = Comparator::new(BUF, BUF, HashAlgorithm::Blake3).verify(&files, false);
+        assert!(sep.same.is_empty());
+        assert_eq!(sep.errors, vec![1]);
+    }
+
+    #[test]
+    fn text_normalized_line_endings() {
+        let source = Memory::new("/mem")
+            .with("lf", b"one\ntwo\n")
+            .with("crlf", b"\xef\xbb\xbfone\r\ntwo\r\n")
+            .with("blanks", b"one  \ntwo\n\n")
+            .with("bin-lf", b"a\0b\n")
+            .with("bin-crlf", b"a\0b\r\n");
+        let files = listed(source);
+        let name = |idx: &usize| files[*idx].path.file_name().unwrap().to_str().unwrap();
+        for (level, same) in [
+            (TextNormalize::LineEndings, vec!["crlf", "lf"]),
+            (TextNormalize::Whitespace, vec!["blanks", "crlf", "lf"]),
+        ] {
+            let policies = PolicyTable::new(vec![level.rule()]).unwrap();
+            let mut cmp =
+                Comparator::new(BUF, BUF, HashAlgorithm::Blake3).with_rules(IgnoreRanges::default(), policies);
+            let sep = cmp.verify(&files, true);
+            let groups: Vec<Vec<&str>> = sep.same.iter().map(|g| g.iter().map(name).collect()).collect();
+            assert_eq!(groups, vec![same], "{:?}", level);
+        }
+    }
+
+    #[test]
+    fn policies_only_apply_to_their_content_types() {
+        let png = |chunks: &[u8]| [b"\x89PNG\r\n\x1a\n".as_slice(), chunks, b"\0\0\0\0IEND\0\0\0\0"].concat();
+        let source = Memory::new("/mem")
+            .with("a.png", &png(b""))
+            .with("a.txt", b"text\n")
+            .with("b.png", &png(b"\0\0\0\x02tEXthi\0\0\0\0"))
+            .with("b.txt", b"text\r\n");
+        let files = listed(source);
+        let rule = |compare| PolicyRule { glob: "*".to_owned(), compare, ranges: None };
+        for (compare, same) in [
+            (Policy::NormalizedLineEndings, vec![vec![1, 3]]),
+            (Policy::IgnoreMetadataImage, vec![vec![0, 2]]),
+        ] {
+            let policies = PolicyTable::new(vec![rule(compare)]).unwrap();
+            let mut cmp =
+                Comparator::new(BUF, BUF, HashAlgorithm::Blake3).with_rules(IgnoreRanges::default(), policies);
+            assert_eq!(cmp.verify(&files, true).same, same, "{:?}", compare);
+        }
+    }
+
+    #[test]
+    fn first_difference_around_the_buffer_size() {
+        let (mut bufa, mut bufb) = ([0; BUF], [0; BUF]);
+        let mut diff = |a: Vec<u8>, b: Vec<u8>| first_difference(Cursor::new(a), Cursor::new(b), &mut bufa, &mut bufb);
+        for size in SIZES.iter().copied().filter(|size| *size > 0) {
+            let mut other = data(size);
+            other[size - 1] ^= 1;
+            assert_eq!(diff(data(size), other).unwrap(), Some(size as u64 - 1), "size {}", size);
+            assert_eq!(diff(data(size), data(size + 1)).unwrap(), Some(size as u64), "size {}", size);
+            assert_eq!(diff(data(size), data(size)).unwrap(), None, "size {}", size);
+        }
     }
 }
@@ -1,35 +1,405 @@
-use crate::file::{walk_dir, FileInfo};
-use fasthash::{city, RandomState};
+use crate::chunk::ChunkEstimate;
+use crate::cmp::Difference;
+use crate::diff::{Coverage, Mirror, Rename, SameName};
+use crate::dirs::{DirGroup, DirOverlap};
+use crate::empty::EmptyDirs;
+use crate::error::Error;
+use crate::file::{list_files, FileInfo};
+use crate::filter::FilterSet;
+use crate::hashing::BuildMetro;
+use crate::i18n::{tr, Msg};
+use crate::index::IndexReport;
+use crate::keep::{KeepPolicy, Role};
+use crate::policy::PolicyTable;
+use crate::regions::IgnoreRanges;
+use crate::similar::SimilarGroup;
+use crate::skipped::Skipped;
+use crate::source::{FileSource, Os};
+use crate::stats::Stats;
+use crate::throughput::BytesRead;
+use crate::volume::Volume;
+use crate::warnings::{self, Kind, Warning};
+use crate::{say, warn};
 use serde::ser::SerializeStruct;
-use serde::Serialize;
-use std::collections::HashMap;
-use std::io;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write as _};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
 use std::path;
+use std::sync::Arc;
 
 pub(crate) type VecIdx = Vec<usize>;
 
+/// How the files of a group were found equal, from the weakest to the strongest evidence
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Confidence {
+    /// only the first bytes were hashed, under `--quick`
+    SampledHash,
+    /// the digests of the whole contents matched
+    FullHash,
+    /// the contents were compared byte by byte, under `--paranoid`
+    ByteCompare,
+    /// the files are all empty
+    Empty,
+    /// the files are hard links to the same inode
+    Inode,
+}
+
+/// What the paths given on the command line are to each other
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ScanMode {
+    /// a single tree, searched for duplicates inside it
+    #[default]
+    #[serde(rename = "self")]
+    #[value(name = "self")]
+    SelfScan,
+    /// two trees, compared against each other and inside each
+    TwoRoots,
+    /// two paths to the same tree, scanned once like a single one, likely a mistake
+    #[value(skip)]
+    SameRoots,
+    /// two copies of a tree, compared file by file at the same relative paths
+    Mirror,
+}
+
+/// What the duplicate groups of the report are ordered by
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortKey {
+    /// the size of each file of the group
+    Size,
+    /// the bytes kept by all but one file of the group
+    Wasted,
+    /// the first path of the group
+    #[default]
+    Path,
+    /// how many files are in the group
+    Count,
+}
+
+/// What becomes of files which are links to the same inode
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum HardlinkMode {
+    /// they are duplicates like any other
+    #[default]
+    Duplicates,
+    /// only one link to every inode stays in the groups, the others take no space
+    Exclude,
+    /// like exclude, and the links are listed in a section of their own
+    Report,
+}
+
+/// What becomes of the empty files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ZeroMode {
+    /// they are listed apart, under `zero`
+    #[default]
+    List,
+    /// they are left out of the report
+    Omit,
+    /// they are one more group of duplicates, which the actions apply to
+    Group,
+}
+
+/// Paths to the same inode, on the same device
+#[derive(Debug, Clone, Serialize)]
+pub struct Hardlinks {
+    pub inode: u64,
+    #[serde(serialize_with = "crate::encoding::lossy_seq")]
+    pub paths: Vec<path::PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub paths_base64: Option<Vec<Option<String>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SortOrder {
+    Asc,
+    Desc,
+}
+
 #[derive(Debug, Default, Clone)]
 pub struct FileSeparation {
     pub same: Vec<VecIdx>,
+    /// parallel to `same`
+    pub confidence: Vec<Confidence>,
     pub unique: VecIdx,
     pub errors: VecIdx,
+    /// digests of the files read whole, like `blake3:af13…`
+    pub hashes: Vec<(usize, String)>,
+}
+
+impl FileSeparation {
+    /// Adds what was found in another group of candidates
+    pub fn append(&mut self, other: FileSeparation) {
+        self.same.extend(other.same);
+        self.confidence.extend(other.confidence);
+        self.unique.extend(other.unique);
+        self.errors.extend(other.errors);
+        self.hashes.extend(other.hashes);
+    }
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct Preprocessed {
+    pub roots: Vec<path::PathBuf>,
+    pub filters: FilterSet,
     pub info: Vec<FileInfo>,
     pub zero: VecIdx,
     pub unique: VecIdx,
     pub same: Vec<VecIdx>,
+    /// how each group of `same` was found equal, parallel to it, for a resumed scan
+    pub confidence: Vec<Confidence>,
     pub to_process: Vec<VecIdx>,
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct Processed {
+    pub roots: Vec<path::PathBuf>,
+    /// the roots as spelled by the user, parallel to `roots`
+    pub given_roots: Vec<path::PathBuf>,
+    pub filters: FilterSet,
     pub info: Vec<FileInfo>,
     pub zero: VecIdx,
     pub unique: VecIdx,
     pub same: Vec<VecIdx>,
+    /// how each group of `same` was found equal, parallel to it
+    pub confidence: Vec<Confidence>,
+    /// files left out of the comparison, with the reason
+    pub skipped: Vec<Skipped>,
+    /// what was said on stderr about the run as a whole
+    pub warnings: Vec<Warning>,
+    /// the scan was stopped by Ctrl-C, files not compared yet are in none of the lists
+    pub partial: bool,
+    pub chunk_estimate: Option<ChunkEstimate>,
+    pub similar_dirs: Option<Vec<DirOverlap>>,
+    /// links to the same inode taken out of the groups of `same`
+    pub hardlinks: Option<Vec<Hardlinks>>,
+    /// directories without any file below them
+    pub empty_dirs: Option<EmptyDirs>,
+    /// directories which are whole copies of each other
+    pub duplicate_dirs: Option<Vec<DirGroup>>,
+    /// files which are mostly the same content without being duplicates
+    pub similar: Option<Vec<SimilarGroup>>,
+    pub renames: Option<Vec<Rename>>,
+    /// names shared by files of different contents
+    pub same_name: Option<Vec<SameName>>,
+    pub coverage: Option<Coverage>,
+    /// the files of lhs and rhs paired by relative path, in mirror mode
+    pub mirror: Option<Mirror>,
+    pub index: Option<IndexReport>,
+    /// where the two files given on their own differ
+    pub difference: Option<Difference>,
+    /// file systems holding the files, empty when they could not be detected
+    pub volumes: Vec<Volume>,
+    /// time, files and bytes of every phase of the scan
+    pub stats: Option<Stats>,
+    /// bytes the comparisons read, out of what the files hold
+    pub bytes_read: Option<BytesRead>,
+    /// byte ranges the comparisons left out, the duplicates may differ there
+    pub ignore_ranges: Option<IgnoreRanges>,
+    /// comparison policies by glob, the duplicates may differ where they look away
+    pub policies: Option<PolicyTable>,
+    /// how the copy to keep is picked in every group of `same`
+    pub keep: Option<KeepPolicy>,
+    pub mode: ScanMode,
+    /// how many groups of `same` the report lists, the ones wasting the most
+    pub top: Option<usize>,
+    /// order of the groups in the report, groups tied on the key stay in path order
+    pub sort: SortKey,
+    pub descending: bool,
+}
+
+impl Processed {
+    /// Index of the scan root containing `path` and the path relative to it
+    pub fn locate<'p>(&self, path: &'p path::Path) -> Option<(usize, &'p path::Path)> {
+        let (idx, root) = self
+            .roots
+            .iter()
+            .enumerate()
+            .filter(|(_, root)| path.starts_with(root))
+            .max_by_key(|(_, root)| root.as_os_str().len())?;
+        path.strip_prefix(root).ok().map(|rel| (idx, rel))
+    }
+
+    /// Whether the files of the group at `idx` of `same` were found equal on their whole contents,
+    /// read as they are on disk, the only groups whose copies may be removed or linked.
+    ///
+    /// Groups found by a sample under `--quick`, or through a policy or ignored ranges which look
+    /// away from some bytes, may hold files which differ.
+    pub fn is_exact(&self, idx: usize) -> bool {
+        let exact = |fl: &FileInfo| {
+            let ignore = self.ignore_ranges.as_ref().map_or(&[][..], |r| r.for_path(&fl.path));
+            match &self.policies {
+                Some(policies) => policies.kind(&fl.path, ignore).is_empty(),
+                None => ignore.is_empty(),
+            }
+        };
+        match self.confidence.get(idx) {
+            Some(Confidence::Empty | Confidence::Inode) => true,
+            Some(Confidence::FullHash | Confidence::ByteCompare) => {
+                self.same[idx].iter().filter_map(|i| self.info.get(*i)).all(exact)
+            }
+            Some(Confidence::SampledHash) | None => false,
+        }
+    }
+
+    /// The groups of `same` which are `is_exact`, with a warning for every other one left alone
+    pub fn exact_groups(&self) -> Vec<&VecIdx> {
+        let mut groups = Vec::with_capacity(self.same.len());
+        for (idx, group) in self.same.iter().enumerate() {
+            match self.is_exact(idx) {
+                true => groups.push(group),
+                false => {
+                    let first = group.iter().filter_map(|i| self.info.get(*i)).map(|fl| &fl.path).min();
+                    if let Some(first) = first {
+                        warn!("{}", tr(Msg::InexactGroup, &[&first.display()]));
+                    }
+                }
+            }
+        }
+        groups
+    }
+
+    /// Bytes that would be reclaimed by keeping a single copy of every duplicate group
+    pub fn wasted_bytes(&self) -> u64 {
+        self.same
+            .iter()
+            .filter_map(|group| {
+                let size = self.info.get(*group.first()?)?.size;
+                Some(size * (group.len() as u64 - 1))
+            })
+            .sum()
+    }
+
+    /// Appends the files and groups of another pass over the same roots
+    pub fn merge(&mut self, other: Processed) {
+        let offset = self.info.len();
+        let shift = |v: VecIdx| -> VecIdx { v.into_iter().map(|idx| idx + offset).collect() };
+        self.info.extend(other.info);
+        self.zero.extend(shift(other.zero));
+        self.unique.extend(shift(other.unique));
+        self.same.extend(other.same.into_iter().map(shift));
+        self.confidence.extend(other.confidence);
+    }
+
+    /// Leaves a single link to every inode in the groups of `same`, a group left with one file is
+    /// unique. The paths of every inode met more than once are returned.
+    pub fn separate_hardlinks(&mut self) -> Vec<Hardlinks> {
+        let mut hardlinks = vec![];
+        let groups = std::mem::take(&mut self.same);
+        let confidence = std::mem::take(&mut self.confidence);
+        for (group, confidence) in groups.into_iter().zip(confidence) {
+            let mut inodes: Vec<VecIdx> = vec![];
+            for idx in group {
+                let fl = &self.info[idx];
+                let same_inode = |other: &VecIdx| {
+                    let first = &self.info[other[0]];
+                    !fl.is_link_target() && (first.device, first.inode) == (fl.device, fl.inode)
+                };
+                match inodes.iter_mut().find(|other| same_inode(other)) {
+                    Some(links) => links.push(idx),
+                    None => inodes.push(vec![idx]),
+                }
+            }
+
+            for links in inodes.iter().filter(|links| links.len() > 1) {
+                let paths: Vec<path::PathBuf> = links.iter().map(|idx| self.info[*idx].path.clone()).collect();
+                let paths_base64 = crate::encoding::raw_seq(&paths);
+                hardlinks.push(Hardlinks { inode: self.info[links[0]].inode, paths, paths_base64 });
+            }
+            match inodes.len() {
+                1 => self.unique.push(inodes[0][0]),
+                _ => {
+                    self.same.push(inodes.into_iter().map(|links| links[0]).collect());
+                    self.confidence.push(confidence);
+                }
+            }
+        }
+        hardlinks
+    }
+
+    /// Path of a file spelled from the user-supplied root, when it differs from the resolved one
+    pub fn given_path(&self, path: &path::Path) -> Option<path::PathBuf> {
+        let (idx, rel) = self.locate(path)?;
+        let given = self.given_roots.get(idx)?;
+        if given == &self.roots[idx] {
+            return None;
+        }
+
+        Some(given.join(rel))
+    }
+
+    /// Path of a file as seen from inside the sysroot, e.g. `/etc/hosts`
+    pub fn image_path(&self, path: &path::Path) -> Option<path::PathBuf> {
+        let sysroot = self.filters.sysroot.as_ref()?;
+        let rel = path.strip_prefix(sysroot).ok()?;
+        Some(path::Path::new("/").join(rel))
+    }
+
+    pub fn volume_of(&self, info: &FileInfo) -> Option<&Volume> {
+        self.volumes.iter().find(|v| v.dev == info.device)
+    }
+
+    /// Index in `group` of the copy to keep, when a keep policy is set
+    pub fn keeper(&self, group: &[&FileInfo]) -> Option<usize> {
+        let lhs = self.roots.first()?;
+        Some(self.keep.as_ref()?.keeper(group, lhs))
+    }
+
+    fn entries<'a>(&self, files: &[&'a FileInfo]) -> Vec<FileEntry<'a>> {
+        self.entries_with_keeper(files, None)
+    }
+
+    fn entries_with_keeper<'a>(&self, files: &[&'a FileInfo], keeper: Option<usize>) -> Vec<FileEntry<'a>> {
+        files
+            .iter()
+            .enumerate()
+            .map(|(idx, info)| FileEntry {
+                info,
+                path_base64: crate::encoding::raw_bytes(&info.path),
+                given_path: self.given_path(&info.path),
+                image_path: self.image_path(&info.path),
+                volume: self.volume_of(info).map(|v| v.name()),
+                role: keeper.map(|k| if k == idx { Role::Keeper } else { Role::Redundant }),
+            })
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct FileEntry<'a> {
+    #[serde(flatten)]
+    info: &'a FileInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path_base64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "crate::encoding::lossy_opt")]
+    given_path: Option<path::PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "crate::encoding::lossy_opt")]
+    image_path: Option<path::PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    role: Option<Role>,
+}
+
+#[derive(Debug, Serialize)]
+struct Root<'a> {
+    #[serde(serialize_with = "crate::encoding::lossy")]
+    given: &'a path::Path,
+    #[serde(serialize_with = "crate::encoding::lossy")]
+    resolved: &'a path::Path,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    given_base64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    resolved_base64: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Savings<'a> {
+    exact_duplicates: u64,
+    chunk_estimate: &'a ChunkEstimate,
 }
 
 #[derive(Debug)]
@@ -63,122 +433,424 @@ impl Serialize for Processed {
     where
         S: serde::Serializer,
     {
-        let mut state = serializer.serialize_struct("Processed", 3)?;
-        let mut same: Vec<Vec<&FileInfo>> = Vec::with_capacity(self.same.len());
-        for v in self.same.iter() {
+        let fields = 10
+            + self.partial as usize
+            + self.chunk_estimate.is_some() as usize
+            + self.similar_dirs.is_some() as usize
+            + self.duplicate_dirs.is_some() as usize
+            + self.empty_dirs.is_some() as usize
+            + self.hardlinks.is_some() as usize
+            + self.similar.is_some() as usize
+            + self.renames.is_some() as usize
+            + self.same_name.is_some() as usize
+            + self.coverage.is_some() as usize
+            + self.mirror.is_some() as usize
+            + self.index.is_some() as usize
+            + self.difference.is_some() as usize
+            + !self.volumes.is_empty() as usize
+            + self.stats.is_some() as usize
+            + self.bytes_read.is_some() as usize
+            + self.ignore_ranges.is_some() as usize
+            + self.policies.is_some() as usize
+            + self.keep.is_some() as usize;
+        let mut state = serializer.serialize_struct("Processed", fields)?;
+        let mut same: Vec<(Vec<&FileInfo>, Option<Confidence>)> = Vec::with_capacity(self.same.len());
+        for (idx, v) in self.same.iter().enumerate() {
             let mut inner = map_to_file_info(v, &self.info).map_err(serde::ser::Error::custom)?;
             inner.sort_by(|a, b| a.path.cmp(&b.path));
-            same.push(inner);
+            same.push((inner, self.confidence.get(idx).copied()));
         }
 
         let mut zero = map_to_file_info(&self.zero, &self.info).map_err(serde::ser::Error::custom)?;
         let mut unique = map_to_file_info(&self.unique, &self.info).map_err(serde::ser::Error::custom)?;
 
         // just for convenience
-        same.sort_by(|a, b| a[0].path.cmp(&b[0].path));
+        same.sort_by(|a, b| a.0[0].path.cmp(&b.0[0].path));
+        if let Some(top) = self.top {
+            // the sort is stable, groups wasting as much stay in path order
+            same.sort_by_key(|(v, _)| std::cmp::Reverse(v[0].size * (v.len() as u64 - 1)));
+            same.truncate(top);
+            same.sort_by(|a, b| a.0[0].path.cmp(&b.0[0].path));
+        }
+        let key = |v: &[&FileInfo]| match self.sort {
+            SortKey::Size => v[0].size,
+            SortKey::Wasted => v[0].size * (v.len() as u64 - 1),
+            SortKey::Path => 0,
+            SortKey::Count => v.len() as u64,
+        };
+        match (self.sort, self.descending) {
+            (SortKey::Path, true) => same.reverse(),
+            (SortKey::Path, false) => {}
+            (_, true) => same.sort_by_key(|(v, _)| std::cmp::Reverse(key(v))),
+            (_, false) => same.sort_by_key(|(v, _)| key(v)),
+        }
         zero.sort_by(|a, b| a.path.cmp(&b.path));
         unique.sort_by(|a, b| a.path.cmp(&b.path));
 
-        state.serialize_field("zero", &zero)?;
-        state.serialize_field("unique", &unique)?;
+        let roots: Vec<Root> = self
+            .roots
+            .iter()
+            .enumerate()
+            .map(|(idx, resolved)| {
+                let given = self.given_roots.get(idx).unwrap_or(resolved);
+                Root {
+                    given,
+                    resolved,
+                    given_base64: crate::encoding::raw_bytes(given),
+                    resolved_base64: crate::encoding::raw_bytes(resolved),
+                }
+            })
+            .collect();
+        let confidence: Vec<Option<Confidence>> = same.iter().map(|(_, c)| *c).collect();
+        let same: Vec<Vec<FileEntry>> = same
+            .iter()
+            .map(|(v, _)| self.entries_with_keeper(v, self.keeper(v)))
+            .collect();
+
+        state.serialize_field("version", &crate::report::SCHEMA_VERSION)?;
+        if self.partial {
+            state.serialize_field("partial", &true)?;
+        }
+        state.serialize_field("roots", &roots)?;
+        state.serialize_field("mode", &self.mode)?;
+        state.serialize_field("zero", &self.entries(&zero))?;
+        state.serialize_field("unique", &self.entries(&unique))?;
         state.serialize_field("same", &same)?;
+        state.serialize_field("confidence", &confidence)?;
+        state.serialize_field("filters", &self.filters)?;
+        state.serialize_field("skipped", &self.skipped)?;
+        state.serialize_field("warnings", &self.warnings)?;
+        if let Some(ignore_ranges) = &self.ignore_ranges {
+            state.serialize_field("ignore_ranges", ignore_ranges)?;
+        }
+        if let Some(policies) = &self.policies {
+            state.serialize_field("policies", policies)?;
+        }
+        if let Some(keep) = &self.keep {
+            state.serialize_field("keep", keep)?;
+        }
+        if let Some(chunk_estimate) = &self.chunk_estimate {
+            let savings = Savings { exact_duplicates: self.wasted_bytes(), chunk_estimate };
+            state.serialize_field("savings", &savings)?;
+        }
+        if let Some(similar_dirs) = &self.similar_dirs {
+            state.serialize_field("similar_dirs", similar_dirs)?;
+        }
+        if let Some(hardlinks) = &self.hardlinks {
+            state.serialize_field("hardlinks", hardlinks)?;
+        }
+        if let Some(empty_dirs) = &self.empty_dirs {
+            state.serialize_field("empty_dirs", empty_dirs)?;
+        }
+        if let Some(duplicate_dirs) = &self.duplicate_dirs {
+            state.serialize_field("duplicate_dirs", duplicate_dirs)?;
+        }
+        if let Some(similar) = &self.similar {
+            state.serialize_field("similar", similar)?;
+        }
+        if let Some(renames) = &self.renames {
+            state.serialize_field("renames", renames)?;
+        }
+        if let Some(same_name) = &self.same_name {
+            state.serialize_field("same_name", same_name)?;
+        }
+        if let Some(coverage) = &self.coverage {
+            state.serialize_field("coverage", coverage)?;
+        }
+        if let Some(mirror) = &self.mirror {
+            state.serialize_field("mirror", mirror)?;
+        }
+        if let Some(index) = &self.index {
+            state.serialize_field("index", index)?;
+        }
+        if let Some(difference) = &self.difference {
+            state.serialize_field("difference", difference)?;
+        }
+        if !self.volumes.is_empty() {
+            state.serialize_field("volumes", &self.volumes)?;
+        }
+        if let Some(stats) = &self.stats {
+            state.serialize_field("stats", stats)?;
+        }
+        if let Some(bytes_read) = &self.bytes_read {
+            state.serialize_field("bytes_read", bytes_read)?;
+        }
         state.end()
     }
 }
 
-pub fn preprocess<P, Q>(lhs: Option<&P>, rhs: Option<&Q>) -> io::Result<Preprocessed>
+pub fn preprocess<P, Q>(
+    lhs: Option<&P>,
+    rhs: Option<&Q>,
+    listed: Option<&[path::PathBuf]>,
+    filters: &FilterSet,
+    policies: &PolicyTable,
+    canonicalize: bool,
+    shard: Shard,
+) -> Result<Preprocessed, Error>
 where
     P: AsRef<path::Path>,
     Q: AsRef<path::Path>,
 {
-    let lpath = resolve_path(&lhs);
-    if let Err(err) = lpath {
-        eprintln!("Unable to resolve path {:?} for preprocessing", lhs.map(|x| x.as_ref()));
-        return Err(err);
+    let lpath = resolve_path(&lhs, canonicalize).inspect_err(|_| {
+        warn!("Unable to resolve path {:?} for preprocessing", lhs.map(|x| x.as_ref()));
+    })?;
+    // the second path may be a remote tree or a bucket, listed at once
+    let remote = match rhs.and_then(|rhs| crate::source::remote(rhs.as_ref())) {
+        Some(Ok(remote)) => Some(remote),
+        Some(Err(source)) => {
+            let path = rhs.map(|rhs| rhs.as_ref().to_owned()).unwrap_or_default();
+            return Err(Error::Walk { path, source });
+        }
+        None => None,
+    };
+    let rpath_buf: path::PathBuf;
+    let rpath: &path::Path = match rhs {
+        None => lpath.as_path(),
+        Some(_) if remote.is_some() => {
+            rpath_buf = remote.as_ref().unwrap().spec();
+            rpath_buf.as_path()
+        }
+        Some(_) => {
+            rpath_buf = resolve_path(&rhs, canonicalize).inspect_err(|_| {
+                warn!("Unable to resolve path {:?} for preprocessing", rhs.map(|x| x.as_ref()));
+            })?;
+            rpath_buf.as_path()
+        }
+    };
+
+    let distinct = !is_same_path(&lpath, rpath);
+    if distinct && (lpath.starts_with(rpath) || rpath.starts_with(&lpath)) {
+        let (outer, inner) = match lpath.starts_with(rpath) {
+            true => (rpath, lpath.as_path()),
+            false => (lpath.as_path(), rpath),
+        };
+        warn!("{} is inside {}, its files are listed twice", inner.display(), outer.display());
+        let msg = format!("inside {}, its files are listed twice", outer.display());
+        warnings::record(Kind::OverlappingRoots, Some(inner), msg);
     }
+    let iter_dir: Box<dyn Iterator<Item = FileInfo>> = match listed {
+        Some(paths) => Box::new(list_files(paths, filters)),
+        None => {
+            let mut sources: Vec<Arc<dyn FileSource>> = vec![Arc::new(Os::new(lpath.clone()))];
+            match remote {
+                Some(remote) => sources.push(remote),
+                None if distinct => sources.push(Arc::new(Os::new(rpath.to_owned()))),
+                None => {}
+            }
+            let mut iter: Box<dyn Iterator<Item = FileInfo>> = Box::new(std::iter::empty());
+            for source in sources {
+                let path = source.spec();
+                let listed = source.list(filters).map_err(|source| Error::Walk { path, source })?;
+                iter = Box::new(iter.chain(listed));
+            }
+            iter
+        }
+    };
 
-    let lpath = lpath.unwrap();
-    let rpath: &path::Path;
-    let rpath_buf: path::PathBuf;
-    if rhs.is_none() {
-        rpath = lpath.as_path();
-    } else {
-        let rpath_buf_res = resolve_path(&rhs);
-        if let Err(err) = rpath_buf_res {
-            eprintln!("Unable to resolve path {:?} for preprocessing", rhs.map(|x| x.as_ref()));
-            return Err(err);
+    let iter_dir: Box<dyn Iterator<Item = FileInfo>> = match filters.scan_archives {
+        true => Box::new(iter_dir.flat_map(|fl| crate::archive::with_members(fl, filters))),
+        false => iter_dir,
+    };
+
+    let mut listed: Vec<(FileInfo, Option<u64>, Option<usize>)> = vec![];
+
+    let _preprocess = crate::stats::phase(crate::stats::Phase::Preprocess, 0);
+    crate::term::start_scan();
+    for value in crate::stats::walked(iter_dir) {
+        if crate::interrupt::interrupted() {
+            break;
+        }
+        crate::term::scanned();
+        // files which may be equal must fall in the same shard
+        let (key, rule) = group_key(&value, policies);
+        if shard.holds(rule.is_some(), key) {
+            listed.push((value, key, rule));
         }
+    }
 
-        rpath_buf = rpath_buf_res.unwrap();
-        rpath = rpath_buf.as_path();
+    crate::stats::count(crate::stats::Phase::Preprocess, listed.len());
+    crate::term::finish();
+    let interrupted = crate::interrupt::interrupted();
+    if interrupted {
+        warn!("{}", tr(Msg::StoppedListing, &[&listed.len()]));
+        let msg = format!("interrupted while listing the files, after {} of them", listed.len());
+        warnings::record(Kind::Interrupted, None, msg);
+    }
+    let (met, filtered) = crate::filter::take_counts();
+    if filtered > 0 && filtered * 10 > met * 9 {
+        warn!("The filters left out {} of the {} files found", filtered, met);
+        let msg = format!("the filters left out {} of the {} files found", filtered, met);
+        warnings::record(Kind::MostlyFiltered, None, msg);
     }
 
-    let iter_lhs = walk_dir(&lpath);
-    let iter_rhs = (lpath.as_path() != rpath)
-        .then_some(walk_dir(&rpath))
+    let mut roots = vec![lpath.clone()];
+    if distinct {
+        roots.push(rpath.to_path_buf());
+    }
+    Ok(group(roots, filters, listed, interrupted))
+}
+
+/// Groups the files of an earlier listing again, once some of them changed, without walking
+/// the roots
+pub fn regroup(
+    roots: Vec<path::PathBuf>,
+    filters: &FilterSet,
+    policies: &PolicyTable,
+    info: Vec<FileInfo>,
+) -> Preprocessed {
+    let listed = info
         .into_iter()
-        .flatten();
+        .map(|fl| {
+            let (key, rule) = group_key(&fl, policies);
+            (fl, key, rule)
+        })
+        .collect();
+    group(roots, filters, listed, false)
+}
+
+/// The share of the files one pass of the scan groups
+#[derive(Debug, Clone, Copy)]
+pub struct Shard {
+    pub pass: usize,
+    pub passes: usize,
+}
+
+impl Shard {
+    pub const ALL: Shard = Shard { pass: 0, passes: 1 };
+
+    /// Whether a file of this size, or under this policy rule, is grouped in this pass.
+    ///
+    /// Empty files, with no key, all go to the first pass.
+    fn holds(&self, rule: bool, key: Option<u64>) -> bool {
+        let Some(key) = key else {
+            return self.pass == 0;
+        };
+
+        // sizes cluster around round numbers, the hash spreads them evenly
+        let hash = xxhash_rust::xxh3::xxh3_64(&[key.to_le_bytes(), [rule as u8; 8]].concat());
+        hash % self.passes as u64 == self.pass as u64
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+pub(crate) fn resolve_path<P>(path: &Option<&P>, canonicalize: bool) -> Result<path::PathBuf, Error>
+where
+    P: AsRef<path::Path>,
+{
+    let walk = |path: &path::Path| {
+        let path = path.to_path_buf();
+        move |source| Error::Walk { path, source }
+    };
+    if let &Some(p) = path {
+        if !canonicalize {
+            std::fs::symlink_metadata(p.as_ref()).map_err(walk(p.as_ref()))?;
+            return Ok(p.as_ref().to_path_buf());
+        }
+
+        return std::fs::canonicalize(p.as_ref()).map_err(walk(p.as_ref()));
+    }
+
+    let cur = std::env::current_dir()?;
+    std::fs::canonicalize(&cur).map_err(walk(&cur))
+}
+
+/// What a file is grouped by: its size, or the policy rule for files a policy may find equal at
+/// different sizes, the latter as `Some`, and no key for empty files
+fn group_key(fl: &FileInfo, policies: &PolicyTable) -> (Option<u64>, Option<usize>) {
+    let rule = policies
+        .rule_for(&fl.path)
+        .filter(|rule| policies.rules[*rule].compare.resizes());
+    let key = match (fl.size, rule) {
+        (0, _) => None,
+        (_, Some(rule)) => Some(rule as u64),
+        (size, None) => Some(size),
+    };
+    (key, rule)
+}
 
+/// Sorts the listed files out, in path order, into the empty ones, the ones alone with their key
+/// and the groups left to compare.
+///
+/// Past an interruption only the empty files are sorted out, a size met once so far may well be
+/// met again further on.
+fn group(
+    roots: Vec<path::PathBuf>,
+    filters: &FilterSet,
+    mut listed: Vec<(FileInfo, Option<u64>, Option<usize>)>,
+    interrupted: bool,
+) -> Preprocessed {
+    // a parallel walk lists the files in any order, they are grouped in path order
+    listed.sort_by(|a, b| a.0.path.cmp(&b.0.path));
     let mut unique: VecIdx = vec![];
     let mut zero_size: VecIdx = vec![];
-    let mut size_map: HashMap<u64, VecIdx, RandomState<city::Hash64>> =
-        HashMap::with_hasher(RandomState::<city::Hash64>::new());
-    let mut contents: Vec<FileInfo> = vec![];
-
-    let iter_dir = iter_lhs.chain(iter_rhs);
-    for (idx, value) in iter_dir.enumerate() {
+    let mut size_map: HashMap<u64, VecIdx, BuildMetro> = HashMap::default();
+    // files whose policy may find them equal at different sizes, by rule
+    let mut rule_map: BTreeMap<usize, VecIdx> = BTreeMap::new();
+    let mut contents: Vec<FileInfo> = Vec::with_capacity(listed.len());
+    for (value, key, rule) in listed {
+        let idx = contents.len();
         contents.push(value);
-        let value = contents.last().unwrap();
-        if value.size == 0 {
-            zero_size.push(idx);
-            continue;
+        match (key, rule) {
+            (None, _) => zero_size.push(idx),
+            (Some(rule), Some(_)) => rule_map.entry(rule as usize).or_default().push(idx),
+            (Some(size), None) => size_map.entry(size).or_default().push(idx),
         }
-        let entry = size_map.entry(value.size);
-        entry.or_default().push(idx);
+    }
+
+    if interrupted {
+        size_map.clear();
+        rule_map.clear();
     }
 
     let same: Vec<VecIdx> = vec![];
     let mut to_be_processed = same.clone();
 
-    for (_, mut value) in size_map.drain() {
+    // the hasher of the map is seeded at random, sizes and paths give an order which holds across runs
+    let mut by_size: Vec<(u64, VecIdx)> = size_map.drain().collect();
+    by_size.sort_unstable_by_key(|(size, _)| *size);
+    let by_path = |a: &usize, b: &usize| contents[*a].path.cmp(&contents[*b].path);
+    for (_, mut value) in by_size
+        .into_iter()
+        .chain(rule_map.into_iter().map(|(rule, v)| (rule as u64, v)))
+    {
+        value.sort_by(by_path);
         // if the sizes are different the files cannot be the same
-        if value.len() == 1 {
-            unique.push(value.pop().unwrap());
-            continue;
-        }
-
-        if !value.is_empty() {
-            to_be_processed.push(value);
+        match value[..] {
+            [] => {}
+            [single] => unique.push(single),
+            _ => to_be_processed.push(value),
         }
     }
 
-    let prep = Preprocessed {
+    unique.sort_by(by_path);
+    zero_size.sort_by(by_path);
+
+    Preprocessed {
+        roots,
+        filters: filters.clone(),
         info: contents,
         zero: zero_size,
         same,
         unique,
+        confidence: vec![],
         to_process: to_be_processed,
-    };
-
-    Ok(prep)
+    }
 }
 
-// ----------
-//  Internal
-// ----------
-
-fn resolve_path<P>(path: &Option<&P>) -> io::Result<path::PathBuf>
-where
-    P: AsRef<path::Path>,
-{
-    if let &Some(p) = path {
-        let path_buf = std::fs::canonicalize(p.as_ref())?;
-        return Ok(path_buf);
+/// Whether both paths lead to the same directory, even when spelled differently
+pub(crate) fn is_same_path(lhs: &path::Path, rhs: &path::Path) -> bool {
+    if lhs == rhs {
+        return true;
     }
 
-    let cur = std::env::current_dir()?;
-    let path_buf = std::fs::canonicalize(cur)?;
-    Ok(path_buf)
+    match (std::fs::metadata(lhs), std::fs::metadata(rhs)) {
+        (Ok(a), Ok(b)) => (a.dev(), a.ino()) == (b.dev(), b.ino()),
+        _ => false,
+    }
 }
 
 fn map_to_file_info<'f>(v: &[usize], info: &'f [FileInfo]) -> Result<Vec<&'f FileInfo>, ProcessedSerializationError> {
@@ -191,6 +863,37 @@ fn map_to_file_info<'f>(v: &[usize], info: &'f [FileInfo]) -> Result<Vec<&'f Fil
     Ok(inner)
 }
 
+/// Writes `contents` to `path`, or only says what would be written when `dry_run` is set
+pub(crate) fn write_file(path: &path::Path, contents: &[u8], dry_run: bool) -> io::Result<()> {
+    if dry_run {
+        say!("{}", tr(Msg::WouldWrite, &[&contents.len(), &path.display()]));
+        return Ok(());
+    }
+
+    std::fs::write(path, contents)
+}
+
+/// Prints `text` and a newline, a reader like `head` closing the pipe early is not an error
+pub(crate) fn print_stdout(text: &str) -> io::Result<()> {
+    match writeln!(io::stdout().lock(), "{}", text) {
+        Err(err) if err.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+        written => written,
+    }
+}
+
+/// Single quotes for `sh`, which keep every byte but the quote itself as it is
+pub(crate) fn shell_quote(s: &std::ffi::OsStr) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for byte in s.as_bytes() {
+        match byte {
+            b'\'' => quoted.extend_from_slice(br"'\''"),
+            _ => quoted.push(*byte),
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
 pub(crate) fn stringify_bytes(bytes: usize) -> String {
     if bytes < 1024 {
         return format!("{}B", bytes);
@@ -206,3 +909,42 @@ pub(crate) fn stringify_bytes(bytes: usize) -> String {
 
     format!("{}Gib", bytes / (1024 * 1024 * 1024))
 }
+
+pub(crate) fn parse_bytes(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let pos = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(pos);
+    let number: u64 = number.parse().map_err(|_| format!("'{}' is not a valid size", s))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1024,
+        "m" | "mb" | "mib" => 1024 * 1024,
+        "g" | "gb" | "gib" => 1024 * 1024 * 1024,
+        "t" | "tb" | "tib" => 1024u64.pow(4),
+        _ => return Err(format!("unknown size unit in '{}'", s)),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or(format!("size '{}' is too large", s))
+}
+
+pub(crate) fn parse_duration(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let pos = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(pos);
+    let number: u64 = number.parse().map_err(|_| format!("'{}' is not a valid duration", s))?;
+    let multiplier: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        "y" => 365 * 24 * 60 * 60,
+        _ => return Err(format!("unknown duration unit in '{}'", s)),
+    };
+
+    number
+        .checked_mul(multiplier)
+        .ok_or(format!("duration '{}' is too large", s))
+}
@@ -1,18 +1,116 @@
+use crate::common::{HardlinkMode, ScanMode, SortKey, ZeroMode};
+use crate::diff::{Direction, NameKey, PathMatch};
+use crate::export::DedupeAction;
+use crate::filter::FilterSet;
+use crate::hashing::HashAlgorithm;
+use crate::keep::KeepPolicy;
+use crate::policy::PolicyTable;
+use crate::regions::IgnoreRanges;
+
 pub(crate) type Key = u64;
 pub const READ_SIZE: usize = 64 * 1024;
 pub const HASH_BUF_SIZE: usize = 4 * 1024;
-pub const MAX_FILE_SIZE: u64 = 1024u64.pow(3);
+/// smallest block `--max-memory` shrinks the read size to
+pub const MIN_READ_SIZE: usize = 4 * 1024;
+pub const CHUNK_SAMPLE_FILES: usize = 1024;
+/// average chunk of the similarity sketches, small enough for documents of a few pages
+pub const SIMILAR_CHUNK_SIZE: usize = 1024;
+/// chunk keys kept per file to estimate similarity
+pub const SIMILAR_SKETCH: usize = 256;
+/// how often `--checkpoint` saves the progress of the comparison
+pub const CHECKPOINT_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
 
 #[derive(Debug)]
 pub struct Config {
     pub lhs: std::path::PathBuf,
     pub rhs: std::path::PathBuf,
-    pub output: Option<std::path::PathBuf>,
+    /// where the report goes, in the format of each, standard output when empty
+    pub output: Vec<std::path::PathBuf>,
     pub verbose: bool,
+    /// time, files and bytes of every phase of the scan, in the summary and the report
+    pub stats: bool,
     pub read_size: usize,
     pub hash_size: usize,
-    pub max_file_size: u64,
-    pub chunks_only: bool,
+    pub chunk_dedup: Option<usize>,
+    pub similar_dirs: Option<f64>,
+    pub duplicate_dirs: bool,
+    /// smallest similarity in percent of the files reported as near duplicates
+    pub similar: Option<f64>,
+    pub detect_renames: bool,
+    /// report names shared by files of different contents
+    pub same_name: Option<NameKey>,
+    pub hardlinks: HardlinkMode,
+    pub zero: ZeroMode,
+    /// how relative paths are matched in mirror mode and for `same_name`
+    pub path_match: PathMatch,
+    pub direction: Option<Direction>,
+    pub filters: FilterSet,
+    pub canonicalize: bool,
+    /// lhs and rhs as spelled by the user
+    pub given: [std::path::PathBuf; 2],
+    /// whether the paths are one tree searched for duplicates inside it, or two trees
+    pub mode: ScanMode,
+    pub append_index: Option<std::path::PathBuf>,
+    pub volume_label: Option<String>,
+    /// directory where the duplicate groups are materialized as hard links
+    pub link_farm: Option<std::path::PathBuf>,
+    /// shell command run for every duplicate group
+    pub exec_per_group: Option<String>,
+    pub hash: HashAlgorithm,
+    pub full_hash: bool,
+    /// trust the prefix hash, without reading the files whole
+    pub quick: bool,
+    pub ignore_ranges: IgnoreRanges,
+    /// comparison policies by glob, from the settings file
+    pub policies: PolicyTable,
+    pub paranoid: bool,
+    pub mmap: bool,
+    /// read the files around the page cache, see `pagecache::set_direct`
+    pub direct_io: bool,
+    /// budget for the read buffers, already applied to `read_size` and `mmap`
+    pub max_memory: Option<usize>,
+    /// walks of the trees, each one grouping a share of the sizes only
+    pub passes: usize,
+    /// where hashes are kept between runs, `None` disables the cache
+    pub cache: Option<std::path::PathBuf>,
+    /// keep hashes in extended attributes of the files instead
+    pub xattr_cache: bool,
+    /// only say what would be written or removed
+    pub dry_run: bool,
+    /// confine the run to writing its own files, see `hardening::confine`
+    pub hardened: bool,
+    /// output directory and how many reports to keep in it
+    pub keep_reports: Option<(std::path::PathBuf, usize)>,
+    /// wasted bytes above which the run fails, `Some(0)` failing on any duplicate
+    pub fail_over: Option<u64>,
+    /// how the copy to keep is picked in every duplicate group
+    pub keep: Option<KeepPolicy>,
+    /// files to compare instead of walking the paths
+    pub files_from: Option<Vec<std::path::PathBuf>>,
+    /// whether stdout gets the redundant copies, NUL-separated, instead of the report
+    pub print0: bool,
+    /// where every file is written with its hash, to check the trees against later
+    pub manifest: Option<std::path::PathBuf>,
+    /// threads walking the trees and comparing groups of candidates at once, spinning disks still
+    /// get one reader each
+    pub threads: usize,
+    /// files read at once, `None` for what the limit of open descriptors leaves
+    pub max_open_files: Option<usize>,
+    /// where the progress of the scan is saved now and then
+    pub checkpoint: Option<std::path::PathBuf>,
+    /// progress saved by an earlier run, to go on from instead of walking the trees
+    pub resume: Option<std::path::PathBuf>,
+    /// shell script cleaning up the duplicates, and what it does with them
+    pub emit_script: Option<(std::path::PathBuf, DedupeAction)>,
+    /// what `rcompare dedupe` does with the redundant copies
+    pub dedupe: Option<DedupeAction>,
+    /// bytes of hex around the first difference of two files given on their own
+    pub context: Option<usize>,
+    /// how many duplicate groups the report lists, the ones wasting the most
+    pub top: Option<usize>,
+    /// order of the duplicate groups in the report
+    pub sort: SortKey,
+    pub descending: bool,
 }
 
 impl Default for Config {
@@ -20,12 +118,59 @@ impl Default for Config {
         Self {
             lhs: std::env::current_dir().unwrap(),
             rhs: std::env::current_dir().unwrap(),
-            output: None,
+            output: vec![],
             verbose: false,
+            stats: false,
             read_size: READ_SIZE,
             hash_size: HASH_BUF_SIZE,
-            max_file_size: MAX_FILE_SIZE,
-            chunks_only: false,
+            chunk_dedup: None,
+            similar_dirs: None,
+            duplicate_dirs: false,
+            similar: None,
+            detect_renames: false,
+            same_name: None,
+            path_match: PathMatch::default(),
+            hardlinks: HardlinkMode::default(),
+            zero: ZeroMode::default(),
+            direction: None,
+            filters: FilterSet::default(),
+            canonicalize: true,
+            given: [std::env::current_dir().unwrap(), std::env::current_dir().unwrap()],
+            mode: ScanMode::SelfScan,
+            append_index: None,
+            volume_label: None,
+            link_farm: None,
+            exec_per_group: None,
+            hash: HashAlgorithm::default(),
+            full_hash: false,
+            quick: false,
+            ignore_ranges: IgnoreRanges::default(),
+            policies: PolicyTable::default(),
+            paranoid: false,
+            mmap: true,
+            direct_io: false,
+            max_memory: None,
+            passes: 1,
+            cache: None,
+            xattr_cache: false,
+            dry_run: false,
+            hardened: false,
+            keep_reports: None,
+            fail_over: None,
+            keep: None,
+            emit_script: None,
+            dedupe: None,
+            files_from: None,
+            print0: false,
+            context: None,
+            manifest: None,
+            threads: 1,
+            max_open_files: None,
+            checkpoint: None,
+            resume: None,
+            top: None,
+            sort: Default::default(),
+            descending: false,
         }
     }
 }
@@ -0,0 +1,75 @@
+/// What a file holds, told by its first bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ContentType {
+    Text,
+    Image,
+    /// zip, tar and compressed streams
+    Archive,
+    Binary,
+}
+
+impl ContentType {
+    /// Classifies content by the magic bytes at its start, `head` being its first block.
+    ///
+    /// Content without a known signature is text unless it has NUL bytes or too many control
+    /// characters, like the heuristics of `git` and `file`.
+    pub fn detect(head: &[u8]) -> Self {
+        if IMAGES.iter().any(|magic| has_magic(head, magic)) || is_isobmff_image(head) {
+            return ContentType::Image;
+        }
+        if ARCHIVES.iter().any(|magic| has_magic(head, magic)) {
+            return ContentType::Archive;
+        }
+
+        let controls = head
+            .iter()
+            .filter(|b| b.is_ascii_control() && !matches!(b, b'\t' | b'\n' | b'\r' | b'\x0c' | b'\x1b'))
+            .count();
+        match head.contains(&0) || controls * 10 > head.len() {
+            true => ContentType::Binary,
+            false => ContentType::Text,
+        }
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// A signature, every part being bytes expected at an offset
+type Magic = &'static [(usize, &'static [u8])];
+
+const IMAGES: [Magic; 7] = [
+    &[(0, b"\x89PNG\r\n\x1a\n")],
+    &[(0, b"\xff\xd8\xff")],
+    &[(0, b"GIF87a")],
+    &[(0, b"GIF89a")],
+    &[(0, b"II*\0")],
+    &[(0, b"MM\0*")],
+    &[(0, b"RIFF"), (8, b"WEBP")],
+];
+
+const ARCHIVES: [Magic; 9] = [
+    &[(0, b"PK\x03\x04")],
+    &[(0, b"PK\x05\x06")],
+    &[(0, b"\x1f\x8b")],
+    // the block size digit goes between the header and the magic of the first block
+    &[(0, b"BZh"), (4, b"1AY&SY")],
+    &[(0, b"\xfd7zXZ\0")],
+    &[(0, b"\x28\xb5\x2f\xfd")],
+    &[(0, b"7z\xbc\xaf\x27\x1c")],
+    &[(0, b"Rar!\x1a\x07")],
+    &[(257, b"ustar")],
+];
+
+fn has_magic(head: &[u8], magic: Magic) -> bool {
+    magic
+        .iter()
+        .all(|(offset, bytes)| head.get(*offset..offset + bytes.len()) == Some(*bytes))
+}
+
+/// HEIF and AVIF images, ISO media files whose brand is an image one
+fn is_isobmff_image(head: &[u8]) -> bool {
+    head.get(4..8) == Some(b"ftyp")
+        && matches!(head.get(8..12), Some(b"heic" | b"heix" | b"mif1" | b"msf1" | b"avif" | b"avis"))
+}
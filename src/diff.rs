@@ -0,0 +1,360 @@
+use crate::common::Processed;
+use crate::warn;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Rename {
+    #[serde(serialize_with = "crate::encoding::lossy")]
+    pub from: PathBuf,
+    #[serde(serialize_with = "crate::encoding::lossy")]
+    pub to: PathBuf,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from_base64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to_base64: Option<String>,
+}
+
+/// Finds files that disappeared from lhs and appeared in rhs under a different relative path
+/// with identical content.
+///
+/// Only meaningful with two distinct roots; a single root never reports renames.
+pub fn detect_renames(res: &Processed) -> Vec<Rename> {
+    if res.roots.len() < 2 {
+        return vec![];
+    }
+
+    let mut relative: [HashSet<&Path>; 2] = [HashSet::new(), HashSet::new()];
+    for fl in res.info.iter() {
+        if let Some((root, rel)) = res.locate(&fl.path) {
+            relative[root].insert(rel);
+        }
+    }
+
+    let mut renames: Vec<Rename> = vec![];
+    for group in res.same.iter() {
+        let mut removed: Vec<&Path> = vec![];
+        let mut added: Vec<&Path> = vec![];
+        let mut size = 0;
+        for fl in group.iter().filter_map(|idx| res.info.get(*idx)) {
+            size = fl.size;
+            match res.locate(&fl.path) {
+                Some((0, rel)) if !relative[1].contains(rel) => removed.push(&fl.path),
+                Some((1, rel)) if !relative[0].contains(rel) => added.push(&fl.path),
+                _ => continue,
+            }
+        }
+
+        removed.sort();
+        added.sort();
+        for (from, to) in removed.into_iter().zip(added) {
+            renames.push(Rename {
+                from: from.to_owned(),
+                to: to.to_owned(),
+                size,
+                from_base64: crate::encoding::raw_bytes(from),
+                to_base64: crate::encoding::raw_bytes(to),
+            });
+        }
+    }
+
+    renames.sort_by(|a, b| a.from.cmp(&b.from));
+    renames
+}
+
+/// Unicode normalization form of the paths, macOS spells names decomposed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnicodeForm {
+    Nfc,
+    Nfd,
+}
+
+/// How relative paths are matched across trees, in mirror mode and for `--same-name`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PathMatch {
+    pub normalize: Option<UnicodeForm>,
+    pub ignore_case: bool,
+}
+
+impl PathMatch {
+    /// The path as matched against the others, paths which are not valid UTF-8 are kept as they are
+    pub fn key<'p>(&self, path: &'p Path) -> Cow<'p, Path> {
+        let Some(s) = path.to_str().filter(|_| self.normalize.is_some() || self.ignore_case) else {
+            return Cow::Borrowed(path);
+        };
+
+        let mut key: String = match self.normalize {
+            Some(UnicodeForm::Nfc) => s.nfc().collect(),
+            Some(UnicodeForm::Nfd) => s.nfd().collect(),
+            None => s.to_owned(),
+        };
+        if self.ignore_case {
+            key = key.to_lowercase();
+        }
+        Cow::Owned(PathBuf::from(key))
+    }
+}
+
+/// What makes two files the same file for `--same-name`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum NameKey {
+    /// the file name, wherever the file is
+    #[default]
+    Name,
+    /// the path below the root
+    Path,
+}
+
+/// Files going by the same name with different contents, likely stale copies of one another
+#[derive(Debug, Clone, Serialize)]
+pub struct SameName {
+    #[serde(serialize_with = "crate::encoding::lossy")]
+    pub name: PathBuf,
+    /// the files holding each content, the most recently modified content first
+    #[serde(serialize_with = "crate::encoding::lossy_groups")]
+    pub versions: Vec<Vec<PathBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name_base64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub versions_base64: Option<Vec<Vec<Option<String>>>>,
+}
+
+/// Finds the names shared by files of more than one content, the inverse of the duplicate groups.
+///
+/// Files are told apart by the groups of `same`, every file outside them being a content of its
+/// own, so this runs before mirror mode clears the groups.
+pub fn same_name(res: &Processed, key: NameKey, matcher: PathMatch) -> Vec<SameName> {
+    let mut content: HashMap<usize, usize> = HashMap::new();
+    for (group, files) in res.same.iter().enumerate() {
+        content.extend(files.iter().map(|idx| (*idx, group)));
+    }
+    // empty files are all the same content, numbered after the groups
+    content.extend(res.zero.iter().map(|idx| (*idx, res.same.len())));
+
+    let mut by_name: HashMap<Cow<Path>, Vec<usize>> = HashMap::new();
+    for (idx, fl) in res.info.iter().enumerate() {
+        let name = match key {
+            NameKey::Name => fl.path.file_name().map(Path::new),
+            NameKey::Path => res.locate(&fl.path).map(|(_, rel)| rel),
+        };
+        if let Some(name) = name.filter(|name| !name.as_os_str().is_empty()) {
+            by_name.entry(matcher.key(name)).or_default().push(idx);
+        }
+    }
+
+    let mut result: Vec<SameName> = vec![];
+    for (_, files) in by_name {
+        // the name as spelled by the first file
+        let first = &res.info[files[0]].path;
+        let name = match key {
+            NameKey::Name => first.file_name().map(Path::new),
+            NameKey::Path => res.locate(first).map(|(_, rel)| rel),
+        };
+        let name = name.unwrap_or(first).to_owned();
+        // files outside the groups are numbered after them and the empty files
+        let mut versions: HashMap<usize, Vec<usize>> = HashMap::new();
+        for idx in files {
+            let version = content.get(&idx).copied().unwrap_or(res.same.len() + 1 + idx);
+            versions.entry(version).or_default().push(idx);
+        }
+        if versions.len() < 2 {
+            continue;
+        }
+
+        let newest = |files: &Vec<usize>| files.iter().map(|idx| res.info[*idx].mtime).max();
+        let mut versions: Vec<Vec<usize>> = versions.into_values().collect();
+        versions.sort_by(|a, b| newest(b).cmp(&newest(a)).then_with(|| a.cmp(b)));
+        let versions: Vec<Vec<PathBuf>> = versions
+            .into_iter()
+            .map(|files| {
+                let mut paths: Vec<PathBuf> = files.iter().map(|idx| res.info[*idx].path.clone()).collect();
+                paths.sort();
+                paths
+            })
+            .collect();
+        result.push(SameName {
+            name_base64: crate::encoding::raw_bytes(&name),
+            versions_base64: crate::encoding::raw_groups(&versions),
+            name,
+            versions,
+        });
+    }
+
+    result.sort_by(|a, b| a.name.cmp(&b.name));
+    result
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Direction {
+    /// lhs and rhs must contain each other
+    Both,
+    /// rhs must contain everything in lhs
+    LhsToRhs,
+    /// lhs must contain everything in rhs
+    RhsToLhs,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Coverage {
+    pub direction: Direction,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "crate::encoding::lossy_vec")]
+    pub missing_in_rhs: Option<Vec<PathBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "crate::encoding::lossy_vec")]
+    pub missing_in_lhs: Option<Vec<PathBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing_in_rhs_base64: Option<Vec<Option<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub missing_in_lhs_base64: Option<Vec<Option<String>>>,
+}
+
+impl Coverage {
+    pub fn is_complete(&self) -> bool {
+        let empty = |v: &Option<Vec<PathBuf>>| v.as_ref().map(|v| v.is_empty()).unwrap_or(true);
+        empty(&self.missing_in_rhs) && empty(&self.missing_in_lhs)
+    }
+}
+
+/// Lists the files of one root without a content-equal copy in the other, as requested by `direction`
+pub fn check_coverage(res: &Processed, direction: Direction) -> Coverage {
+    // for every file, the roots holding a copy of its content
+    let mut present: Vec<[bool; 2]> = vec![[false; 2]; res.info.len()];
+    let groups = res
+        .same
+        .iter()
+        .map(|v| v.as_slice())
+        .chain(res.unique.chunks(1))
+        .chain(std::iter::once(res.zero.as_slice()));
+
+    for group in groups {
+        let mut roots = [false; 2];
+        for idx in group.iter() {
+            if let Some((root, _)) = res.info.get(*idx).and_then(|fl| res.locate(&fl.path)) {
+                roots[root.min(1)] = true;
+            }
+        }
+
+        for idx in group.iter() {
+            if let Some(p) = present.get_mut(*idx) {
+                *p = roots;
+            }
+        }
+    }
+
+    let missing = |from: usize, to: usize| -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = res
+            .info
+            .iter()
+            .zip(present.iter())
+            .filter(|(fl, roots)| res.locate(&fl.path).map(|(r, _)| r == from).unwrap_or(false) && !roots[to])
+            .map(|(fl, _)| fl.path.clone())
+            .collect();
+        paths.sort();
+        paths
+    };
+
+    let check_rhs = matches!(direction, Direction::Both | Direction::LhsToRhs);
+    let check_lhs = matches!(direction, Direction::Both | Direction::RhsToLhs);
+    let missing_in_rhs = check_rhs.then(|| missing(0, 1));
+    let missing_in_lhs = check_lhs.then(|| missing(1, 0));
+    Coverage {
+        direction,
+        missing_in_rhs_base64: missing_in_rhs.as_deref().and_then(crate::encoding::raw_seq),
+        missing_in_lhs_base64: missing_in_lhs.as_deref().and_then(crate::encoding::raw_seq),
+        missing_in_rhs,
+        missing_in_lhs,
+    }
+}
+
+/// How two copies of a tree differ, pairing their files by relative path like `diff -rq`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Mirror {
+    /// relative paths only found in rhs
+    pub added: Vec<String>,
+    /// relative paths only found in lhs
+    pub removed: Vec<String>,
+    /// relative paths found in both with different contents
+    pub changed: Vec<String>,
+    pub unchanged: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub added_base64: Option<Vec<Option<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub removed_base64: Option<Vec<Option<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub changed_base64: Option<Vec<Option<String>>>,
+}
+
+impl Mirror {
+    pub fn is_identical(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Pairs the files of lhs and rhs by relative path and compares each pair.
+///
+/// The contents were already grouped by the scan, two files of a pair are the same when they
+/// fell in the same group, so only files with a candidate of the same size were ever read. Paths
+/// are matched through `matcher`, the report spells them as lhs does.
+pub fn mirror_diff(res: &Processed, matcher: PathMatch) -> Mirror {
+    // for every file, an id shared by the files of the same content
+    let mut content: Vec<usize> = (0..res.info.len()).collect();
+    let groups = res
+        .same
+        .iter()
+        .map(|v| v.as_slice())
+        .chain(std::iter::once(res.zero.as_slice()));
+    for (n, group) in groups.enumerate() {
+        for idx in group.iter() {
+            if let Some(id) = content.get_mut(*idx) {
+                *id = res.info.len() + n;
+            }
+        }
+    }
+
+    let mut pairs: BTreeMap<Cow<Path>, [Option<usize>; 2]> = BTreeMap::new();
+    for (idx, fl) in res.info.iter().enumerate() {
+        let Some((root, rel)) = res.locate(&fl.path) else {
+            continue;
+        };
+        let side = &mut pairs.entry(matcher.key(rel)).or_default()[root.min(1)];
+        match side {
+            Some(other) => warn!(
+                "{} and {} are the same path once normalized, only the first is compared",
+                res.info[*other].path.display(),
+                fl.path.display()
+            ),
+            None => *side = Some(idx),
+        }
+    }
+
+    let (mut added, mut removed, mut changed): (Vec<&Path>, Vec<&Path>, Vec<&Path>) = (vec![], vec![], vec![]);
+    let mut unchanged = 0;
+    for pair in pairs.into_values() {
+        let spelled = pair[0].or(pair[1]).and_then(|idx| res.locate(&res.info[idx].path));
+        let rel = spelled.map(|(_, rel)| rel).unwrap_or(Path::new(""));
+        match pair {
+            [Some(_), None] => removed.push(rel),
+            [None, Some(_)] => added.push(rel),
+            [Some(l), Some(r)] if content[l] == content[r] => unchanged += 1,
+            [Some(_), Some(_)] => changed.push(rel),
+            [None, None] => {}
+        }
+    }
+
+    let lossy = |paths: &[&Path]| paths.iter().map(|p| p.to_string_lossy().into_owned()).collect();
+    Mirror {
+        added: lossy(&added),
+        removed: lossy(&removed),
+        changed: lossy(&changed),
+        unchanged,
+        added_base64: crate::encoding::raw_seq(&added),
+        removed_base64: crate::encoding::raw_seq(&removed),
+        changed_base64: crate::encoding::raw_seq(&changed),
+    }
+}
@@ -0,0 +1,322 @@
+use crate::common::Processed;
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DirOverlap {
+    #[serde(serialize_with = "crate::encoding::lossy")]
+    pub lhs: PathBuf,
+    #[serde(serialize_with = "crate::encoding::lossy")]
+    pub rhs: PathBuf,
+    pub lhs_bytes: u64,
+    pub rhs_bytes: u64,
+    pub shared_bytes: u64,
+    /// shared bytes as a percentage of the larger directory
+    pub overlap: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lhs_base64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rhs_base64: Option<String>,
+}
+
+/// Finds pairs of directories sharing at least `threshold` percent of their content by bytes.
+///
+/// A directory's content is everything below it. Pairs where one directory contains the other
+/// are ignored, and so are pairs nested inside another reported pair, which keeps the report
+/// to the largest common subtrees.
+pub fn similar_directories(res: &Processed, threshold: f64) -> Vec<DirOverlap> {
+    let mut dir_bytes: HashMap<&Path, u64> = HashMap::new();
+    for fl in res.info.iter() {
+        for dir in ancestors(&fl.path, &res.roots) {
+            *dir_bytes.entry(dir).or_default() += fl.size;
+        }
+    }
+
+    let mut shared: HashMap<(&Path, &Path), u64> = HashMap::new();
+    for group in res.same.iter() {
+        let size = match group.first().and_then(|idx| res.info.get(*idx)) {
+            Some(fl) => fl.size,
+            None => continue,
+        };
+
+        // the copies below every directory, and the folders holding them
+        let mut counts: HashMap<&Path, u64> = HashMap::new();
+        let mut own: Vec<&Path> = vec![];
+        for fl in group.iter().filter_map(|idx| res.info.get(*idx)) {
+            let mut dirs = ancestors(&fl.path, &res.roots).peekable();
+            own.extend(dirs.peek());
+            for dir in dirs {
+                *counts.entry(dir).or_default() += 1;
+            }
+        }
+        own.sort_unstable();
+        own.dedup();
+
+        // two directories share copies when each holds one in a folder of its own, so the pairs
+        // come from the pairs of those folders and their parents, below the first one they share
+        let mut pairs: HashSet<(&Path, &Path)> = HashSet::new();
+        for (i, a) in own.iter().enumerate() {
+            for b in own[i + 1..].iter() {
+                for pa in ancestors_or_self(a, &res.roots) {
+                    if b.starts_with(pa) {
+                        break;
+                    }
+                    for pb in ancestors_or_self(b, &res.roots) {
+                        if pa.starts_with(pb) {
+                            break;
+                        }
+                        pairs.insert(if pa < pb { (pa, pb) } else { (pb, pa) });
+                    }
+                }
+            }
+        }
+        for (a, b) in pairs {
+            *shared.entry((a, b)).or_default() += size * counts[a].min(counts[b]);
+        }
+    }
+
+    let mut overlaps: HashMap<(&Path, &Path), DirOverlap> = HashMap::new();
+    for ((a, b), shared_bytes) in shared.into_iter() {
+        let lhs_bytes = dir_bytes.get(a).copied().unwrap_or(0);
+        let rhs_bytes = dir_bytes.get(b).copied().unwrap_or(0);
+        let overlap = 100.0 * shared_bytes as f64 / lhs_bytes.max(rhs_bytes).max(1) as f64;
+        if overlap < threshold {
+            continue;
+        }
+
+        let item = DirOverlap {
+            lhs: a.to_owned(),
+            rhs: b.to_owned(),
+            lhs_bytes,
+            rhs_bytes,
+            shared_bytes,
+            overlap,
+            lhs_base64: crate::encoding::raw_bytes(a),
+            rhs_base64: crate::encoding::raw_bytes(b),
+        };
+        overlaps.insert((a, b), item);
+    }
+
+    let mut result: Vec<DirOverlap> = overlaps
+        .iter()
+        .filter(|((a, b), _)| {
+            // drop the pair if it is nested inside another reported pair, up to the folder both share
+            let covered = a.ancestors().take_while(|pa| !b.starts_with(pa)).any(|pa| {
+                b.ancestors().take_while(|pb| !pa.starts_with(pb)).any(|pb| {
+                    let same = (pa, pb) == (*a, *b);
+                    !same && (overlaps.contains_key(&(pa, pb)) || overlaps.contains_key(&(pb, pa)))
+                })
+            });
+            !covered
+        })
+        .map(|(_, item)| item.clone())
+        .collect();
+
+    result.sort_by(|a, b| {
+        b.shared_bytes
+            .cmp(&a.shared_bytes)
+            .then_with(|| (&a.lhs, &a.rhs).cmp(&(&b.lhs, &b.rhs)))
+    });
+    result
+}
+
+/// Directories holding the same files at the same relative paths, each a copy of the others
+#[derive(Debug, Clone, Serialize)]
+pub struct DirGroup {
+    #[serde(serialize_with = "crate::encoding::lossy_seq")]
+    pub dirs: Vec<PathBuf>,
+    /// files below each of the directories
+    pub files: usize,
+    /// bytes below each of the directories
+    pub bytes: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dirs_base64: Option<Vec<Option<String>>>,
+}
+
+/// Finds directories whose whole content is identical, the same relative paths to the same
+/// content, among the files of the scan.
+///
+/// Directories are told apart bottom up by the names and the contents of their files and the
+/// classes of their subdirectories. Groups inside a reported group, like the subdirectories of two
+/// copies, are left out, and so are directories without any file below them.
+pub fn duplicate_directories(res: &Processed) -> Vec<DirGroup> {
+    let mut content: HashMap<usize, Content> = HashMap::new();
+    for (group, files) in res.same.iter().enumerate() {
+        content.extend(files.iter().map(|idx| (*idx, Content::Group(group))));
+    }
+    content.extend(res.zero.iter().map(|idx| (*idx, Content::Empty)));
+
+    let mut entries: HashMap<&Path, Vec<(&OsStr, Entry)>> = HashMap::new();
+    let mut totals: HashMap<&Path, (usize, u64)> = HashMap::new();
+    for (idx, fl) in res.info.iter().enumerate() {
+        let mut dirs = ancestors(&fl.path, &res.roots).peekable();
+        let (Some(parent), Some(name)) = (dirs.peek(), fl.path.file_name()) else {
+            continue;
+        };
+        let file = content.get(&idx).copied().unwrap_or(Content::Unique(idx));
+        entries.entry(parent).or_default().push((name, Entry::File(file)));
+        for dir in dirs {
+            let total = totals.entry(dir).or_default();
+            total.0 += 1;
+            total.1 += fl.size;
+        }
+    }
+
+    // deepest first, every directory is classed before its parent
+    let mut dirs: Vec<&Path> = totals.keys().copied().collect();
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    let mut classes: HashMap<Vec<(&OsStr, Entry)>, usize> = HashMap::new();
+    let mut class_of: HashMap<&Path, usize> = HashMap::new();
+    for dir in dirs.iter().copied() {
+        let mut listing = entries.remove(dir).unwrap_or_default();
+        listing.sort_unstable();
+        let next = classes.len();
+        let class = *classes.entry(listing).or_insert(next);
+        class_of.insert(dir, class);
+        if let (Some(parent), Some(name)) = (dir.parent(), dir.file_name()) {
+            if totals.contains_key(parent) {
+                entries.entry(parent).or_default().push((name, Entry::Dir(class)));
+            }
+        }
+    }
+
+    let mut by_class: HashMap<usize, Vec<&Path>> = HashMap::new();
+    for dir in dirs.iter().copied() {
+        by_class.entry(class_of[dir]).or_default().push(dir);
+    }
+    by_class.retain(|_, dirs| dirs.len() > 1);
+
+    let mut groups: Vec<DirGroup> = by_class
+        .values()
+        .filter(|dirs| {
+            // the copies of a reported parent have the same children
+            let parents: Vec<Option<usize>> = dirs
+                .iter()
+                .map(|dir| dir.parent().and_then(|p| class_of.get(p)).copied())
+                .collect();
+            let distinct: HashSet<Option<&Path>> = dirs.iter().map(|dir| dir.parent()).collect();
+            let covered =
+                distinct.len() == dirs.len() && parents[0].is_some() && parents.iter().all(|p| *p == parents[0]);
+            !covered
+        })
+        .map(|dirs| {
+            let (files, bytes) = totals[dirs[0]];
+            let mut dirs: Vec<PathBuf> = dirs.iter().map(|dir| dir.to_path_buf()).collect();
+            dirs.sort();
+            DirGroup { dirs_base64: crate::encoding::raw_seq(&dirs), dirs, files, bytes }
+        })
+        .collect();
+
+    groups.sort_by(|a, b| {
+        let wasted = |g: &DirGroup| g.bytes * (g.dirs.len() as u64 - 1);
+        wasted(b).cmp(&wasted(a)).then_with(|| a.dirs.cmp(&b.dirs))
+    });
+    groups
+}
+
+/// Space taken below a directory, with every content counted once
+#[derive(Debug, Clone, Serialize)]
+pub struct DirUsage {
+    #[serde(serialize_with = "crate::encoding::lossy")]
+    pub path: PathBuf,
+    /// the sizes of all the files, as `du --apparent-size` counts them
+    pub apparent: u64,
+    /// what would be left with a single copy of every duplicate
+    pub unique: u64,
+    pub duplicated: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_base64: Option<String>,
+}
+
+/// Usage of every directory of the scan, sorted by path and at most `max_depth` below the roots
+pub fn disk_usage(res: &Processed, max_depth: Option<usize>) -> Vec<DirUsage> {
+    let mut apparent: HashMap<&Path, u64> = HashMap::new();
+    for fl in res.info.iter() {
+        for dir in ancestors(&fl.path, &res.roots) {
+            *apparent.entry(dir).or_default() += fl.size;
+        }
+    }
+
+    // every copy past the first one below a directory is duplicated there
+    let mut duplicated: HashMap<&Path, u64> = HashMap::new();
+    for group in res.same.iter() {
+        let Some(size) = group.first().and_then(|idx| res.info.get(*idx)).map(|fl| fl.size) else {
+            continue;
+        };
+
+        let mut counts: HashMap<&Path, u64> = HashMap::new();
+        for fl in group.iter().filter_map(|idx| res.info.get(*idx)) {
+            for dir in ancestors(&fl.path, &res.roots) {
+                *counts.entry(dir).or_default() += 1;
+            }
+        }
+        for (dir, count) in counts {
+            *duplicated.entry(dir).or_default() += size * (count - 1);
+        }
+    }
+
+    let depth = |dir: &Path| {
+        let root = res
+            .roots
+            .iter()
+            .filter(|r| dir.starts_with(r))
+            .map(|r| r.components().count())
+            .max();
+        dir.components().count() - root.unwrap_or(0)
+    };
+    let mut usage: Vec<DirUsage> = apparent
+        .into_iter()
+        .filter(|(dir, _)| max_depth.map(|max| depth(dir) <= max).unwrap_or(true))
+        .map(|(dir, apparent)| {
+            let duplicated = duplicated.get(dir).copied().unwrap_or(0);
+            DirUsage {
+                path: dir.to_owned(),
+                apparent,
+                unique: apparent - duplicated,
+                duplicated,
+                path_base64: crate::encoding::raw_bytes(dir),
+            }
+        })
+        .collect();
+    usage.sort_by(|a, b| a.path.cmp(&b.path));
+    usage
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// What a file holds, as far as the comparison told
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Content {
+    Empty,
+    /// the index of its group of `same`
+    Group(usize),
+    /// the index of the file, like no other
+    Unique(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+enum Entry {
+    File(Content),
+    /// the class of the subdirectory
+    Dir(usize),
+}
+
+/// `dir` and the directories containing it, up to the scan root it belongs to
+fn ancestors_or_self<'p>(dir: &'p Path, roots: &'p [PathBuf]) -> impl Iterator<Item = &'p Path> {
+    std::iter::once(dir).chain(ancestors(dir, roots))
+}
+
+/// Directories containing `path`, up to and including the scan root it belongs to
+fn ancestors<'p>(path: &'p Path, roots: &'p [PathBuf]) -> impl Iterator<Item = &'p Path> {
+    let root = roots
+        .iter()
+        .filter(|r| path.starts_with(r))
+        .max_by_key(|r| r.as_os_str().len());
+    path.ancestors()
+        .skip(1)
+        .take_while(move |dir| root.map(|r| dir.starts_with(r)).unwrap_or(false))
+}
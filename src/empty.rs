@@ -0,0 +1,95 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Directories met by the walk holding nothing but directories, with how many
+static LISTED: Mutex<Vec<(PathBuf, usize)>> = Mutex::new(vec![]);
+
+/// Directories without a single file below them
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct EmptyDirs {
+    /// the topmost empty directories, removing them removes every empty one
+    #[serde(serialize_with = "crate::encoding::lossy_seq")]
+    pub dirs: Vec<PathBuf>,
+    /// empty directories laid out the same way below, like copies of a project skeleton
+    #[serde(serialize_with = "crate::encoding::lossy_groups")]
+    pub same: Vec<Vec<PathBuf>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dirs_base64: Option<Vec<Option<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub same_base64: Option<Vec<Vec<Option<String>>>>,
+}
+
+/// Records a directory the walk listed whole, which held `dirs` subdirectories and nothing else
+pub(crate) fn record(path: &Path, dirs: usize) {
+    LISTED.lock().unwrap().push((path.to_path_buf(), dirs));
+}
+
+/// The directories recorded so far, a directory met again by another pass is listed once
+pub fn take() -> Vec<(PathBuf, usize)> {
+    let mut listed = std::mem::take(&mut *LISTED.lock().unwrap());
+    listed.sort();
+    listed.dedup();
+    listed
+}
+
+/// Finds the directories of `listed` which have no file below them, leaving out the roots.
+///
+/// A directory whose subdirectories were not all walked, being excluded or too deep, is not taken
+/// for empty.
+pub fn empty_directories(listed: Vec<(PathBuf, usize)>, roots: &[PathBuf]) -> EmptyDirs {
+    let dirs: HashMap<&Path, usize> = listed.iter().map(|(path, dirs)| (path.as_path(), *dirs)).collect();
+    let mut order: Vec<&Path> = dirs.keys().copied().collect();
+    order.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+
+    // every empty directory is classed by the names and the classes of its subdirectories
+    let mut kids: HashMap<&Path, Vec<(OsString, usize)>> = HashMap::new();
+    let mut classes: HashMap<Vec<(OsString, usize)>, usize> = HashMap::new();
+    let mut class_of: HashMap<&Path, usize> = HashMap::new();
+    for dir in order {
+        let mut layout = kids.remove(dir).unwrap_or_default();
+        if layout.len() != dirs[dir] {
+            continue;
+        }
+
+        layout.sort();
+        let next = classes.len();
+        let class = *classes.entry(layout).or_insert(next);
+        class_of.insert(dir, class);
+        if let (Some(parent), Some(name)) = (dir.parent(), dir.file_name()) {
+            kids.entry(parent).or_default().push((name.to_owned(), class));
+        }
+    }
+
+    let is_root = |dir: &Path| roots.iter().any(|root| root == dir);
+    let mut top: Vec<&Path> = class_of
+        .keys()
+        .copied()
+        .filter(|dir| !is_root(dir))
+        .filter(|dir| {
+            dir.parent()
+                .map(|parent| is_root(parent) || !class_of.contains_key(parent))
+                .unwrap_or(true)
+        })
+        .collect();
+    top.sort();
+
+    let mut by_class: HashMap<usize, Vec<PathBuf>> = HashMap::new();
+    for dir in top.iter() {
+        if dirs[dir] > 0 {
+            by_class.entry(class_of[dir]).or_default().push(dir.to_path_buf());
+        }
+    }
+    let mut same: Vec<Vec<PathBuf>> = by_class.into_values().filter(|group| group.len() > 1).collect();
+    same.sort();
+
+    let dirs: Vec<PathBuf> = top.into_iter().map(Path::to_path_buf).collect();
+    EmptyDirs {
+        dirs_base64: crate::encoding::raw_seq(&dirs),
+        same_base64: crate::encoding::raw_groups(&same),
+        dirs,
+        same,
+    }
+}
@@ -0,0 +1,87 @@
+//! Serialization helpers for paths which are not valid UTF-8.
+//!
+//! serde refuses to serialize such paths, so report fields go through these helpers instead:
+//! every path is written as a lossy display string, and carries the exact bytes in base64 in a
+//! `_base64` field alongside it whenever the lossy form is not faithful.
+
+use base64::Engine;
+use serde::ser::SerializeSeq;
+use serde::Serializer;
+use std::path::{Path, PathBuf};
+
+pub fn lossy<S, P>(path: &P, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+    P: AsRef<Path>,
+{
+    serializer.serialize_str(&path.as_ref().to_string_lossy())
+}
+
+pub fn lossy_opt<S: Serializer>(path: &Option<PathBuf>, serializer: S) -> Result<S::Ok, S::Error> {
+    match path {
+        Some(p) => lossy(p, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn lossy_vec<S: Serializer>(paths: &Option<Vec<PathBuf>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match paths {
+        Some(paths) => lossy_seq(paths, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+pub fn lossy_seq<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+    let mut seq = serializer.serialize_seq(Some(paths.len()))?;
+    for p in paths.iter() {
+        seq.serialize_element(&p.to_string_lossy())?;
+    }
+    seq.end()
+}
+
+pub fn lossy_groups<S: Serializer>(groups: &[Vec<PathBuf>], serializer: S) -> Result<S::Ok, S::Error> {
+    let mut seq = serializer.serialize_seq(Some(groups.len()))?;
+    for group in groups.iter() {
+        let group: Vec<_> = group.iter().map(|p| p.to_string_lossy()).collect();
+        seq.serialize_element(&group)?;
+    }
+    seq.end()
+}
+
+/// The raw bytes of `path` in base64, only for paths which are not valid UTF-8
+pub fn raw_bytes(path: &Path) -> Option<String> {
+    use std::os::unix::ffi::OsStrExt;
+    if path.to_str().is_some() {
+        return None;
+    }
+
+    Some(base64::engine::general_purpose::STANDARD.encode(path.as_os_str().as_bytes()))
+}
+
+/// The raw bytes of every path of `paths` in base64, `None` for the valid UTF-8 ones, only when
+/// some path is not
+pub fn raw_seq<P: AsRef<Path>>(paths: &[P]) -> Option<Vec<Option<String>>> {
+    let raw: Vec<Option<String>> = paths.iter().map(|p| raw_bytes(p.as_ref())).collect();
+    raw.iter().any(Option::is_some).then_some(raw)
+}
+
+/// `raw_seq` of every group, only when some path of any group is not valid UTF-8
+pub fn raw_groups(groups: &[Vec<PathBuf>]) -> Option<Vec<Vec<Option<String>>>> {
+    groups.iter().any(|group| raw_seq(group).is_some()).then(|| {
+        groups
+            .iter()
+            .map(|group| group.iter().map(|p| raw_bytes(p)).collect())
+            .collect()
+    })
+}
+
+/// Path of a file entry of a report, exact when the entry carries the raw bytes
+pub fn entry_path(entry: &serde_json::Value) -> Option<PathBuf> {
+    use std::os::unix::ffi::OsStringExt;
+    if let Some(raw) = entry.get("path_base64").and_then(|p| p.as_str()) {
+        let bytes = base64::engine::general_purpose::STANDARD.decode(raw).ok()?;
+        return Some(PathBuf::from(std::ffi::OsString::from_vec(bytes)));
+    }
+
+    entry.get("path").and_then(|p| p.as_str()).map(PathBuf::from)
+}
@@ -0,0 +1,34 @@
+use std::io;
+use std::path::PathBuf;
+
+/// What can go wrong while scanning, hashing, comparing and reporting
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum Error {
+    /// a root or a directory under it could not be read
+    #[error("could not walk {}: {source}", path.display())]
+    Walk { path: PathBuf, source: io::Error },
+    #[error("could not hash {}: {source}", path.display())]
+    Hash { path: PathBuf, source: io::Error },
+    #[error("could not compare {} with {}: {source}", lhs.display(), rhs.display())]
+    Compare {
+        lhs: PathBuf,
+        rhs: PathBuf,
+        source: io::Error,
+    },
+    #[error("could not write the report: {0}")]
+    Report(#[from] serde_json::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(err) => err,
+            err => io::Error::other(err),
+        }
+    }
+}
@@ -0,0 +1,527 @@
+use crate::common::{shell_quote, stringify_bytes, Processed};
+use crate::file::{mtime, FileInfo};
+use crate::i18n::{tr, Msg};
+use crate::sandbox::Sandbox;
+use crate::trash::Trash;
+use crate::{say, warn};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Materializes every duplicate group as a directory of hard links under `root`, like
+/// `group-000001/0-report.pdf`, a browsable view of the results taking no extra space.
+///
+/// Files on another file system than `root` cannot be linked, they are skipped with a message.
+pub fn link_farm(res: &Processed, root: &Path, dry_run: bool) -> io::Result<()> {
+    check_link_farm(root)?;
+
+    if !dry_run {
+        fs::create_dir_all(root)?;
+    }
+    let sandbox = match dry_run {
+        true => None,
+        false => Some(Sandbox::new(&[root])?),
+    };
+
+    let mut links = 0;
+    for (n, group) in res.same.iter().enumerate() {
+        let dir = root.join(format!("group-{:06}", n + 1));
+        if let Some(sandbox) = &sandbox {
+            sandbox.create_dir(&dir)?;
+        }
+
+        for (i, idx) in group.iter().enumerate() {
+            let Some(fl) = res.info.get(*idx) else {
+                eprintln!("Could not find file at position {}", &idx);
+                continue;
+            };
+
+            // members of a group often share their name, the position keeps them apart
+            let name = fl.path.file_name().unwrap_or(fl.path.as_os_str()).to_string_lossy();
+            let link = dir.join(format!("{}-{}", i, name));
+            let Some(sandbox) = &sandbox else {
+                say!("{}", tr(Msg::WouldLink, &[&fl.path.display(), &link.display()]));
+                continue;
+            };
+
+            match sandbox.hard_link(&fl.content_path(), &link) {
+                Ok(()) => links += 1,
+                Err(err) => eprintln!("Could not link {} into {}: {}", &fl.path.display(), &dir.display(), err),
+            }
+        }
+    }
+
+    if !dry_run && !crate::term::is_quiet() {
+        say!("{}", tr(Msg::LinkedGroups, &[&links, &res.same.len(), &root.display()]));
+    }
+    Ok(())
+}
+
+/// The link farm goes into a new or empty directory, so its groups never mix with older ones
+pub fn check_link_farm(root: &Path) -> io::Result<()> {
+    if root.exists() && fs::read_dir(root)?.next().is_some() {
+        let msg = format!("{} must be empty to hold the duplicate groups", root.display());
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+    }
+    Ok(())
+}
+
+/// Runs `command` through `sh -c` once for every duplicate group.
+///
+/// `{paths}` in the command stands for the paths of the group, each quoted for the shell. Without
+/// it the paths are fed on stdin, each one ended by a NUL byte like `find -print0`. `RCOMPARE_SIZE`
+/// and `RCOMPARE_COUNT` hold the size of the files and how many there are, `RCOMPARE_KEEP` the
+/// path of the copy to keep when a keep policy is set. Groups which are not `Processed::is_exact`
+/// are left out, the command may well remove copies.
+pub fn exec_per_group(res: &Processed, command: &str, dry_run: bool) -> io::Result<()> {
+    let on_stdin = !command.contains("{paths}");
+    let mut failed = 0;
+    let groups = res.exact_groups();
+    for group in groups.iter() {
+        let mut files: Vec<&FileInfo> = group.iter().filter_map(|idx| res.info.get(*idx)).collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let Some(first) = files.first() else {
+            continue;
+        };
+
+        let line = match on_stdin {
+            true => command.as_bytes().to_vec(),
+            false => {
+                let paths: Vec<Vec<u8>> = files.iter().map(|fl| shell_quote(fl.path.as_os_str())).collect();
+                replace(command.as_bytes(), b"{paths}", &paths.join(&b' '))
+            }
+        };
+        let line = OsStr::from_bytes(&line);
+        if dry_run {
+            say!("{}", tr(Msg::WouldRun, &[&line.to_string_lossy()]));
+            continue;
+        }
+
+        let mut sh = Command::new("sh");
+        sh.arg("-c")
+            .arg(line)
+            .env("RCOMPARE_SIZE", first.size.to_string())
+            .env("RCOMPARE_COUNT", files.len().to_string())
+            .stdin(if on_stdin { Stdio::piped() } else { Stdio::null() });
+        if let Some(keeper) = res.keeper(&files) {
+            sh.env("RCOMPARE_KEEP", &files[keeper].path);
+        }
+        let mut child = sh.spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            let input: Vec<u8> = files
+                .iter()
+                .flat_map(|fl| fl.path.as_os_str().as_bytes().iter().copied().chain([0]))
+                .collect();
+            // the command does not have to read them all
+            match stdin.write_all(&input) {
+                Err(err) if err.kind() != io::ErrorKind::BrokenPipe => return Err(err),
+                _ => {}
+            }
+        }
+
+        let status = child.wait()?;
+        if !status.success() {
+            warn!("The command failed for the group of {} ({})", first.path.display(), status);
+            failed += 1;
+        }
+    }
+
+    if failed > 0 {
+        let msg = format!("the command failed for {} of the {} groups", failed, groups.len());
+        return Err(io::Error::other(msg));
+    }
+    Ok(())
+}
+
+/// What is done with the redundant copies of a duplicate group
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DedupeAction {
+    /// delete them
+    #[default]
+    Remove,
+    /// replace them with hard links to the kept copy
+    Hardlink,
+    /// replace them with copy-on-write clones of the kept copy, on Btrfs, XFS and the like
+    Reflink,
+    /// leave every path as it is and have the kernel share the extents of the kept copy, once it
+    /// checked the contents are the same, on Btrfs, XFS and the like
+    ShareExtents,
+    /// move them to the trash, `remove` with `--trash`
+    #[value(skip)]
+    Trash,
+}
+
+impl DedupeAction {
+    /// The action asked for, `remove` moving the copies to the trash with `trash` and only
+    /// deleting them for good with `force`
+    pub fn with_removal(self, trash: bool, force: bool) -> Result<Self, Msg> {
+        match (self, trash, force) {
+            (DedupeAction::Remove, true, false) => Ok(DedupeAction::Trash),
+            (DedupeAction::Remove, false, true) => Ok(DedupeAction::Remove),
+            (DedupeAction::Remove, false, false) => Err(Msg::RemoveNeedsTrashOrForce),
+            (_, false, false) => Ok(self),
+            (_, _, _) => Err(Msg::TrashOrForceOnlyRemove),
+        }
+    }
+}
+
+/// Writes a shell script removing or linking the redundant copies of every duplicate group, for
+/// the user to read before running it.
+///
+/// The keep policy picks the copy to keep, the first path of the group without one. Copies which
+/// are already hard links to it are left alone, so are the groups which are not
+/// `Processed::is_exact`, and a group is skipped when the kept copy is gone by the time the script
+/// runs.
+pub fn emit_script(res: &Processed, path: &Path, action: DedupeAction, dry_run: bool) -> io::Result<()> {
+    let mut script = b"#!/bin/sh\n".to_vec();
+    let roots: Vec<String> = res.roots.iter().map(|root| root.display().to_string()).collect();
+    _ = writeln!(script, "# Written by rcompare for the duplicates of {}", roots.join(", "));
+    if let Some(keep) = &res.keep {
+        _ = writeln!(script, "# Keep policy: {}", keep);
+    }
+    _ = writeln!(script, "# Review it before running it, the files may have changed since the scan.");
+    script.extend_from_slice(b"set -eu\n");
+
+    let mut files = 0;
+    for group in res.exact_groups() {
+        let Some((keeper, redundant)) = split_group(res, group) else {
+            continue;
+        };
+
+        let kept = shell_quote(keeper.path.as_os_str());
+        _ = writeln!(script, "\n# {} copies of {} bytes", group.len(), keeper.size);
+        script.extend_from_slice(b"if test -f ");
+        script.extend_from_slice(&kept);
+        script.extend_from_slice(b"; then\n");
+        for fl in redundant {
+            script.extend_from_slice(b"    ");
+            let command: &[u8] = match action {
+                DedupeAction::Remove => b"rm -f -- ",
+                DedupeAction::Hardlink => b"ln -f -- ",
+                DedupeAction::Reflink => b"cp --reflink=always -f -- ",
+                DedupeAction::ShareExtents => b"duperemove -dq -- ",
+                DedupeAction::Trash => b"gio trash -- ",
+            };
+            script.extend_from_slice(command);
+            if !matches!(action, DedupeAction::Remove | DedupeAction::Trash) {
+                script.extend_from_slice(&kept);
+                script.push(b' ');
+            }
+            script.extend_from_slice(&shell_quote(fl.path.as_os_str()));
+            script.push(b'\n');
+            files += 1;
+        }
+        script.extend_from_slice(b"fi\n");
+    }
+
+    crate::common::write_file(path, &script, dry_run)?;
+    if !dry_run {
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+        if !crate::term::is_quiet() {
+            say!("{}", tr(Msg::WroteScript, &[&files, &path.display()]));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the redundant copies of every duplicate group to `out`, each path ended by a NUL byte,
+/// for `xargs -0 rm` and the like.
+///
+/// The copy kept is left out, so are the hard links to it and the groups which are not
+/// `Processed::is_exact`, as for the other cleanups.
+pub fn print_redundant<W: Write>(res: &Processed, out: &mut W) -> io::Result<()> {
+    for group in res.exact_groups() {
+        let Some((_, redundant)) = split_group(res, group) else {
+            continue;
+        };
+        for fl in redundant {
+            out.write_all(fl.path.as_os_str().as_bytes())?;
+            out.write_all(b"\0")?;
+        }
+    }
+    out.flush()
+}
+
+/// Removes the redundant copies of every duplicate group, or replaces them with hard links or
+/// clones of the copy kept, without leaving the scanned roots.
+///
+/// Only the groups found equal on their exact contents are touched, see `Processed::is_exact`. A
+/// copy whose inode, size or modification time changed since the scan is left alone, so are
+/// symbolic links, which take no space of their own.
+///
+/// Returns how many copies were replaced and the bytes they took.
+pub fn dedupe(res: &Processed, action: DedupeAction, dry_run: bool) -> io::Result<(usize, u64)> {
+    let sandbox = match dry_run {
+        true => None,
+        false => {
+            let roots: Vec<&PathBuf> = res
+                .roots
+                .iter()
+                .filter(|root| !crate::source::is_remote(root))
+                .collect();
+            Some(Sandbox::new(&roots)?)
+        }
+    };
+
+    let (mut files, mut reclaimed, mut failed) = (0, 0, 0);
+    let mut trash = Trash::default();
+    for group in res.exact_groups() {
+        let Some((keeper, redundant)) = split_group(res, group) else {
+            continue;
+        };
+
+        for fl in redundant.into_iter().filter(|fl| fl.symlink.is_none()) {
+            if !is_unchanged(fl) {
+                warn!("{}", tr(Msg::ChangedSinceScan, &[&fl.path.display()]));
+                continue;
+            }
+            let Some(sandbox) = &sandbox else {
+                let msg = match action {
+                    DedupeAction::Remove => tr(Msg::WouldRemove, &[&fl.path.display()]),
+                    DedupeAction::Hardlink => tr(Msg::WouldLink, &[&keeper.path.display(), &fl.path.display()]),
+                    DedupeAction::Reflink => tr(Msg::WouldClone, &[&keeper.path.display(), &fl.path.display()]),
+                    DedupeAction::ShareExtents => tr(Msg::WouldShare, &[&keeper.path.display(), &fl.path.display()]),
+                    DedupeAction::Trash => tr(Msg::WouldTrash, &[&fl.path.display()]),
+                };
+                say!("{}", msg);
+                continue;
+            };
+
+            let done = match action {
+                DedupeAction::Trash => trash.put(sandbox, &fl.path),
+                _ => replace_copy(sandbox, keeper, fl, action),
+            };
+            match done {
+                Ok(()) => {
+                    files += 1;
+                    reclaimed += fl.size;
+                }
+                Err(err) => {
+                    warn!("Could not deduplicate {}: {}", fl.path.display(), err);
+                    failed += 1;
+                }
+            }
+        }
+    }
+
+    if !dry_run && !crate::term::is_quiet() {
+        say!("{}", tr(Msg::Deduped, &[&files, &stringify_bytes(reclaimed as usize)]));
+    }
+    if failed > 0 {
+        return Err(io::Error::other(format!("{} redundant files could not be deduplicated", failed)));
+    }
+    Ok((files, reclaimed))
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// The copy to keep in a group, by the keep policy or else the first path, and the copies which
+/// are not hard links to it already
+fn split_group<'a>(res: &'a Processed, group: &[usize]) -> Option<(&'a FileInfo, Vec<&'a FileInfo>)> {
+    // the copies in a remote tree or an archive are only there to compare with
+    let mut group: Vec<&FileInfo> = group
+        .iter()
+        .filter_map(|idx| res.info.get(*idx))
+        .filter(|fl| fl.origin.is_none())
+        .collect();
+    group.sort_by(|a, b| a.path.cmp(&b.path));
+    let keeper = group.get(res.keeper(&group).unwrap_or(0)).copied()?;
+    let redundant: Vec<&FileInfo> = group
+        .into_iter()
+        .filter(|fl| (fl.device, fl.inode) != (keeper.device, keeper.inode))
+        .collect();
+    (!redundant.is_empty()).then_some((keeper, redundant))
+}
+
+fn is_unchanged(fl: &FileInfo) -> bool {
+    fs::symlink_metadata(&fl.path)
+        .map(|meta| (meta.ino(), meta.size(), mtime(&meta)) == (fl.inode, fl.size, fl.mtime))
+        .unwrap_or(false)
+}
+
+/// Links or clones the kept copy next to `fl` first, then renames it over `fl`, so `fl` is never
+/// missing. Shared extents need no renaming, `fl` stays the same file.
+fn replace_copy(sandbox: &Sandbox, keeper: &FileInfo, fl: &FileInfo, action: DedupeAction) -> io::Result<()> {
+    let source = fs::canonicalize(keeper.content_path())?;
+    if action == DedupeAction::ShareExtents {
+        return sandbox.share_extents(&source, &fl.path, fl.size);
+    }
+    let mut name = OsString::from(format!(".rcompare-{}-", std::process::id()));
+    name.push(fl.path.file_name().unwrap_or_default());
+    let temp = fl.path.with_file_name(name);
+
+    let staged = match action {
+        DedupeAction::Remove => return sandbox.remove_file(&fl.path),
+        DedupeAction::Hardlink => sandbox.hard_link(&source, &temp),
+        DedupeAction::Reflink => sandbox.clone_file(&source, &temp, fl.mode),
+        DedupeAction::ShareExtents | DedupeAction::Trash => unreachable!("not replaced"),
+    };
+    staged.and_then(|()| sandbox.rename(&temp, &fl.path)).inspect_err(|_| {
+        _ = sandbox.remove_file(&temp);
+    })
+}
+
+fn replace(haystack: &[u8], needle: &[u8], with: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut rest = haystack;
+    while let Some(pos) = rest.windows(needle.len()).position(|w| w == needle) {
+        out.extend_from_slice(&rest[..pos]);
+        out.extend_from_slice(with);
+        rest = &rest[pos + needle.len()..];
+    }
+    out.extend_from_slice(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use std::convert::TryFrom;
+
+    /// A new directory holding `files`, named after the test
+    fn tree(name: &str, files: &[(&str, &[u8])]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rcompare-export-{}-{}", std::process::id(), name));
+        _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        for (file, content) in files {
+            fs::write(dir.join(file), content).unwrap();
+        }
+        dir
+    }
+
+    fn scan(dir: &Path, args: &[&str]) -> Processed {
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        args.extend(["--no-cache".to_owned(), "--self".to_owned(), dir.display().to_string()]);
+        let config = Config::try_from(crate::cli::parse_scan(&args).unwrap()).unwrap();
+        crate::run_scan(&config).unwrap()
+    }
+
+    /// The files of `dir` by name, with their contents and inodes
+    fn contents(dir: &Path) -> Vec<(OsString, Vec<u8>, u64)> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let inode = fs::metadata(&path).unwrap().ino();
+                (path.file_name().unwrap().to_owned(), fs::read(&path).unwrap(), inode)
+            })
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn dedupe_leaves_sampled_groups() {
+        let (mut a, mut b) = (vec![7; 64 * 1024], vec![7; 64 * 1024]);
+        (a[60 * 1024], b[60 * 1024]) = (1, 2);
+        let dir = tree("quick", &[("a", &a), ("b", &b)]);
+        let res = scan(&dir, &["--quick"]);
+        assert_eq!(res.same.len(), 1);
+
+        let before = contents(&dir);
+        assert_eq!(dedupe(&res, DedupeAction::Remove, false).unwrap(), (0, 0));
+        assert_eq!(contents(&dir), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dedupe_leaves_groups_with_ignored_ranges() {
+        let dir = tree("ranges", &[("a", b"AAAA same body"), ("b", b"BBBB same body")]);
+        let res = scan(&dir, &["--ignore-ranges", "0..4"]);
+        assert_eq!(res.same.len(), 1);
+
+        let before = contents(&dir);
+        assert_eq!(dedupe(&res, DedupeAction::Remove, false).unwrap(), (0, 0));
+        assert_eq!(contents(&dir), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dedupe_leaves_normalized_groups() {
+        let dir = tree("text", &[("a.txt", b"a\nb\r\n"), ("b.txt", b"a\r\nb\n")]);
+        let res = scan(&dir, &["--text-normalize"]);
+        assert_eq!(res.same.len(), 1);
+
+        let before = contents(&dir);
+        assert_eq!(dedupe(&res, DedupeAction::Hardlink, false).unwrap(), (0, 0));
+        assert_eq!(contents(&dir), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn trash_leaves_sampled_groups() {
+        let (mut a, mut b) = (vec![7; 64 * 1024], vec![7; 64 * 1024]);
+        (a[60 * 1024], b[60 * 1024]) = (1, 2);
+        let dir = tree("trash", &[("a", &a), ("b", &b)]);
+        let res = scan(&dir, &["--quick"]);
+        assert_eq!(res.same.len(), 1);
+
+        let before = contents(&dir);
+        assert_eq!(dedupe(&res, DedupeAction::Trash, false).unwrap(), (0, 0));
+        assert_eq!(contents(&dir), before);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn print_redundant_leaves_sampled_groups() {
+        let (mut a, mut b) = (vec![7; 64 * 1024], vec![7; 64 * 1024]);
+        (a[60 * 1024], b[60 * 1024]) = (1, 2);
+        let dir = tree("print0", &[("a", &a), ("b", &b)]);
+        let mut out = vec![];
+        print_redundant(&scan(&dir, &[]), &mut out).unwrap();
+        assert!(out.is_empty());
+
+        let res = scan(&dir, &["--quick"]);
+        assert_eq!(res.same.len(), 1);
+        print_redundant(&res, &mut out).unwrap();
+        assert!(out.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn emit_script_leaves_masked_groups() {
+        let dir = tree("script", &[("a", b"AAAA same body"), ("b", b"BBBB same body")]);
+        let res = scan(&dir, &["--ignore-ranges", "0..4"]);
+        assert_eq!(res.same.len(), 1);
+
+        let script = dir.with_extension("sh");
+        emit_script(&res, &script, DedupeAction::Remove, false).unwrap();
+        assert!(Command::new("sh").arg(&script).status().unwrap().success());
+        assert_eq!(contents(&dir).len(), 2);
+        fs::remove_file(&script).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn emit_script_skips_groups_without_their_kept_copy() {
+        let dir = tree("guard", &[("a", b"first"), ("b", b"first"), ("c", b"second"), ("d", b"second")]);
+        let res = scan(&dir, &[]);
+
+        let script = dir.with_extension("sh");
+        emit_script(&res, &script, DedupeAction::Remove, false).unwrap();
+        fs::remove_file(dir.join("a")).unwrap();
+        assert!(Command::new("sh").arg(&script).status().unwrap().success());
+        let names: Vec<OsString> = contents(&dir).into_iter().map(|(name, _, _)| name).collect();
+        assert_eq!(names, ["b", "c"]);
+        fs::remove_file(&script).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dedupe_links_exact_groups() {
+        let dir = tree("exact", &[("a", b"same content"), ("b", b"same content")]);
+        let res = scan(&dir, &[]);
+
+        assert_eq!(dedupe(&res, DedupeAction::Hardlink, false).unwrap(), (1, 12));
+        let after = contents(&dir);
+        assert_eq!(after[0].2, after[1].2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
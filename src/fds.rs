@@ -0,0 +1,76 @@
+use std::convert::TryFrom;
+use std::sync::{Condvar, Mutex};
+
+/// Descriptors left for what a run opens besides the files it reads: the standard streams, the
+/// hash cache, the folders being listed, the reports and the connections of a remote tree
+const RESERVED: usize = 64;
+
+/// A comparison holds two files at once, the cap never goes below
+const MIN_OPEN_FILES: usize = 2;
+
+/// Files open for reading and the most allowed at once
+static OPEN: Mutex<(usize, usize)> = Mutex::new((0, usize::MAX));
+static CLOSED: Condvar = Condvar::new();
+
+/// Files held open until dropped
+pub struct Permit {
+    count: usize,
+}
+
+/// Caps the files read at once to `limit`, by default to what the `RLIMIT_NOFILE` soft limit
+/// leaves once the other descriptors are set aside.
+///
+/// A limit above the soft one raises it, as far as the hard one allows. Returns the cap in force.
+pub fn set_limit(limit: Option<usize>) -> usize {
+    let (mut soft, hard) = rlimit();
+    let wanted = limit.unwrap_or_else(|| soft.saturating_sub(RESERVED));
+    let needed = wanted.saturating_add(RESERVED);
+    if needed > soft && raise(needed.min(hard)) {
+        soft = needed.min(hard);
+    }
+
+    let cap = wanted.min(soft.saturating_sub(RESERVED)).max(MIN_OPEN_FILES);
+    OPEN.lock().unwrap().1 = cap;
+    CLOSED.notify_all();
+    cap
+}
+
+/// Waits until `count` more files can be opened, taking them all at once so that a comparison
+/// never holds one file while waiting for the other
+pub fn acquire(count: usize) -> Permit {
+    let open = OPEN.lock().unwrap();
+    let mut open = CLOSED
+        .wait_while(open, |(open, limit)| *open > 0 && *open + count > *limit)
+        .unwrap();
+    open.0 += count;
+    Permit { count }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        OPEN.lock().unwrap().0 -= self.count;
+        CLOSED.notify_all();
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// The soft and hard limits of open descriptors
+fn rlimit() -> (usize, usize) {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        // the usual default of Linux
+        return (1024, 1024);
+    }
+    let size = |n: libc::rlim_t| usize::try_from(n).unwrap_or(usize::MAX);
+    (size(limit.rlim_cur), size(limit.rlim_max))
+}
+
+/// Raises the soft limit of open descriptors to `soft`
+fn raise(soft: usize) -> bool {
+    let (_, hard) = rlimit();
+    let limit = libc::rlimit { rlim_cur: soft as libc::rlim_t, rlim_max: hard as libc::rlim_t };
+    unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) == 0 }
+}
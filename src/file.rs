@@ -1,14 +1,104 @@
-use serde::Serialize;
+use crate::filter::{Filter, FilterSet};
+use crate::pagecache::Sequential;
+use crate::skipped::{self, Stage};
+use crate::source::FileSource;
+use crate::warn;
+use crate::warnings::{self, Kind};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashSet, VecDeque};
+use std::ffi;
 use std::fs;
 use std::io;
 use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path;
+use std::sync::Arc;
 
 #[derive(Debug, Default, Clone, Serialize)]
 pub struct FileInfo {
     pub inode: u64,
     pub size: u64,
+    #[serde(serialize_with = "crate::encoding::lossy")]
     pub path: path::PathBuf,
+    /// how the entry was treated when it is a symbolic link
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub symlink: Option<SymlinkMode>,
+    /// where the content is read from, when it differs from `path`
+    #[serde(skip)]
+    pub source: Option<path::PathBuf>,
+    /// device holding the content as `major:minor`, see `volume::detect_volumes`
+    #[serde(serialize_with = "crate::volume::serialize_device")]
+    pub device: u64,
+    /// hard links to the inode, all of them and not only the scanned ones
+    pub links: u64,
+    /// digest of the whole content, like `blake3:af13…`, for the files read whole
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// modification time in nanoseconds since the epoch
+    pub mtime: i64,
+    /// permission bits, written in octal like `0644`
+    #[serde(serialize_with = "serialize_mode")]
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    /// where the content is streamed from, for the files of a remote tree or a bucket and the
+    /// members of an archive
+    #[serde(skip)]
+    pub origin: Option<Arc<dyn FileSource>>,
+}
+
+impl FileInfo {
+    /// The regular file at `path`, from its metadata
+    pub fn from_metadata(path: path::PathBuf, metadata: &fs::Metadata) -> Self {
+        FileInfo {
+            path,
+            inode: metadata.ino(),
+            size: metadata.size(),
+            device: metadata.dev(),
+            links: metadata.nlink(),
+            mtime: mtime(metadata),
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            ..Default::default()
+        }
+    }
+
+    pub fn content_path(&self) -> &path::Path {
+        self.source.as_deref().unwrap_or(&self.path)
+    }
+
+    /// Opens the content for reading, streamed from its origin when it has one
+    pub fn open(&self) -> io::Result<Box<dyn io::Read>> {
+        match &self.origin {
+            Some(origin) => origin.open(&self.path),
+            None => Ok(Box::new(Sequential::open(self.content_path())?)),
+        }
+    }
+
+    /// The file as it is now, from its origin when it has one
+    pub fn metadata(&self) -> io::Result<FileInfo> {
+        match &self.origin {
+            Some(origin) => origin.metadata(&self.path),
+            None => Ok(FileInfo::from_metadata(self.path.clone(), &fs::metadata(self.content_path())?)),
+        }
+    }
+
+    /// Whether the entry stands for the link itself, its content being the target path
+    pub fn is_link_target(&self) -> bool {
+        self.symlink == Some(SymlinkMode::CompareTarget)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkMode {
+    /// ignore symbolic links altogether
+    Skip,
+    /// resolve links and compare what they point to, descending into linked directories
+    #[default]
+    Follow,
+    /// do not resolve links, two links are the same when they point to the same path
+    CompareTarget,
 }
 
 pub(crate) fn is_path_valid<P: AsRef<path::Path>>(file: P) -> io::Result<bool> {
@@ -21,41 +111,118 @@ pub(crate) fn is_path_valid<P: AsRef<path::Path>>(file: P) -> io::Result<bool> {
     Ok(tipo.is_dir() | tipo.is_file())
 }
 
-pub fn walk_dir<P: AsRef<path::Path>>(dir: &P) -> PathIter {
-    PathIter::new(dir)
+/// The files under `dir` which pass `filters`, listed by several threads when `walk::threads`
+/// allows and `dir` is a folder
+pub fn walk_dir<P: AsRef<path::Path>>(dir: &P, filters: &FilterSet) -> Box<dyn Iterator<Item = FileInfo>> {
+    let threads = crate::walk::threads();
+    if threads > 1 && dir.as_ref().is_dir() {
+        return Box::new(crate::walk::ParallelWalk::new(dir.as_ref(), filters, threads));
+    }
+
+    let filter = Filter::new(filters.clone(), dir.as_ref());
+    Box::new(PathIter::new(dir, filter))
+}
+
+/// The files of an explicit list, like the output of `find`, through the same filters as a walk.
+///
+/// Directories in the list are left out rather than walked, `find` lists their files as well.
+pub fn list_files<'a>(paths: &'a [path::PathBuf], filters: &'a FilterSet) -> impl Iterator<Item = FileInfo> + 'a {
+    paths
+        .iter()
+        .filter(|path| !path.is_dir())
+        .flat_map(move |path| walk_dir(path, filters))
+}
+
+/// Reads a list of paths from a file, or from stdin for `-`, one per line or NUL-terminated.
+///
+/// Relative paths are taken from the current directory, the directory holding each file is
+/// resolved with `canonicalize` so the files line up with walked ones, the name itself is kept in
+/// case it is a link.
+pub fn read_list(source: &str, nul: bool, canonicalize: bool) -> io::Result<Vec<path::PathBuf>> {
+    use std::io::Read;
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut bytes = vec![];
+    match source {
+        "-" => _ = io::stdin().lock().read_to_end(&mut bytes)?,
+        _ => _ = fs::File::open(source)?.read_to_end(&mut bytes)?,
+    }
+
+    let cwd = std::env::current_dir()?;
+    let separator = if nul { b'\0' } else { b'\n' };
+    let mut paths = vec![];
+    for entry in bytes.split(|b| *b == separator).filter(|e| !e.is_empty()) {
+        let path = cwd.join(ffi::OsStr::from_bytes(entry));
+        let resolved = match (canonicalize, path.parent(), path.file_name()) {
+            (true, Some(parent), Some(name)) => fs::canonicalize(parent).map(|parent| parent.join(name)),
+            _ => Ok(path.clone()),
+        };
+        match resolved {
+            Ok(path) => paths.push(path),
+            Err(err) => {
+                if skipped::record(&path, Stage::Scan, &err) {
+                    warn!("Could not find {} from the list, skipping it: {}", path.display(), err);
+                }
+            }
+        }
+    }
+    Ok(paths)
 }
 
 pub struct PathIter {
     stack: Vec<PathSelection>,
     current: PathSelection,
+    filter: Filter,
+    /// (device, inode) of every directory listed so far, so links cannot loop or repeat a subtree
+    visited: HashSet<(u64, u64)>,
+    /// linked directories, only listed once the real tree is exhausted
+    links: Vec<(path::PathBuf, usize)>,
 }
 
 impl PathIter {
-    fn new<P>(path: &P) -> Self
+    fn new<P>(path: &P, filter: Filter) -> Self
     where
         P: AsRef<path::Path>,
     {
         let valid = check_if_file_is_valid(path);
         if !valid {
-            return Self { stack: vec![], current: PathSelection::Empty };
+            return Self::with_current(PathSelection::Empty, filter);
         }
 
         if path.as_ref().is_file() {
-            return Self {
-                stack: vec![],
-                current: PathSelection::File(Some(path.as_ref().to_owned())),
-            };
+            return Self::with_current(PathSelection::File(Some(path.as_ref().to_owned())), filter);
         }
         let entry = path.as_ref().read_dir();
-        if entry.is_err() {
-            eprintln!("There was an error when reading {}, skipping it", &path.as_ref().display());
-            return Self { stack: vec![], current: PathSelection::Empty };
+        if let Err(err) = &entry {
+            skip_dir(path.as_ref(), err);
+            return Self::with_current(PathSelection::Empty, filter);
         }
 
-        Self {
-            stack: vec![],
-            current: PathSelection::Folder(entry.unwrap(), path.as_ref().to_owned()),
+        let current = PathSelection::Folder(entry.unwrap(), path.as_ref().to_owned(), 0, Listing::default());
+        let mut iter = Self::with_current(current, filter);
+        iter.visited.extend(dir_id(path.as_ref()));
+        iter
+    }
+
+    fn with_current(current: PathSelection, filter: Filter) -> Self {
+        Self { stack: vec![], current, filter, visited: HashSet::new(), links: vec![] }
+    }
+
+    /// Opens the next linked directory which was not visited yet
+    fn next_link(&mut self) -> Option<PathSelection> {
+        while let Some((path, depth)) = self.links.pop() {
+            if !visit(&mut self.visited, &path) {
+                continue;
+            }
+
+            let dir = path.read_dir();
+            if let Err(err) = &dir {
+                skip_dir(&path, err);
+                continue;
+            }
+            return Some(PathSelection::Folder(dir.unwrap(), path, depth, Listing::default()));
         }
+        None
     }
 }
 
@@ -63,50 +230,252 @@ impl Iterator for PathIter {
     type Item = FileInfo;
 
     fn next(&mut self) -> Option<Self::Item> {
+        let depth = self.current.depth() + 1;
         for path in self.current.by_ref() {
-            let valid = check_if_file_is_valid(&path);
-            if !valid {
-                continue;
-            }
+            match step(path, depth, &mut self.filter) {
+                Step::File(info) => return Some(info),
+                Step::Link(real) => self.links.push((real, depth)),
+                Step::Dir(path) => {
+                    if !visit(&mut self.visited, &path) {
+                        continue;
+                    }
 
-            if path.is_file() {
-                let metadata = path.metadata();
-                if metadata.is_err() {
-                    let file_ = &path.as_path().display();
-                    eprintln!("Could not access metadata for file {}", &file_);
-                    eprintln!("Skipping file {}", &file_);
-                    continue;
+                    let dir = path.read_dir();
+                    if let Err(err) = &dir {
+                        skip_dir(&path, err);
+                        continue;
+                    }
+                    self.stack
+                        .push(PathSelection::Folder(dir.unwrap(), path, depth, Listing::default()));
                 }
-
-                let metadata = metadata.unwrap();
-                let info = FileInfo { path, inode: metadata.ino(), size: metadata.size() };
-                return Some(info);
+                Step::Skip => {}
             }
-
-            let dir = path.read_dir();
-            if dir.is_err() {
-                eprintln!("There was an error when reading {}, skipping it", &path.display());
-                continue;
+        }
+        if let PathSelection::Folder(_, path, _, listing) = &self.current {
+            if self.filter.empty_dirs() && listing.complete && listing.entries == listing.dirs {
+                crate::empty::record(path, listing.dirs);
             }
-            self.stack.push(PathSelection::Folder(dir.unwrap(), path));
         }
-        let new = self.stack.pop()?;
+        let new = match self.stack.pop() {
+            Some(new) => new,
+            None => self.next_link()?,
+        };
         self.current = new;
         self.next()
     }
 }
 
+/// What the walk does with an entry of a folder
+pub(crate) enum Step {
+    File(FileInfo),
+    /// a folder to descend into, unless it was visited already
+    Dir(path::PathBuf),
+    /// the target of a linked folder, only listed once the real tree is exhausted
+    Link(path::PathBuf),
+    Skip,
+}
+
+/// Sorts out the entry at `path` of a folder `depth` levels below the root, through `filter`
+pub(crate) fn step(path: path::PathBuf, depth: usize, filter: &mut Filter) -> Step {
+    let is_link = path
+        .symlink_metadata()
+        .map(|m| m.file_type().is_symlink())
+        .unwrap_or(false);
+    let symlink = is_link.then_some(filter.symlinks());
+    match symlink {
+        Some(SymlinkMode::Skip) => return Step::Skip,
+        Some(SymlinkMode::CompareTarget) => {
+            return match link_info(path, filter) {
+                Some(info) => Step::File(info),
+                None => Step::Skip,
+            };
+        }
+        _ => {}
+    }
+
+    // inside a sysroot, links are resolved against the image instead of the host
+    let source = match (symlink, filter.sysroot()) {
+        (Some(SymlinkMode::Follow), Some(sysroot)) => match resolve_in_sysroot(sysroot, &path) {
+            Ok(resolved) => Some(resolved),
+            Err(err) => {
+                let reason = format!("could not resolve inside the sysroot: {}", err);
+                if skipped::record(&path, Stage::Scan, reason) {
+                    warn!("Could not resolve {} inside the sysroot: {}", &path.display(), err);
+                }
+                return Step::Skip;
+            }
+        },
+        _ => None,
+    };
+    let real = source.clone().unwrap_or_else(|| path.clone());
+
+    let valid = check_if_file_is_valid(&real);
+    if !valid {
+        return Step::Skip;
+    }
+
+    if real.is_file() {
+        let metadata = real.metadata();
+        if let Err(err) = &metadata {
+            if skipped::record(&path, Stage::Scan, err) {
+                let file_ = &path.as_path().display();
+                warn!("Could not access metadata for file {}", &file_);
+                warn!("Skipping file {}", &file_);
+            }
+            return Step::Skip;
+        }
+
+        let metadata = metadata.unwrap();
+        if !filter.accepts_file(&path, &metadata) {
+            return Step::Skip;
+        }
+
+        return Step::File(FileInfo { symlink, source, ..FileInfo::from_metadata(path, &metadata) });
+    }
+
+    if filter.max_depth().map(|max| depth >= max).unwrap_or(false) {
+        return Step::Skip;
+    }
+
+    if !filter.accepts_dir(&path) {
+        return Step::Skip;
+    }
+
+    match is_link {
+        true => Step::Link(real),
+        false => Step::Dir(path),
+    }
+}
+
 // ----------
 //  Internal
 // ----------
 
+pub(crate) fn mtime(meta: &fs::Metadata) -> i64 {
+    meta.mtime()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(meta.mtime_nsec())
+}
+
+/// Records that the rest of the folder at `path` could not be listed
+pub(crate) fn unreadable_rest(path: &path::Path, err: &io::Error) {
+    let msg = format!("could not list the rest of the folder: {}", err);
+    if skipped::record(path, Stage::Scan, &msg) {
+        warn!("There was an error when reading the folder {}", &path.display());
+    }
+    warnings::record(Kind::UnreadableDirectory, Some(path), msg);
+}
+
+pub(crate) fn serialize_mode<S: serde::Serializer>(mode: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:04o}", mode))
+}
+
+pub(crate) fn dir_id(path: &path::Path) -> Option<(u64, u64)> {
+    fs::metadata(path).map(|meta| (meta.dev(), meta.ino())).ok()
+}
+
+/// Resolves `path` as if `sysroot` was the root directory, so absolute links stay inside the image
+pub(crate) fn resolve_in_sysroot(sysroot: &path::Path, path: &path::Path) -> io::Result<path::PathBuf> {
+    const MAX_LINKS: usize = 40;
+    let Ok(inner) = path.strip_prefix(sysroot) else {
+        return fs::canonicalize(path);
+    };
+
+    let mut pending: VecDeque<ffi::OsString> = components(inner).collect();
+    let mut resolved = sysroot.to_path_buf();
+    let mut links = 0;
+    while let Some(name) = pending.pop_front() {
+        if name == ".." {
+            if resolved != sysroot {
+                resolved.pop();
+            }
+            continue;
+        }
+
+        let candidate = resolved.join(&name);
+        let is_link = fs::symlink_metadata(&candidate)?.file_type().is_symlink();
+        if !is_link {
+            resolved = candidate;
+            continue;
+        }
+
+        links += 1;
+        if links > MAX_LINKS {
+            return Err(io::Error::other(format!("too many levels of symbolic links in {}", path.display())));
+        }
+
+        let target = fs::read_link(&candidate)?;
+        if target.is_absolute() {
+            resolved = sysroot.to_path_buf();
+        }
+        for component in components(&target).collect::<Vec<_>>().into_iter().rev() {
+            pending.push_front(component);
+        }
+    }
+
+    Ok(resolved)
+}
+
+fn components(path: &path::Path) -> impl Iterator<Item = ffi::OsString> + '_ {
+    path.components().filter_map(|c| match c {
+        path::Component::Normal(name) => Some(name.to_owned()),
+        path::Component::ParentDir => Some("..".into()),
+        _ => None,
+    })
+}
+
+/// Records the directory as visited, false when it was already
+pub(crate) fn visit(visited: &mut HashSet<(u64, u64)>, path: &path::Path) -> bool {
+    let Some(id) = dir_id(path) else {
+        return true;
+    };
+
+    if !visited.insert(id) {
+        warn!("Directory {} was already visited, skipping it", &path.display());
+        return false;
+    }
+    true
+}
+
+fn link_info(path: path::PathBuf, filter: &mut Filter) -> Option<FileInfo> {
+    let metadata = path.symlink_metadata();
+    let target = fs::read_link(&path);
+    if let Some(err) = metadata.as_ref().err().or(target.as_ref().err()) {
+        if skipped::record(&path, Stage::Scan, err) {
+            warn!("Could not read the symbolic link {}, skipping it", &path.display());
+        }
+        return None;
+    }
+
+    let metadata = metadata.unwrap();
+    if !filter.accepts_file(&path, &metadata) {
+        return None;
+    }
+
+    let size = target.unwrap().as_os_str().len() as u64;
+    Some(FileInfo {
+        size,
+        symlink: Some(SymlinkMode::CompareTarget),
+        ..FileInfo::from_metadata(path, &metadata)
+    })
+}
+
 #[derive(Debug)]
 enum PathSelection {
     File(Option<path::PathBuf>),
-    Folder(std::fs::ReadDir, path::PathBuf),
+    Folder(std::fs::ReadDir, path::PathBuf, usize, Listing),
     Empty,
 }
 
+impl PathSelection {
+    fn depth(&self) -> usize {
+        match self {
+            Self::Folder(_, _, depth, _) => *depth,
+            _ => 0,
+        }
+    }
+}
+
 impl Iterator for PathSelection {
     type Item = path::PathBuf;
     fn next(&mut self) -> Option<Self::Item> {
@@ -118,32 +487,61 @@ impl Iterator for PathSelection {
             return f.take();
         }
 
-        if let Self::Folder(f, path) = self {
-            let entry = f.next()?;
-            if entry.is_err() {
-                eprintln!("There was an error when reading the folder {}", &path.display());
+        if let Self::Folder(f, path, _, listing) = self {
+            let Some(entry) = f.next() else {
+                listing.complete = true;
+                return None;
+            };
+            if let Err(err) = &entry {
+                unreadable_rest(path, err);
                 return None;
             }
 
-            return Some(entry.unwrap().path());
+            let entry = entry.unwrap();
+            listing.entries += 1;
+            listing.dirs += entry.file_type().map(|t| t.is_dir()).unwrap_or(false) as usize;
+            return Some(entry.path());
         }
         None
     }
 }
 
+/// What a folder held, to tell the ones holding nothing but folders
+#[derive(Debug, Default)]
+struct Listing {
+    entries: usize,
+    dirs: usize,
+    /// every entry was read
+    complete: bool,
+}
+
+/// A directory which cannot be listed leaves out its whole subtree, which the report warns about
+pub(crate) fn skip_dir(path: &path::Path, err: &io::Error) {
+    if skipped::record(path, Stage::Scan, err) {
+        warn!("There was an error when reading {}, skipping it", &path.display());
+    }
+    warnings::record(
+        Kind::UnreadableDirectory,
+        Some(path),
+        format!("could not list the directory: {}", err),
+    );
+}
+
 fn check_if_file_is_valid<P: AsRef<path::Path>>(dir: &P) -> bool {
     let valid = is_path_valid(dir);
-    if valid.is_err() {
-        eprintln!(
-            "There was an error when checking whether the file {:?} is valid, skipping it",
-            &dir.as_ref().display()
-        );
+    if let Err(err) = &valid {
+        if skipped::record(dir.as_ref(), Stage::Scan, err) {
+            warn!(
+                "There was an error when checking whether the file {:?} is valid, skipping it",
+                &dir.as_ref().display()
+            );
+        }
         return false;
     }
 
     let result = valid.unwrap();
-    if !result {
-        eprintln!("File {:?} is not valid, skipping it", &dir.as_ref().display());
+    if !result && skipped::record(dir.as_ref(), Stage::Scan, "not a regular file nor a directory") {
+        warn!("File {:?} is not valid, skipping it", &dir.as_ref().display());
     }
     result
 }
@@ -0,0 +1,303 @@
+use crate::file::SymlinkMode;
+use crate::skipped::{self, Stage};
+use crate::warn;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::Gitignore;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// files met by the walks since the last `take_counts`, and how many of them the filters left out
+static FILES_MET: AtomicU64 = AtomicU64::new(0);
+static FILES_FILTERED: AtomicU64 = AtomicU64::new(0);
+
+/// Every rule deciding which entries take part in a run.
+///
+/// The same set is applied by the walk in every mode and serialized into the report, so two runs
+/// with the same flags always look at the same files.
+#[derive(Debug, Clone, Serialize)]
+pub struct FilterSet {
+    /// lowercase extensions without the leading dot, `None` accepts every file
+    pub extensions: Option<Vec<String>>,
+    /// globs matched against both the file name and the full path
+    pub exclude: Vec<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// only keep files modified within this many seconds
+    pub newer_than: Option<u64>,
+    /// only keep files modified more than this many seconds ago
+    pub older_than: Option<u64>,
+    /// prune dotted entries and, on Windows, entries with the hidden attribute
+    pub skip_hidden: bool,
+    /// honour `.gitignore` files found inside the scanned trees
+    pub gitignore: bool,
+    /// deepest level to list, the entries of the root are at depth 1
+    pub max_depth: Option<usize>,
+    /// do not descend into directories mounted from another device than the root
+    pub one_file_system: bool,
+    pub symlinks: SymlinkMode,
+    /// also list the members of zip and tar archives, as `archive.zip!/inner/file`
+    pub scan_archives: bool,
+    /// also record the directories without any file below them
+    pub empty_dirs: bool,
+    /// directory standing for `/` when resolving absolute links and displaying paths
+    #[serde(serialize_with = "crate::encoding::lossy_opt")]
+    pub sysroot: Option<PathBuf>,
+    #[serde(skip)]
+    pub(crate) exclude_set: GlobSet,
+}
+
+impl Default for FilterSet {
+    fn default() -> Self {
+        Self {
+            extensions: None,
+            exclude: vec![],
+            min_size: None,
+            max_size: None,
+            newer_than: None,
+            older_than: None,
+            skip_hidden: false,
+            gitignore: false,
+            max_depth: None,
+            one_file_system: false,
+            symlinks: SymlinkMode::default(),
+            scan_archives: false,
+            empty_dirs: false,
+            sysroot: None,
+            exclude_set: GlobSet::empty(),
+        }
+    }
+}
+
+impl FilterSet {
+    pub fn with_exclude(mut self, globs: Vec<String>) -> io::Result<Self> {
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs.iter() {
+            let glob = Glob::new(glob).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            builder.add(glob);
+        }
+
+        self.exclude_set = builder
+            .build()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        self.exclude = globs;
+        Ok(self)
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        if self.exclude.is_empty() {
+            return false;
+        }
+
+        let name_match = path.file_name().map(|n| self.exclude_set.is_match(n)).unwrap_or(false);
+        name_match || self.exclude_set.is_match(path)
+    }
+
+    fn accepts_metadata(&self, meta: &fs::Metadata) -> bool {
+        self.accepts_size_and_age(meta.len(), meta.modified().ok())
+    }
+
+    fn accepts_size_and_age(&self, size: u64, modified: Option<SystemTime>) -> bool {
+        if self.min_size.map(|min| size < min).unwrap_or(false) {
+            return false;
+        }
+
+        if self.max_size.map(|max| size > max).unwrap_or(false) {
+            return false;
+        }
+
+        if self.newer_than.is_none() && self.older_than.is_none() {
+            return true;
+        }
+
+        let age = modified
+            .and_then(|m| SystemTime::now().duration_since(m).ok())
+            .unwrap_or(Duration::ZERO)
+            .as_secs();
+
+        let newer = self.newer_than.map(|secs| age <= secs).unwrap_or(true);
+        let older = self.older_than.map(|secs| age > secs).unwrap_or(true);
+        newer && older
+    }
+
+    /// Whether a file listed under `root` without a walk passes, like the files of a remote tree.
+    ///
+    /// Hidden entries count by name alone and gitignore files are not read.
+    pub(crate) fn accepts_listed(&self, root: &Path, path: &Path, size: u64, mtime: i64) -> bool {
+        let modified = SystemTime::UNIX_EPOCH.checked_add(Duration::from_nanos(mtime.max(0) as u64));
+        let hidden = self.skip_hidden
+            && path
+                .strip_prefix(root)
+                .unwrap_or(path)
+                .components()
+                .any(|c| c.as_os_str().to_string_lossy().starts_with('.'));
+        !hidden && self.accepts_extension(path) && self.accepts_size_and_age(size, modified) && !self.is_excluded(path)
+    }
+
+    fn accepts_extension(&self, path: &Path) -> bool {
+        let Some(extensions) = &self.extensions else {
+            return true;
+        };
+
+        path.extension()
+            .map(|ext| ext.to_string_lossy().to_lowercase())
+            .map(|ext| extensions.iter().any(|e| e == &ext))
+            .unwrap_or(false)
+    }
+}
+
+/// Applies a `FilterSet` during a walk, caching the `.gitignore` files read along the way
+pub(crate) struct Filter {
+    set: FilterSet,
+    root: PathBuf,
+    root_dev: Option<u64>,
+    ignores: HashMap<PathBuf, Option<Gitignore>>,
+}
+
+impl Filter {
+    pub(crate) fn new(set: FilterSet, root: &Path) -> Self {
+        let root_dev = fs::metadata(root).map(|meta| meta.dev()).ok();
+        Self { set, root: root.to_owned(), root_dev, ignores: HashMap::new() }
+    }
+
+    pub(crate) fn max_depth(&self) -> Option<usize> {
+        self.set.max_depth
+    }
+
+    pub(crate) fn symlinks(&self) -> SymlinkMode {
+        self.set.symlinks
+    }
+
+    pub(crate) fn empty_dirs(&self) -> bool {
+        self.set.empty_dirs
+    }
+
+    pub(crate) fn sysroot(&self) -> Option<&Path> {
+        self.set.sysroot.as_deref()
+    }
+
+    /// Whether the walk should descend into `path`
+    pub(crate) fn accepts_dir(&mut self, path: &Path) -> bool {
+        if self.set.gitignore && path.file_name().map(|n| n == ".git").unwrap_or(false) {
+            return false;
+        }
+
+        if self.set.one_file_system && !self.is_on_root_device(path) {
+            return false;
+        }
+
+        self.accepts_entry(path, true)
+    }
+
+    /// Whether the regular file at `path` should be compared
+    pub(crate) fn accepts_file(&mut self, path: &Path, meta: &fs::Metadata) -> bool {
+        let accepted =
+            self.set.accepts_extension(path) && self.set.accepts_metadata(meta) && self.accepts_entry(path, false);
+        FILES_MET.fetch_add(1, Ordering::Relaxed);
+        FILES_FILTERED.fetch_add(!accepted as u64, Ordering::Relaxed);
+        accepted
+    }
+
+    fn accepts_entry(&mut self, path: &Path, is_dir: bool) -> bool {
+        if self.set.skip_hidden && is_hidden(path) {
+            return false;
+        }
+
+        if self.set.is_excluded(path) {
+            return false;
+        }
+
+        !(self.set.gitignore && self.is_git_ignored(path, is_dir))
+    }
+
+    fn is_on_root_device(&self, path: &Path) -> bool {
+        let Some(root_dev) = self.root_dev else {
+            return true;
+        };
+
+        match fs::metadata(path) {
+            Ok(meta) => meta.dev() == root_dev,
+            Err(err) => {
+                if skipped::record(path, Stage::Scan, &err) {
+                    warn!("Could not access metadata for {}: {}", &path.display(), err);
+                }
+                false
+            }
+        }
+    }
+
+    fn is_git_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        let dirs: Vec<PathBuf> = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&self.root))
+            .map(|dir| dir.to_owned())
+            .collect();
+
+        // the closest .gitignore has the last word
+        for dir in dirs {
+            let ignore = self.ignores.entry(dir).or_insert_with_key(|dir| load_gitignore(dir));
+            let Some(ignore) = ignore else {
+                continue;
+            };
+
+            let matched = ignore.matched(path, is_dir);
+            if matched.is_ignore() {
+                return true;
+            }
+
+            if matched.is_whitelist() {
+                return false;
+            }
+        }
+
+        false
+    }
+}
+
+/// Files met by the walks and how many of them the filters left out, then starts counting again.
+///
+/// Files inside excluded directories are never met, they do not count.
+pub(crate) fn take_counts() -> (u64, u64) {
+    (FILES_MET.swap(0, Ordering::Relaxed), FILES_FILTERED.swap(0, Ordering::Relaxed))
+}
+
+// ----------
+//  Internal
+// ----------
+
+fn load_gitignore(dir: &Path) -> Option<Gitignore> {
+    let file = dir.join(".gitignore");
+    if !file.is_file() {
+        return None;
+    }
+
+    let (ignore, err) = Gitignore::new(&file);
+    if let Some(err) = err {
+        warn!("There was an error when reading {}: {}", &file.display(), err);
+    }
+    Some(ignore)
+}
+
+fn is_hidden(path: &Path) -> bool {
+    let dotted = path
+        .file_name()
+        .map(|name| name.to_string_lossy().starts_with('.'))
+        .unwrap_or(false);
+
+    #[cfg(windows)]
+    let dotted = dotted || {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        fs::symlink_metadata(path)
+            .map(|meta| meta.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+            .unwrap_or(false)
+    };
+
+    dotted
+}
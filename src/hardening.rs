@@ -0,0 +1,88 @@
+use crate::config::Config;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+use crate::seccomp::deny_metadata_changes;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Makes the rest of the run unable to modify the trees it reads, as `--hardened` asks.
+///
+/// The kernel is told through Landlock that files may only be written, created, removed or
+/// renamed beneath the folders of the reports, the checkpoint, the index, the manifest, the
+/// script and the hash cache, which must lie outside the scanned folders, and through seccomp that
+/// no file may get other rights, owners, times or extended attributes. Reads stay allowed
+/// everywhere. Kernels without Landlock get an error rather than an unconfined run.
+pub fn confine(config: &Config) -> io::Result<()> {
+    if config.dedupe.is_some() {
+        return Err(invalid("--hardened runs only read the trees, they cannot dedupe them".to_owned()));
+    }
+
+    let mut files: Vec<&Path> = config.output.iter().map(PathBuf::as_path).collect();
+    files.extend(config.checkpoint.as_deref());
+    files.extend(config.append_index.as_deref());
+    files.extend(config.manifest.as_deref());
+    files.extend(config.emit_script.as_ref().map(|(path, _)| path.as_path()));
+    if let Some(dir) = config.cache.as_deref().and_then(Path::parent) {
+        // the cache is opened once confined, its folder has to be there already
+        std::fs::create_dir_all(dir)?;
+    }
+    files.extend(config.cache.as_deref());
+
+    let roots: Vec<PathBuf> = [&config.lhs, &config.rhs]
+        .iter()
+        .filter_map(|root| std::fs::canonicalize(root).ok())
+        .collect();
+    let mut writable: Vec<PathBuf> = vec![];
+    for file in files.into_iter().filter(|file| file.as_os_str() != "-") {
+        let parent = file
+            .parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new("."));
+        let dir = std::fs::canonicalize(parent)?;
+        if let Some(root) = roots
+            .iter()
+            .find(|root| dir.starts_with(root) || root.starts_with(&dir))
+        {
+            let msg = format!(
+                "--hardened keeps {} writable, it cannot be inside or above the scanned {}",
+                dir.display(),
+                root.display()
+            );
+            return Err(invalid(msg));
+        }
+        if !writable.contains(&dir) {
+            writable.push(dir);
+        }
+    }
+    restrict(&writable)
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// Landlock for the files written, created, removed or renamed, seccomp for their rights, owners,
+/// times and extended attributes
+#[cfg(target_os = "linux")]
+fn restrict(writable: &[PathBuf]) -> io::Result<()> {
+    crate::landlock::restrict(writable)?;
+    deny_metadata_changes()
+}
+
+#[cfg(all(target_os = "linux", not(any(target_arch = "x86_64", target_arch = "aarch64"))))]
+fn deny_metadata_changes() -> io::Result<()> {
+    let msg = "--hardened only knows the system calls of x86_64 and aarch64";
+    Err(io::Error::new(io::ErrorKind::Unsupported, msg))
+}
+
+/// Only Linux has Landlock, elsewhere the run is refused rather than left unconfined
+#[cfg(not(target_os = "linux"))]
+fn restrict(_: &[PathBuf]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "--hardened needs Landlock, which only Linux has",
+    ))
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
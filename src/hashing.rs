@@ -0,0 +1,259 @@
+use crate::config::Key;
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::hash::{BuildHasherDefault, Hasher};
+use xxhash_rust::xxh3::Xxh3;
+
+/// A streaming hasher over file content
+pub trait FileHasher {
+    fn update(&mut self, bytes: &[u8]);
+
+    /// The full digest, most significant byte first
+    fn digest(&self) -> Vec<u8>;
+
+    /// The first 8 bytes of the digest, used to group files
+    fn key(&self) -> Key {
+        let digest = self.digest();
+        let mut bytes = [0u8; 8];
+        let n = digest.len().min(8);
+        bytes[..n].copy_from_slice(&digest[..n]);
+        Key::from_be_bytes(bytes)
+    }
+
+    fn hex(&self) -> String {
+        self.digest().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum HashAlgorithm {
+    /// MetroHash64, fast and not cryptographic
+    #[default]
+    Metro,
+    /// XXH3 64 bits, SIMD accelerated and not cryptographic
+    Xxh3,
+    /// BLAKE3, cryptographic and fast
+    Blake3,
+    /// SHA-256, cryptographic
+    Sha256,
+}
+
+impl HashAlgorithm {
+    pub fn hasher(self) -> Box<dyn FileHasher> {
+        match self {
+            Self::Metro => Box::new(Metro64::new(0)),
+            Self::Xxh3 => Box::new(Xxh3Hasher(Xxh3::new())),
+            Self::Blake3 => Box::new(Blake3Hasher(blake3::Hasher::new())),
+            Self::Sha256 => Box::new(Sha256Hasher(sha2::Sha256::new())),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Metro => "metro",
+            Self::Xxh3 => "xxh3",
+            Self::Blake3 => "blake3",
+            Self::Sha256 => "sha256",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Metro, Self::Xxh3, Self::Blake3, Self::Sha256]
+            .iter()
+            .copied()
+            .find(|alg| alg.name() == name)
+    }
+
+    /// Whether finding two different files with the same digest is practically impossible
+    pub fn is_cryptographic(self) -> bool {
+        matches!(self, Self::Blake3 | Self::Sha256)
+    }
+}
+
+/// Builds the hasher of the maps keyed by sizes or hashes, which need no protection against collisions
+pub(crate) type BuildMetro = BuildHasherDefault<Metro64>;
+
+// ----------
+//  Internal
+// ----------
+
+struct Xxh3Hasher(Xxh3);
+
+impl FileHasher for Xxh3Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn digest(&self) -> Vec<u8> {
+        self.0.digest().to_be_bytes().to_vec()
+    }
+}
+
+struct Blake3Hasher(blake3::Hasher);
+
+impl FileHasher for Blake3Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn digest(&self) -> Vec<u8> {
+        self.0.finalize().as_bytes().to_vec()
+    }
+}
+
+struct Sha256Hasher(sha2::Sha256);
+
+impl FileHasher for Sha256Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn digest(&self) -> Vec<u8> {
+        self.0.clone().finalize().to_vec()
+    }
+}
+
+const K0: u64 = 0xD6D0_18F5;
+const K1: u64 = 0xA2AA_033B;
+const K2: u64 = 0x6299_2FC1;
+const K3: u64 = 0x30BC_5B29;
+
+/// Streaming MetroHash64, so hashing a whole file does not buffer it in memory
+pub(crate) struct Metro64 {
+    vseed: u64,
+    v: [u64; 4],
+    buf: [u8; 32],
+    buffered: usize,
+    bytes: u64,
+}
+
+impl Metro64 {
+    pub(crate) fn new(seed: u64) -> Self {
+        let vseed = seed.wrapping_add(K2).wrapping_mul(K0);
+        Self { vseed, v: [vseed; 4], buf: [0; 32], buffered: 0, bytes: 0 }
+    }
+
+    fn block(&mut self, block: &[u8]) {
+        let v = &mut self.v;
+        v[0] = v[0].wrapping_add(read_u64(&block[0..]).wrapping_mul(K0));
+        v[0] = v[0].rotate_right(29).wrapping_add(v[2]);
+        v[1] = v[1].wrapping_add(read_u64(&block[8..]).wrapping_mul(K1));
+        v[1] = v[1].rotate_right(29).wrapping_add(v[3]);
+        v[2] = v[2].wrapping_add(read_u64(&block[16..]).wrapping_mul(K2));
+        v[2] = v[2].rotate_right(29).wrapping_add(v[0]);
+        v[3] = v[3].wrapping_add(read_u64(&block[24..]).wrapping_mul(K3));
+        v[3] = v[3].rotate_right(29).wrapping_add(v[1]);
+    }
+}
+
+impl Default for Metro64 {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl Hasher for Metro64 {
+    fn write(&mut self, bytes: &[u8]) {
+        self.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let mut v = self.v;
+        if self.bytes >= 32 {
+            v[2] ^= (v[0].wrapping_add(v[3]).wrapping_mul(K0).wrapping_add(v[1]))
+                .rotate_right(37)
+                .wrapping_mul(K1);
+            v[3] ^= (v[1].wrapping_add(v[2]).wrapping_mul(K1).wrapping_add(v[0]))
+                .rotate_right(37)
+                .wrapping_mul(K0);
+            v[0] ^= (v[0].wrapping_add(v[2]).wrapping_mul(K0).wrapping_add(v[3]))
+                .rotate_right(37)
+                .wrapping_mul(K1);
+            v[1] ^= (v[1].wrapping_add(v[3]).wrapping_mul(K1).wrapping_add(v[2]))
+                .rotate_right(37)
+                .wrapping_mul(K0);
+            v[0] = self.vseed.wrapping_add(v[0] ^ v[1]);
+        }
+
+        let mut tail = &self.buf[..self.buffered];
+        if tail.len() >= 16 {
+            v[1] = v[0].wrapping_add(read_u64(tail).wrapping_mul(K2));
+            v[1] = v[1].rotate_right(29).wrapping_mul(K3);
+            v[2] = v[0].wrapping_add(read_u64(&tail[8..]).wrapping_mul(K2));
+            v[2] = v[2].rotate_right(29).wrapping_mul(K3);
+            v[1] ^= v[1].wrapping_mul(K0).rotate_right(21).wrapping_add(v[2]);
+            v[2] ^= v[2].wrapping_mul(K3).rotate_right(21).wrapping_add(v[1]);
+            v[0] = v[0].wrapping_add(v[2]);
+            tail = &tail[16..];
+        }
+
+        if tail.len() >= 8 {
+            v[0] = v[0].wrapping_add(read_u64(tail).wrapping_mul(K3));
+            v[0] ^= v[0].rotate_right(55).wrapping_mul(K1);
+            tail = &tail[8..];
+        }
+
+        if tail.len() >= 4 {
+            let word = u32::from_le_bytes([tail[0], tail[1], tail[2], tail[3]]) as u64;
+            v[0] = v[0].wrapping_add(word.wrapping_mul(K3));
+            v[0] ^= v[0].rotate_right(26).wrapping_mul(K1);
+            tail = &tail[4..];
+        }
+
+        if tail.len() >= 2 {
+            let word = u16::from_le_bytes([tail[0], tail[1]]) as u64;
+            v[0] = v[0].wrapping_add(word.wrapping_mul(K3));
+            v[0] ^= v[0].rotate_right(48).wrapping_mul(K1);
+            tail = &tail[2..];
+        }
+
+        if !tail.is_empty() {
+            v[0] = v[0].wrapping_add((tail[0] as u64).wrapping_mul(K3));
+            v[0] ^= v[0].rotate_right(37).wrapping_mul(K1);
+        }
+
+        v[0] ^= v[0].rotate_right(28);
+        v[0] = v[0].wrapping_mul(K0);
+        v[0] ^= v[0].rotate_right(29);
+        v[0]
+    }
+}
+
+impl FileHasher for Metro64 {
+    fn update(&mut self, mut bytes: &[u8]) {
+        self.bytes += bytes.len() as u64;
+        if self.buffered > 0 {
+            let n = (32 - self.buffered).min(bytes.len());
+            self.buf[self.buffered..self.buffered + n].copy_from_slice(&bytes[..n]);
+            self.buffered += n;
+            bytes = &bytes[n..];
+            if self.buffered < 32 {
+                return;
+            }
+
+            let block = self.buf;
+            self.block(&block);
+            self.buffered = 0;
+        }
+
+        let mut blocks = bytes.chunks_exact(32);
+        for block in blocks.by_ref() {
+            self.block(block);
+        }
+
+        let rest = blocks.remainder();
+        self.buf[..rest.len()].copy_from_slice(rest);
+        self.buffered = rest.len();
+    }
+
+    fn digest(&self) -> Vec<u8> {
+        Hasher::finish(self).to_be_bytes().to_vec()
+    }
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(word)
+}
@@ -0,0 +1,307 @@
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+/// Languages of the human-facing messages, the reports and other machine formats stay in English
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Lang {
+    #[default]
+    En,
+    Es,
+}
+
+/// Every message shown to the user, `{}` marks where the arguments go
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Msg {
+    Compatible,
+    EstimatingChunks,
+    FindingSimilar,
+    RenamesNeedTwoPaths,
+    CompareNeedsTwoPaths,
+    RemoveNeedsTrashOrForce,
+    TrashOrForceOnlyRemove,
+    ModeNeedsTwoPaths,
+    StdoutTwice,
+    Rescanning,
+    Serving,
+    ServeToken,
+    AddingVolume,
+    DirectionNeedsTwoPaths,
+    WritingReport,
+    Complete,
+    StoppedListing,
+    StoppedComparing,
+    PartialReport,
+    ResumeWith,
+    Resuming,
+    Scanning,
+    Progress,
+    Hashing,
+    Comparing,
+    UnableToHash,
+    StandardizeError,
+    SavedProfile,
+    InstalledCron,
+    WroteUnit,
+    EnabledTimer,
+    EnableTimerFailed,
+    WouldWrite,
+    WouldWriteDatabase,
+    WouldRemove,
+    WouldRun,
+    WouldLink,
+    WouldClone,
+    WouldShare,
+    WouldTrash,
+    Deduped,
+    ChangedSinceScan,
+    InexactGroup,
+    LinkedGroups,
+    WroteScript,
+    WroteManifest,
+    Pass,
+    FilesDiffer,
+    Watching,
+    TooManyDuplicates,
+    DuHeader,
+    StagePrefix,
+    StageDigest,
+    StageContent,
+    Queued,
+    FoundSoFar,
+    DeviceThroughput,
+    DeviceRate,
+    WorkerThroughput,
+    WorkerRate,
+    BytesRead,
+    PhaseStats,
+    PhaseRate,
+    PhaseWalk,
+    PhasePreprocess,
+    PhaseHash,
+    PhaseCompare,
+    Throughput,
+    SameRoots,
+    SelfNeedsOnePath,
+    SkippedMore,
+    SkippedBatch,
+}
+
+static LANG: OnceLock<Lang> = OnceLock::new();
+
+/// Picks the language from `--lang`, or else from `LC_ALL`, `LC_MESSAGES` and `LANG`
+pub fn init(lang: Option<Lang>) {
+    _ = LANG.set(lang.unwrap_or_else(from_env));
+}
+
+pub fn text(msg: Msg) -> &'static str {
+    match LANG.get().copied().unwrap_or_default() {
+        Lang::En => english(msg),
+        Lang::Es => spanish(msg),
+    }
+}
+
+/// The message with its `{}` replaced by `args`, in order
+pub fn tr(msg: Msg, args: &[&dyn Display]) -> String {
+    let mut out = String::new();
+    let mut args = args.iter();
+    let mut parts = text(msg).split("{}").peekable();
+    while let Some(part) = parts.next() {
+        out.push_str(part);
+        if parts.peek().is_some() {
+            out.push_str(&args.next().map(|a| a.to_string()).unwrap_or_default());
+        }
+    }
+    out
+}
+
+// ----------
+//  Internal
+// ----------
+
+fn from_env() -> Lang {
+    let locale = ["LC_ALL", "LC_MESSAGES", "LANG"]
+        .iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.is_empty())
+        .unwrap_or_default();
+
+    // e.g. es_AR.UTF-8
+    match locale.split(['_', '.', '@']).next().unwrap_or_default() {
+        "es" => Lang::Es,
+        _ => Lang::En,
+    }
+}
+
+fn english(msg: Msg) -> &'static str {
+    match msg {
+        Msg::Compatible => "{} is compatible with schema version {}",
+        Msg::EstimatingChunks => "Estimating chunk-level deduplication with {} chunks",
+        Msg::FindingSimilar => "Looking for files sharing at least {}% of their content",
+        Msg::RenamesNeedTwoPaths => "Rename detection needs two different paths, skipping it",
+        Msg::CompareNeedsTwoPaths => "rcompare compare needs two different paths",
+        Msg::RemoveNeedsTrashOrForce => {
+            "Removing the copies needs --trash to move them to the trash, or --force to delete them for good"
+        }
+        Msg::TrashOrForceOnlyRemove => "--trash and --force only go with --action remove, and not together",
+        Msg::ModeNeedsTwoPaths => "--mode two-roots and --mode mirror need two different paths",
+        Msg::StdoutTwice => "--print0 and -o - both write to stdout, pick one",
+        Msg::Rescanning => "{} paths changed, scanning again",
+        Msg::Serving => "Listening on http://{}",
+        Msg::ServeToken => "Requests need Authorization: Bearer and the token in {}",
+        Msg::AddingVolume => "Adding volume '{}' to the index {}",
+        Msg::DirectionNeedsTwoPaths => "Checking a direction needs two different paths, skipping it",
+        Msg::WritingReport => "Writing report to file '{}'",
+        Msg::Complete => "rcompare complete!",
+        Msg::StoppedListing => "Interrupted while listing the files, after {} of them",
+        Msg::StoppedComparing => "Interrupted after comparing {} of {} groups of candidates, at files of {}",
+        Msg::PartialReport => "The report is partial, it only holds the groups confirmed before the interruption",
+        Msg::ResumeWith => "The progress is saved, go on with --resume {}",
+        Msg::Resuming => "Resuming the scan saved in {}, {} groups of candidates are left",
+        Msg::Scanning => "Scanning",
+        Msg::Progress => "Progress: {}% --- {}",
+        Msg::Hashing => "Hashing {}",
+        Msg::Comparing => "Comparing {} vs {}",
+        Msg::UnableToHash => "Unable to hash file {}",
+        Msg::StandardizeError => "There was an error when standardizing the path '{}'. Error: {}",
+        Msg::SavedProfile => "Saved profile '{}' to {}",
+        Msg::InstalledCron => "Installed the cron entry for profile '{}'",
+        Msg::WroteUnit => "Wrote {} and its timer to {}",
+        Msg::EnabledTimer => "Enabled {}.timer",
+        Msg::EnableTimerFailed => "Could not enable the timer, run `systemctl --user enable --now {}.timer`",
+        Msg::WouldWrite => "Dry run, would write {} bytes to {}",
+        Msg::WouldWriteDatabase => "Dry run, would write {} files in {} groups to the database {}",
+        Msg::WouldRemove => "Dry run, would remove {}",
+        Msg::WouldRun => "Dry run, would run {}",
+        Msg::WouldLink => "Dry run, would link {} as {}",
+        Msg::WouldClone => "Dry run, would clone {} as {}",
+        Msg::WouldShare => "Dry run, would share the extents of {} with {}",
+        Msg::WouldTrash => "Dry run, would move {} to the trash",
+        Msg::Deduped => "Deduplicated {} redundant files, reclaiming {}",
+        Msg::ChangedSinceScan => "{} changed since the scan, leaving it alone",
+        Msg::InexactGroup => {
+            "The copies of {} were not compared on their exact contents, leaving them alone - rescan without --quick, \
+             --ignore-ranges, --text-normalize or policies to act on them"
+        }
+        Msg::LinkedGroups => "Linked {} files of {} duplicate groups into {}",
+        Msg::WroteScript => "Wrote the commands for {} redundant files to {}, review it before running it",
+        Msg::WroteManifest => "Wrote the hashes of {} files to the manifest {}",
+        Msg::Pass => "Pass {} of {}",
+        Msg::FilesDiffer => "{} and {} differ from byte {}",
+        Msg::Watching => "Watching {} and {} directories under it for changes",
+        Msg::TooManyDuplicates => "Found {} duplicate groups of {} files wasting {}, over the limit of {}",
+        Msg::DuHeader => "apparent\tunique\tduplicated\tdirectory",
+        Msg::StagePrefix => "Hashing the first bytes",
+        Msg::StageDigest => "Hashing whole files",
+        Msg::StageContent => "Comparing byte by byte",
+        Msg::Queued => "{} groups of candidates queued",
+        Msg::FoundSoFar => "{} duplicates in {} groups so far, wasting {}",
+        Msg::DeviceThroughput => "Reads by device:",
+        Msg::DeviceRate => "  {}: {} in {}s waiting, {}/s",
+        Msg::WorkerThroughput => "Time by worker:",
+        Msg::WorkerRate => "  {}: {}s busy, {} read, {}% of it waiting for reads",
+        Msg::BytesRead => "Read {} of the {} the files hold, the filters spared {}% of it",
+        Msg::PhaseStats => "Time by phase, {}s in all:",
+        Msg::PhaseRate => "  {}: {}s, {} files at {}/s, {} read at {}/s",
+        Msg::PhaseWalk => "walk",
+        Msg::PhasePreprocess => "grouping",
+        Msg::PhaseHash => "hashing",
+        Msg::PhaseCompare => "comparison",
+        Msg::Throughput => "Reading at {}",
+        Msg::SameRoots => {
+            "{} and {} are the same directory, looking for duplicates inside it, pass --self if that is what you meant"
+        }
+        Msg::SelfNeedsOnePath => "--self searches a single directory, but {} and {} are different ones",
+        Msg::SkippedMore => "More files are skipped with '{}', they are only counted until the end",
+        Msg::SkippedBatch => "{}: {} files skipped under {}, the report lists them all",
+    }
+}
+
+fn spanish(msg: Msg) -> &'static str {
+    match msg {
+        Msg::Compatible => "{} es compatible con la versión {} del esquema",
+        Msg::EstimatingChunks => "Estimando la deduplicación por bloques con bloques de {}",
+        Msg::FindingSimilar => "Buscando archivos que compartan al menos un {}% de su contenido",
+        Msg::RenamesNeedTwoPaths => "Detectar renombrados requiere dos rutas distintas, se omite",
+        Msg::CompareNeedsTwoPaths => "rcompare compare requiere dos rutas distintas",
+        Msg::RemoveNeedsTrashOrForce => {
+            "Quitar las copias requiere --trash para moverlas a la papelera, o --force para borrarlas del todo"
+        }
+        Msg::TrashOrForceOnlyRemove => "--trash y --force solo van con --action remove, y no juntas",
+        Msg::ModeNeedsTwoPaths => "--mode two-roots y --mode mirror requieren dos rutas distintas",
+        Msg::StdoutTwice => "--print0 y -o - escriben ambos en stdout, elija uno",
+        Msg::Rescanning => "{} rutas cambiaron, se escanea de nuevo",
+        Msg::Serving => "Escuchando en http://{}",
+        Msg::ServeToken => "Las peticiones requieren Authorization: Bearer y el token de {}",
+        Msg::AddingVolume => "Agregando el volumen '{}' al índice {}",
+        Msg::DirectionNeedsTwoPaths => "Verificar una dirección requiere dos rutas distintas, se omite",
+        Msg::WritingReport => "Escribiendo el informe en el archivo '{}'",
+        Msg::Complete => "¡rcompare terminó!",
+        Msg::StoppedListing => "Interrumpido mientras se listaban los archivos, tras {} de ellos",
+        Msg::StoppedComparing => "Interrumpido tras comparar {} de {} grupos de candidatos, en archivos de {}",
+        Msg::PartialReport => "El informe es parcial, solo contiene los grupos confirmados antes de la interrupción",
+        Msg::ResumeWith => "El progreso está guardado, continúa con --resume {}",
+        Msg::Resuming => "Reanudando el análisis guardado en {}, quedan {} grupos de candidatos",
+        Msg::Scanning => "Recorriendo",
+        Msg::Progress => "Progreso: {}% --- {}",
+        Msg::Hashing => "Calculando el hash de {}",
+        Msg::Comparing => "Comparando {} con {}",
+        Msg::UnableToHash => "No se pudo calcular el hash del archivo {}",
+        Msg::StandardizeError => "Hubo un error al normalizar la ruta '{}'. Error: {}",
+        Msg::SavedProfile => "Perfil '{}' guardado en {}",
+        Msg::InstalledCron => "Se instaló la entrada de cron del perfil '{}'",
+        Msg::WroteUnit => "Se escribió {} y su temporizador en {}",
+        Msg::EnabledTimer => "Se activó {}.timer",
+        Msg::EnableTimerFailed => {
+            "No se pudo activar el temporizador, ejecute `systemctl --user enable --now {}.timer`"
+        }
+        Msg::WouldWrite => "Simulación, se escribirían {} bytes en {}",
+        Msg::WouldWriteDatabase => "Simulación, se escribirían {} ficheros en {} grupos en la base de datos {}",
+        Msg::WouldRemove => "Simulación, se eliminaría {}",
+        Msg::WouldRun => "Simulación, se ejecutaría {}",
+        Msg::WouldLink => "Simulación, se enlazaría {} como {}",
+        Msg::WouldClone => "Simulación, se clonaría {} como {}",
+        Msg::WouldShare => "Simulación, se compartirían las extensiones de {} con {}",
+        Msg::WouldTrash => "Simulación, se movería {} a la papelera",
+        Msg::Deduped => "Se deduplicaron {} archivos redundantes, recuperando {}",
+        Msg::ChangedSinceScan => "{} cambió desde el análisis, no se toca",
+        Msg::InexactGroup => {
+            "Las copias de {} no se compararon por su contenido exacto, no se tocan - vuelva a analizar sin --quick, \
+             --ignore-ranges, --text-normalize ni políticas para actuar sobre ellas"
+        }
+        Msg::LinkedGroups => "Se enlazaron {} archivos de {} grupos de duplicados en {}",
+        Msg::WroteScript => {
+            "Se escribieron las órdenes para {} archivos redundantes en {}, revíselo antes de ejecutarlo"
+        }
+        Msg::WroteManifest => "Se escribieron los hashes de {} archivos en el manifiesto {}",
+        Msg::Pass => "Pasada {} de {}",
+        Msg::FilesDiffer => "{} y {} difieren a partir del byte {}",
+        Msg::Watching => "Vigilando los cambios en {} y {} directorios bajo él",
+        Msg::TooManyDuplicates => {
+            "Se encontraron {} grupos de duplicados de {} archivos que desperdician {}, por encima del límite de {}"
+        }
+        Msg::DuHeader => "aparente\túnico\tduplicado\tdirectorio",
+        Msg::StagePrefix => "Calculando el hash de los primeros bytes",
+        Msg::StageDigest => "Calculando el hash de archivos completos",
+        Msg::StageContent => "Comparando byte a byte",
+        Msg::Queued => "{} grupos de candidatos en cola",
+        Msg::FoundSoFar => "{} duplicados en {} grupos hasta ahora, desperdiciando {}",
+        Msg::DeviceThroughput => "Lecturas por dispositivo:",
+        Msg::DeviceRate => "  {}: {} en {}s de espera, {}/s",
+        Msg::WorkerThroughput => "Tiempo por hilo:",
+        Msg::WorkerRate => "  {}: {}s ocupado, {} leídos, {}% de él esperando lecturas",
+        Msg::BytesRead => "Se leyeron {} de los {} que ocupan los archivos, los filtros ahorraron un {}%",
+        Msg::PhaseStats => "Tiempo por fase, {}s en total:",
+        Msg::PhaseRate => "  {}: {}s, {} archivos a {}/s, {} leídos a {}/s",
+        Msg::PhaseWalk => "recorrido",
+        Msg::PhasePreprocess => "agrupación",
+        Msg::PhaseHash => "hash",
+        Msg::PhaseCompare => "comparación",
+        Msg::Throughput => "Leyendo a {}",
+        Msg::SameRoots => {
+            "{} y {} son el mismo directorio, se buscan duplicados dentro de él, use --self si es lo que quería"
+        }
+        Msg::SelfNeedsOnePath => "--self busca en un único directorio, pero {} y {} son distintos",
+        Msg::SkippedMore => "Se omiten más archivos por '{}', solo se cuentan hasta el final",
+        Msg::SkippedBatch => "{}: {} archivos omitidos bajo {}, el informe los lista todos",
+    }
+}
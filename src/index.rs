@@ -0,0 +1,139 @@
+use crate::cmp::hash_full_file;
+use crate::common::Processed;
+use crate::hashing::HashAlgorithm;
+use crate::i18n::{tr, Msg};
+use crate::say;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+/// One file recorded in a multi-volume index, written as a JSON line
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexEntry {
+    pub volume: String,
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_base64: Option<String>,
+    pub size: u64,
+    /// full content hash, in hexadecimal
+    pub hash: String,
+    #[serde(default)]
+    pub algorithm: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct IndexReport {
+    pub volumes: Vec<String>,
+    pub entries: usize,
+    pub same: Vec<Vec<IndexEntry>>,
+}
+
+pub fn load_index<P: AsRef<Path>>(path: &P) -> io::Result<Vec<IndexEntry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err),
+    };
+
+    let mut entries = vec![];
+    for (n, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let entry = serde_json::from_str(&line)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("line {} of the index: {}", n + 1, e)))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Replaces the entries of `volume` in the index with the files of this run, then groups the
+/// duplicates across every volume recorded so far.
+///
+/// Cryptographic algorithms are kept as given, the others are replaced by BLAKE3, as volumes
+/// indexed apart are only ever matched by their digests.
+pub fn append_to_index<P: AsRef<Path>>(
+    path: &P,
+    volume: &str,
+    res: &Processed,
+    read_size: usize,
+    algorithm: HashAlgorithm,
+    dry_run: bool,
+) -> io::Result<IndexReport> {
+    let algorithm = match algorithm.is_cryptographic() {
+        true => algorithm,
+        false => HashAlgorithm::Blake3,
+    };
+    let mut entries: Vec<IndexEntry> = load_index(path)?.into_iter().filter(|e| e.volume != volume).collect();
+
+    for fl in res.info.iter().filter(|fl| fl.size > 0 && !fl.is_link_target()) {
+        let hash = hash_full_file(&fl.content_path(), read_size, algorithm);
+        if let Err(err) = hash {
+            eprintln!("Unable to hash file {} for the index", &fl.path.display());
+            eprintln!("Error: {:?}", err);
+            continue;
+        }
+
+        entries.push(IndexEntry {
+            volume: volume.to_owned(),
+            path: fl.path.to_string_lossy().into_owned(),
+            path_base64: crate::encoding::raw_bytes(&fl.path),
+            size: fl.size,
+            hash: hash.unwrap().hex(),
+            algorithm: algorithm.name().to_owned(),
+        });
+    }
+
+    let mut contents = vec![];
+    for entry in entries.iter() {
+        serde_json::to_writer(&mut contents, entry)?;
+        contents.push(b'\n');
+    }
+
+    if dry_run {
+        say!("{}", tr(Msg::WouldWrite, &[&contents.len(), &path.as_ref().display()]));
+    } else {
+        write_index(path, &contents)?;
+    }
+
+    // digests of different algorithms never match, even from older runs with another --hash
+    let mut groups: HashMap<(u64, &str, &str), Vec<&IndexEntry>> = HashMap::new();
+    for entry in entries.iter() {
+        let key = (entry.size, entry.algorithm.as_str(), entry.hash.as_str());
+        groups.entry(key).or_default().push(entry);
+    }
+
+    let mut same: Vec<Vec<IndexEntry>> = groups
+        .into_values()
+        .filter(|group| group.len() > 1)
+        .map(|group| group.into_iter().cloned().collect())
+        .collect();
+    for group in same.iter_mut() {
+        group.sort_by(|a, b| (&a.volume, &a.path).cmp(&(&b.volume, &b.path)));
+    }
+    same.sort_by(|a, b| (&a[0].volume, &a[0].path).cmp(&(&b[0].volume, &b[0].path)));
+
+    let volumes: BTreeSet<&String> = entries.iter().map(|e| &e.volume).collect();
+    Ok(IndexReport {
+        volumes: volumes.into_iter().cloned().collect(),
+        entries: entries.len(),
+        same,
+    })
+}
+
+// ----------
+//  Internal
+// ----------
+
+fn write_index<P: AsRef<Path>>(path: &P, contents: &[u8]) -> io::Result<()> {
+    // write next to the index first, so an interrupted run never leaves it truncated
+    let mut name = path.as_ref().as_os_str().to_owned();
+    name.push(".tmp");
+    let tmp = PathBuf::from(name);
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)
+}
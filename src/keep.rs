@@ -0,0 +1,118 @@
+use crate::file::FileInfo;
+use serde::Serialize;
+use std::cmp::Ordering;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// One way to tell which copy of a duplicate group is worth keeping
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeepRule {
+    /// the file modified first
+    Oldest,
+    /// the file modified last
+    Newest,
+    /// a file under this directory, for priority lists of several of them
+    Prefer(PathBuf),
+    /// a file under the first path given
+    PreferLhs,
+    /// the file with the fewest components, then the fewest bytes, in its path
+    ShortestPath,
+}
+
+/// Rules tried in order to pick the keeper of each group, the next rule breaking the ties of the
+/// previous one and the path order breaking the last ties.
+///
+/// Written as a comma-separated list, like `prefer:/srv/master,prefer:/srv/backup,newest`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepPolicy {
+    pub rules: Vec<KeepRule>,
+}
+
+/// What a file of a duplicate group becomes under the keep policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    Keeper,
+    Redundant,
+}
+
+impl KeepPolicy {
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let rules = spec
+            .split(',')
+            .map(str::trim)
+            .filter(|rule| !rule.is_empty())
+            .map(|rule| match rule {
+                "oldest" => Ok(KeepRule::Oldest),
+                "newest" => Ok(KeepRule::Newest),
+                "prefer-lhs" => Ok(KeepRule::PreferLhs),
+                "shortest-path" => Ok(KeepRule::ShortestPath),
+                _ => match rule.strip_prefix("prefer:") {
+                    Some(dir) if !dir.is_empty() => Ok(KeepRule::Prefer(PathBuf::from(dir))),
+                    _ => Err(format!(
+                        "unknown keep rule {}, expected oldest, newest, prefer-lhs, shortest-path or prefer:<dir>",
+                        rule
+                    )),
+                },
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if rules.is_empty() {
+            return Err("the keep policy needs at least one rule".to_owned());
+        }
+        Ok(Self { rules })
+    }
+
+    /// Index in `files` of the copy to keep, `lhs` being the first path of the scan
+    pub fn keeper(&self, files: &[&FileInfo], lhs: &Path) -> usize {
+        (0..files.len())
+            .min_by(|&a, &b| {
+                self.rules
+                    .iter()
+                    .map(|rule| rule.compare(files[a], files[b], lhs))
+                    .find(|order| order.is_ne())
+                    .unwrap_or_else(|| files[a].path.cmp(&files[b].path))
+            })
+            .unwrap_or(0)
+    }
+}
+
+impl KeepRule {
+    /// `Less` when `a` is the better keeper
+    fn compare(&self, a: &FileInfo, b: &FileInfo, lhs: &Path) -> Ordering {
+        // true sorts after false, so the files matching come first
+        let under = |dir: &Path| (!a.path.starts_with(dir)).cmp(&!b.path.starts_with(dir));
+        match self {
+            KeepRule::Oldest => a.mtime.cmp(&b.mtime),
+            KeepRule::Newest => b.mtime.cmp(&a.mtime),
+            KeepRule::Prefer(dir) => under(dir),
+            KeepRule::PreferLhs => under(lhs),
+            KeepRule::ShortestPath => {
+                let length = |f: &FileInfo| (f.path.components().count(), f.path.as_os_str().len());
+                length(a).cmp(&length(b))
+            }
+        }
+    }
+}
+
+impl fmt::Display for KeepPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rules: Vec<String> = self
+            .rules
+            .iter()
+            .map(|rule| match rule {
+                KeepRule::Oldest => "oldest".to_owned(),
+                KeepRule::Newest => "newest".to_owned(),
+                KeepRule::Prefer(dir) => format!("prefer:{}", dir.display()),
+                KeepRule::PreferLhs => "prefer-lhs".to_owned(),
+                KeepRule::ShortestPath => "shortest-path".to_owned(),
+            })
+            .collect();
+        f.write_str(&rules.join(","))
+    }
+}
+
+impl Serialize for KeepPolicy {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
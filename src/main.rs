@@ -1,38 +1,539 @@
+// the schema of the report is one large json! literal
+#![recursion_limit = "256"]
+
+pub mod archive;
+pub mod cache;
+pub mod checkpoint;
+pub mod chunk;
 pub mod cli;
 pub mod cmp;
 pub mod common;
 pub mod config;
+pub mod content;
+pub mod diff;
+pub mod dirs;
+pub mod empty;
+pub mod encoding;
+pub mod error;
+pub mod export;
+pub mod fds;
 pub mod file;
-use clap::Parser;
-use cli::Cli;
+pub mod filter;
+pub mod hardening;
+pub mod hashing;
+pub mod i18n;
+pub mod index;
+pub mod interrupt;
+pub mod keep;
+#[cfg(target_os = "linux")]
+pub mod landlock;
+pub mod manifest;
+pub mod metrics;
+pub mod pagecache;
+pub mod policy;
+pub mod readers;
+pub mod regions;
+pub mod remote;
+pub mod report;
+#[cfg(feature = "s3")]
+pub mod s3;
+pub mod sandbox;
+pub mod schedule;
+#[cfg(all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub mod seccomp;
+pub mod serve;
+pub mod settings;
+pub mod similar;
+pub mod sink;
+pub mod skipped;
+pub mod source;
+pub mod sparse;
+pub mod stats;
+pub mod term;
+pub mod throughput;
+pub mod trash;
+pub mod verify;
+pub mod volume;
+pub mod walk;
+pub mod warnings;
+#[cfg(target_os = "linux")]
+pub mod watch;
+use i18n::{text, tr, Msg};
 use std::convert::TryInto;
-use std::io::{self, Write};
+use std::io;
 
-fn main() -> io::Result<()> {
-    let cli = Cli::parse();
+fn main() -> error::Result<()> {
+    let cli = cli::parse();
+    i18n::init(cli.lang);
+    term::init(cli.plain, cli.quiet, !cli.no_progress, !cli.no_dashboard, cli.color);
+    let (mut scan, compare, dedupe, interval) = match cli.command {
+        None => (cli.scan, false, None, None),
+        Some(cli::Command::Scan(scan)) => (scan, false, None, None),
+        Some(cli::Command::Compare(scan)) => (scan, true, None, None),
+        Some(cli::Command::Dedupe { action, trash, force, scan }) => {
+            let action = action.with_removal(trash, force).map_err(|msg| {
+                eprintln!("{}", text(msg));
+                io::Error::new(io::ErrorKind::InvalidInput, text(msg))
+            })?;
+            (scan, false, Some(action), None)
+        }
+        Some(cli::Command::Watch { interval, scan }) => (scan, false, None, Some(interval)),
+        Some(cli::Command::Serve { listen }) => {
+            // the report is kept for the API, scans given over it write no file
+            return Ok(serve::serve(&listen, run_scan).inspect_err(|e| eprintln!("{}", e))?);
+        }
+        Some(command) => return Ok(run_command(command, cli.dry_run)?),
+    };
+    #[cfg(not(target_os = "linux"))]
+    if interval.is_some() {
+        return Err(no_watch().into());
+    }
 
-    let config: config::Config = cli.try_into()?;
+    if let Some(path) = &cli.check_compat {
+        report::check_compat(path).inspect_err(|e| eprintln!("{}", e))?;
+        if !term::is_quiet() {
+            say!("{}", tr(Msg::Compatible, &[path, &report::SCHEMA_VERSION]));
+        }
+        return Ok(());
+    }
+
+    scan.settings = cli.config;
+    let mut config: config::Config = scan.try_into()?;
+    if compare && !matches!(config.mode, common::ScanMode::TwoRoots | common::ScanMode::Mirror) {
+        eprintln!("{}", text(Msg::CompareNeedsTwoPaths));
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, text(Msg::CompareNeedsTwoPaths)).into());
+    }
+    config.dedupe = dedupe;
+    config.dry_run = cli.dry_run;
     if config.verbose {
-        println!("The config struct is: {:?}", &config);
+        say!("The config struct is: {:?}", &config);
+        throughput::enable();
+    }
+    if config.stats {
+        stats::enable();
     }
 
-    if let Some(path) = &config.output {
+    // before anything is written, the reports are then created confined
+    if config.hardened {
+        hardening::confine(&config).inspect_err(|e| eprintln!("{}", e))?;
+    }
+    // fail early rather than after a long scan
+    for path in config
+        .output
+        .iter()
+        .filter(|path| !config.dry_run && path.as_os_str() != "-")
+    {
         _ = std::fs::File::create(path)?;
     }
+    if let Some(root) = &config.link_farm {
+        export::check_link_farm(root).inspect_err(|e| eprintln!("{}", e))?;
+    }
+
+    interrupt::install();
+    pagecache::set_direct(config.direct_io);
+    let open_files = fds::set_limit(config.max_open_files);
+    if config.max_open_files.is_some_and(|wanted| wanted > open_files) {
+        eprintln!("--max-open-files is capped at {} by the limit of open files", open_files);
+    }
+    #[cfg(target_os = "linux")]
+    if let Some(interval) = interval {
+        // the paths as the scan spells them, so the changes line up with its files
+        let roots: Vec<_> = [&config.lhs, &config.rhs]
+            .iter()
+            .map(|path| common::resolve_path(&Some(path), config.canonicalize).unwrap_or_else(|_| path.to_path_buf()))
+            .collect();
+        let mut own: Vec<&std::path::Path> = config.output.iter().map(|path| path.as_path()).collect();
+        own.extend(config.checkpoint.as_deref());
+        own.extend(config.append_index.as_deref());
+        own.extend(config.cache.as_deref());
+        own.extend(config.keep_reports.as_ref().map(|(dir, _)| dir.as_path()));
+        let mut tree = watch::TreeWatch::new(&roots, &own)?;
+        let mut res = run_scan(&config)?;
+        loop {
+            write_reports(&config, &res)?;
+            if res.partial {
+                stop_partial();
+            }
+            let changed = tree.wait(std::time::Duration::from_secs(interval))?;
+            // only the changed files are listed again, the hash cache spares reading the others
+            let listing = watch::update(&config, &res, &changed);
+            res = scan_listed(&config, listing)?;
+        }
+    }
+
+    let res = run_scan(&config)?;
+    write_reports(&config, &res)?;
+    // nothing is acted upon after an interruption, the report is all there is
+    if res.partial {
+        stop_partial();
+    }
+    if let Some(root) = &config.link_farm {
+        export::link_farm(&res, root, config.dry_run).inspect_err(|e| eprintln!("{}", e))?;
+    }
+    if let Some(path) = &config.manifest {
+        manifest::write_manifest(path, &res, config.read_size, config.hash, config.dry_run)?;
+    }
+    if let Some(action) = config.dedupe {
+        export::dedupe(&res, action, config.dry_run).inspect_err(|e| eprintln!("{}", e))?;
+    }
+    if let Some((path, action)) = &config.emit_script {
+        export::emit_script(&res, path, *action, config.dry_run).inspect_err(|e| eprintln!("{}", e))?;
+    }
+    // after the report is saved, a failing command does not lose it
+    if let Some(command) = &config.exec_per_group {
+        export::exec_per_group(&res, command, config.dry_run).inspect_err(|e| eprintln!("{}", e))?;
+    }
+    if !term::is_quiet() {
+        say!("{}", text(Msg::Complete));
+    }
+    let identical = res.mirror.as_ref().map(|m| m.is_identical()).unwrap_or(true);
+    if !config.dry_run && (!identical || !res.coverage.as_ref().map(|c| c.is_complete()).unwrap_or(true)) {
+        std::process::exit(1);
+    }
+    if let Some(limit) = config.fail_over {
+        let wasted = res.wasted_bytes();
+        if wasted > limit {
+            let files: usize = res.same.iter().map(|g| g.len()).sum();
+            let msg = tr(
+                Msg::TooManyDuplicates,
+                &[
+                    &res.same.len(),
+                    &files,
+                    &common::stringify_bytes(wasted as usize),
+                    &common::stringify_bytes(limit as usize),
+                ],
+            );
+            eprintln!("{}", msg);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
+
+/// Scans the paths of `config` and runs the analyses asked for, the part of a run repeated by
+/// `watch`
+fn run_scan(config: &config::Config) -> io::Result<common::Processed> {
+    scan_listed(config, None)
+}
+
+/// Like `run_scan`, comparing the files of `listing` instead of walking the paths when given one
+fn scan_listed(config: &config::Config, mut listing: Option<common::Preprocessed>) -> io::Result<common::Processed> {
+    let started = std::time::Instant::now();
+    let mut cmp = cmp::Comparator::from_config(config);
+    walk::set_threads(config.threads);
+    let mut res = common::Processed::default();
+    // two files on their own are compared directly, to tell where they differ
+    let pair = config.lhs.is_file()
+        && config.rhs.is_file()
+        && config.files_from.is_none()
+        && config.passes == 1
+        && config.ignore_ranges.is_empty()
+        && config.policies.is_empty()
+        && config.resume.is_none();
+    for pass in 0..config.passes {
+        if config.passes > 1 && !term::is_quiet() {
+            say!("{}", tr(Msg::Pass, &[&(pass + 1), &config.passes]));
+        }
+        let shard = common::Shard { pass, passes: config.passes };
+        let prep = match (listing.take(), &config.resume) {
+            (Some(prep), _) => prep,
+            (None, Some(path)) => {
+                let prep = checkpoint::load(path, &config.lhs, &config.rhs, &config.filters)
+                    .inspect_err(|e| eprintln!("{}: {}", path.display(), e))?;
+                if !term::is_quiet() {
+                    say!("{}", tr(Msg::Resuming, &[&path.display(), &prep.to_process.len()]));
+                }
+                prep
+            }
+            (None, None) => common::preprocess(
+                Some(&config.lhs),
+                Some(&config.rhs),
+                config.files_from.as_deref(),
+                &config.filters,
+                &config.policies,
+                config.canonicalize,
+                shard,
+            )?,
+        };
+        let part = match pair {
+            true => cmp.process_pair(prep, config.context)?,
+            false => cmp.process_files(prep, config.verbose),
+        };
+        match pass {
+            0 => res = part,
+            _ => res.merge(part),
+        }
+        if interrupt::interrupted() {
+            break;
+        }
+    }
+    res.partial = interrupt::interrupted();
+    if config.stats {
+        let stats = stats::take(started.elapsed());
+        if !term::is_quiet() {
+            stats::summary(&stats).iter().for_each(|line| say!("{}", line));
+        }
+        res.stats = Some(stats);
+    }
+    let bytes_read = throughput::take_read(res.info.iter().map(|fl| fl.size).sum());
+    if !term::is_quiet() {
+        let (read, total) = (bytes_read.read as usize, bytes_read.total as usize);
+        let (read, total) = (common::stringify_bytes(read), common::stringify_bytes(total));
+        say!("{}", tr(Msg::BytesRead, &[&read, &total, &bytes_read.spared()]));
+    }
+    res.bytes_read = Some(bytes_read);
+    res.given_roots = config.given[..res.roots.len()].to_vec();
+    if let Some(difference) = res.difference.as_ref().filter(|_| !term::is_quiet()) {
+        say!(
+            "{}",
+            tr(Msg::FilesDiffer, &[&config.lhs.display(), &config.rhs.display(), &difference.offset])
+        );
+    }
+    if let Some(key) = config.same_name {
+        res.same_name = Some(diff::same_name(&res, key, config.path_match));
+    }
+    if config.mode == common::ScanMode::Mirror {
+        // the pairs replace the duplicate groups
+        res.mirror = Some(diff::mirror_diff(&res, config.path_match));
+        (res.zero, res.unique, res.same, res.confidence) = Default::default();
+    }
+    res.volumes = volume::detect_volumes(&res.info);
+    if config.verbose {
+        throughput::summary(&res.volumes)
+            .iter()
+            .for_each(|line| say!("{}", line));
+    }
+    skipped::summarize();
+    res.skipped = skipped::take();
+    if config.filters.empty_dirs {
+        res.empty_dirs = Some(empty::empty_directories(empty::take(), &res.roots));
+    }
+    res.warnings = warnings::take();
+    res.ignore_ranges = Some(config.ignore_ranges.clone()).filter(|r| !r.is_empty());
+    res.policies = Some(config.policies.clone()).filter(|p| !p.is_empty());
+    res.mode = config.mode;
+    res.keep = config.keep.clone();
+    res.top = config.top;
+    (res.sort, res.descending) = (config.sort, config.descending);
+    if let Some(avg) = config.chunk_dedup.filter(|_| !res.partial) {
+        if config.verbose {
+            say!("{}", tr(Msg::EstimatingChunks, &[&common::stringify_bytes(avg)]));
+        }
+        res.chunk_estimate = Some(chunk::estimate_chunk_dedup(&res.info, avg, config.read_size));
+    }
 
-    let prep = common::preprocess(Some(&config.lhs), Some(&config.rhs))?;
-    let mut cmp = cmp::Comparator::from_config(&config);
-    let res = cmp.process_files(prep, config.chunks_only, config.verbose);
-    let rpt = serde_json::to_string_pretty(&res).unwrap();
+    if let Some(threshold) = config.similar_dirs {
+        res.similar_dirs = Some(dirs::similar_directories(&res, threshold));
+    }
 
-    if let Some(path) = &config.output {
-        println!("Writing report to file '{}'", path.display());
-        let file = std::fs::File::create(path)?;
-        let mut file = std::io::BufWriter::new(file);
-        file.write_all(rpt.as_bytes())?;
-    } else {
-        println!("{rpt}");
+    if config.duplicate_dirs {
+        res.duplicate_dirs = Some(dirs::duplicate_directories(&res));
+    }
+
+    if let Some(threshold) = config.similar.filter(|_| !res.partial) {
+        if config.verbose {
+            say!("{}", tr(Msg::FindingSimilar, &[&threshold]));
+        }
+        res.similar = Some(similar::similar_files(&res, threshold, config.read_size));
+    }
+
+    if config.detect_renames {
+        if res.roots.len() < 2 {
+            eprintln!("{}", text(Msg::RenamesNeedTwoPaths));
+        }
+        res.renames = Some(diff::detect_renames(&res));
+    }
+
+    // a volume half scanned would stand in the index for the whole of it
+    if let Some(path) = config.append_index.as_ref().filter(|_| !res.partial) {
+        let volume = config
+            .volume_label
+            .clone()
+            .unwrap_or_else(|| config.lhs.display().to_string());
+        if config.verbose {
+            say!("{}", tr(Msg::AddingVolume, &[&volume, &path.display()]));
+        }
+        res.index = Some(index::append_to_index(
+            path,
+            &volume,
+            &res,
+            config.read_size,
+            config.hash,
+            config.dry_run,
+        )?);
+    }
+
+    if let Some(direction) = config.direction {
+        if res.roots.len() < 2 {
+            eprintln!("{}", text(Msg::DirectionNeedsTwoPaths));
+        } else {
+            res.coverage = Some(diff::check_coverage(&res, direction));
+        }
+    }
+
+    // the analyses above see the empty files as one content
+    match config.zero {
+        common::ZeroMode::List => {}
+        common::ZeroMode::Omit => res.zero.clear(),
+        common::ZeroMode::Group => {
+            let zero = std::mem::take(&mut res.zero);
+            match zero.len() {
+                0 => {}
+                1 => res.unique.extend(zero),
+                _ => {
+                    res.same.push(zero);
+                    res.confidence.push(common::Confidence::Empty);
+                }
+            }
+        }
+    }
+
+    // the analyses above see every link, the report and the actions only one per inode
+    if config.hardlinks != common::HardlinkMode::Duplicates {
+        let hardlinks = res.separate_hardlinks();
+        if config.hardlinks == common::HardlinkMode::Report {
+            res.hardlinks = Some(hardlinks);
+        }
+    }
+
+    Ok(res)
+}
+
+/// Says the report just written is partial and quits as a process killed by Ctrl-C would
+fn stop_partial() -> ! {
+    eprintln!("{}", text(Msg::PartialReport));
+    std::process::exit(130);
+}
+
+/// Writes the report to every output, or to stdout without any
+fn write_reports(config: &config::Config, res: &common::Processed) -> error::Result<()> {
+    // serialized once, every sink writes from the same report
+    let rpt = serde_json::to_value(res)?;
+
+    if config.print0 {
+        export::print_redundant(res, &mut io::stdout().lock())?;
+    } else if config.output.is_empty() {
+        common::print_stdout(&serde_json::to_string_pretty(&rpt)?)?;
+    }
+    for sink in config.output.iter().map(|path| sink::for_path(path)) {
+        if !config.dry_run && !term::is_quiet() && sink.path().as_os_str() != "-" {
+            say!("{}", tr(Msg::WritingReport, &[&sink.path().display()]));
+        }
+        sink.write(&rpt, config.dry_run)
+            .inspect_err(|e| eprintln!("{}: {}", sink.path().display(), e))?;
+    }
+    if !config.output.is_empty() {
+        if let Some((dir, keep)) = &config.keep_reports {
+            // under a dry run the new report is not there yet, but it still takes a place
+            let keep = if config.dry_run { keep.saturating_sub(1) } else { *keep };
+            report::prune_reports(dir, keep, config.dry_run)?;
+        }
     }
-    println!("rcompare complete!");
     Ok(())
 }
+
+fn run_command(command: cli::Command, dry_run: bool) -> io::Result<()> {
+    match command {
+        cli::Command::Scan(_)
+        | cli::Command::Compare(_)
+        | cli::Command::Dedupe { .. }
+        | cli::Command::Watch { .. }
+        | cli::Command::Serve { .. } => {
+            unreachable!("scans are run by main")
+        }
+        cli::Command::Schedule(cli::ScheduleCommand::Install { cron, profile, timer, keep, reports, args }) => {
+            let reports = match reports {
+                Some(dir) => std::path::PathBuf::from(dir),
+                None => schedule::default_reports(&profile)?,
+            };
+            schedule::install(&profile, &cron, timer, &schedule::Profile { args, reports, keep }, dry_run)
+        }
+        cli::Command::Schedule(cli::ScheduleCommand::Run { profile }) => {
+            let code = schedule::run(&profile, dry_run)?;
+            if code != 0 {
+                std::process::exit(code);
+            }
+            Ok(())
+        }
+        cli::Command::Report(cli::ReportCommand::Upgrade { report, output }) => {
+            let upgraded = report::upgrade(&report).inspect_err(|e| eprintln!("{}", e))?;
+            print_report(&upgraded, output, dry_run)
+        }
+        cli::Command::Schema { output } => print_report(&report::json_schema(), output, dry_run),
+        #[cfg(target_os = "linux")]
+        cli::Command::Cache(cli::CacheCommand::Watch { dir, cache }) => {
+            let cache = cache
+                .map(std::path::PathBuf::from)
+                .or_else(cache::default_path)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no cache path given and HOME is not set"))?;
+            watch::watch(std::path::Path::new(&dir), &cache).inspect_err(|e| eprintln!("{}", e))
+        }
+        #[cfg(not(target_os = "linux"))]
+        cli::Command::Cache(cli::CacheCommand::Watch { .. }) => Err(no_watch()),
+        cli::Command::Du { dir, max_depth } => {
+            let root = std::fs::canonicalize(dir.unwrap_or_else(|| ".".to_owned()))?;
+            let config = config::Config {
+                lhs: root.clone(),
+                rhs: root.clone(),
+                given: [root.clone(), root],
+                cache: cache::default_path(),
+                // the cache is only read under a dry run
+                dry_run,
+                ..Default::default()
+            };
+            let prep = common::preprocess(
+                Some(&config.lhs),
+                None::<&std::path::PathBuf>,
+                None,
+                &config.filters,
+                &config.policies,
+                config.canonicalize,
+                common::Shard::ALL,
+            )?;
+            let res = cmp::Comparator::from_config(&config).process_files(prep, false);
+            println!("{}", text(Msg::DuHeader));
+            for usage in dirs::disk_usage(&res, max_depth) {
+                let size = |bytes: u64| common::stringify_bytes(bytes as usize);
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    size(usage.apparent),
+                    size(usage.unique),
+                    size(usage.duplicated),
+                    usage.path.display()
+                );
+            }
+            Ok(())
+        }
+        cli::Command::Verify { target, manifest: Some(manifest), output, .. } => {
+            let dir = target.as_deref().map(std::path::Path::new);
+            let check =
+                manifest::check_manifest(&manifest, dir, config::READ_SIZE).inspect_err(|e| eprintln!("{}", e))?;
+            print_report(&serde_json::to_value(&check)?, output, dry_run)?;
+            if !check.is_clean() {
+                std::process::exit(1);
+            }
+            Ok(())
+        }
+        cli::Command::Verify { target, groups, paranoid, output, .. } => {
+            let report = target.unwrap_or_default();
+            let updated = verify::reverify(&report, &groups, paranoid).inspect_err(|e| eprintln!("{}", e))?;
+            print_report(&updated, output, dry_run)
+        }
+    }
+}
+
+/// Watching relies on inotify, which only Linux has
+#[cfg(not(target_os = "linux"))]
+fn no_watch() -> io::Error {
+    let msg = "watching needs inotify, which only Linux has";
+    eprintln!("{}", msg);
+    io::Error::new(io::ErrorKind::Unsupported, msg)
+}
+
+fn print_report(report: &serde_json::Value, output: Option<String>, dry_run: bool) -> io::Result<()> {
+    let rpt = serde_json::to_string_pretty(report)?;
+    match output {
+        Some(path) if path != "-" => common::write_file(std::path::Path::new(&path), rpt.as_bytes(), dry_run),
+        _ => common::print_stdout(&rpt),
+    }
+}
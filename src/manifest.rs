@@ -0,0 +1,195 @@
+use crate::cmp::hash_full_file;
+use crate::common::{write_file, Processed};
+use crate::file::walk_dir;
+use crate::filter::FilterSet;
+use crate::hashing::HashAlgorithm;
+use crate::i18n::{tr, Msg};
+use crate::skipped::{self, Stage};
+use crate::{say, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+pub const MANIFEST_VERSION: u32 = 1;
+
+/// Every file of the scanned trees with its size and a strong hash, to check them for changes and
+/// corruption later with `rcompare verify --manifest`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub roots: Vec<String>,
+    pub algorithm: String,
+    pub files: Vec<ManifestEntry>,
+}
+
+/// One file of a manifest, its path relative to its root
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// position of the root in `roots`, left out for the first one
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub root: usize,
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path_base64: Option<String>,
+    pub size: u64,
+    /// modification time in nanoseconds since the epoch, to tell edits from corruption
+    pub mtime: i64,
+    /// full content hash, in hexadecimal
+    pub hash: String,
+}
+
+/// How the trees differ from a manifest, paths being relative to their root
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ManifestCheck {
+    pub roots: Vec<String>,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    /// files whose content changed along with their modification time
+    pub modified: Vec<String>,
+    /// files whose content changed while their size and modification time stayed, like bit rot does
+    pub corrupted: Vec<String>,
+    pub unchanged: usize,
+}
+
+impl ManifestCheck {
+    pub fn is_clean(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.modified.is_empty() && self.corrupted.is_empty()
+    }
+}
+
+/// Hashes every file of the scan whole and writes the manifest to `path`.
+///
+/// Cryptographic algorithms are kept as given, the others are replaced by BLAKE3 so a match
+/// stands for the same content.
+pub fn write_manifest<P: AsRef<Path>>(
+    path: &P,
+    res: &Processed,
+    read_size: usize,
+    algorithm: HashAlgorithm,
+    dry_run: bool,
+) -> io::Result<()> {
+    let algorithm = match algorithm.is_cryptographic() {
+        true => algorithm,
+        false => HashAlgorithm::Blake3,
+    };
+
+    let mut files = vec![];
+    for fl in res.info.iter().filter(|fl| !fl.is_link_target()) {
+        let Some((root, rel)) = res.locate(&fl.path) else {
+            continue;
+        };
+        let hash = match hash_full_file(&fl.content_path(), read_size, algorithm) {
+            Ok(hash) => hash.hex(),
+            Err(err) => {
+                if skipped::record(&fl.path, Stage::Compare, &err) {
+                    warn!("Unable to hash file {} for the manifest: {}", fl.path.display(), err);
+                }
+                continue;
+            }
+        };
+
+        files.push(ManifestEntry {
+            root,
+            path: rel.to_string_lossy().into_owned(),
+            path_base64: crate::encoding::raw_bytes(rel),
+            size: fl.size,
+            mtime: fl.mtime,
+            hash,
+        });
+    }
+    files.sort_by(|a, b| (a.root, &a.path).cmp(&(b.root, &b.path)));
+
+    let manifest = Manifest {
+        version: MANIFEST_VERSION,
+        roots: res.roots.iter().map(|r| r.to_string_lossy().into_owned()).collect(),
+        algorithm: algorithm.name().to_owned(),
+        files,
+    };
+    write_file(path.as_ref(), serde_json::to_string_pretty(&manifest)?.as_bytes(), dry_run)?;
+    if !dry_run && !crate::term::is_quiet() {
+        say!("{}", tr(Msg::WroteManifest, &[&manifest.files.len(), &path.as_ref().display()]));
+    }
+    Ok(())
+}
+
+/// Walks the trees of a manifest again, or `dir` in place of its single root, and tells the files
+/// added, removed, modified and corrupted since.
+///
+/// Every file is walked, the filters of the scan which wrote the manifest are not applied again.
+pub fn check_manifest<P: AsRef<Path>>(path: &P, dir: Option<&Path>, read_size: usize) -> io::Result<ManifestCheck> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let manifest: Manifest = serde_json::from_slice(&fs::read(path)?)
+        .map_err(|e| invalid(format!("manifest {}: {}", path.as_ref().display(), e)))?;
+    if manifest.version > MANIFEST_VERSION {
+        return Err(invalid(format!("manifest version {} is newer than this rcompare", manifest.version)));
+    }
+    let algorithm = HashAlgorithm::from_name(&manifest.algorithm)
+        .ok_or_else(|| invalid(format!("unknown hash algorithm {}", manifest.algorithm)))?;
+
+    let roots: Vec<PathBuf> = match dir {
+        Some(_) if manifest.roots.len() > 1 => {
+            let msg = format!(
+                "the manifest covers {} paths, the directory cannot stand for them",
+                manifest.roots.len()
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        Some(dir) => vec![fs::canonicalize(dir)?],
+        None => manifest.roots.iter().map(PathBuf::from).collect(),
+    };
+
+    let mut expected: BTreeMap<(usize, PathBuf), &ManifestEntry> = manifest
+        .files
+        .iter()
+        .map(|entry| ((entry.root, entry_path(entry)), entry))
+        .collect();
+    let mut check = ManifestCheck {
+        roots: roots.iter().map(|r| r.to_string_lossy().into_owned()).collect(),
+        ..Default::default()
+    };
+    for (idx, root) in roots.iter().enumerate() {
+        for fl in walk_dir(root, &FilterSet::default()) {
+            let rel = fl.path.strip_prefix(root).unwrap_or(&fl.path).to_path_buf();
+            let shown = rel.to_string_lossy().into_owned();
+            let Some(entry) = expected.remove(&(idx, rel)) else {
+                check.added.push(shown);
+                continue;
+            };
+
+            let same_meta = fl.size == entry.size && fl.mtime == entry.mtime;
+            let hash = match fl.size == entry.size {
+                true => hash_full_file(&fl.content_path(), read_size, algorithm).map(|h| h.hex()),
+                false => Ok(String::new()),
+            };
+            match hash {
+                Ok(hash) if hash == entry.hash => check.unchanged += 1,
+                Ok(_) if same_meta => check.corrupted.push(shown),
+                Ok(_) => check.modified.push(shown),
+                // content which cannot be read back fails the check as well
+                Err(err) => {
+                    if skipped::record(&fl.path, Stage::Compare, &err) {
+                        warn!("Unable to hash file {}: {}", fl.path.display(), err);
+                    }
+                    check.corrupted.push(shown);
+                }
+            }
+        }
+    }
+    check.removed = expected.values().map(|entry| entry.path.clone()).collect();
+    Ok(check)
+}
+
+// ----------
+//  Internal
+// ----------
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+fn entry_path(entry: &ManifestEntry) -> PathBuf {
+    let value = serde_json::json!({ "path": entry.path, "path_base64": entry.path_base64 });
+    crate::encoding::entry_path(&value).unwrap_or_else(|| PathBuf::from(&entry.path))
+}
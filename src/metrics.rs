@@ -0,0 +1,132 @@
+use crate::common::Processed;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Upper bounds of the buckets of the scan durations, in seconds, from a folder to a whole disk
+const DURATION_BUCKETS: [f64; 8] = [1.0, 5.0, 30.0, 60.0, 300.0, 900.0, 3600.0, 14400.0];
+
+/// What the scans and dedupes of a server did since it started, for `GET /metrics` in the
+/// Prometheus text format
+#[derive(Debug, Default)]
+pub struct Metrics {
+    scans: u64,
+    failed: u64,
+    files: u64,
+    bytes_hashed: u64,
+    /// scans which took at most each bound of `DURATION_BUCKETS`
+    durations: [u64; DURATION_BUCKETS.len()],
+    seconds: f64,
+    /// duplicate groups and the bytes they waste, as of the latest finished scan
+    latest: Option<(u64, u64)>,
+    deduped: u64,
+    reclaimed: u64,
+}
+
+impl Metrics {
+    /// Counts a scan which went through, in `elapsed`
+    pub fn scanned(&mut self, res: &Processed, elapsed: Duration) {
+        self.timed(elapsed);
+        self.files += res.info.len() as u64;
+        self.bytes_hashed += res.bytes_read.as_ref().map_or(0, |bytes| bytes.read);
+        self.latest = Some((res.same.len() as u64, res.wasted_bytes()));
+    }
+
+    /// Counts a scan which failed after `elapsed`
+    pub fn failed(&mut self, elapsed: Duration) {
+        self.timed(elapsed);
+        self.failed += 1;
+    }
+
+    /// Counts the `files` a dedupe removed or linked and the `bytes` it got back
+    pub fn deduped(&mut self, files: usize, bytes: u64) {
+        self.deduped += files as u64;
+        self.reclaimed += bytes;
+    }
+
+    /// The metrics in the text format, with the number of scans in every state of `jobs`
+    pub fn render(&self, jobs: &[(&str, usize)]) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, String)]| {
+            _ = writeln!(out, "# HELP rcompare_{} {}", name, help);
+            _ = writeln!(out, "# TYPE rcompare_{} {}", name, kind);
+            for (suffix, value) in samples {
+                _ = writeln!(out, "rcompare_{}{} {}", name, suffix, value);
+            }
+        };
+
+        let states: Vec<_> = jobs
+            .iter()
+            .map(|(state, n)| (format!("{{state=\"{}\"}}", state), n.to_string()))
+            .collect();
+        metric("scans", "gauge", "Scans asked for, by state.", &states);
+        metric("scans_failed_total", "counter", "Scans which failed.", &[one(self.failed)]);
+        metric("files_scanned_total", "counter", "Files listed by the scans.", &[one(self.files)]);
+        metric(
+            "bytes_hashed_total",
+            "counter",
+            "Bytes read to hash and compare the candidates.",
+            &[one(self.bytes_hashed)],
+        );
+
+        let mut buckets = vec![];
+        let mut below = 0;
+        for (bound, n) in DURATION_BUCKETS.iter().zip(self.durations) {
+            below += n;
+            buckets.push((format!("_bucket{{le=\"{}\"}}", bound), below.to_string()));
+        }
+        buckets.push(("_bucket{le=\"+Inf\"}".to_owned(), self.scans.to_string()));
+        buckets.push(("_sum".to_owned(), self.seconds.to_string()));
+        buckets.push(("_count".to_owned(), self.scans.to_string()));
+        metric(
+            "scan_duration_seconds",
+            "histogram",
+            "Time the scans took, failed ones included.",
+            &buckets,
+        );
+
+        if let Some((groups, wasted)) = self.latest {
+            metric("duplicate_groups", "gauge", "Duplicate groups of the latest scan.", &[one(groups)]);
+            metric(
+                "reclaimable_bytes",
+                "gauge",
+                "Bytes keeping one copy of every group of the latest scan would free.",
+                &[one(wasted)],
+            );
+        }
+        metric(
+            "deduped_files_total",
+            "counter",
+            "Copies removed or linked by the dedupes.",
+            &[one(self.deduped)],
+        );
+        metric(
+            "reclaimed_bytes_total",
+            "counter",
+            "Bytes the dedupes freed.",
+            &[one(self.reclaimed)],
+        );
+        out
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+impl Metrics {
+    /// Counts a scan which took `elapsed` in the histogram
+    fn timed(&mut self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        self.scans += 1;
+        self.seconds += seconds;
+        // each scan in its own bucket, `render` adds up the ones below
+        if let Some(bucket) = DURATION_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.durations[bucket] += 1;
+        }
+    }
+}
+
+/// A metric without labels
+fn one(value: u64) -> (String, String) {
+    (String::new(), value.to_string())
+}
@@ -0,0 +1,128 @@
+use std::fs::{File, Metadata};
+use std::io::{self, Read};
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether files are read with `O_DIRECT`, around the page cache
+static DIRECT: AtomicBool = AtomicBool::new(false);
+
+/// Alignment of the offsets, lengths and buffers of direct reads, a logical block on most disks
+const ALIGN: usize = 4096;
+
+/// The most bytes asked to the disk at once by a direct read
+const DIRECT_BLOCK: usize = 1 << 20;
+
+pub fn set_direct(direct: bool) {
+    DIRECT.store(direct, Ordering::Relaxed);
+}
+
+/// A file read once from start to end.
+///
+/// The kernel is told to read ahead, and once the file is closed that the pages read are no longer
+/// needed, so a whole-disk scan does not push the working set of the machine out of the page
+/// cache. With `set_direct` the reads skip the page cache altogether. Only Linux is given the
+/// hints and the direct reads, elsewhere files are read as they are.
+pub struct Sequential {
+    file: File,
+    /// the aligned buffer of direct reads
+    direct: Option<Block>,
+}
+
+impl Sequential {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        #[cfg(target_os = "linux")]
+        if DIRECT.load(Ordering::Relaxed) {
+            match std::fs::OpenOptions::new()
+                .read(true)
+                .custom_flags(libc::O_DIRECT)
+                .open(path)
+            {
+                Ok(file) => return Ok(Self { file, direct: Some(Block::new()) }),
+                // tmpfs and some network file systems have no direct reads, they get the hints
+                Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        let file = File::open(path)?;
+        #[cfg(target_os = "linux")]
+        advise(&file, libc::POSIX_FADV_SEQUENTIAL);
+        Ok(Self { file, direct: None })
+    }
+
+    pub fn metadata(&self) -> io::Result<Metadata> {
+        self.file.metadata()
+    }
+
+    /// The file itself, to map it in memory
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Read for Sequential {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(block) = &mut self.direct else {
+            return self.file.read(buf);
+        };
+        if block.pos == block.len && !block.eof {
+            // as much as asked for, so the first bytes of a file do not cost a whole block
+            let want = (buf.len().div_ceil(ALIGN) * ALIGN).clamp(ALIGN, DIRECT_BLOCK);
+            let n = self.file.read(block.aligned(want))?;
+            // past a short read the offset is no longer aligned, it only happens at the end
+            block.eof = n < want;
+            (block.pos, block.len) = (0, n);
+        }
+
+        let n = buf.len().min(block.len - block.pos);
+        let start = block.start + block.pos;
+        buf[..n].copy_from_slice(&block.bytes[start..start + n]);
+        block.pos += n;
+        Ok(n)
+    }
+}
+
+impl Drop for Sequential {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        if self.direct.is_none() {
+            advise(&self.file, libc::POSIX_FADV_DONTNEED);
+        }
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+struct Block {
+    bytes: Vec<u8>,
+    /// where the aligned part of `bytes` starts
+    start: usize,
+    pos: usize,
+    len: usize,
+    eof: bool,
+}
+
+impl Block {
+    #[cfg(target_os = "linux")]
+    fn new() -> Self {
+        let bytes = vec![0; DIRECT_BLOCK + ALIGN];
+        let start = bytes.as_ptr().align_offset(ALIGN);
+        Self { bytes, start, pos: 0, len: 0, eof: false }
+    }
+
+    fn aligned(&mut self, len: usize) -> &mut [u8] {
+        &mut self.bytes[self.start..self.start + len]
+    }
+}
+
+/// A hint on how the whole of `file` is read, the kernel is free to ignore it
+#[cfg(target_os = "linux")]
+fn advise(file: &File, advice: libc::c_int) {
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, advice) };
+}
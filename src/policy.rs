@@ -0,0 +1,394 @@
+use crate::content::ContentType;
+use crate::regions::{IgnoreRanges, Masked};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::io::{self, BufRead, BufReader, Read};
+use std::ops::Range;
+use std::path::Path;
+
+/// How the content of a file is read before hashing and comparing it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Policy {
+    /// every byte counts
+    Exact,
+    /// line endings, a leading BOM and trailing blanks at the end of lines and of the file do not count
+    NormalizedText,
+    /// line endings and a leading BOM do not count
+    NormalizedLineEndings,
+    /// only the image data of PNG and JPEG files counts, not the EXIF, XMP, text or time chunks
+    IgnoreMetadataImage,
+    /// the bytes of `ranges` do not count
+    IgnoreRanges,
+}
+
+impl Policy {
+    /// Whether files of different sizes may still compare equal
+    pub fn resizes(&self) -> bool {
+        matches!(
+            self,
+            Policy::NormalizedText | Policy::NormalizedLineEndings | Policy::IgnoreMetadataImage
+        )
+    }
+}
+
+/// What `--text-normalize` leaves out when comparing text files
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TextNormalize {
+    /// CRLF and LF line endings and a leading UTF-8 BOM
+    #[default]
+    LineEndings,
+    /// line endings, a leading BOM and blanks at the end of lines and of the file
+    Whitespace,
+}
+
+impl TextNormalize {
+    /// The rule comparing every file this way, after the rules of the settings
+    pub fn rule(&self) -> PolicyRule {
+        let compare = match self {
+            TextNormalize::LineEndings => Policy::NormalizedLineEndings,
+            TextNormalize::Whitespace => Policy::NormalizedText,
+        };
+        PolicyRule { glob: "*".to_owned(), compare, ranges: None }
+    }
+}
+
+/// One entry of the policy table, as written in the settings file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRule {
+    /// matched against both the file name and the full path, like `--exclude`
+    pub glob: String,
+    pub compare: Policy,
+    /// for `ignore-ranges`, e.g. `0..512,1K..2K`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ranges: Option<String>,
+}
+
+/// Policies picked by the path of each file, the first matching rule wins and other files are
+/// compared exactly.
+#[derive(Debug, Clone, Default)]
+pub struct PolicyTable {
+    pub rules: Vec<PolicyRule>,
+    ranges: Vec<Vec<Range<u64>>>,
+    set: GlobSet,
+}
+
+impl PolicyTable {
+    pub fn new(rules: Vec<PolicyRule>) -> io::Result<Self> {
+        let invalid = |e: String| io::Error::new(io::ErrorKind::InvalidInput, e);
+        let mut builder = GlobSetBuilder::new();
+        let mut ranges = vec![];
+        for rule in rules.iter() {
+            builder.add(Glob::new(&rule.glob).map_err(|e| invalid(e.to_string()))?);
+            let parsed = match (rule.compare, &rule.ranges) {
+                (Policy::IgnoreRanges, Some(spec)) => {
+                    IgnoreRanges::parse(std::slice::from_ref(spec)).map_err(invalid)?
+                }
+                (Policy::IgnoreRanges, None) => {
+                    return Err(invalid(format!("the rule for {} needs ranges", rule.glob)))
+                }
+                _ => IgnoreRanges::default(),
+            };
+            ranges.push(parsed.for_path(Path::new("")).to_vec());
+        }
+
+        let set = builder.build().map_err(|e| invalid(e.to_string()))?;
+        Ok(Self { rules, ranges, set })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Index of the rule applying to `path`, if any
+    pub fn rule_for(&self, path: &Path) -> Option<usize> {
+        if self.rules.is_empty() {
+            return None;
+        }
+
+        let mut matches = self.set.matches(path);
+        if let Some(name) = path.file_name() {
+            matches.extend(self.set.matches(name));
+        }
+        matches.into_iter().min()
+    }
+
+    pub fn policy_for(&self, path: &Path) -> Policy {
+        self.rule_for(path)
+            .map(|idx| self.rules[idx].compare)
+            .unwrap_or(Policy::Exact)
+    }
+
+    /// Reads `inner` the way the policy of `path` sees it, with `ignore` masked out of exact reads.
+    ///
+    /// The policy only applies to the types of content it is meant for, as told by the first block.
+    pub fn reader<'a, R: Read + 'a>(&'a self, path: &Path, inner: R, ignore: &'a [Range<u64>]) -> Box<dyn Read + 'a> {
+        let Some(idx) = self.rule_for(path) else {
+            return Box::new(Masked::new(inner, ignore));
+        };
+
+        let mut inner = BufReader::new(inner);
+        // a failed read fails again when the content is read
+        let content = inner.fill_buf().map(ContentType::detect).unwrap_or(ContentType::Binary);
+        match crate::cmp::strategy(content, self.rules[idx].compare) {
+            Policy::Exact => Box::new(inner),
+            Policy::IgnoreRanges => Box::new(Masked::new(inner, &self.ranges[idx])),
+            Policy::NormalizedText => Box::new(NormalizedText::new(inner, true)),
+            Policy::NormalizedLineEndings => Box::new(NormalizedText::new(inner, false)),
+            Policy::IgnoreMetadataImage => Box::new(ImageData::new(inner)),
+        }
+    }
+
+    /// Tells the cached hashes of differently read contents apart, empty for plain exact reads
+    pub fn kind(&self, path: &Path, ignore: &[Range<u64>]) -> String {
+        match self.rule_for(path) {
+            None if ignore.is_empty() => String::new(),
+            None => format!("-ignore-{}", crate::regions::describe(ignore)),
+            Some(idx) => match self.rules[idx].compare {
+                Policy::Exact => String::new(),
+                Policy::IgnoreRanges => format!("-ignore-{}", crate::regions::describe(&self.ranges[idx])),
+                Policy::NormalizedText => "-text".to_owned(),
+                Policy::NormalizedLineEndings => "-eol".to_owned(),
+                Policy::IgnoreMetadataImage => "-image".to_owned(),
+            },
+        }
+    }
+}
+
+impl Serialize for PolicyTable {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.rules.serialize(serializer)
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// Text with its line endings turned into `\n`, without a leading BOM nor, when trimmed, trailing
+/// blanks
+struct NormalizedText<R> {
+    inner: BufReader<R>,
+    out: VecDeque<u8>,
+    trim: bool,
+    /// spaces and tabs kept until something other than the end of the line follows them
+    blanks: Vec<u8>,
+    newlines: usize,
+    after_cr: bool,
+    started: bool,
+}
+
+impl<R: Read> NormalizedText<R> {
+    fn new(inner: R, trim: bool) -> Self {
+        Self {
+            inner: BufReader::new(inner),
+            out: VecDeque::new(),
+            trim,
+            blanks: vec![],
+            newlines: 0,
+            after_cr: false,
+            started: false,
+        }
+    }
+
+    fn push(&mut self, byte: u8) {
+        match byte {
+            b'\n' if self.after_cr => {}
+            b'\r' if !self.trim => self.out.push_back(b'\n'),
+            _ if !self.trim => self.out.push_back(byte),
+            b'\r' | b'\n' => {
+                self.blanks.clear();
+                self.newlines += 1;
+            }
+            b' ' | b'\t' => self.blanks.push(byte),
+            _ => {
+                self.out.extend(std::iter::repeat_n(b'\n', self.newlines));
+                self.out.extend(self.blanks.drain(..));
+                self.out.push_back(byte);
+                self.newlines = 0;
+            }
+        }
+        self.after_cr = byte == b'\r';
+    }
+}
+
+impl<R: Read> Read for NormalizedText<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.out.is_empty() {
+            let chunk = self.inner.fill_buf()?;
+            if chunk.is_empty() {
+                // blank lines and blanks at the very end are dropped
+                break;
+            }
+
+            let mut chunk = chunk.to_vec();
+            if !self.started {
+                self.started = true;
+                if chunk.starts_with(b"\xef\xbb\xbf") {
+                    chunk.drain(..3);
+                }
+            }
+            self.inner.consume(self.inner.buffer().len());
+            chunk.into_iter().for_each(|b| self.push(b));
+        }
+
+        let n = buf.len().min(self.out.len());
+        for (dst, src) in buf.iter_mut().zip(self.out.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Png,
+    Jpeg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Start,
+    Header,
+    Pass(u64),
+    Skip(u64),
+    /// the rest of the file goes through untouched
+    Tail,
+}
+
+/// The chunks of a PNG or the segments of a JPEG holding the image itself, without metadata.
+///
+/// Other files, and whatever follows a malformed chunk, go through untouched.
+struct ImageData<R> {
+    inner: BufReader<R>,
+    format: Option<Format>,
+    state: State,
+    out: Vec<u8>,
+}
+
+impl<R: Read> ImageData<R> {
+    /// PNG chunks telling about the image rather than being part of it
+    const PNG_METADATA: [&'static [u8; 4]; 6] = [b"tEXt", b"zTXt", b"iTXt", b"tIME", b"eXIf", b"iCCP"];
+
+    fn new(inner: R) -> Self {
+        Self {
+            inner: BufReader::new(inner),
+            format: None,
+            state: State::Start,
+            out: vec![],
+        }
+    }
+
+    fn take(&mut self, n: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(n);
+        (&mut self.inner).take(n as u64).read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Decides what to do with the next chunk or segment
+    fn next_header(&mut self) -> io::Result<State> {
+        match self.format {
+            Some(Format::Png) => {
+                let header = self.take(8)?;
+                if header.len() < 8 {
+                    self.out.extend(header);
+                    return Ok(State::Tail);
+                }
+
+                // the length leaves out the type before the data and the CRC after it
+                let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as u64 + 4;
+                if Self::PNG_METADATA.iter().any(|t| &header[4..] == *t) {
+                    return Ok(State::Skip(len));
+                }
+                self.out.extend(header);
+                Ok(State::Pass(len))
+            }
+            Some(Format::Jpeg) => {
+                let marker = self.take(2)?;
+                if marker.len() < 2 || marker[0] != 0xff {
+                    self.out.extend(marker);
+                    return Ok(State::Tail);
+                }
+
+                match marker[1] {
+                    // markers without a length
+                    0xd8 | 0xd0..=0xd7 | 0x01 => {
+                        self.out.extend(marker);
+                        return Ok(State::Header);
+                    }
+                    // the entropy coded data and the end of the image
+                    0xda | 0xd9 => {
+                        self.out.extend(marker);
+                        return Ok(State::Tail);
+                    }
+                    _ => {}
+                }
+
+                let len = self.take(2)?;
+                if len.len() < 2 {
+                    self.out.extend(marker.into_iter().chain(len));
+                    return Ok(State::Tail);
+                }
+
+                let rest = (u16::from_be_bytes([len[0], len[1]]) as u64).saturating_sub(2);
+                // APP1 to APP15 hold EXIF, XMP, ICC profiles and the like, COM holds comments
+                if matches!(marker[1], 0xe1..=0xef | 0xfe) {
+                    return Ok(State::Skip(rest));
+                }
+                self.out.extend(marker.into_iter().chain(len));
+                Ok(State::Pass(rest))
+            }
+            None => Ok(State::Tail),
+        }
+    }
+}
+
+impl<R: Read> Read for ImageData<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if !self.out.is_empty() {
+                let n = buf.len().min(self.out.len());
+                buf[..n].copy_from_slice(&self.out[..n]);
+                self.out.drain(..n);
+                return Ok(n);
+            }
+
+            self.state = match self.state {
+                State::Start => {
+                    let head = self.inner.fill_buf()?;
+                    if head.starts_with(b"\x89PNG\r\n\x1a\n") {
+                        self.format = Some(Format::Png);
+                        State::Pass(8)
+                    } else if head.starts_with(b"\xff\xd8") {
+                        self.format = Some(Format::Jpeg);
+                        State::Header
+                    } else {
+                        State::Tail
+                    }
+                }
+                State::Tail => return self.inner.read(buf),
+                State::Pass(0) | State::Skip(0) | State::Header => self.next_header()?,
+                State::Pass(left) => {
+                    let max = buf.len().min(left.min(usize::MAX as u64) as usize);
+                    let n = self.inner.read(&mut buf[..max])?;
+                    self.state = if n == 0 {
+                        State::Tail
+                    } else {
+                        State::Pass(left - n as u64)
+                    };
+                    return Ok(n);
+                }
+                State::Skip(left) => {
+                    let skipped = io::copy(&mut (&mut self.inner).take(left), &mut io::sink())?;
+                    match skipped < left {
+                        true => State::Tail,
+                        false => State::Header,
+                    }
+                }
+            };
+        }
+    }
+}
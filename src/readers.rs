@@ -0,0 +1,71 @@
+use crate::volume::is_rotational;
+use std::collections::HashMap;
+use std::sync::{Condvar, Mutex};
+
+/// How many workers read from every device at once.
+///
+/// A spinning disk seeks back and forth between parallel readers and gets slower than with a
+/// single one, so it gets one reader. Solid state and network storage keep up with as many as
+/// there are workers.
+pub struct Readers {
+    threads: usize,
+    /// readers at work and the most allowed, by device
+    devices: Mutex<HashMap<u64, (usize, usize)>>,
+    freed: Condvar,
+}
+
+/// Readers held on devices until dropped
+pub struct Permit<'r> {
+    readers: &'r Readers,
+    devices: Vec<u64>,
+}
+
+impl Readers {
+    pub fn new(threads: usize) -> Self {
+        Self { threads, devices: Mutex::new(HashMap::new()), freed: Condvar::new() }
+    }
+
+    /// Waits until every device of `devices` takes one more reader.
+    ///
+    /// The devices are taken all at once, a worker never holds some while waiting for the others.
+    pub fn acquire(&self, devices: &[u64]) -> Permit<'_> {
+        let mut devices = devices.to_vec();
+        devices.sort_unstable();
+        devices.dedup();
+
+        let mut state = self.devices.lock().unwrap();
+        for dev in devices.iter() {
+            state.entry(*dev).or_insert_with(|| (0, self.limit(*dev)));
+        }
+        let busy = |state: &HashMap<u64, (usize, usize)>| {
+            devices.iter().any(|dev| {
+                let (readers, limit) = state[dev];
+                readers >= limit
+            })
+        };
+        let mut state = self.freed.wait_while(state, |state| busy(state)).unwrap();
+        for dev in devices.iter() {
+            state.get_mut(dev).unwrap().0 += 1;
+        }
+        Permit { readers: self, devices }
+    }
+
+    fn limit(&self, dev: u64) -> usize {
+        match is_rotational(dev) {
+            Some(true) => 1,
+            _ => self.threads,
+        }
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        let mut state = self.readers.devices.lock().unwrap();
+        for dev in self.devices.iter() {
+            if let Some((readers, _)) = state.get_mut(dev) {
+                *readers -= 1;
+            }
+        }
+        self.readers.freed.notify_all();
+    }
+}
@@ -0,0 +1,133 @@
+use crate::common::parse_bytes;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::path::Path;
+
+/// Byte ranges left out of every comparison, for formats embedding serial numbers or timestamps
+/// at fixed offsets, like firmware dumps.
+///
+/// Ranges given with an extension, as in `bin=0..512`, only apply to files with it and replace
+/// the ranges given without one.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct IgnoreRanges {
+    #[serde(serialize_with = "serialize_ranges")]
+    all: Vec<Range<u64>>,
+    #[serde(serialize_with = "serialize_by_ext")]
+    by_ext: BTreeMap<String, Vec<Range<u64>>>,
+}
+
+impl IgnoreRanges {
+    /// Parses specs like `0..512`, `4K..` or `bin=0..16,32..48`
+    pub fn parse(specs: &[String]) -> Result<Self, String> {
+        let mut ranges = Self::default();
+        for spec in specs.iter() {
+            let (ext, list) = match spec.split_once('=') {
+                Some((ext, list)) => (Some(ext.trim().trim_start_matches('.').to_lowercase()), list),
+                None => (None, spec.as_str()),
+            };
+
+            let parsed = list.split(',').map(parse_range).collect::<Result<Vec<_>, _>>()?;
+            match ext {
+                Some(ext) => ranges.by_ext.entry(ext).or_default().extend(parsed),
+                None => ranges.all.extend(parsed),
+            }
+        }
+
+        merge(&mut ranges.all);
+        ranges.by_ext.values_mut().for_each(merge);
+        Ok(ranges)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.all.is_empty() && self.by_ext.is_empty()
+    }
+
+    /// The ranges to leave out of the file at `path`, sorted and disjoint
+    pub fn for_path(&self, path: &Path) -> &[Range<u64>] {
+        let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+        ext.and_then(|ext| self.by_ext.get(&ext)).unwrap_or(&self.all)
+    }
+}
+
+/// Reads through `inner`, turning the bytes of `ranges` into zeros
+pub struct Masked<'r, R> {
+    inner: R,
+    ranges: &'r [Range<u64>],
+    pos: u64,
+}
+
+impl<'r, R: Read> Masked<'r, R> {
+    pub fn new(inner: R, ranges: &'r [Range<u64>]) -> Self {
+        Self { inner, ranges, pos: 0 }
+    }
+}
+
+impl<R: Read> Read for Masked<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        let read = self.pos..self.pos + n as u64;
+        for range in self.ranges.iter() {
+            let (start, end) = (range.start.max(read.start), range.end.min(read.end));
+            if start < end {
+                buf[(start - read.start) as usize..(end - read.start) as usize].fill(0);
+            }
+        }
+        self.pos = read.end;
+        Ok(n)
+    }
+}
+
+/// `a..b` as written in the specs, an open end written `a..`
+pub fn describe(ranges: &[Range<u64>]) -> String {
+    let describe = |r: &Range<u64>| match r.end {
+        u64::MAX => format!("{}..", r.start),
+        end => format!("{}..{}", r.start, end),
+    };
+    ranges.iter().map(describe).collect::<Vec<_>>().join(",")
+}
+
+// ----------
+//  Internal
+// ----------
+
+fn parse_range(spec: &str) -> Result<Range<u64>, String> {
+    let (start, end) = spec
+        .trim()
+        .split_once("..")
+        .ok_or_else(|| format!("'{}' is not a range like 0..512", spec))?;
+    let start = parse_bytes(start)?;
+    let end = match end.trim() {
+        "" => u64::MAX,
+        end => parse_bytes(end)?,
+    };
+
+    if start >= end {
+        return Err(format!("the range '{}' is empty", spec));
+    }
+    Ok(start..end)
+}
+
+fn merge(ranges: &mut Vec<Range<u64>>) {
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = vec![];
+    for range in ranges.drain(..) {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    *ranges = merged;
+}
+
+fn serialize_ranges<S: serde::Serializer>(ranges: &[Range<u64>], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&describe(ranges))
+}
+
+fn serialize_by_ext<S: serde::Serializer>(
+    by_ext: &BTreeMap<String, Vec<Range<u64>>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(by_ext.iter().map(|(ext, ranges)| (ext, describe(ranges))))
+}
@@ -0,0 +1,227 @@
+use crate::common::shell_quote;
+use crate::file::{FileInfo, SymlinkMode};
+use crate::filter::FilterSet;
+use crate::source::FileSource;
+use std::ffi::OsString;
+use std::fs::DirBuilder;
+use std::io::{self, Read};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::Arc;
+
+/// Remote devices are numbered down from the top, far from the local ones, so their inodes never
+/// pass for local hard links
+const REMOTE_DEVICES: u64 = u64::MAX;
+
+/// A tree on another host, written `[user@]host:/path` and reached with `ssh`.
+///
+/// The files are listed with GNU `find` and read with `cat` on the host, every command going
+/// through one shared connection.
+#[derive(Debug)]
+pub struct Remote {
+    /// `[user@]host`, as given to `ssh`
+    pub target: String,
+    pub root: PathBuf,
+}
+
+/// Splits `[user@]host:/path` into the host and the path, unless a local path goes by that name
+pub fn parse(spec: &Path) -> Option<(String, PathBuf)> {
+    if spec.exists() {
+        return None;
+    }
+
+    let spec = spec.to_str()?;
+    let (target, path) = spec.split_once(':')?;
+    // a target starting with a dash would be read by ssh as one of its options
+    let valid = !target.is_empty() && !target.starts_with('-') && !target.contains('/') && !path.is_empty();
+    valid.then(|| (target.to_owned(), PathBuf::from(path)))
+}
+
+impl Remote {
+    pub fn new(target: String, root: PathBuf) -> Self {
+        Self { target, root }
+    }
+
+    fn ssh(&self, command: &OsString) -> Command {
+        let mut ssh = Command::new("ssh");
+        ssh.args(["-T", "-o", "BatchMode=yes"]);
+        // without a private folder for the socket every command opens its own connection
+        if let Some(dir) = control_dir() {
+            let control = dir.join("ssh-%C");
+            ssh.args(["-o", "ControlMaster=auto", "-o", "ControlPersist=60", "-o"])
+                .arg(OsString::from_vec(
+                    [b"ControlPath=".as_slice(), control.as_os_str().as_bytes()].concat(),
+                ));
+        }
+        ssh.arg(&self.target).arg("--").arg(command).stdin(Stdio::null());
+        ssh
+    }
+
+    /// Runs `find` on the host from `root`, the records NUL-separated
+    fn find(&self, root: &Path, follow: bool, options: &str) -> io::Result<Vec<u8>> {
+        let mut command = OsString::from("find ");
+        if follow {
+            command.push("-L ");
+        }
+        command.push(quote(root));
+        command.push(format!(" {}-type f -printf '%s %T@ %i %D %m %U %G %n %p\\0'", options));
+        let output = self.ssh(&command).stderr(Stdio::inherit()).output()?;
+        if !output.status.success() && output.stdout.is_empty() {
+            let msg = format!("listing {} failed: {}", self.local_path(root).display(), output.status);
+            return Err(io::Error::other(msg));
+        }
+        Ok(output.stdout)
+    }
+
+    /// The path on the host of a file listed as `[user@]host:/path`
+    fn host_path<'a>(&self, path: &'a Path) -> &'a Path {
+        let prefix = format!("{}:", self.target);
+        let bytes = path.as_os_str().as_bytes();
+        Path::new(std::ffi::OsStr::from_bytes(bytes.strip_prefix(prefix.as_bytes()).unwrap_or(bytes)))
+    }
+
+    fn local_path(&self, remote: &Path) -> PathBuf {
+        let mut spec = OsString::from(format!("{}:", self.target));
+        spec.push(remote);
+        PathBuf::from(spec)
+    }
+
+    /// One `find` record, `size mtime inode device mode uid gid links path`, and the path on the
+    /// host
+    fn parse_record(&self, record: &[u8]) -> Option<(PathBuf, FileInfo)> {
+        let mut fields = record.splitn(9, |b| *b == b' ');
+        let mut next = || std::str::from_utf8(fields.next()?).ok();
+        let size = next()?.parse().ok()?;
+        let mtime = parse_mtime(next()?)?;
+        let inode = next()?.parse().ok()?;
+        let device: u64 = next()?.parse().ok()?;
+        let mode = u32::from_str_radix(next()?, 8).ok()?;
+        let uid = next()?.parse().ok()?;
+        let gid = next()?.parse().ok()?;
+        let links = next()?.parse().ok()?;
+        let path = PathBuf::from(OsString::from_vec(fields.next()?.to_vec()));
+        let fl = FileInfo {
+            path: self.local_path(&path),
+            size,
+            inode,
+            device: REMOTE_DEVICES - device,
+            links,
+            mtime,
+            mode,
+            uid,
+            gid,
+            ..Default::default()
+        };
+        Some((path, fl))
+    }
+}
+
+impl FileSource for Remote {
+    fn spec(&self) -> PathBuf {
+        self.local_path(&self.root)
+    }
+
+    fn list(self: Arc<Self>, filters: &FilterSet) -> io::Result<Box<dyn Iterator<Item = FileInfo>>> {
+        let output = self.find(&self.root, filters.symlinks == SymlinkMode::Follow, "")?;
+        let origin: Arc<dyn FileSource> = Arc::clone(&self) as Arc<dyn FileSource>;
+        let mut files = vec![];
+        for record in output.split(|b| *b == 0).filter(|r| !r.is_empty()) {
+            let Some((path, mut fl)) = self.parse_record(record) else {
+                continue;
+            };
+            let depth = path
+                .strip_prefix(&self.root)
+                .map(|rel| rel.components().count())
+                .unwrap_or(0);
+            if filters.max_depth.map(|max| depth > max).unwrap_or(false) {
+                continue;
+            }
+            if filters.accepts_listed(&self.root, &path, fl.size, fl.mtime) {
+                fl.origin = Some(Arc::clone(&origin));
+                files.push(fl);
+            }
+        }
+        Ok(Box::new(files.into_iter()))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        let mut command = OsString::from("cat -- ");
+        command.push(quote(self.host_path(path)));
+        let mut child = self
+            .ssh(&command)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Box::new(RemoteFile { child, stdout, path: path.to_owned() }))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let output = self.find(self.host_path(path), false, "-maxdepth 0 ")?;
+        let record = output.split(|b| *b == 0).next().unwrap_or_default();
+        let not_found = || io::Error::new(io::ErrorKind::NotFound, format!("{} is gone", path.display()));
+        self.parse_record(record).map(|(_, fl)| fl).ok_or_else(not_found)
+    }
+}
+
+/// The content of a remote file, an error rather than a short read when `cat` fails
+pub struct RemoteFile {
+    child: Child,
+    stdout: ChildStdout,
+    path: PathBuf,
+}
+
+impl Read for RemoteFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                let msg = format!("reading {} over ssh failed: {}", self.path.display(), status);
+                return Err(io::Error::other(msg));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for RemoteFile {
+    fn drop(&mut self) {
+        // prefixes only read the start, the rest is not worth waiting for
+        _ = self.child.kill();
+        _ = self.child.wait();
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// A folder only the user can enter, for the sockets of the shared connections, which another
+/// user could otherwise plant in a shared temporary folder: `$XDG_RUNTIME_DIR/rcompare`, or
+/// `~/.ssh/rcompare`
+fn control_dir() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("rcompare"),
+        None => Path::new(&std::env::var_os("HOME")?).join(".ssh").join("rcompare"),
+    };
+    DirBuilder::new().recursive(true).mode(0o700).create(&dir).ok()?;
+    let meta = std::fs::symlink_metadata(&dir).ok()?;
+    let private = meta.is_dir() && meta.uid() == unsafe { libc::getuid() } && meta.mode() & 0o077 == 0;
+    private.then_some(dir)
+}
+
+/// `%T@` of `find`, seconds with a fraction, in nanoseconds
+fn parse_mtime(s: &str) -> Option<i64> {
+    let (secs, frac) = s.split_once('.').unwrap_or((s, ""));
+    let secs: i64 = secs.parse().ok()?;
+    let frac = format!("{:0<9}", &frac[..frac.len().min(9)]);
+    Some(secs.saturating_mul(1_000_000_000).saturating_add(frac.parse().ok()?))
+}
+
+/// Single quotes for the remote shell
+fn quote(s: &Path) -> OsString {
+    OsString::from_vec(shell_quote(s.as_os_str()))
+}
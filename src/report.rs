@@ -0,0 +1,477 @@
+use crate::filter::FilterSet;
+use crate::i18n::{tr, Msg};
+use crate::sandbox::Sandbox;
+use crate::say;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the report layout, bumped whenever a field changes meaning or disappears
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// Name prefix of the reports written into an output directory
+pub const REPORT_PREFIX: &str = "rcompare-";
+
+/// Extensions of the compressed forms of a report, pruned along with it
+const COMPRESSED: [&str; 4] = ["gz", "xz", "zst", "bz2"];
+
+/// Path of a new timestamped report inside `dir`
+pub fn new_report_path(dir: &Path) -> PathBuf {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    dir.join(format!("{}{:012}.json", REPORT_PREFIX, secs))
+}
+
+/// Removes the oldest reports of `dir` so only the `keep` latest remain.
+///
+/// A report and its compressed forms, like `rcompare-000000000001.json.gz`, count as one.
+pub fn prune_reports(dir: &Path, keep: usize, dry_run: bool) -> io::Result<()> {
+    let mut reports: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+
+        if let Some(stem) = report_stem(name) {
+            reports.entry(stem.to_owned()).or_default().push(path);
+        }
+    }
+
+    // the timestamps are zero padded, so the names sort by age
+    let excess = reports.len().saturating_sub(keep);
+    // a bad name or a symlink in the directory must not turn pruning into removing something else
+    let sandbox = Sandbox::new(&[dir])?;
+    for path in reports.into_values().take(excess).flatten() {
+        if dry_run {
+            say!("{}", tr(Msg::WouldRemove, &[&path.display()]));
+            continue;
+        }
+
+        if let Err(err) = sandbox.remove_file(&path) {
+            eprintln!("Could not remove the old report {}: {}", &path.display(), err);
+        }
+    }
+    Ok(())
+}
+
+/// Reads a report and checks it uses the schema of this build.
+///
+/// Reports written before the schema was versioned have no `version` field and count as version 0.
+pub fn check_compat<P: AsRef<Path>>(path: &P) -> io::Result<Value> {
+    let path = path.as_ref();
+    let report = read_report(path)?;
+    let version = schema_version(&report);
+    if version > SCHEMA_VERSION {
+        return Err(too_new(path, version));
+    }
+
+    if version < SCHEMA_VERSION {
+        let msg = format!(
+            "{} uses the older schema version {}, convert it with `rcompare report upgrade {}` or scan again",
+            path.display(),
+            version,
+            path.display()
+        );
+        return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+    }
+
+    Ok(report)
+}
+
+/// Reads a report of any earlier schema and converts it to the current one
+pub fn upgrade<P: AsRef<Path>>(path: &P) -> io::Result<Value> {
+    let path = path.as_ref();
+    let mut report = read_report(path)?;
+    let version = schema_version(&report);
+    if version > SCHEMA_VERSION {
+        return Err(too_new(path, version));
+    }
+
+    for from in version..SCHEMA_VERSION {
+        report = match from {
+            0 => upgrade_v0(report)?,
+            _ => unreachable!("no conversion from schema version {}", from),
+        };
+    }
+    Ok(report)
+}
+
+/// JSON Schema of the reports written with `SCHEMA_VERSION`.
+///
+/// The report is serialized by hand, so the schema is kept by hand next to the version it
+/// describes. Fields may be added without a new version, tools should allow unknown ones.
+pub fn json_schema() -> Value {
+    let path =
+        json!({ "type": "string", "description": "lossy UTF-8, see the _base64 field alongside for the exact bytes" });
+    let paths = json!({ "type": "array", "items": path });
+    let raw = json!({ "type": "string", "description": "the raw bytes of a path which is not valid UTF-8" });
+    let raws = json!({
+        "type": "array",
+        "items": { "type": ["string", "null"] },
+        "description": "the raw bytes of the paths which are not valid UTF-8, null for the others"
+    });
+    let entry = json!({
+        "type": "object",
+        "required": ["inode", "size", "path"],
+        "properties": {
+            "inode": { "type": "integer", "minimum": 0 },
+            "size": { "type": "integer", "minimum": 0 },
+            "path": path,
+            "symlink": { "enum": ["skip", "follow", "compare-target"] },
+            "device": { "type": "string", "description": "major:minor, as in volumes" },
+            "links": { "type": "integer", "minimum": 0 },
+            "hash": { "type": "string", "description": "algorithm and hexadecimal digest of the files read whole" },
+            "mtime": { "type": "integer", "description": "modification time in nanoseconds since the epoch" },
+            "mode": { "type": "string", "pattern": "^[0-7]{4}$", "description": "permission bits in octal" },
+            "uid": { "type": "integer", "minimum": 0 },
+            "gid": { "type": "integer", "minimum": 0 },
+            "path_base64": raw,
+            "given_path": { "type": "string", "description": "the path spelled from the root given by the user" },
+            "image_path": { "type": "string", "description": "the path seen from inside the sysroot" },
+            "volume": { "type": "string" },
+            "role": { "enum": ["keeper", "redundant"], "description": "in duplicate groups, under the keep policy" }
+        }
+    });
+    let entries = json!({ "type": "array", "items": entry });
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "rcompare report",
+        "type": "object",
+        "required": [
+            "version", "roots", "mode", "zero", "unique", "same", "confidence", "filters", "skipped", "warnings"
+        ],
+        "properties": {
+            "version": { "const": SCHEMA_VERSION },
+            "partial": {
+                "const": true,
+                "description": "the scan was interrupted, files not compared yet are in none of the lists"
+            },
+            "roots": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["given", "resolved"],
+                    "properties": { "given": path, "resolved": path, "given_base64": raw, "resolved_base64": raw }
+                }
+            },
+            "mode": { "enum": ["self", "two-roots", "same-roots", "mirror"] },
+            "zero": entries,
+            "unique": entries,
+            "same": { "type": "array", "items": entries, "description": "groups of files with the same content" },
+            "confidence": {
+                "type": "array",
+                "items": { "enum": ["sampled-hash", "full-hash", "byte-compare", "empty", "inode", null] },
+                "description": "how each group of same was found equal, parallel to it"
+            },
+            "filters": { "type": "object", "description": "the options deciding which files took part" },
+            "skipped": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["path", "stage", "reason"],
+                    "properties": {
+                        "path": path,
+                        "stage": { "enum": ["scan", "compare"] },
+                        "reason": { "type": "string" },
+                        "path_base64": raw
+                    }
+                }
+            },
+            "warnings": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["kind", "message"],
+                    "properties": {
+                        "kind": { "enum": ["overlapping-roots", "unreadable-directory", "unreadable-archive", "mostly-filtered", "cache", "interrupted"] },
+                        "path": path,
+                        "message": { "type": "string" },
+                        "path_base64": raw
+                    }
+                }
+            },
+            "ignore_ranges": {
+                "type": "object",
+                "properties": {
+                    "all": { "type": "string" },
+                    "by_ext": { "type": "object", "additionalProperties": { "type": "string" } }
+                }
+            },
+            "policies": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["glob", "compare"],
+                    "properties": {
+                        "glob": { "type": "string" },
+                        "compare": {
+                            "enum": ["exact", "normalized-text", "normalized-line-endings", "ignore-metadata-image", "ignore-ranges"]
+                        },
+                        "ranges": { "type": "string" }
+                    }
+                }
+            },
+            "keep": { "type": "string", "description": "the keep policy, like prefer:/srv/master,newest" },
+            "savings": {
+                "type": "object",
+                "properties": {
+                    "exact_duplicates": { "type": "integer" },
+                    "chunk_estimate": { "type": "object" }
+                }
+            },
+            "similar_dirs": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "lhs": path,
+                        "rhs": path,
+                        "lhs_bytes": { "type": "integer" },
+                        "rhs_bytes": { "type": "integer" },
+                        "shared_bytes": { "type": "integer" },
+                        "overlap": { "type": "number" },
+                        "lhs_base64": raw,
+                        "rhs_base64": raw
+                    }
+                }
+            },
+            "hardlinks": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": { "inode": { "type": "integer" }, "paths": paths, "paths_base64": raws }
+                }
+            },
+            "empty_dirs": {
+                "type": "object",
+                "properties": {
+                    "dirs": paths,
+                    "same": { "type": "array", "items": paths },
+                    "dirs_base64": raws,
+                    "same_base64": { "type": "array", "items": raws }
+                }
+            },
+            "duplicate_dirs": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "dirs": paths,
+                        "files": { "type": "integer" },
+                        "bytes": { "type": "integer" },
+                        "dirs_base64": raws
+                    }
+                }
+            },
+            "similar": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "files": paths,
+                        "pairs": {
+                            "type": "array",
+                            "items": {
+                                "type": "object",
+                                "properties": {
+                                    "lhs": path,
+                                    "rhs": path,
+                                    "score": { "type": "number" },
+                                    "lhs_base64": raw,
+                                    "rhs_base64": raw
+                                }
+                            }
+                        },
+                        "files_base64": raws
+                    }
+                }
+            },
+            "renames": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "from": path,
+                        "to": path,
+                        "size": { "type": "integer" },
+                        "from_base64": raw,
+                        "to_base64": raw
+                    }
+                }
+            },
+            "same_name": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "properties": {
+                        "name": path,
+                        "versions": { "type": "array", "items": paths },
+                        "name_base64": raw,
+                        "versions_base64": { "type": "array", "items": raws }
+                    }
+                }
+            },
+            "coverage": {
+                "type": "object",
+                "required": ["direction"],
+                "properties": {
+                    "direction": { "enum": ["both", "lhs-to-rhs", "rhs-to-lhs"] },
+                    "missing_in_rhs": paths,
+                    "missing_in_lhs": paths,
+                    "missing_in_rhs_base64": raws,
+                    "missing_in_lhs_base64": raws
+                }
+            },
+            "mirror": {
+                "type": "object",
+                "description": "relative paths, in mirror mode",
+                "properties": {
+                    "added": { "type": "array", "items": { "type": "string" } },
+                    "removed": { "type": "array", "items": { "type": "string" } },
+                    "changed": { "type": "array", "items": { "type": "string" } },
+                    "unchanged": { "type": "integer" },
+                    "added_base64": raws,
+                    "removed_base64": raws,
+                    "changed_base64": raws
+                }
+            },
+            "difference": {
+                "type": "object",
+                "description": "where the two files given on their own differ",
+                "required": ["offset"],
+                "properties": {
+                    "offset": { "type": "integer" },
+                    "context_start": { "type": "integer" },
+                    "lhs": { "type": "string", "description": "bytes in hex" },
+                    "rhs": { "type": "string", "description": "bytes in hex" }
+                }
+            },
+            "index": {
+                "type": "object",
+                "properties": {
+                    "volumes": { "type": "array", "items": { "type": "string" } },
+                    "entries": { "type": "integer" },
+                    "same": { "type": "array", "items": { "type": "array", "items": { "type": "object" } } }
+                }
+            },
+            "volumes": {
+                "type": "array",
+                "items": {
+                    "type": "object",
+                    "required": ["device", "mount_point", "source", "fs_type"],
+                    "properties": {
+                        "device": { "type": "string" },
+                        "mount_point": path,
+                        "source": { "type": "string" },
+                        "fs_type": { "type": "string" },
+                        "uuid": { "type": "string" },
+                        "label": { "type": "string" }
+                    }
+                }
+            },
+            "stats": {
+                "type": "object",
+                "required": ["seconds", "phases"],
+                "properties": {
+                    "seconds": { "type": "number", "description": "wall time of the scan" },
+                    "phases": {
+                        "type": "array",
+                        "items": {
+                            "type": "object",
+                            "required": ["phase", "seconds", "files", "bytes", "files_per_second", "bytes_per_second"],
+                            "properties": {
+                                "phase": { "enum": ["walk", "preprocess", "hash", "compare"] },
+                                "seconds": {
+                                    "type": "number",
+                                    "description": "time spent in the phase, added up over the workers"
+                                },
+                                "files": { "type": "integer", "minimum": 0 },
+                                "bytes": { "type": "integer", "minimum": 0 },
+                                "files_per_second": { "type": "integer", "minimum": 0 },
+                                "bytes_per_second": { "type": "integer", "minimum": 0 }
+                            }
+                        }
+                    }
+                }
+            },
+            "bytes_read": {
+                "type": "object",
+                "required": ["read", "total"],
+                "properties": {
+                    "read": { "type": "integer", "minimum": 0, "description": "bytes the comparisons read" },
+                    "total": {
+                        "type": "integer",
+                        "minimum": 0,
+                        "description": "bytes the files hold, what reading every file whole once would take"
+                    }
+                }
+            }
+        }
+    })
+}
+
+// ----------
+//  Internal
+// ----------
+
+fn read_report(path: &Path) -> io::Result<Value> {
+    let file = io::BufReader::new(fs::File::open(path)?);
+    serde_json::from_reader(file)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{} is not a report: {}", path.display(), e)))
+}
+
+/// Reports written before the schema was versioned have no `version` field and count as version 0
+fn schema_version(report: &Value) -> u64 {
+    report.get("version").and_then(|v| v.as_u64()).unwrap_or(0)
+}
+
+fn too_new(path: &Path, version: u64) -> io::Error {
+    let msg = format!(
+        "{} uses schema version {} but this rcompare only reads up to version {}, please update rcompare",
+        path.display(),
+        version,
+        SCHEMA_VERSION
+    );
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Unversioned reports may lack the scanned roots and the filters, which came later
+fn upgrade_v0(report: Value) -> io::Result<Value> {
+    let Value::Object(mut old) = report else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "a report must be a JSON object"));
+    };
+
+    let mut new = Map::new();
+    new.insert("version".to_owned(), Value::from(1));
+    // the roots were not recorded, they cannot be recovered
+    new.insert("roots".to_owned(), old.remove("roots").unwrap_or_else(|| Value::Array(vec![])));
+    for key in ["zero", "unique", "same"] {
+        new.insert(key.to_owned(), old.remove(key).unwrap_or_else(|| Value::Array(vec![])));
+    }
+    let filters = match old.remove("filters") {
+        Some(filters) => filters,
+        None => serde_json::to_value(FilterSet::default())?,
+    };
+    new.insert("filters".to_owned(), filters);
+    new.extend(old);
+    Ok(Value::Object(new))
+}
+
+fn report_stem(name: &str) -> Option<&str> {
+    if !name.starts_with(REPORT_PREFIX) {
+        return None;
+    }
+
+    let name = match name.rsplit_once('.') {
+        Some((stem, ext)) if COMPRESSED.contains(&ext) => stem,
+        _ => name,
+    };
+    name.strip_suffix(".json")
+}
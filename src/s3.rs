@@ -0,0 +1,387 @@
+use crate::file::FileInfo;
+use crate::filter::FilterSet;
+use crate::source::FileSource;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One device for every object, far from the local ones and from those of the SSH trees
+const DEVICE: u64 = u64::MAX / 2;
+
+/// A prefix of a bucket, written `s3://bucket/prefix` and reached over the S3 API.
+///
+/// The credentials, region and endpoint come from the usual `AWS_*` variables, and a bucket
+/// with no credentials around is read anonymously. Requests go to the bucket in the path, so
+/// any S3 compatible storage works with `AWS_ENDPOINT_URL`.
+#[derive(Debug)]
+pub struct Bucket {
+    name: String,
+    prefix: String,
+    endpoint: String,
+    region: String,
+    credentials: Option<Credentials>,
+    /// the ETag of each listed key, to be sure the object read is the one listed
+    etags: Mutex<HashMap<String, String>>,
+}
+
+#[derive(Debug)]
+struct Credentials {
+    access_key: String,
+    secret_key: String,
+    token: Option<String>,
+}
+
+impl Bucket {
+    /// The bucket behind `bucket/prefix`, the part of an `s3://` path after the scheme
+    pub fn new(spec: &str) -> io::Result<Self> {
+        let (name, prefix) = spec.split_once('/').unwrap_or((spec, ""));
+        if name.is_empty() {
+            let msg = format!("s3://{} does not name a bucket", spec);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+
+        let var = |name: &str| std::env::var(name).ok().filter(|v| !v.is_empty());
+        let region = var("AWS_REGION")
+            .or_else(|| var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|| "us-east-1".to_owned());
+        let endpoint = var("AWS_ENDPOINT_URL_S3")
+            .or_else(|| var("AWS_ENDPOINT_URL"))
+            .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", region));
+        let credentials = match (var("AWS_ACCESS_KEY_ID"), var("AWS_SECRET_ACCESS_KEY")) {
+            (Some(access_key), Some(secret_key)) => {
+                Some(Credentials { access_key, secret_key, token: var("AWS_SESSION_TOKEN") })
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            name: name.to_owned(),
+            prefix: prefix.trim_start_matches('/').to_owned(),
+            endpoint: endpoint.trim_end_matches('/').to_owned(),
+            region,
+            credentials,
+            etags: Mutex::default(),
+        })
+    }
+
+    /// One page of `ListObjectsV2` under `prefix`, the objects and the token of the next page
+    fn list_page(&self, prefix: &str, token: Option<&str>) -> io::Result<(Vec<Object>, Option<String>)> {
+        let mut query = vec![("list-type", "2"), ("prefix", prefix)];
+        if let Some(token) = token {
+            query.push(("continuation-token", token));
+        }
+        let mut body = String::new();
+        self.request("", &query, &[])?.read_to_string(&mut body)?;
+        parse_listing(&body)
+    }
+
+    /// Sends a signed `GET` for `key`, the bucket itself when empty
+    fn request(&self, key: &str, query: &[(&str, &str)], headers: &[(&str, &str)]) -> io::Result<impl Read> {
+        let path = match key.is_empty() {
+            true => format!("/{}", encode(&self.name, false)),
+            false => format!("/{}/{}", encode(&self.name, false), encode(key, false)),
+        };
+        let mut query: Vec<(String, String)> = query.iter().map(|(k, v)| (encode(k, true), encode(v, true))).collect();
+        query.sort();
+        let query = query
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let url = match query.is_empty() {
+            true => format!("{}{}", self.endpoint, path),
+            false => format!("{}{}?{}", self.endpoint, path, query),
+        };
+        let mut request = ureq::get(&url);
+        for (name, value) in headers {
+            request = request.set(name, value);
+        }
+        if let Some(credentials) = &self.credentials {
+            for (name, value) in self.sign(credentials, &path, &query, headers) {
+                request = request.set(&name, &value);
+            }
+        }
+
+        match request.call() {
+            Ok(response) => Ok(response.into_reader()),
+            Err(ureq::Error::Status(status, response)) => {
+                let detail = response.into_string().unwrap_or_default();
+                let code = between(&detail, "<Code>", "</Code>").unwrap_or("");
+                let object = format!("s3://{}/{}", self.name, key);
+                let msg = format!("{}: HTTP {} {}", object.trim_end_matches('/'), status, code);
+                let kind = match status {
+                    403 => io::ErrorKind::PermissionDenied,
+                    404 => io::ErrorKind::NotFound,
+                    _ => io::ErrorKind::Other,
+                };
+                Err(io::Error::new(kind, msg.trim_end().to_owned()))
+            }
+            Err(err) => Err(io::Error::other(err.to_string())),
+        }
+    }
+
+    /// The headers of an AWS Signature Version 4 for a `GET` with no payload
+    fn sign(
+        &self,
+        credentials: &Credentials,
+        path: &str,
+        query: &str,
+        extra: &[(&str, &str)],
+    ) -> Vec<(String, String)> {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let (date, time) = utc(secs as i64);
+        let stamp = format!("{}T{}Z", date, time);
+        let host = self
+            .endpoint
+            .split_once("://")
+            .map(|(_, h)| h)
+            .unwrap_or(&self.endpoint);
+        let host = host.split('/').next().unwrap_or(host);
+
+        let mut headers: Vec<(String, String)> = vec![
+            ("host".to_owned(), host.to_owned()),
+            ("x-amz-content-sha256".to_owned(), "UNSIGNED-PAYLOAD".to_owned()),
+            ("x-amz-date".to_owned(), stamp.clone()),
+        ];
+        if let Some(token) = &credentials.token {
+            headers.push(("x-amz-security-token".to_owned(), token.clone()));
+        }
+        headers.extend(extra.iter().map(|(k, v)| (k.to_ascii_lowercase(), v.trim().to_owned())));
+        headers.sort();
+
+        let signed = headers.iter().map(|(k, _)| k.as_str()).collect::<Vec<_>>().join(";");
+        let canonical: String = headers.iter().map(|(k, v)| format!("{}:{}\n", k, v)).collect();
+        let request = format!("GET\n{}\n{}\n{}\n{}\nUNSIGNED-PAYLOAD", path, query, canonical, signed);
+        let scope = format!("{}/{}/s3/aws4_request", date, self.region);
+        let to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            stamp,
+            scope,
+            hex(&hmac_sha256::Hash::hash(request.as_bytes()))
+        );
+
+        let key = format!("AWS4{}", credentials.secret_key);
+        let key = hmac_sha256::HMAC::mac(date.as_bytes(), key.as_bytes());
+        let key = hmac_sha256::HMAC::mac(self.region.as_bytes(), key);
+        let key = hmac_sha256::HMAC::mac(b"s3", key);
+        let key = hmac_sha256::HMAC::mac(b"aws4_request", key);
+        let signature = hex(&hmac_sha256::HMAC::mac(to_sign.as_bytes(), key));
+
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key, scope, signed, signature
+        );
+        headers.retain(|(k, _)| k != "host" && !extra.iter().any(|(e, _)| e.eq_ignore_ascii_case(k)));
+        headers.push(("authorization".to_owned(), authorization));
+        headers
+    }
+
+    /// The key of an object from its path in the report
+    fn key<'a>(&self, path: &'a Path) -> io::Result<&'a str> {
+        let key = path
+            .to_str()
+            .and_then(|p| p.strip_prefix("s3://"))
+            .and_then(|p| p.strip_prefix(self.name.as_str()))
+            .and_then(|p| p.strip_prefix('/'));
+        key.ok_or_else(|| {
+            let msg = format!("{} is not in s3://{}", path.display(), self.name);
+            io::Error::new(io::ErrorKind::InvalidInput, msg)
+        })
+    }
+
+    /// The object as a file, before it is numbered
+    fn info(&self, object: &Object) -> FileInfo {
+        FileInfo {
+            path: PathBuf::from(format!("s3://{}/{}", self.name, object.key)),
+            size: object.size,
+            device: DEVICE,
+            links: 1,
+            mtime: object.mtime,
+            mode: 0o644,
+            ..Default::default()
+        }
+    }
+}
+
+impl FileSource for Bucket {
+    fn spec(&self) -> PathBuf {
+        PathBuf::from(format!("s3://{}/{}", self.name, self.prefix))
+    }
+
+    fn list(self: Arc<Self>, filters: &FilterSet) -> io::Result<Box<dyn Iterator<Item = FileInfo>>> {
+        let root = Path::new(&self.prefix);
+        let mut files = vec![];
+        let mut etags = HashMap::new();
+        let mut token = None;
+        loop {
+            let (objects, next) = self.list_page(&self.prefix, token.as_deref())?;
+            for object in objects {
+                // the keys ending with a slash are folders made in a console
+                if object.key.ends_with('/') {
+                    continue;
+                }
+                let path = Path::new(&object.key);
+                let depth = path.strip_prefix(root).map(|rel| rel.components().count()).unwrap_or(0);
+                if filters.max_depth.map(|max| depth > max).unwrap_or(false) {
+                    continue;
+                }
+                if !filters.accepts_listed(root, path, object.size, object.mtime) {
+                    continue;
+                }
+
+                files.push(FileInfo {
+                    inode: files.len() as u64 + 1,
+                    origin: Some(Arc::clone(&self) as Arc<dyn FileSource>),
+                    ..self.info(&object)
+                });
+                etags.insert(object.key, object.etag);
+            }
+            match next {
+                Some(next) => token = Some(next),
+                None => break,
+            }
+        }
+        *self.etags.lock().unwrap() = etags;
+        Ok(Box::new(files.into_iter()))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        let key = self.key(path)?;
+        // an object replaced since the listing would not have the size the groups rely on
+        let etag = self.etags.lock().unwrap().get(key).cloned();
+        let headers: Vec<(&str, &str)> = etag.iter().map(|etag| ("If-Match", etag.as_str())).collect();
+        Ok(Box::new(self.request(key, &[], &headers)?))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let key = self.key(path)?;
+        // the key itself comes first among the keys it prefixes
+        let (objects, _) = self.list_page(key, None)?;
+        match objects.iter().find(|object| object.key == key) {
+            Some(object) => Ok(self.info(object)),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is gone", path.display()))),
+        }
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+#[derive(Debug, Default)]
+struct Object {
+    key: String,
+    size: u64,
+    etag: String,
+    mtime: i64,
+}
+
+/// The objects of a `ListBucketResult` and the token of the next page when it is truncated
+fn parse_listing(body: &str) -> io::Result<(Vec<Object>, Option<String>)> {
+    let invalid = |err: &dyn std::fmt::Display| io::Error::new(io::ErrorKind::InvalidData, err.to_string());
+    let mut reader = Reader::from_str(body);
+    let (mut objects, mut next) = (vec![], None);
+    let mut object: Option<Object> = None;
+    let mut element = String::new();
+    loop {
+        match reader.read_event().map_err(|e| invalid(&e))? {
+            Event::Start(start) => {
+                element = String::from_utf8_lossy(start.local_name().as_ref()).into_owned();
+                if element == "Contents" {
+                    object = Some(Object::default());
+                }
+            }
+            Event::End(end) => {
+                if end.local_name().as_ref() == b"Contents" {
+                    objects.extend(object.take());
+                }
+                element.clear();
+            }
+            Event::Text(text) => {
+                let text = text.unescape().map_err(|e| invalid(&e))?;
+                match (element.as_str(), object.as_mut()) {
+                    ("Key", Some(object)) => object.key = text.into_owned(),
+                    ("Size", Some(object)) => object.size = text.trim().parse().map_err(|e| invalid(&e))?,
+                    ("ETag", Some(object)) => object.etag = text.into_owned(),
+                    ("LastModified", Some(object)) => object.mtime = parse_time(&text).unwrap_or(0),
+                    ("NextContinuationToken", None) => next = Some(text.into_owned()),
+                    _ => {}
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok((objects, next))
+}
+
+/// `2024-05-01T12:30:00.000Z` in nanoseconds since the epoch
+fn parse_time(s: &str) -> Option<i64> {
+    let (date, time) = s.trim().trim_end_matches('Z').split_once('T')?;
+    let mut date = date.splitn(3, '-').map(|p| p.parse::<i64>());
+    let (y, m, d) = (date.next()?.ok()?, date.next()?.ok()?, date.next()?.ok()?);
+    let (time, frac) = time.split_once('.').unwrap_or((time, ""));
+    let mut time = time.splitn(3, ':').map(|p| p.parse::<i64>());
+    let (h, min, sec) = (time.next()?.ok()?, time.next()?.ok()?, time.next()?.ok()?);
+    let nanos: i64 = format!("{:0<9}", &frac[..frac.len().min(9)]).parse().ok()?;
+
+    let secs = days_from_civil(y, m, d) * 86400 + h * 3600 + min * 60 + sec;
+    Some(secs * 1_000_000_000 + nanos)
+}
+
+/// The date and the time of `secs` since the epoch, as `YYYYMMDD` and `HHMMSS` in UTC
+fn utc(secs: i64) -> (String, String) {
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+    // civil from days, after Howard Hinnant
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + i64::from(m <= 2);
+    let date = format!("{:04}{:02}{:02}", y, m, d);
+    let time = format!("{:02}{:02}{:02}", rem / 3600, rem % 3600 / 60, rem % 60);
+    (date, time)
+}
+
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Percent-encoding of the S3 API, slashes kept in paths
+fn encode(s: &str, query: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(b as char),
+            b'/' if !query => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn between<'a>(s: &'a str, start: &str, end: &str) -> Option<&'a str> {
+    let rest = &s[s.find(start)? + start.len()..];
+    Some(&rest[..rest.find(end)?])
+}
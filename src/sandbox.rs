@@ -0,0 +1,254 @@
+use std::ffi::CString;
+use std::fs::File;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::FromRawFd;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::path::{Component, Path, PathBuf};
+
+/// Runs the actions which modify files, confined to a set of directories.
+///
+/// Every path is resolved by the kernel with `openat2(RESOLVE_BENEATH)` from a descriptor of its
+/// directory, so neither `..` nor a symlink swapped in meanwhile can lead outside of it. Kernels
+/// without `openat2`, and systems other than Linux, get an error rather than an unconfined action.
+pub struct Sandbox {
+    roots: Vec<(PathBuf, OwnedFd)>,
+}
+
+impl Sandbox {
+    pub fn new<P: AsRef<Path>>(roots: &[P]) -> io::Result<Self> {
+        let mut opened = vec![];
+        for root in roots.iter() {
+            let path = absolute(root.as_ref())?;
+            let fd = File::open(&path)?;
+            opened.push((path, fd.into()));
+        }
+        Ok(Self { roots: opened })
+    }
+
+    pub fn remove_file<P: AsRef<Path>>(&self, path: &P) -> io::Result<()> {
+        let (dir, name) = self.open_parent(path.as_ref())?;
+        check(unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) })
+    }
+
+    pub fn create_dir<P: AsRef<Path>>(&self, path: &P) -> io::Result<()> {
+        let (dir, name) = self.open_parent(path.as_ref())?;
+        check(unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o777) })
+    }
+
+    /// Links `src`, which may be anywhere, as `dst`, which must be inside the sandbox
+    pub fn hard_link<P: AsRef<Path>, Q: AsRef<Path>>(&self, src: &P, dst: &Q) -> io::Result<()> {
+        let src = CString::new(src.as_ref().as_os_str().as_bytes())?;
+        let (dir, name) = self.open_parent(dst.as_ref())?;
+        check(unsafe { libc::linkat(libc::AT_FDCWD, src.as_ptr(), dir.as_raw_fd(), name.as_ptr(), 0) })
+    }
+
+    /// Moves `src`, which must be inside the sandbox, to `dst`, which may be anywhere on the same
+    /// file system, like a trash
+    pub fn move_out<P: AsRef<Path>, Q: AsRef<Path>>(&self, src: &P, dst: &Q) -> io::Result<()> {
+        let (dir, name) = self.open_parent(src.as_ref())?;
+        let dst = CString::new(dst.as_ref().as_os_str().as_bytes())?;
+        check(unsafe { libc::renameat(dir.as_raw_fd(), name.as_ptr(), libc::AT_FDCWD, dst.as_ptr()) })
+    }
+
+    /// Moves `src` over `dst`, both inside the sandbox
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&self, src: &P, dst: &Q) -> io::Result<()> {
+        let (src_dir, src_name) = self.open_parent(src.as_ref())?;
+        let (dst_dir, dst_name) = self.open_parent(dst.as_ref())?;
+        check(unsafe { libc::renameat(src_dir.as_raw_fd(), src_name.as_ptr(), dst_dir.as_raw_fd(), dst_name.as_ptr()) })
+    }
+
+    /// Creates `dst`, which must be inside the sandbox, as a copy-on-write clone of `src`, which
+    /// may be anywhere. File systems without shared extents fail with `EOPNOTSUPP` or `EXDEV`.
+    #[cfg(target_os = "linux")]
+    pub fn clone_file<P: AsRef<Path>, Q: AsRef<Path>>(&self, src: &P, dst: &Q, mode: u32) -> io::Result<()> {
+        let src = File::open(src)?;
+        let (dir, name) = self.open_parent(dst.as_ref())?;
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags, mode as libc::c_uint) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let dst = unsafe { OwnedFd::from_raw_fd(fd) };
+        let cloned = check(unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) });
+        if cloned.is_err() {
+            unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), 0) };
+        }
+        cloned
+    }
+
+    /// Has the kernel share the extents of `src`, which may be anywhere, with the first `len` bytes
+    /// of `dst`, which must be inside the sandbox, through `FIDEDUPERANGE`.
+    ///
+    /// The kernel locks both files and compares them itself, a `dst` which differs is left alone
+    /// with an error. Every path stays as it is, with its inode, owner and times.
+    #[cfg(target_os = "linux")]
+    pub fn share_extents<P: AsRef<Path>, Q: AsRef<Path>>(&self, src: &P, dst: &Q, len: u64) -> io::Result<()> {
+        let src = File::open(src)?;
+        let (dir, name) = self.open_parent(dst.as_ref())?;
+        let flags = libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let dst = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        // file systems share at most so many bytes per call, Btrfs 16M
+        let mut offset = 0;
+        while offset < len {
+            let mut range = DedupeRange {
+                src_offset: offset,
+                src_length: len - offset,
+                dest_count: 1,
+                reserved1: 0,
+                reserved2: 0,
+                info: DedupeRangeInfo {
+                    dest_fd: dst.as_raw_fd() as i64,
+                    dest_offset: offset,
+                    bytes_deduped: 0,
+                    status: 0,
+                    reserved: 0,
+                },
+            };
+            check(unsafe { libc::ioctl(src.as_raw_fd(), FIDEDUPERANGE, &mut range as *mut DedupeRange) })?;
+            match range.info.status {
+                FILE_DEDUPE_RANGE_SAME if range.info.bytes_deduped > 0 => offset += range.info.bytes_deduped,
+                FILE_DEDUPE_RANGE_SAME => break,
+                FILE_DEDUPE_RANGE_DIFFERS => {
+                    let msg = format!("the content differs from the kept copy from byte {} on", offset);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+                }
+                errno => return Err(io::Error::from_raw_os_error(-errno)),
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn clone_file<P: AsRef<Path>, Q: AsRef<Path>>(&self, _: &P, _: &Q, _: u32) -> io::Result<()> {
+        Err(unsupported("cloning files"))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn share_extents<P: AsRef<Path>, Q: AsRef<Path>>(&self, _: &P, _: &Q, _: u64) -> io::Result<()> {
+        Err(unsupported("sharing extents"))
+    }
+
+    /// The directory holding `path`, opened beneath the root it belongs to, and the name left in it
+    fn open_parent(&self, path: &Path) -> io::Result<(OwnedFd, CString)> {
+        let path = absolute(path)?;
+        let (root, rel) = self
+            .roots
+            .iter()
+            .find_map(|(root, fd)| Some((fd, path.strip_prefix(root).ok()?)))
+            .ok_or_else(|| outside(&path))?;
+
+        let name = match rel.components().next_back() {
+            Some(Component::Normal(name)) => CString::new(name.as_bytes())?,
+            _ => return Err(outside(&path)),
+        };
+        let parent = match rel.parent().map(|p| p.as_os_str().as_bytes()) {
+            Some(parent) if !parent.is_empty() => CString::new(parent)?,
+            _ => CString::new(".")?,
+        };
+
+        let fd = open_beneath(root, &parent).map_err(|err| match err.raw_os_error() {
+            Some(libc::EXDEV) => outside(&path),
+            _ => err,
+        })?;
+        Ok((fd, name))
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// `_IOWR(0x94, 54, struct file_dedupe_range)` of `linux/fs.h`, which the libc crate lacks
+#[cfg(target_os = "linux")]
+const FIDEDUPERANGE: libc::c_ulong = 0xc018_9436;
+#[cfg(target_os = "linux")]
+const FILE_DEDUPE_RANGE_SAME: i32 = 0;
+#[cfg(target_os = "linux")]
+const FILE_DEDUPE_RANGE_DIFFERS: i32 = 1;
+
+/// `struct file_dedupe_range` with a single destination
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct DedupeRange {
+    src_offset: u64,
+    src_length: u64,
+    dest_count: u16,
+    reserved1: u16,
+    reserved2: u32,
+    info: DedupeRangeInfo,
+}
+
+/// `struct file_dedupe_range_info`
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct DedupeRangeInfo {
+    dest_fd: i64,
+    dest_offset: u64,
+    bytes_deduped: u64,
+    /// `FILE_DEDUPE_RANGE_SAME`, `FILE_DEDUPE_RANGE_DIFFERS` or a negated errno
+    status: i32,
+    reserved: u32,
+}
+
+/// Opens the directory `parent` relative to `root`, never leaving it on the way
+#[cfg(target_os = "linux")]
+fn open_beneath(root: &OwnedFd, parent: &CString) -> io::Result<OwnedFd> {
+    let mut how: libc::open_how = unsafe { std::mem::zeroed() };
+    how.flags = (libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) as u64;
+    how.resolve = libc::RESOLVE_BENEATH | libc::RESOLVE_NO_MAGICLINKS;
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_openat2,
+            root.as_raw_fd(),
+            parent.as_ptr(),
+            &how as *const libc::open_how,
+            std::mem::size_of::<libc::open_how>(),
+        )
+    };
+    if fd < 0 {
+        let err = io::Error::last_os_error();
+        return Err(match err.raw_os_error() {
+            Some(libc::ENOSYS) => io::Error::new(
+                io::ErrorKind::Unsupported,
+                "this kernel cannot confine file actions, openat2 needs Linux 5.6",
+            ),
+            _ => err,
+        });
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+/// Only Linux resolves a path beneath a directory in the kernel, elsewhere nothing is modified
+#[cfg(not(target_os = "linux"))]
+fn open_beneath(_: &OwnedFd, _: &CString) -> io::Result<OwnedFd> {
+    Err(unsupported("confining file actions"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Unsupported, format!("{} needs Linux", what))
+}
+
+fn absolute(path: &Path) -> io::Result<PathBuf> {
+    Ok(std::env::current_dir()?.join(path))
+}
+
+fn outside(path: &Path) -> io::Error {
+    let msg = format!("{} is outside the directories rcompare may modify", path.display());
+    io::Error::new(io::ErrorKind::PermissionDenied, msg)
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    match ret {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
@@ -0,0 +1,276 @@
+use crate::common::write_file;
+use crate::i18n::{tr, Msg};
+use crate::settings::config_dir;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// What runs a scheduled profile
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Timer {
+    /// a line in the user crontab
+    Cron,
+    /// a systemd user timer and service
+    Systemd,
+}
+
+/// A named scan, saved so it can be run again unattended
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Profile {
+    /// arguments of the scan, as given on the command line
+    pub args: Vec<String>,
+    /// where the timestamped reports are written
+    pub reports: PathBuf,
+    /// how many reports to keep, the oldest ones are removed after each run
+    pub keep: usize,
+}
+
+/// Saves the profile and installs an entry running it on the `cron` schedule
+pub fn install(name: &str, cron: &str, timer: Timer, profile: &Profile, dry_run: bool) -> io::Result<()> {
+    check_name(name)?;
+    let calendar = to_calendar(cron)?;
+    let path = profile_path(name)?;
+    if !dry_run {
+        fs::create_dir_all(path.parent().unwrap())?;
+    }
+    write_file(&path, serde_json::to_string_pretty(profile)?.as_bytes(), dry_run)?;
+    if !dry_run {
+        println!("{}", tr(Msg::SavedProfile, &[&name, &path.display()]));
+    }
+
+    let exe = std::env::current_exe()?;
+    match timer {
+        Timer::Cron => install_cron(name, cron, &exe, dry_run),
+        Timer::Systemd => install_systemd(name, &calendar, &exe, dry_run),
+    }
+}
+
+/// Runs a saved profile, writing a timestamped report and removing the ones beyond `keep`.
+///
+/// Returns the exit code of the scan.
+pub fn run(name: &str, dry_run: bool) -> io::Result<i32> {
+    check_name(name)?;
+    let path = profile_path(name)?;
+    let profile: Profile = serde_json::from_str(&fs::read_to_string(&path)?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("profile {}: {}", path.display(), e)))?;
+
+    if !dry_run {
+        fs::create_dir_all(&profile.reports)?;
+    }
+    let status = Command::new(std::env::current_exe()?)
+        .args(&profile.args)
+        .args(dry_run.then_some("--dry-run"))
+        .arg("-o")
+        .arg(&profile.reports)
+        .arg("--keep-reports")
+        .arg(profile.keep.to_string())
+        .status()?;
+
+    Ok(status.code().unwrap_or(1))
+}
+
+/// Default location of the reports of a profile
+pub fn default_reports(name: &str) -> io::Result<PathBuf> {
+    let state = match std::env::var_os("XDG_STATE_HOME").filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => std::env::var_os("HOME")
+            .map(|home| Path::new(&home).join(".local").join("state"))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "neither XDG_STATE_HOME nor HOME is set"))?,
+    };
+    Ok(state.join("rcompare").join(name))
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// Profile names end up in file and unit names, keep them to a safe alphabet
+fn check_name(name: &str) -> io::Result<()> {
+    let valid = !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        let msg = format!("invalid profile name '{}', use letters, digits, '-' and '_'", name);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+    Ok(())
+}
+
+fn profile_path(name: &str) -> io::Result<PathBuf> {
+    Ok(config_dir()?
+        .join("rcompare")
+        .join("profiles")
+        .join(format!("{}.json", name)))
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn install_cron(name: &str, cron: &str, exe: &Path, dry_run: bool) -> io::Result<()> {
+    let marker = format!("# rcompare:{}", name);
+    let entry = format!(
+        "{} {} schedule run --profile {} {}",
+        cron,
+        quote(&exe.to_string_lossy()),
+        name,
+        marker
+    );
+    if dry_run {
+        println!("{}", tr(Msg::WouldRun, &[&"crontab -"]));
+        println!("{}", entry);
+        return Ok(());
+    }
+
+    let current = Command::new("crontab").arg("-l").stderr(Stdio::null()).output()?;
+    // an empty crontab makes `crontab -l` fail, start from scratch then
+    let current = if current.status.success() {
+        String::from_utf8_lossy(&current.stdout).into_owned()
+    } else {
+        String::new()
+    };
+
+    let mut lines: Vec<String> = current
+        .lines()
+        .filter(|line| !line.ends_with(&marker))
+        .map(|line| line.to_owned())
+        .collect();
+    lines.push(entry);
+
+    let mut child = Command::new("crontab").arg("-").stdin(Stdio::piped()).spawn()?;
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all((lines.join("\n") + "\n").as_bytes())?;
+    if !child.wait()?.success() {
+        return Err(io::Error::other("crontab refused the new entry"));
+    }
+
+    println!("{}", tr(Msg::InstalledCron, &[&name]));
+    Ok(())
+}
+
+fn install_systemd(name: &str, calendar: &[String], exe: &Path, dry_run: bool) -> io::Result<()> {
+    let dir = config_dir()?.join("systemd").join("user");
+    if !dry_run {
+        fs::create_dir_all(&dir)?;
+    }
+    let unit = format!("rcompare-{}", name);
+
+    let service = format!(
+        "[Unit]\nDescription=rcompare scan of profile {name}\n\n[Service]\nType=oneshot\nExecStart={} schedule run --profile {name}\n",
+        quote(&exe.to_string_lossy())
+    );
+    let events: String = calendar.iter().map(|event| format!("OnCalendar={}\n", event)).collect();
+    let timer = format!(
+        "[Unit]\nDescription=Scheduled rcompare scan of profile {name}\n\n[Timer]\n{events}Persistent=true\n\n[Install]\nWantedBy=timers.target\n"
+    );
+    write_file(&dir.join(format!("{}.service", unit)), service.as_bytes(), dry_run)?;
+    write_file(&dir.join(format!("{}.timer", unit)), timer.as_bytes(), dry_run)?;
+    if dry_run {
+        println!("{}", tr(Msg::WouldRun, &[&format!("systemctl --user enable --now {}.timer", unit)]));
+        return Ok(());
+    }
+    println!("{}", tr(Msg::WroteUnit, &[&unit, &dir.display()]));
+
+    let enabled = Command::new("systemctl")
+        .args(["--user", "daemon-reload"])
+        .status()
+        .and_then(|_| {
+            Command::new("systemctl")
+                .args(["--user", "enable", "--now"])
+                .arg(format!("{}.timer", unit))
+                .status()
+        });
+    match enabled {
+        Ok(status) if status.success() => println!("{}", tr(Msg::EnabledTimer, &[&unit])),
+        _ => eprintln!("{}", tr(Msg::EnableTimerFailed, &[&unit])),
+    }
+    Ok(())
+}
+
+/// Translates a cron expression into systemd calendar events, the timer firing on any of them.
+///
+/// When both the day of the month and the day of the week are restricted, cron runs on either
+/// while systemd needs both to match, so each gets an event of its own.
+fn to_calendar(cron: &str) -> io::Result<Vec<String>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid cron expression '{}'", cron));
+    match cron.trim() {
+        "@hourly" => return Ok(vec!["hourly".to_owned()]),
+        "@daily" | "@midnight" => return Ok(vec!["daily".to_owned()]),
+        "@weekly" => return Ok(vec!["weekly".to_owned()]),
+        "@monthly" => return Ok(vec!["monthly".to_owned()]),
+        "@yearly" | "@annually" => return Ok(vec!["yearly".to_owned()]),
+        _ => {}
+    }
+
+    let fields: Vec<&str> = cron.split_whitespace().collect();
+    let [minute, hour, dom, month, dow] = fields[..] else {
+        return Err(invalid());
+    };
+
+    // cron only takes a field starting with `*` for unrestricted, `*/2` included
+    let any_day = dom.starts_with('*');
+    let minute = calendar_field(minute, 0, 59).ok_or_else(invalid)?;
+    let hour = calendar_field(hour, 0, 23).ok_or_else(invalid)?;
+    let dom = calendar_field(dom, 1, 31).ok_or_else(invalid)?;
+    let month = calendar_field(month, 1, 12).ok_or_else(invalid)?;
+    let dow = weekdays(dow).ok_or_else(invalid)?;
+
+    let time = format!("{}:{}:00", hour, minute);
+    Ok(match dow {
+        Some(days) if !any_day => vec![
+            format!("*-{}-{} {}", month, dom, time),
+            format!("{} *-{}-* {}", days, month, time),
+        ],
+        Some(days) => vec![format!("{} *-{}-{} {}", days, month, dom, time)],
+        None => vec![format!("*-{}-{} {}", month, dom, time)],
+    })
+}
+
+fn calendar_field(field: &str, min: u32, max: u32) -> Option<String> {
+    let parts: Option<Vec<String>> = field
+        .split(',')
+        .map(|part| {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, Some(step.parse::<u32>().ok().filter(|s| *s > 0)?)),
+                None => (part, None),
+            };
+
+            let range = match range {
+                "*" if step.is_some() => format!("{:02}", min),
+                "*" => "*".to_owned(),
+                _ => match range.split_once('-') {
+                    Some((a, b)) => format!("{:02}..{:02}", number(a, min, max)?, number(b, min, max)?),
+                    None => format!("{:02}", number(range, min, max)?),
+                },
+            };
+            Some(match step {
+                Some(step) => format!("{}/{}", range, step),
+                None => range,
+            })
+        })
+        .collect();
+    parts.map(|p| p.join(","))
+}
+
+fn weekdays(field: &str) -> Option<Option<String>> {
+    const DAYS: [&str; 8] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+    if field == "*" {
+        return Some(None);
+    }
+
+    let parts: Option<Vec<String>> = field
+        .split(',')
+        .map(|part| match part.split_once('-') {
+            Some((a, b)) => Some(format!("{}..{}", DAYS[number(a, 0, 7)? as usize], DAYS[number(b, 0, 7)? as usize])),
+            None => Some(DAYS[number(part, 0, 7)? as usize].to_owned()),
+        })
+        .collect();
+    parts.map(|p| Some(p.join(",")))
+}
+
+fn number(s: &str, min: u32, max: u32) -> Option<u32> {
+    s.parse().ok().filter(|n| (min..=max).contains(n))
+}
@@ -0,0 +1,116 @@
+use std::io;
+
+/// Makes the process and its children fail with `EPERM` to change the rights, the owner, the
+/// times or the extended attributes of any file, which Landlock lets through.
+///
+/// The run never changes them, its own outputs included, so the calls are denied everywhere
+/// rather than beneath the scanned folders only.
+pub fn deny_metadata_changes() -> io::Result<()> {
+    let mut program = vec![
+        // another calling convention, like i386 calls on x86_64, would go by other numbers
+        load(ARCH_OFFSET),
+        jump(libc::BPF_JEQ, AUDIT_ARCH, 1, 0),
+        ret(DENY),
+        load(NR_OFFSET),
+    ];
+    #[cfg(target_arch = "x86_64")]
+    {
+        // the x32 calls are the same ones with this bit set
+        program.push(jump(libc::BPF_JGE, X32_SYSCALL_BIT, 0, 1));
+        program.push(ret(DENY));
+    }
+    for nr in DENIED.iter().chain(LEGACY_DENIED) {
+        program.push(jump(libc::BPF_JEQ, *nr as u32, 0, 1));
+        program.push(ret(DENY));
+    }
+    program.push(ret(libc::SECCOMP_RET_ALLOW));
+
+    let prog = libc::sock_fprog { len: program.len() as u16, filter: program.as_mut_ptr() };
+    // the caller set no_new_privs already, for Landlock
+    let installed = unsafe {
+        libc::prctl(
+            libc::PR_SET_SECCOMP,
+            libc::SECCOMP_MODE_FILTER,
+            &prog as *const libc::sock_fprog,
+            0,
+            0,
+        )
+    };
+    if installed != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// `AUDIT_ARCH_*` of `linux/audit.h`, which the libc crate lacks
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+#[cfg(target_arch = "x86_64")]
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Offsets in `struct seccomp_data`
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+
+const DENY: u32 = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+/// `fchmodat2` of Linux 6.6 and `setxattrat` and `removexattrat` of Linux 6.13, numbered alike on
+/// every architecture, which the libc crate lacks
+const SYS_FCHMODAT2: libc::c_long = 452;
+const SYS_SETXATTRAT: libc::c_long = 463;
+const SYS_REMOVEXATTRAT: libc::c_long = 466;
+
+const DENIED: &[libc::c_long] = &[
+    libc::SYS_fchmod,
+    libc::SYS_fchmodat,
+    SYS_FCHMODAT2,
+    libc::SYS_fchown,
+    libc::SYS_fchownat,
+    libc::SYS_utimensat,
+    libc::SYS_setxattr,
+    libc::SYS_lsetxattr,
+    libc::SYS_fsetxattr,
+    SYS_SETXATTRAT,
+    libc::SYS_removexattr,
+    libc::SYS_lremovexattr,
+    libc::SYS_fremovexattr,
+    SYS_REMOVEXATTRAT,
+];
+
+/// The older calls, which architectures added since only have through the ones above
+#[cfg(target_arch = "x86_64")]
+const LEGACY_DENIED: &[libc::c_long] = &[
+    libc::SYS_chmod,
+    libc::SYS_chown,
+    libc::SYS_lchown,
+    libc::SYS_utime,
+    libc::SYS_utimes,
+    libc::SYS_futimesat,
+];
+#[cfg(target_arch = "aarch64")]
+const LEGACY_DENIED: &[libc::c_long] = &[];
+
+fn load(offset: u32) -> libc::sock_filter {
+    libc::sock_filter {
+        code: (libc::BPF_LD | libc::BPF_W | libc::BPF_ABS) as u16,
+        jt: 0,
+        jf: 0,
+        k: offset,
+    }
+}
+
+/// Skips `jt` instructions when the loaded word compares to `k` as `op` says, `jf` otherwise
+fn jump(op: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code: (libc::BPF_JMP | op | libc::BPF_K) as u16, jt, jf, k }
+}
+
+fn ret(k: u32) -> libc::sock_filter {
+    libc::sock_filter { code: (libc::BPF_RET | libc::BPF_K) as u16, jt: 0, jf: 0, k }
+}
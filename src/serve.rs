@@ -0,0 +1,467 @@
+use crate::cli::ScanArgs;
+use crate::common::Processed;
+use crate::config::Config;
+use crate::export::DedupeAction;
+use crate::i18n::{text, tr, Msg};
+use crate::metrics::Metrics;
+use crate::{say, warn};
+use clap::ValueEnum;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs::{DirBuilder, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Bodies are a few arguments, anything larger is a mistake or an abuse
+const MAX_BODY: usize = 1024 * 1024;
+
+/// The request line and the headers, far more than any client of the API sends
+const MAX_HEAD: u64 = 64 * 1024;
+
+/// Where a requested scan is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum State {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+/// Serves the API on `address` until the process is stopped, `run` doing the scans.
+///
+/// Every request carries `Authorization: Bearer <token>`, the token being written only readable
+/// by the user to `token_path` at startup. Requests from browsers, which send an `Origin`, for
+/// another host than the address, or with a body other than `application/json` are refused, so
+/// a web page cannot drive the API. Scans cannot read lists or write files of their own choosing.
+///
+/// - `POST /scans` with `{"args": ["/srv/photos", "--keep", "newest"]}`, the arguments of
+///   `rcompare scan`, queues a scan and answers its id
+/// - `GET /scans` lists the scans, `GET /scans/<id>` tells the state and progress of one
+/// - `GET /scans/<id>/report` answers the report of a finished scan
+/// - `POST /scans/<id>/dedupe` with `{"action": "hardlink", "dry_run": false}` deduplicates the
+///   groups of a finished scan, `remove` also takes `"trash": true` or `"force": true`. Groups not
+///   compared on their exact contents are left alone and counted in `inexact_groups`
+/// - `GET /metrics` answers the files, bytes, groups and durations of the scans so far, in the
+///   Prometheus text format
+///
+/// Scans and dedupes run one at a time on the calling thread, in the order they were asked for.
+pub fn serve<F: Fn(&Config) -> io::Result<Processed>>(address: &str, run: F) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let local = listener.local_addr()?;
+    let token_path = token_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no place for the token, HOME is not set"))?;
+    let token = write_token(&token_path)?;
+    if !crate::term::is_quiet() {
+        say!("{}", tr(Msg::Serving, &[&local]));
+        say!("{}", tr(Msg::ServeToken, &[&token_path.display()]));
+    }
+
+    let shared = Arc::new(Shared {
+        jobs: Mutex::default(),
+        metrics: Mutex::default(),
+        token,
+        port: local.port(),
+    });
+    let (tasks, queue) = mpsc::channel();
+    let server = Arc::clone(&shared);
+    std::thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let (shared, tasks) = (Arc::clone(&server), tasks.clone());
+            std::thread::spawn(move || {
+                if let Err(err) = handle(stream, &shared, &tasks) {
+                    warn!("Could not answer a request: {}", err);
+                }
+            });
+        }
+    });
+    let (jobs, metrics) = (&shared.jobs, &shared.metrics);
+
+    // the scans share the global progress and skipped files, they cannot overlap
+    let mut results: HashMap<usize, Processed> = HashMap::new();
+    for task in queue {
+        match task {
+            Task::Scan(id, config) => {
+                jobs.lock().unwrap()[id].state = State::Running;
+                let started = Instant::now();
+                let outcome = run(&config).and_then(|res| Ok((serde_json::to_value(&res)?, res)));
+                let mut jobs = jobs.lock().unwrap();
+                match outcome {
+                    Ok((report, res)) => {
+                        metrics.lock().unwrap().scanned(&res, started.elapsed());
+                        (jobs[id].state, jobs[id].report) = (State::Done, Some(report));
+                        results.insert(id, res);
+                    }
+                    Err(err) => {
+                        metrics.lock().unwrap().failed(started.elapsed());
+                        (jobs[id].state, jobs[id].error) = (State::Failed, Some(err.to_string()));
+                    }
+                }
+            }
+            Task::Dedupe { id, action, dry_run, reply } => {
+                let outcome = match results.get(&id) {
+                    Some(res) => {
+                        let inexact = (0..res.same.len()).filter(|idx| !res.is_exact(*idx)).count();
+                        crate::export::dedupe(res, action, dry_run)
+                            .map(|(files, reclaimed)| (files, reclaimed, inexact))
+                            .map_err(|e| e.to_string())
+                    }
+                    None => Err(format!("scan {} has no results", id)),
+                };
+                if let (Ok((files, reclaimed, _)), false) = (&outcome, dry_run) {
+                    metrics.lock().unwrap().deduped(*files, *reclaimed);
+                }
+                _ = reply.send(outcome);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Where the token of the API is written, `$XDG_RUNTIME_DIR/rcompare/serve.token`, or in the
+/// folder of the hash cache without a runtime folder
+pub fn token_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("rcompare"),
+        None => crate::cache::default_path()?.parent()?.to_owned(),
+    };
+    Some(dir.join("serve.token"))
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// Random bytes of the token, as many as a key
+const TOKEN_BYTES: usize = 32;
+
+/// What the threads answering requests share
+struct Shared {
+    jobs: Mutex<Vec<Job>>,
+    metrics: Mutex<Metrics>,
+    token: String,
+    /// port the server listens on, the only one `Host` may name
+    port: u16,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    /// names in lower case
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Job {
+    args: Vec<String>,
+    state: State,
+    error: Option<String>,
+    report: Option<Value>,
+}
+
+enum Task {
+    Scan(usize, Box<Config>),
+    Dedupe {
+        id: usize,
+        action: DedupeAction,
+        dry_run: bool,
+        /// the copies deduplicated, the bytes they took and the groups left alone, which were not
+        /// compared on their exact contents
+        reply: Sender<Result<(usize, u64, usize), String>>,
+    },
+}
+
+fn handle(stream: TcpStream, shared: &Shared, tasks: &Sender<Task>) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    let mut reader = BufReader::new(&stream);
+    let (status, body) = match read_request(&mut reader) {
+        Ok(request) => match admit(&request, shared) {
+            Err((status, msg)) => (status, json!({ "error": msg })),
+            Ok(()) if request.method == "GET" && request.path == "/metrics" => {
+                let text = metrics(shared);
+                return respond(&stream, 200, "text/plain; version=0.0.4", text.as_bytes());
+            }
+            Ok(()) => route(&request.method, &request.path, &request.body, &shared.jobs, tasks),
+        },
+        Err(err) => (400, json!({ "error": err.to_string() })),
+    };
+    respond(&stream, status, "application/json", &serde_json::to_vec_pretty(&body)?)
+}
+
+/// Lets in the requests with the token, sent by a program rather than a web page
+fn admit(request: &Request, shared: &Shared) -> Result<(), (u16, &'static str)> {
+    if request.header("origin").is_some() {
+        return Err((403, "requests from web pages are refused"));
+    }
+    if !request
+        .header("host")
+        .is_some_and(|host| is_own_host(host, shared.port))
+    {
+        return Err((403, "Host must be localhost or an address of the server"));
+    }
+    let token = request
+        .header("authorization")
+        .and_then(|auth| auth.strip_prefix("Bearer "));
+    if !token.is_some_and(|token| same_secret(token.trim().as_bytes(), shared.token.as_bytes())) {
+        return Err((401, "missing or wrong token, see Authorization: Bearer in the documentation"));
+    }
+    let json = request
+        .header("content-type")
+        .is_some_and(|kind| kind.split(';').next().unwrap_or_default().trim() == "application/json");
+    if !request.body.is_empty() && !json {
+        return Err((415, "the body must be application/json"));
+    }
+    Ok(())
+}
+
+/// Whether `host` names the server by an address or as localhost, rather than by a name which
+/// could be pointed at it afterwards
+fn is_own_host(host: &str, port: u16) -> bool {
+    let (name, given) = match host.rsplit_once(':') {
+        Some((name, given)) if !given.contains(']') => (name, given.parse().ok()),
+        _ => (host, Some(80)),
+    };
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    given == Some(port) && (name.eq_ignore_ascii_case("localhost") || name.parse::<IpAddr>().is_ok())
+}
+
+/// Compares in a time which does not tell how much of `given` is right
+fn same_secret(given: &[u8], secret: &[u8]) -> bool {
+    given.len() == secret.len() && given.iter().zip(secret).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn metrics(shared: &Shared) -> String {
+    let mut states = [State::Queued, State::Running, State::Done, State::Failed].map(|s| (state_name(s), 0));
+    for job in shared.jobs.lock().unwrap().iter() {
+        states[job.state as usize].1 += 1;
+    }
+    let states: Vec<(&str, usize)> = states.iter().map(|(name, n)| (name.as_str(), *n)).collect();
+    shared.metrics.lock().unwrap().render(&states)
+}
+
+/// Writes a new random token to `path`, only readable by the user
+fn write_token(path: &std::path::Path) -> io::Result<String> {
+    let mut bytes = [0; TOKEN_BYTES];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    let token: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    if let Some(dir) = path.parent() {
+        DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    }
+    // a file left by another run could have looser permissions, or be a link
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {}
+    }
+    let mut file = OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+    file.write_all(token.as_bytes())?;
+    Ok(token)
+}
+
+/// The first option given to a scan which reads or writes a file wherever it says, which a caller
+/// of the API must not pick
+fn path_option(scan: &ScanArgs) -> Option<&'static str> {
+    [
+        (!scan.output.is_empty(), "-o"),
+        (scan.files_from.is_some(), "--files-from"),
+        (scan.settings.is_some(), "--config"),
+    ]
+    .iter()
+    .find_map(|(set, option)| set.then_some(*option))
+}
+
+/// The first option of a scan which writes a file wherever it says, which a caller of the API
+/// must not pick
+fn writing_option(config: &Config) -> Option<&'static str> {
+    let cache = config.cache.is_some() && config.cache != crate::cache::default_path();
+    [
+        (config.checkpoint.is_some(), "--checkpoint"),
+        (config.append_index.is_some(), "--append-index"),
+        (config.manifest.is_some(), "--manifest"),
+        (config.emit_script.is_some(), "--emit-script"),
+        (config.link_farm.is_some(), "--link-farm"),
+        (config.exec_per_group.is_some(), "--exec-per-group"),
+        (config.xattr_cache, "--xattr-cache"),
+        (cache, "--cache"),
+        (config.hardened, "--hardened"),
+    ]
+    .iter()
+    .find_map(|(set, option)| set.then_some(*option))
+}
+
+fn respond(mut stream: &TcpStream, status: u16, content_type: &str, body: &[u8]) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        415 => "Unsupported Media Type",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        reason,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_owned());
+    let mut head = reader.by_ref().take(MAX_HEAD);
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(invalid("malformed request line"));
+    };
+    let (method, path) = (method.to_owned(), path.to_owned());
+
+    let mut headers = vec![];
+    loop {
+        line.clear();
+        if head.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_owned()));
+        }
+    }
+    if head.limit() == 0 {
+        return Err(invalid("request head too large"));
+    }
+    let length = match headers.iter().find(|(name, _)| name == "content-length") {
+        Some((_, value)) => value.parse().map_err(|_| invalid("invalid Content-Length"))?,
+        None => 0,
+    };
+    if length > MAX_BODY {
+        return Err(invalid("request body too large"));
+    }
+
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Request { method, path, headers, body })
+}
+
+fn route(method: &str, path: &str, body: &[u8], jobs: &Mutex<Vec<Job>>, tasks: &Sender<Task>) -> (u16, Value) {
+    let error = |status: u16, msg: String| (status, json!({ "error": msg }));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let id = segments.get(1).and_then(|id| id.parse::<usize>().ok());
+    let known = id.filter(|id| *id < jobs.lock().unwrap().len());
+    if id.is_some() && known.is_none() {
+        return error(404, format!("no scan {}", segments[1]));
+    }
+
+    match (method, segments.as_slice(), known) {
+        ("GET", ["scans"], _) => {
+            let jobs = jobs.lock().unwrap();
+            let list: Vec<Value> = jobs.iter().enumerate().map(|(id, job)| status(id, job)).collect();
+            (200, Value::Array(list))
+        }
+        ("POST", ["scans"], _) => {
+            let request: Value = serde_json::from_slice(body).unwrap_or_default();
+            let Some(args) = request["args"].as_array() else {
+                return error(400, "expected {\"args\": [...]}, the arguments of rcompare scan".to_owned());
+            };
+            let args: Vec<String> = args.iter().filter_map(|a| a.as_str()).map(str::to_owned).collect();
+            let scan = match crate::cli::parse_scan(&args) {
+                Ok(scan) => scan,
+                Err(err) => return error(400, err.render().to_string()),
+            };
+            if let Some(option) = path_option(&scan) {
+                return error(400, format!("{} is not available over the API", option));
+            }
+            let mut config = match Config::try_from(scan) {
+                Ok(config) => config,
+                Err(err) => return error(400, err.to_string()),
+            };
+            // the outputs of the settings are for the scans run by hand, the API answers its reports
+            config.output.clear();
+            if let Some(option) = writing_option(&config) {
+                return error(400, format!("{} is not available over the API", option));
+            }
+
+            let mut jobs = jobs.lock().unwrap();
+            let id = jobs.len();
+            jobs.push(Job { args, state: State::Queued, error: None, report: None });
+            _ = tasks.send(Task::Scan(id, Box::new(config)));
+            (202, json!({ "id": id }))
+        }
+        ("GET", ["scans", _], Some(id)) => {
+            let jobs = jobs.lock().unwrap();
+            let mut status = status(id, &jobs[id]);
+            if jobs[id].state == State::Running {
+                status["progress"] = serde_json::to_value(crate::term::progress()).unwrap_or_default();
+            }
+            (200, status)
+        }
+        ("GET", ["scans", _, "report"], Some(id)) => match &jobs.lock().unwrap()[id] {
+            Job { report: Some(report), .. } => (200, report.clone()),
+            job => error(409, format!("scan {} is {}", id, state_name(job.state))),
+        },
+        ("POST", ["scans", _, "dedupe"], Some(id)) => {
+            let state = jobs.lock().unwrap()[id].state;
+            if state != State::Done {
+                return error(409, format!("scan {} is {}", id, state_name(state)));
+            }
+            let request: Value = serde_json::from_slice(body).unwrap_or_default();
+            let action = match request["action"].as_str().map(|a| DedupeAction::from_str(a, true)) {
+                None => DedupeAction::default(),
+                Some(Ok(action)) => action,
+                Some(Err(err)) => return error(400, err),
+            };
+            let (trash, force) = (request["trash"].as_bool(), request["force"].as_bool());
+            let action = match action.with_removal(trash.unwrap_or(false), force.unwrap_or(false)) {
+                Ok(action) => action,
+                Err(msg) => return error(400, text(msg).to_owned()),
+            };
+            let dry_run = request["dry_run"].as_bool().unwrap_or(false);
+
+            let (reply, answer) = mpsc::channel();
+            _ = tasks.send(Task::Dedupe { id, action, dry_run, reply });
+            match answer.recv() {
+                Ok(Ok((files, reclaimed, inexact))) => (
+                    200,
+                    json!({ "files": files, "reclaimed": reclaimed, "inexact_groups": inexact, "dry_run": dry_run }),
+                ),
+                Ok(Err(err)) => error(500, err),
+                Err(err) => error(500, err.to_string()),
+            }
+        }
+        _ => error(404, format!("no route for {} {}", method, path)),
+    }
+}
+
+fn status(id: usize, job: &Job) -> Value {
+    json!({ "id": id, "args": job.args, "state": job.state, "error": job.error })
+}
+
+fn state_name(state: State) -> String {
+    serde_json::to_value(state)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_owned))
+        .unwrap_or_default()
+}
@@ -0,0 +1,133 @@
+use crate::common::parse_bytes;
+use crate::hashing::HashAlgorithm;
+use crate::policy::PolicyRule;
+use serde::{Deserialize, Deserializer};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What is read from `config.toml`, for settings too long to give as flags and for defaults of
+/// the flags.
+///
+/// The layers go from the built-in defaults to the file, then the `RCOMPARE_*` environment
+/// variables, then the flags, each one overriding the previous. The excluded globs add up instead.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Settings {
+    /// read block size, a number of bytes or a size like `"1M"`
+    #[serde(default, deserialize_with = "size")]
+    pub read_size: Option<u64>,
+    /// folders listed and groups compared at once, at least 1
+    #[serde(default, deserialize_with = "count")]
+    pub threads: Option<u32>,
+    /// hash algorithm used to group files
+    #[serde(default)]
+    pub hash: Option<HashAlgorithm>,
+    /// globs skipped on every scan, like `[".git", "node_modules"]`
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// output paths when `-o` is not given, their extensions picking the formats
+    #[serde(default)]
+    pub output: Vec<String>,
+    /// how files are compared, by glob, e.g.
+    ///
+    /// ```toml
+    /// [[policy]]
+    /// glob = "*.{txt,md}"
+    /// compare = "normalized-text"
+    /// ```
+    #[serde(default)]
+    pub policy: Vec<PolicyRule>,
+}
+
+/// Reads the settings from `path`, or else from `config.toml` in the configuration directory
+/// when it exists, then applies the environment variables over them
+pub fn load(path: Option<&Path>) -> io::Result<Settings> {
+    let path = match path {
+        Some(path) => Some(path.to_path_buf()),
+        None => config_dir()
+            .map(|dir| dir.join("rcompare").join("config.toml"))
+            .ok()
+            .filter(|path| path.exists()),
+    };
+
+    let settings = match path {
+        Some(path) => {
+            let text = std::fs::read_to_string(&path)?;
+            toml::from_str(&text)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?
+        }
+        None => Settings::default(),
+    };
+    settings.with_env()
+}
+
+/// `$XDG_CONFIG_HOME`, or `~/.config`
+pub(crate) fn config_dir() -> io::Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+
+    std::env::var_os("HOME")
+        .map(|home| Path::new(&home).join(".config"))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "neither XDG_CONFIG_HOME nor HOME is set"))
+}
+
+// ----------
+//  Internal
+// ----------
+
+impl Settings {
+    /// `RCOMPARE_READ_SIZE`, `RCOMPARE_THREADS`, `RCOMPARE_HASH`, `RCOMPARE_EXCLUDE` and
+    /// `RCOMPARE_OUTPUT`, the last two separated by commas
+    fn with_env(mut self) -> io::Result<Self> {
+        let invalid = |name: &str, e: String| io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", name, e));
+        let list = |value: String| value.split(',').filter(|s| !s.is_empty()).map(str::to_owned).collect();
+
+        if let Some(value) = var("RCOMPARE_READ_SIZE") {
+            self.read_size = Some(parse_bytes(&value).map_err(|e| invalid("RCOMPARE_READ_SIZE", e))?);
+        }
+        if let Some(value) = var("RCOMPARE_THREADS") {
+            let threads = value.parse().ok().filter(|n| *n > 0);
+            self.threads =
+                Some(threads.ok_or_else(|| invalid("RCOMPARE_THREADS", format!("{} is not a count", value)))?);
+        }
+        if let Some(value) = var("RCOMPARE_HASH") {
+            let hash = HashAlgorithm::from_name(&value.to_lowercase())
+                .ok_or_else(|| invalid("RCOMPARE_HASH", format!("unknown hash algorithm {}", value)))?;
+            self.hash = Some(hash);
+        }
+        if let Some(value) = var("RCOMPARE_EXCLUDE") {
+            self.exclude.extend(list(value));
+        }
+        if let Some(value) = var("RCOMPARE_OUTPUT") {
+            self.output = list(value);
+        }
+        Ok(self)
+    }
+}
+
+/// An environment variable, unset when empty
+fn var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+fn size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(Some(bytes)),
+        Size::Text(text) => parse_bytes(&text).map(Some).map_err(serde::de::Error::custom),
+    }
+}
+
+fn count<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    match u32::deserialize(deserializer)? {
+        0 => Err(serde::de::Error::custom("expected at least 1")),
+        n => Ok(Some(n)),
+    }
+}
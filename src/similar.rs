@@ -0,0 +1,204 @@
+use crate::chunk::Chunker;
+use crate::common::Processed;
+use crate::config::{SIMILAR_CHUNK_SIZE, SIMILAR_SKETCH};
+use crate::file::FileInfo;
+use crate::warn;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Files which are mostly the same content, like a truncated download and the full one
+#[derive(Debug, Clone, Serialize)]
+pub struct SimilarGroup {
+    #[serde(serialize_with = "crate::encoding::lossy_seq")]
+    pub files: Vec<PathBuf>,
+    /// every pair of the group found similar, the most similar first
+    pub pairs: Vec<SimilarPair>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub files_base64: Option<Vec<Option<String>>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SimilarPair {
+    #[serde(serialize_with = "crate::encoding::lossy")]
+    pub lhs: PathBuf,
+    #[serde(serialize_with = "crate::encoding::lossy")]
+    pub rhs: PathBuf,
+    /// estimated percentage of the content chunks the two files share
+    pub score: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lhs_base64: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rhs_base64: Option<String>,
+}
+
+/// Finds files sharing at least `threshold` percent of their content chunks, duplicates aside.
+///
+/// Every file is cut into content defined chunks, so an insertion only changes the chunks around
+/// it, and kept as the `SIMILAR_SKETCH` smallest chunk keys. The share of keys held by both files
+/// among the smallest of the two sketches estimates the resemblance of the contents. A group of
+/// `same` stands in with its first file, files which are similar through a third one end up in
+/// the same group. Only files holding a key in common are compared, found through the files
+/// holding each key.
+pub fn similar_files(res: &Processed, threshold: f64, read_size: usize) -> Vec<SimilarGroup> {
+    let candidates = res
+        .unique
+        .iter()
+        .chain(res.same.iter().filter_map(|group| group.first()))
+        .filter_map(|idx| res.info.get(*idx))
+        .filter(|fl| fl.size > 0 && !fl.is_link_target());
+
+    let mut chunker = Chunker::new(SIMILAR_CHUNK_SIZE);
+    let mut sketches: Vec<Sketch> = candidates
+        .filter_map(|fl| Sketch::new(fl, &mut chunker, read_size))
+        .collect();
+    sketches.sort_by_key(|s| s.chunks);
+
+    // only files sharing keys can be similar, the others are never compared
+    let mut holders: HashMap<u64, Vec<usize>> = HashMap::new();
+    for (i, sketch) in sketches.iter().enumerate() {
+        for key in sketch.keys.iter() {
+            holders.entry(*key).or_default().push(i);
+        }
+    }
+
+    let mut pairs: Vec<(usize, usize, f64)> = vec![];
+    let mut shared = vec![0usize; sketches.len()];
+    for (i, a) in sketches.iter().enumerate() {
+        let mut met: Vec<usize> = vec![];
+        for key in a.keys.iter() {
+            let others = &holders[key];
+            for j in others[others.partition_point(|j| *j <= i)..].iter() {
+                if shared[*j] == 0 {
+                    met.push(*j);
+                }
+                shared[*j] += 1;
+            }
+        }
+
+        met.sort_unstable();
+        for j in met {
+            let (b, common) = (&sketches[j], std::mem::take(&mut shared[j]));
+            // no more than the smaller file's chunks can be shared
+            if 100.0 * (a.chunks as f64) < threshold * b.chunks as f64 {
+                continue;
+            }
+            // the resemblance looks at no fewer keys than the longer sketch holds
+            let seen = a.keys.len().max(b.keys.len()).min(SIMILAR_SKETCH);
+            if 100.0 * (common as f64) < threshold * seen as f64 {
+                continue;
+            }
+            let score = 100.0 * a.resemblance(b);
+            if score >= threshold {
+                pairs.push((i, j, score));
+            }
+        }
+    }
+
+    let mut parent: Vec<usize> = (0..sketches.len()).collect();
+    for (a, b, _) in pairs.iter() {
+        let (ra, rb) = (find(&mut parent, *a), find(&mut parent, *b));
+        parent[ra.max(rb)] = ra.min(rb);
+    }
+
+    let mut groups: HashMap<usize, SimilarGroup> = HashMap::new();
+    for (a, b, score) in pairs {
+        let root = find(&mut parent, a);
+        let group =
+            groups
+                .entry(root)
+                .or_insert_with(|| SimilarGroup { files: vec![], pairs: vec![], files_base64: None });
+        let (lhs, rhs) = (sketches[a].path.clone(), sketches[b].path.clone());
+        let (lhs, rhs) = if lhs <= rhs { (lhs, rhs) } else { (rhs, lhs) };
+        group.pairs.push(SimilarPair {
+            lhs_base64: crate::encoding::raw_bytes(&lhs),
+            rhs_base64: crate::encoding::raw_bytes(&rhs),
+            lhs,
+            rhs,
+            score,
+        });
+    }
+
+    let mut groups: Vec<SimilarGroup> = groups
+        .into_values()
+        .map(|mut group| {
+            let mut files: Vec<PathBuf> = group
+                .pairs
+                .iter()
+                .flat_map(|p| [p.lhs.clone(), p.rhs.clone()])
+                .collect();
+            files.sort();
+            files.dedup();
+            group.files_base64 = crate::encoding::raw_seq(&files);
+            group.files = files;
+            group.pairs.sort_by(|a, b| {
+                b.score
+                    .total_cmp(&a.score)
+                    .then_with(|| (&a.lhs, &a.rhs).cmp(&(&b.lhs, &b.rhs)))
+            });
+            group
+        })
+        .collect();
+    groups.sort_by(|a, b| a.files.cmp(&b.files));
+    groups
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// The smallest chunk keys of a file, and how many distinct chunks it has in all
+struct Sketch {
+    path: PathBuf,
+    keys: Vec<u64>,
+    chunks: usize,
+}
+
+impl Sketch {
+    fn new(fl: &FileInfo, chunker: &mut Chunker, read_size: usize) -> Option<Self> {
+        let chunks = fl.open().and_then(|file| chunker.chunk_reader(file, read_size));
+        let mut keys: Vec<u64> = match chunks {
+            Ok(chunks) => chunks.into_iter().map(|(key, _)| key).collect(),
+            Err(err) => {
+                warn!("Unable to read file {} to look for similar files: {}", fl.path.display(), err);
+                return None;
+            }
+        };
+        keys.sort_unstable();
+        keys.dedup();
+        let chunks = keys.len();
+        keys.truncate(SIMILAR_SKETCH);
+        Some(Self { path: fl.path.clone(), keys, chunks })
+    }
+
+    /// Estimated share of the chunks of both files which are in each, from 0 to 1.
+    ///
+    /// The smallest keys of the union are all in the sketches, those held by both are shared.
+    fn resemblance(&self, other: &Sketch) -> f64 {
+        let (mut a, mut b) = (self.keys.iter().peekable(), other.keys.iter().peekable());
+        let (mut seen, mut shared) = (0usize, 0usize);
+        while seen < SIMILAR_SKETCH {
+            match (a.peek(), b.peek()) {
+                (Some(x), Some(y)) if x == y => {
+                    shared += 1;
+                    a.next();
+                    b.next();
+                }
+                (Some(x), Some(y)) if x < y => _ = a.next(),
+                (Some(_), Some(_)) | (None, Some(_)) => _ = b.next(),
+                (Some(_), None) => _ = a.next(),
+                (None, None) => break,
+            }
+            seen += 1;
+        }
+        shared as f64 / seen.max(1) as f64
+    }
+}
+
+fn find(parent: &mut [usize], mut idx: usize) -> usize {
+    while parent[idx] != idx {
+        parent[idx] = parent[parent[idx]];
+        idx = parent[idx];
+    }
+    idx
+}
@@ -0,0 +1,246 @@
+use crate::common::{print_stdout, stringify_bytes, write_file};
+use crate::error::Result;
+use crate::i18n::{tr, Msg};
+use crate::say;
+use rusqlite::{params, Connection};
+use serde_json::Value;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Somewhere a report goes, in the format it takes there
+pub trait Sink {
+    fn path(&self) -> &Path;
+
+    /// Writes the report, as serialized from `Processed`
+    fn write(&self, report: &Value, dry_run: bool) -> Result<()>;
+}
+
+/// The sink for an output path, picked by its extension: `.md` gets a Markdown summary, `.db`
+/// and `.sqlite` a SQLite database, anything else the JSON report, `-` standing for stdout
+pub fn for_path(path: &Path) -> Box<dyn Sink> {
+    if path.as_os_str() == "-" {
+        return Box::new(Json(path.to_owned()));
+    }
+    let ext = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+    match ext.as_deref() {
+        Some("md") => Box::new(Markdown(path.to_owned())),
+        Some("db" | "sqlite" | "sqlite3") => Box::new(Sqlite(path.to_owned())),
+        _ => Box::new(Json(path.to_owned())),
+    }
+}
+
+/// The whole report, the format every other tool of the crate reads back
+pub struct Json(pub PathBuf);
+
+impl Sink for Json {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn write(&self, report: &Value, dry_run: bool) -> Result<()> {
+        if self.0.as_os_str() == "-" {
+            return Ok(print_stdout(&serde_json::to_string_pretty(report)?)?);
+        }
+        Ok(write_file(&self.0, serde_json::to_string_pretty(report)?.as_bytes(), dry_run)?)
+    }
+}
+
+/// Totals and a table of the duplicate groups, for people
+pub struct Markdown(pub PathBuf);
+
+impl Sink for Markdown {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn write(&self, report: &Value, dry_run: bool) -> Result<()> {
+        let groups = groups(report);
+        let count = |key: &str| report[key].as_array().map(|a| a.len()).unwrap_or(0);
+        let wasted: u64 = groups.iter().map(|g| g.wasted()).sum();
+        let files: usize = groups.iter().map(|g| g.paths.len()).sum();
+        let roots: Vec<String> = array(&report["roots"])
+            .iter()
+            .filter_map(|root| root["given"].as_str())
+            .map(|root| format!("`{}`", escape(root)))
+            .collect();
+
+        let mut md = String::from("# rcompare report\n\n");
+        _ = writeln!(md, "- roots: {}", roots.join(", "));
+        _ = writeln!(
+            md,
+            "- {} duplicate groups holding {} files, wasting {}",
+            groups.len(),
+            files,
+            stringify_bytes(wasted as usize)
+        );
+        _ = writeln!(
+            md,
+            "- {} unique files, {} empty files, {} skipped",
+            count("unique"),
+            count("zero"),
+            count("skipped")
+        );
+        for warning in array(&report["warnings"]) {
+            _ = writeln!(md, "- warning: {}", escape(warning["message"].as_str().unwrap_or_default()));
+        }
+
+        if !groups.is_empty() {
+            md.push_str("\n## Duplicate groups\n\n| # | size | copies | wasted | confidence | files |\n");
+            md.push_str("|---:|---:|---:|---:|---|---|\n");
+        }
+        for (n, group) in groups.iter().enumerate() {
+            let paths: Vec<String> = group.paths.iter().map(|p| format!("`{}`", escape(p))).collect();
+            _ = writeln!(
+                md,
+                "| {} | {} | {} | {} | {} | {} |",
+                n,
+                stringify_bytes(group.size as usize),
+                group.paths.len(),
+                stringify_bytes(group.wasted() as usize),
+                group.confidence,
+                paths.join("<br>")
+            );
+        }
+        Ok(write_file(&self.0, md.as_bytes(), dry_run)?)
+    }
+}
+
+/// Every file and group in tables, for dashboards and queries.
+///
+/// `files` holds one row per file with the group it belongs to, if any, and `groups` one row per
+/// duplicate group. An existing database at the path is replaced.
+pub struct Sqlite(pub PathBuf);
+
+impl Sink for Sqlite {
+    fn path(&self) -> &Path {
+        &self.0
+    }
+
+    fn write(&self, report: &Value, dry_run: bool) -> Result<()> {
+        let same = array(&report["same"]);
+        if dry_run {
+            let files: usize = ["zero", "unique"]
+                .iter()
+                .map(|k| array(&report[*k]).len())
+                .sum::<usize>()
+                + same.iter().map(|g| array(g).len()).sum::<usize>();
+            say!("{}", tr(Msg::WouldWriteDatabase, &[&files, &same.len(), &self.0.display()]));
+            return Ok(());
+        }
+
+        match fs::remove_file(&self.0) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+        let mut conn = Connection::open(&self.0).map_err(io::Error::other)?;
+        let tx = conn.transaction().map_err(io::Error::other)?;
+        tx.execute_batch(
+            "CREATE TABLE report (key TEXT PRIMARY KEY, value TEXT NOT NULL);
+             CREATE TABLE groups (id INTEGER PRIMARY KEY, size INTEGER NOT NULL, confidence TEXT);
+             CREATE TABLE files (
+                 path TEXT NOT NULL,
+                 size INTEGER NOT NULL,
+                 inode INTEGER NOT NULL,
+                 device TEXT,
+                 links INTEGER,
+                 hash TEXT,
+                 mtime INTEGER,
+                 mode TEXT,
+                 uid INTEGER,
+                 gid INTEGER,
+                 state TEXT NOT NULL,
+                 role TEXT,
+                 group_id INTEGER REFERENCES groups (id)
+             );",
+        )
+        .map_err(io::Error::other)?;
+
+        for key in ["version", "mode", "roots", "filters"] {
+            tx.execute(
+                "INSERT INTO report (key, value) VALUES (?1, ?2)",
+                params![key, report[key].to_string()],
+            )
+            .map_err(io::Error::other)?;
+        }
+        for (n, group) in groups(report).iter().enumerate() {
+            tx.execute(
+                "INSERT INTO groups (id, size, confidence) VALUES (?1, ?2, ?3)",
+                params![n as i64, group.size as i64, group.confidence],
+            )
+            .map_err(io::Error::other)?;
+        }
+        let insert = |entry: &Value, state: &str, group: Option<usize>| {
+            tx.execute(
+                "INSERT INTO files (path, size, inode, device, links, hash, mtime, mode, uid, gid, state, role, group_id)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13)",
+                params![
+                    entry["path"].as_str(),
+                    entry["size"].as_i64(),
+                    entry["inode"].as_i64(),
+                    entry["device"].as_str(),
+                    entry["links"].as_i64(),
+                    entry["hash"].as_str(),
+                    entry["mtime"].as_i64(),
+                    entry["mode"].as_str(),
+                    entry["uid"].as_i64(),
+                    entry["gid"].as_i64(),
+                    state,
+                    entry["role"].as_str(),
+                    group.map(|g| g as i64)
+                ],
+            )
+            .map_err(io::Error::other)
+        };
+        for (state, key) in [("empty", "zero"), ("unique", "unique")] {
+            for entry in array(&report[key]) {
+                insert(entry, state, None)?;
+            }
+        }
+        for (n, group) in same.iter().enumerate() {
+            for entry in array(group) {
+                insert(entry, "duplicate", Some(n))?;
+            }
+        }
+        Ok(tx.commit().map_err(io::Error::other)?)
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+struct Group<'a> {
+    size: u64,
+    paths: Vec<&'a str>,
+    confidence: &'a str,
+}
+
+impl Group<'_> {
+    fn wasted(&self) -> u64 {
+        self.size * (self.paths.len() as u64).saturating_sub(1)
+    }
+}
+
+fn array(value: &Value) -> &[Value] {
+    value.as_array().map(Vec::as_slice).unwrap_or_default()
+}
+
+fn groups(report: &Value) -> Vec<Group<'_>> {
+    let confidence = array(&report["confidence"]);
+    array(&report["same"])
+        .iter()
+        .enumerate()
+        .map(|(n, group)| Group {
+            size: array(group).first().and_then(|e| e["size"].as_u64()).unwrap_or(0),
+            paths: array(group).iter().filter_map(|e| e["path"].as_str()).collect(),
+            confidence: confidence.get(n).and_then(|c| c.as_str()).unwrap_or_default(),
+        })
+        .collect()
+}
+
+/// Keeps paths from breaking the table
+fn escape(s: &str) -> String {
+    s.replace('|', "\\|").replace('`', "'").replace('\n', " ")
+}
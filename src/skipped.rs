@@ -0,0 +1,129 @@
+use crate::i18n::{tr, Msg};
+use crate::warn;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Files warned about on stderr for each kind of failure, the rest are summarized at the end
+const SHOWN_PER_KIND: usize = 10;
+
+static SKIPPED: Mutex<Vec<Skipped>> = Mutex::new(vec![]);
+/// files recorded so far by kind of failure
+static KINDS: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+/// When a file was left out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Stage {
+    /// while walking the trees, the file was never a candidate
+    Scan,
+    /// while hashing or comparing, the file has no group
+    Compare,
+}
+
+/// A file or directory left out of the comparison, and why
+#[derive(Debug, Clone, Serialize)]
+pub struct Skipped {
+    #[serde(serialize_with = "crate::encoding::lossy")]
+    pub path: PathBuf,
+    pub stage: Stage,
+    pub reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_base64: Option<String>,
+}
+
+/// Records a file left out, for the report.
+///
+/// The warning on stderr is up to the caller, it says more than the report needs to. It should
+/// only be printed when this returns true: past a few files failing the same way, they are only
+/// counted, and `summarize` tells how many there were.
+pub fn record<R: ToString>(path: &Path, stage: Stage, reason: R) -> bool {
+    let reason = reason.to_string();
+    let kind = kind_of(&reason);
+    let count = {
+        let mut kinds = KINDS.lock().unwrap();
+        let count = kinds.entry(kind.clone()).or_default();
+        *count += 1;
+        *count
+    };
+    if count == SHOWN_PER_KIND + 1 {
+        warn!("{}", tr(Msg::SkippedMore, &[&kind]));
+    }
+
+    let skipped = Skipped {
+        path: path.to_path_buf(),
+        stage,
+        reason,
+        path_base64: crate::encoding::raw_bytes(path),
+    };
+    SKIPPED.lock().unwrap().push(skipped);
+    count <= SHOWN_PER_KIND
+}
+
+/// Warns once for every kind of failure which was not shown file by file, with how many files
+/// failed that way and the deepest directory holding them all
+pub fn summarize() {
+    let counts = std::mem::take(&mut *KINDS.lock().unwrap());
+    if counts.values().all(|count| *count <= SHOWN_PER_KIND) {
+        return;
+    }
+
+    // another pass of the scan may have met the same files again
+    let mut batches: BTreeMap<String, (usize, PathBuf)> = BTreeMap::new();
+    for skipped in dedup(SKIPPED.lock().unwrap().clone()) {
+        let kind = kind_of(&skipped.reason);
+        if counts.get(&kind).copied().unwrap_or(0) <= SHOWN_PER_KIND {
+            continue;
+        }
+        batches
+            .entry(kind)
+            .and_modify(|(count, under)| {
+                *count += 1;
+                *under = common_ancestor(under, &skipped.path);
+            })
+            .or_insert_with(|| (1, skipped.path.clone()));
+    }
+    for (kind, (count, under)) in batches {
+        warn!("{}", tr(Msg::SkippedBatch, &[&kind, &count, &under.display()]));
+    }
+}
+
+/// Every file recorded so far, sorted by path.
+///
+/// A file met again by another pass of the scan is listed once.
+pub fn take() -> Vec<Skipped> {
+    dedup(std::mem::take(&mut *SKIPPED.lock().unwrap()))
+}
+
+// ----------
+//  Internal
+// ----------
+
+fn dedup(mut skipped: Vec<Skipped>) -> Vec<Skipped> {
+    skipped.sort_by(|a, b| a.path.cmp(&b.path));
+    skipped.dedup_by(|a, b| a.path == b.path && a.stage == b.stage && a.reason == b.reason);
+    skipped
+}
+
+/// The innermost cause of a failure, like `permission denied`, without the paths around it
+fn kind_of(reason: &str) -> String {
+    let cause = reason.rsplit(": ").next().unwrap_or(reason);
+    let cause = match cause.rfind(" (os error ") {
+        Some(pos) => &cause[..pos],
+        None => cause,
+    };
+    let mut chars = cause.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+fn common_ancestor(a: &Path, b: &Path) -> PathBuf {
+    a.components()
+        .zip(b.components())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a)
+        .collect()
+}
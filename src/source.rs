@@ -0,0 +1,143 @@
+use crate::file::{walk_dir, FileInfo};
+use crate::filter::FilterSet;
+use crate::pagecache::Sequential;
+use crate::remote::Remote;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Where files are listed and read from: the local file system, a remote tree, a bucket or an
+/// archive.
+///
+/// The comparison only goes through `FileInfo::open` and `FileInfo::metadata`, the files listed
+/// by a source keep it as their `origin`. Local files have none, the file system is the default.
+pub trait FileSource: fmt::Debug + Send + Sync {
+    /// How the root shows in the report, like `[user@]host:/path`
+    fn spec(&self) -> PathBuf;
+
+    /// The regular files under the root which pass `filters`
+    fn list(self: Arc<Self>, filters: &FilterSet) -> io::Result<Box<dyn Iterator<Item = FileInfo>>>;
+
+    /// Streams the content of the file behind `path`, as listed by `list`
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>>;
+
+    /// The file behind `path` as it is now, to catch a file changed since it was listed
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo>;
+}
+
+/// A tree of the local file system, walked as it is listed
+#[derive(Debug)]
+pub struct Os {
+    root: PathBuf,
+}
+
+impl Os {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+}
+
+impl FileSource for Os {
+    fn spec(&self) -> PathBuf {
+        self.root.clone()
+    }
+
+    fn list(self: Arc<Self>, filters: &FilterSet) -> io::Result<Box<dyn Iterator<Item = FileInfo>>> {
+        Ok(Box::new(walk_dir(&self.root, filters)))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        Ok(Box::new(Sequential::open(path)?))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        Ok(FileInfo::from_metadata(path.to_owned(), &fs::metadata(path)?))
+    }
+}
+
+/// The source `spec` stands for, unless it is a local path
+pub fn remote(spec: &Path) -> Option<io::Result<Arc<dyn FileSource>>> {
+    if spec.exists() {
+        return None;
+    }
+
+    if let Some(bucket) = spec.to_str().and_then(|s| s.strip_prefix("s3://")) {
+        #[cfg(feature = "s3")]
+        return Some(crate::s3::Bucket::new(bucket).map(|b| Arc::new(b) as Arc<dyn FileSource>));
+        #[cfg(not(feature = "s3"))]
+        {
+            let msg = format!("s3://{} needs rcompare built with the s3 feature", bucket);
+            return Some(Err(io::Error::new(io::ErrorKind::Unsupported, msg)));
+        }
+    }
+    let (target, root) = crate::remote::parse(spec)?;
+    Some(Ok(Arc::new(Remote::new(target, root))))
+}
+
+/// Whether `path` names a remote tree or a bucket rather than a local path
+pub fn is_remote(path: &Path) -> bool {
+    let bucket = path.to_str().map(|s| s.starts_with("s3://")).unwrap_or(false);
+    (bucket && !path.exists()) || crate::remote::parse(path).is_some()
+}
+
+/// Files held in memory under a made up root, for the tests
+#[cfg(test)]
+#[derive(Debug)]
+pub struct Memory {
+    root: PathBuf,
+    files: std::collections::BTreeMap<PathBuf, Vec<u8>>,
+}
+
+#[cfg(test)]
+impl Memory {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into(), files: Default::default() }
+    }
+
+    /// Adds a file at `name` under the root
+    pub fn with(mut self, name: &str, content: &[u8]) -> Self {
+        self.files.insert(self.root.join(name), content.to_vec());
+        self
+    }
+
+    fn info(&self, path: &Path, content: &[u8]) -> FileInfo {
+        let inode = self.files.keys().position(|p| p == path).unwrap_or(0) as u64 + 1;
+        FileInfo {
+            path: path.to_owned(),
+            size: content.len() as u64,
+            inode,
+            links: 1,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+impl FileSource for Memory {
+    fn spec(&self) -> PathBuf {
+        self.root.clone()
+    }
+
+    fn list(self: Arc<Self>, filters: &FilterSet) -> io::Result<Box<dyn Iterator<Item = FileInfo>>> {
+        let origin: Arc<dyn FileSource> = Arc::clone(&self) as Arc<dyn FileSource>;
+        let files: Vec<FileInfo> = self
+            .files
+            .iter()
+            .filter(|(path, content)| filters.accepts_listed(&self.root, path, content.len() as u64, 0))
+            .map(|(path, content)| FileInfo { origin: Some(Arc::clone(&origin)), ..self.info(path, content) })
+            .collect();
+        Ok(Box::new(files.into_iter()))
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read>> {
+        let content = self.files.get(path).ok_or(io::ErrorKind::NotFound)?;
+        Ok(Box::new(io::Cursor::new(content.clone())))
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<FileInfo> {
+        let content = self.files.get(path).ok_or(io::ErrorKind::NotFound)?;
+        Ok(self.info(path, content))
+    }
+}
@@ -0,0 +1,60 @@
+use std::fs::{File, Metadata};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+
+/// Whether the file has holes, fewer blocks allocated than its size takes
+pub fn is_sparse(meta: &Metadata) -> bool {
+    meta.blocks() * 512 < meta.len()
+}
+
+/// The allocated parts of the first `size` bytes of `file` as offsets and lengths, the holes
+/// between them read as zeros.
+///
+/// A file system which cannot tell the holes apart has the whole file as one extent.
+pub fn data_extents(file: &File, size: u64) -> io::Result<Vec<(u64, u64)>> {
+    let fd = file.as_raw_fd();
+    let mut extents = vec![];
+    let mut pos = 0;
+    while pos < size {
+        let start = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let err = io::Error::last_os_error();
+            return match err.raw_os_error() {
+                // a hole up to the end
+                Some(libc::ENXIO) => Ok(extents),
+                Some(libc::EINVAL) => Ok(vec![(0, size)]),
+                _ => Err(err),
+            };
+        }
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let (start, end) = (start as u64, (end as u64).min(size));
+        if start >= end {
+            break;
+        }
+        extents.push((start, end - start));
+        pos = end;
+    }
+    Ok(extents)
+}
+
+/// The parts allocated in either `a` or `b`, sorted and without overlaps
+pub fn union(a: &[(u64, u64)], b: &[(u64, u64)]) -> Vec<(u64, u64)> {
+    let mut all: Vec<(u64, u64)> = a.iter().chain(b).copied().collect();
+    all.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(all.len());
+    for (start, len) in all {
+        match merged.last_mut() {
+            Some((last, last_len)) if start <= *last + *last_len => {
+                *last_len = (*last_len).max(start + len - *last);
+            }
+            _ => merged.push((start, len)),
+        }
+    }
+    merged
+}
@@ -0,0 +1,200 @@
+use crate::common::stringify_bytes;
+use crate::i18n::{text, tr, Msg};
+use serde::Serialize;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// off unless `--stats`, the phases are timed on every file
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static PHASES: Mutex<[Totals; 4]> = Mutex::new([NONE; 4]);
+
+thread_local! {
+    /// the phase the thread is in and since when
+    static CURRENT: Cell<Option<(Phase, Instant)>> = const { Cell::new(None) };
+}
+
+/// The parts of a scan, told apart to see whether it waits on metadata or on reads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    /// listing the folders and reading the metadata of the files
+    Walk,
+    /// grouping the files by size and rule
+    Preprocess,
+    /// hashing the first bytes, then the whole content of the candidates
+    Hash,
+    /// comparing the candidates byte by byte
+    Compare,
+}
+
+const PHASES_IN_ORDER: [Phase; 4] = [Phase::Walk, Phase::Preprocess, Phase::Hash, Phase::Compare];
+
+/// What every phase of a scan took, in `--stats`
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Stats {
+    /// wall time of the scan
+    pub seconds: f64,
+    pub phases: Vec<PhaseStats>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PhaseStats {
+    pub phase: Phase,
+    /// time spent in the phase, added up over the workers when several run at once
+    pub seconds: f64,
+    /// files gone through the phase, a file hashed by its first bytes then whole counts twice
+    pub files: u64,
+    pub bytes: u64,
+    pub files_per_second: u64,
+    pub bytes_per_second: u64,
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Counts the time until the guard is dropped, and the bytes read meanwhile, for `phase`, which
+/// goes through `files` more files.
+///
+/// Phases nest, the time of the inner one is not counted for the outer one.
+pub fn phase(phase: Phase, files: usize) -> Span {
+    if !is_enabled() {
+        return Span { previous: None };
+    }
+
+    let now = Instant::now();
+    let previous = CURRENT.with(|current| current.replace(Some((phase, now))));
+    let mut totals = PHASES.lock().unwrap();
+    if let Some((outer, since)) = previous {
+        totals[outer as usize].time += now - since;
+    }
+    totals[phase as usize].files += files as u64;
+    Span { previous: Some(previous.map(|(outer, _)| outer)) }
+}
+
+/// Counts `files` more files for `phase`, when they are only known at its end
+pub fn count(phase: Phase, files: usize) {
+    if is_enabled() {
+        PHASES.lock().unwrap()[phase as usize].files += files as u64;
+    }
+}
+
+/// Counts `bytes` read by the current thread for the phase it is in
+pub fn read(bytes: u64) {
+    if !is_enabled() {
+        return;
+    }
+    if let Some((phase, _)) = CURRENT.with(Cell::get) {
+        PHASES.lock().unwrap()[phase as usize].bytes += bytes;
+    }
+}
+
+/// Counts the time spent waiting for the files of `files` as the walk
+pub fn walked<I: Iterator>(files: I) -> Walked<I> {
+    Walked { files }
+}
+
+/// The phases recorded since the last call, for a scan which took `elapsed`
+pub fn take(elapsed: Duration) -> Stats {
+    let totals = std::mem::replace(&mut *PHASES.lock().unwrap(), [NONE; 4]);
+    let phases = PHASES_IN_ORDER
+        .iter()
+        .map(|phase| {
+            let Totals { time, files, bytes } = totals[*phase as usize];
+            let rate = |n: u64| match time.is_zero() {
+                true => 0,
+                false => (n as f64 / time.as_secs_f64()) as u64,
+            };
+            PhaseStats {
+                phase: *phase,
+                seconds: time.as_secs_f64(),
+                files,
+                bytes,
+                files_per_second: rate(files),
+                bytes_per_second: rate(bytes),
+            }
+        })
+        .collect();
+    Stats { seconds: elapsed.as_secs_f64(), phases }
+}
+
+/// The phases one by one, with their rates
+pub fn summary(stats: &Stats) -> Vec<String> {
+    let mut lines = vec![tr(Msg::PhaseStats, &[&format!("{:.1}", stats.seconds)])];
+    for phase in stats.phases.iter() {
+        let name = match phase.phase {
+            Phase::Walk => Msg::PhaseWalk,
+            Phase::Preprocess => Msg::PhasePreprocess,
+            Phase::Hash => Msg::PhaseHash,
+            Phase::Compare => Msg::PhaseCompare,
+        };
+        lines.push(tr(
+            Msg::PhaseRate,
+            &[
+                &text(name),
+                &format!("{:.1}", phase.seconds),
+                &phase.files,
+                &phase.files_per_second,
+                &stringify_bytes(phase.bytes as usize),
+                &stringify_bytes(phase.bytes_per_second as usize),
+            ],
+        ));
+    }
+    lines
+}
+
+/// See `phase`
+pub struct Span {
+    /// the phase to go back to once dropped, `None` when the phases are not timed
+    previous: Option<Option<Phase>>,
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let Some(previous) = self.previous else {
+            return;
+        };
+        let now = Instant::now();
+        let current = CURRENT.with(|current| current.replace(previous.map(|outer| (outer, now))));
+        if let Some((phase, since)) = current {
+            PHASES.lock().unwrap()[phase as usize].time += now - since;
+        }
+    }
+}
+
+/// See `walked`
+pub struct Walked<I> {
+    files: I,
+}
+
+impl<I: Iterator> Iterator for Walked<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let _walk = phase(Phase::Walk, 0);
+        let file = self.files.next();
+        if file.is_some() {
+            count(Phase::Walk, 1);
+        }
+        file
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+#[derive(Debug, Clone, Copy)]
+struct Totals {
+    time: Duration,
+    files: u64,
+    bytes: u64,
+}
+
+const NONE: Totals = Totals { time: Duration::ZERO, files: 0, bytes: 0 };
@@ -0,0 +1,304 @@
+use crate::common::stringify_bytes;
+use crate::i18n::{text, tr, Msg};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use serde::Serialize;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+static PLAIN: AtomicBool = AtomicBool::new(false);
+static QUIET: AtomicBool = AtomicBool::new(false);
+static PROGRESS: AtomicBool = AtomicBool::new(true);
+/// whether stdout is kept for the report, see `say!`
+static STDERR_CHATTER: AtomicBool = AtomicBool::new(false);
+/// whether the progress is redrawn in place with ANSI sequences, rather than printed line by line
+static ANIMATED: AtomicBool = AtomicBool::new(false);
+static FORCED: AtomicBool = AtomicBool::new(false);
+/// whether animated progress shows the dashboard rather than the bar alone
+static DASHBOARD: AtomicBool = AtomicBool::new(true);
+static LAST_PERCENT: AtomicU64 = AtomicU64::new(u64::MAX);
+static BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+static DASH: Mutex<Option<Dashboard>> = Mutex::new(None);
+/// duplicates found so far, for the dashboard
+static FOUND_FILES: AtomicU64 = AtomicU64::new(0);
+static FOUND_GROUPS: AtomicU64 = AtomicU64::new(0);
+static FOUND_WASTED: AtomicU64 = AtomicU64::new(0);
+
+/// Where the running scan is, for the `serve` API
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Progress {
+    /// `scan` while walking, `compare` while reading, `idle` in between
+    pub stage: &'static str,
+    /// files found while walking, bytes settled while reading
+    pub done: u64,
+    /// bytes of the candidates while reading
+    pub total: Option<u64>,
+    pub groups: u64,
+    pub files: u64,
+    pub wasted: u64,
+}
+
+/// Lines drawn above the bar during the hash and compare phases
+struct Dashboard {
+    multi: MultiProgress,
+    phase: ProgressBar,
+    file: ProgressBar,
+    found: ProgressBar,
+    /// read rates by device, in verbose mode
+    rates: Option<ProgressBar>,
+}
+
+/// When to use ANSI control sequences
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Color {
+    /// only when stderr is an interactive terminal
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// `eprintln!` which steps around the progress bar instead of garbling it
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::term::suspend(|| eprintln!($($arg)*))
+    };
+}
+
+/// `println!` for messages about the run, which go to stderr instead when stdout carries the
+/// report or paths for another program
+#[macro_export]
+macro_rules! say {
+    ($($arg:tt)*) => {
+        match $crate::term::is_stdout_taken() {
+            true => $crate::term::suspend(|| eprintln!($($arg)*)),
+            false => println!($($arg)*),
+        }
+    };
+}
+
+/// Turns off ANSI sequences, in-place rewrites, box drawing and emoji in human outputs.
+///
+/// Dumb terminals get the plain output even without `--plain`. Quiet runs show no progress.
+pub fn init(plain: bool, quiet: bool, progress: bool, dashboard: bool, color: Color) {
+    let dumb = std::env::var("TERM").map(|term| term == "dumb").unwrap_or(false);
+    let plain = plain || dumb;
+    PLAIN.store(plain, Ordering::Relaxed);
+    QUIET.store(quiet, Ordering::Relaxed);
+    PROGRESS.store(progress && !quiet, Ordering::Relaxed);
+
+    // pipes and log files only get whole lines
+    let animated = match color {
+        Color::Auto => std::io::stderr().is_terminal(),
+        Color::Always => true,
+        Color::Never => false,
+    };
+    ANIMATED.store(animated && !plain, Ordering::Relaxed);
+    FORCED.store(color == Color::Always, Ordering::Relaxed);
+    DASHBOARD.store(dashboard, Ordering::Relaxed);
+}
+
+/// Whether outputs must stay line oriented, for screen readers and braille terminals
+pub fn is_plain() -> bool {
+    PLAIN.load(Ordering::Relaxed)
+}
+
+/// Whether banners and other chatter are left out, leaving the report, warnings and errors
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Keeps stdout for the output of the run, every message going to stderr
+pub fn take_stdout() {
+    STDERR_CHATTER.store(true, Ordering::Relaxed);
+}
+
+pub fn is_stdout_taken() -> bool {
+    STDERR_CHATTER.load(Ordering::Relaxed)
+}
+
+/// Starts the walk phase, counting the files found
+pub fn start_scan() {
+    let template = format!("{{spinner}} {} {{human_pos}} {{elapsed}}", text(Msg::Scanning));
+    let bar = ProgressBar::with_draw_target(None, target());
+    bar.set_style(ProgressStyle::with_template(&template).unwrap());
+    bar.enable_steady_tick(Duration::from_millis(120));
+    replace(Some(bar));
+}
+
+pub fn scanned() {
+    if let Some(bar) = BAR.lock().unwrap().as_ref() {
+        bar.inc(1);
+    }
+}
+
+/// Starts the hash and compare phases, over the `total` bytes of the candidate files
+pub fn start_compare(total: u64) {
+    let template = "{bar:30} {bytes}/{total_bytes} {bytes_per_sec} ETA {eta} {wide_msg}";
+    let bar = ProgressBar::with_draw_target(Some(total), target());
+    bar.set_style(ProgressStyle::with_template(template).unwrap());
+    LAST_PERCENT.store(u64::MAX, Ordering::Relaxed);
+    [&FOUND_FILES, &FOUND_GROUPS, &FOUND_WASTED]
+        .iter()
+        .for_each(|n| n.store(0, Ordering::Relaxed));
+
+    let animated = ANIMATED.load(Ordering::Relaxed) && PROGRESS.load(Ordering::Relaxed);
+    if animated && DASHBOARD.load(Ordering::Relaxed) {
+        let multi = MultiProgress::with_draw_target(target());
+        let line = || {
+            let line = multi.add(ProgressBar::new_spinner());
+            line.set_style(ProgressStyle::with_template("{wide_msg}").unwrap());
+            line
+        };
+        let rates = crate::throughput::is_enabled().then(line);
+        let dash = Dashboard { phase: line(), file: line(), found: line(), rates, multi };
+        dash.found.set_message(found_line());
+        let bar = dash.multi.add(bar);
+        bar.set_style(
+            ProgressStyle::with_template("{bar:30} {bytes}/{total_bytes} {bytes_per_sec} ETA {eta}").unwrap(),
+        );
+        replace(Some(bar));
+        *DASH.lock().unwrap() = Some(dash);
+        return;
+    }
+    replace(Some(bar));
+}
+
+/// Shows the stage of the pipeline and how many groups of candidates are left after this one
+pub fn phase(stage: Msg, queued: usize) {
+    if let Some(dash) = DASH.lock().unwrap().as_ref() {
+        dash.phase
+            .set_message(format!("{} · {}", text(stage), tr(Msg::Queued, &[&queued])));
+    }
+}
+
+/// Counts a duplicate group as soon as it is found
+pub fn found(files: usize, wasted: u64) {
+    FOUND_FILES.fetch_add(files as u64, Ordering::Relaxed);
+    FOUND_GROUPS.fetch_add(1, Ordering::Relaxed);
+    FOUND_WASTED.fetch_add(wasted, Ordering::Relaxed);
+    if let Some(dash) = DASH.lock().unwrap().as_ref() {
+        dash.found.set_message(found_line());
+    }
+}
+
+/// Shows what is being read now.
+///
+/// In plain mode the message gets its own line instead, at most one per percent.
+pub fn working_on(msg: String) {
+    let guard = BAR.lock().unwrap();
+    let Some(bar) = guard.as_ref() else {
+        return;
+    };
+
+    if ANIMATED.load(Ordering::Relaxed) {
+        match DASH.lock().unwrap().as_ref() {
+            Some(dash) => {
+                dash.file.set_message(msg);
+                if let Some(rates) = &dash.rates {
+                    rates.set_message(tr(Msg::Throughput, &[&crate::throughput::current()]));
+                }
+            }
+            None => bar.set_message(msg),
+        }
+        return;
+    }
+
+    if !PROGRESS.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let percent = (bar.position() * 100)
+        .checked_div(bar.length().unwrap_or(0))
+        .unwrap_or(100);
+    if LAST_PERCENT.swap(percent, Ordering::Relaxed) != percent {
+        eprintln!("{}", tr(Msg::Progress, &[&percent, &msg]));
+        if crate::throughput::is_enabled() {
+            eprintln!("{}", tr(Msg::Throughput, &[&crate::throughput::current()]));
+        }
+    }
+}
+
+/// Counts the `bytes` of a file which needs no more reading
+pub fn settle(bytes: u64) {
+    if let Some(bar) = BAR.lock().unwrap().as_ref() {
+        bar.inc(bytes);
+    }
+}
+
+pub fn finish() {
+    replace(None);
+    if let Some(dash) = DASH.lock().unwrap().take() {
+        [Some(dash.phase), Some(dash.file), Some(dash.found), dash.rates]
+            .iter()
+            .flatten()
+            .for_each(|line| line.finish_and_clear());
+        _ = dash.multi.clear();
+    }
+}
+
+/// A snapshot of the progress, tracked even when it is not drawn
+pub fn progress() -> Progress {
+    let bar = BAR.lock().unwrap().clone();
+    let (stage, done, total) = match &bar {
+        None => ("idle", 0, None),
+        Some(bar) => match bar.length() {
+            None => ("scan", bar.position(), None),
+            Some(total) => ("compare", bar.position(), Some(total)),
+        },
+    };
+    Progress {
+        stage,
+        done,
+        total,
+        groups: FOUND_GROUPS.load(Ordering::Relaxed),
+        files: FOUND_FILES.load(Ordering::Relaxed),
+        wasted: FOUND_WASTED.load(Ordering::Relaxed),
+    }
+}
+
+/// Runs `f` with the progress bar cleared, so what it prints does not mix with it
+pub fn suspend<F: FnOnce() -> R, R>(f: F) -> R {
+    // cloned out of the locks, `f` may report progress itself
+    let multi = DASH.lock().unwrap().as_ref().map(|dash| dash.multi.clone());
+    if let Some(multi) = multi {
+        return multi.suspend(f);
+    }
+    let bar = BAR.lock().unwrap().clone();
+    match bar {
+        Some(bar) => bar.suspend(f),
+        None => f(),
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// The bar is drawn on stderr, a hidden bar still counts for the line by line progress
+fn target() -> ProgressDrawTarget {
+    if !(ANIMATED.load(Ordering::Relaxed) && PROGRESS.load(Ordering::Relaxed)) {
+        return ProgressDrawTarget::hidden();
+    }
+
+    match FORCED.load(Ordering::Relaxed) {
+        // drawn even when stderr is not a terminal
+        true => ProgressDrawTarget::term_like(Box::new(console::Term::stderr())),
+        false => ProgressDrawTarget::stderr(),
+    }
+}
+
+fn found_line() -> String {
+    let files = FOUND_FILES.load(Ordering::Relaxed);
+    let groups = FOUND_GROUPS.load(Ordering::Relaxed);
+    let wasted = stringify_bytes(FOUND_WASTED.load(Ordering::Relaxed) as usize);
+    tr(Msg::FoundSoFar, &[&files, &groups, &wasted])
+}
+
+fn replace(bar: Option<ProgressBar>) {
+    if let Some(old) = std::mem::replace(&mut *BAR.lock().unwrap(), bar) {
+        old.finish_and_clear();
+    }
+}
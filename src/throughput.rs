@@ -0,0 +1,207 @@
+use crate::common::stringify_bytes;
+use crate::i18n::{text, tr, Msg};
+use crate::volume::{device_name, Volume};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// off unless verbose, timing every read is not free
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static DEVICES: Mutex<BTreeMap<u64, Usage>> = Mutex::new(BTreeMap::new());
+static WORKERS: Mutex<BTreeMap<String, Worker>> = Mutex::new(BTreeMap::new());
+/// bytes the comparisons read, counted whether the reads are timed or not
+static READ: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes read and the time spent waiting for them
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Usage {
+    pub bytes: u64,
+    pub wait: Duration,
+}
+
+impl Usage {
+    /// Bytes per second while waiting for reads
+    pub fn rate(&self) -> u64 {
+        match self.wait.is_zero() {
+            true => 0,
+            false => (self.bytes as f64 / self.wait.as_secs_f64()) as u64,
+        }
+    }
+
+    fn add(&mut self, other: Usage) {
+        self.bytes += other.bytes;
+        self.wait += other.wait;
+    }
+}
+
+/// Time a thread spent on the files, the part not waiting for reads went to hashing and comparing
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Worker {
+    pub busy: Duration,
+    pub read: Usage,
+}
+
+/// Bytes the comparisons read against what the files hold, the rest was spared by the size and
+/// hash filters and the cache
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct BytesRead {
+    pub read: u64,
+    /// what reading every file whole once would take
+    pub total: u64,
+}
+
+impl BytesRead {
+    /// Share of `total` left unread, in percent
+    pub fn spared(&self) -> u64 {
+        match self.total {
+            0 => 0,
+            total => total.saturating_sub(self.read) * 100 / total,
+        }
+    }
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Reads through `inner`, counting the bytes and the time spent for the `device` of the file
+pub fn timed<R: Read>(inner: R, device: u64) -> Timed<R> {
+    Timed { inner, device, usage: Usage::default() }
+}
+
+/// Counts bytes read some other way, like through a memory map
+pub fn record(device: u64, usage: Usage) {
+    if !is_enabled() {
+        return;
+    }
+    DEVICES.lock().unwrap().entry(device).or_default().add(usage);
+    WORKERS
+        .lock()
+        .unwrap()
+        .entry(worker_name())
+        .or_default()
+        .read
+        .add(usage);
+}
+
+/// Counts bytes read by the comparisons, for the total and the phase of the thread
+pub fn read(bytes: u64) {
+    READ.fetch_add(bytes, Ordering::Relaxed);
+    crate::stats::read(bytes);
+}
+
+/// The bytes read since the last call, out of the `total` the files hold
+pub fn take_read(total: u64) -> BytesRead {
+    BytesRead { read: READ.swap(0, Ordering::Relaxed), total }
+}
+
+/// Counts the time until the guard is dropped as busy for the current thread
+pub fn work() -> Work {
+    Work { start: is_enabled().then(Instant::now) }
+}
+
+/// The read rate of every device, on one line for the progress
+pub fn current() -> String {
+    DEVICES
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(dev, usage)| format!("{} {}/s", device_name(*dev), stringify_bytes(usage.rate() as usize)))
+        .collect::<Vec<_>>()
+        .join(" · ")
+}
+
+/// The breakdown by device and by worker, to tell a slow disk from a busy processor
+pub fn summary(volumes: &[Volume]) -> Vec<String> {
+    let mut lines = vec![text(Msg::DeviceThroughput).to_owned()];
+    for (dev, usage) in DEVICES.lock().unwrap().iter() {
+        let name = match volumes.iter().find(|v| v.dev == *dev) {
+            Some(v) => format!("{} ({} on {})", v.device, v.source, v.mount_point.display()),
+            None => device_name(*dev),
+        };
+        lines.push(tr(
+            Msg::DeviceRate,
+            &[
+                &name,
+                &stringify_bytes(usage.bytes as usize),
+                &format!("{:.1}", usage.wait.as_secs_f64()),
+                &stringify_bytes(usage.rate() as usize),
+            ],
+        ));
+    }
+
+    lines.push(text(Msg::WorkerThroughput).to_owned());
+    for (name, worker) in WORKERS.lock().unwrap().iter() {
+        let waiting = match worker.busy.is_zero() {
+            true => 0.0,
+            false => (worker.read.wait.as_secs_f64() * 100.0 / worker.busy.as_secs_f64()).min(100.0),
+        };
+        lines.push(tr(
+            Msg::WorkerRate,
+            &[
+                name,
+                &format!("{:.1}", worker.busy.as_secs_f64()),
+                &stringify_bytes(worker.read.bytes as usize),
+                &format!("{:.0}", waiting),
+            ],
+        ));
+    }
+    lines
+}
+
+/// Reader counting for the throughput, see `timed`
+pub struct Timed<R> {
+    inner: R,
+    device: u64,
+    usage: Usage,
+}
+
+impl<R: Read> Read for Timed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !is_enabled() && !crate::stats::is_enabled() {
+            let n = self.inner.read(buf)?;
+            self.usage.bytes += n as u64;
+            return Ok(n);
+        }
+
+        let start = Instant::now();
+        let n = self.inner.read(buf)?;
+        self.usage.add(Usage { bytes: n as u64, wait: start.elapsed() });
+        Ok(n)
+    }
+}
+
+impl<R> Drop for Timed<R> {
+    fn drop(&mut self) {
+        record(self.device, self.usage);
+        read(self.usage.bytes);
+    }
+}
+
+/// See `work`
+pub struct Work {
+    start: Option<Instant>,
+}
+
+impl Drop for Work {
+    fn drop(&mut self) {
+        if let Some(start) = self.start {
+            WORKERS.lock().unwrap().entry(worker_name()).or_default().busy += start.elapsed();
+        }
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+fn worker_name() -> String {
+    std::thread::current().name().unwrap_or("worker").to_owned()
+}
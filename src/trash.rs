@@ -0,0 +1,181 @@
+use crate::sandbox::Sandbox;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// The trash cans files are moved to instead of being deleted, as the freedesktop.org trash
+/// specification lays them out, so file managers list them and can restore them.
+///
+/// A file goes to the home trash when it lives on the same file system, otherwise to
+/// `$topdir/.Trash/$uid`, or else `$topdir/.Trash-$uid`, at the top of its own. A file is never
+/// copied across file systems.
+#[derive(Default)]
+pub struct Trash {
+    /// the trash of every device met so far
+    cans: HashMap<u64, PathBuf>,
+}
+
+impl Trash {
+    /// Moves `path`, which must be inside `sandbox`, to the trash of its file system, along with
+    /// the `.trashinfo` file telling where it came from
+    pub fn put(&mut self, sandbox: &Sandbox, path: &Path) -> io::Result<()> {
+        let meta = fs::symlink_metadata(path)?;
+        let can = match self.cans.get(&meta.dev()) {
+            Some(can) => can.clone(),
+            None => {
+                let can = trash_can(path, meta.dev())?;
+                self.cans.insert(meta.dev(), can.clone());
+                can
+            }
+        };
+
+        let parent = match path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            Some(parent) => fs::canonicalize(parent)?,
+            None => std::env::current_dir()?,
+        };
+        let original = parent.join(path.file_name().unwrap_or_default());
+        let (name, info) = write_info(&can, &original)?;
+        sandbox.move_out(&path, &can.join("files").join(name)).inspect_err(|_| {
+            _ = fs::remove_file(&info);
+        })
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// The trash of the file system `dev` holding `path`, created when missing
+fn trash_can(path: &Path, dev: u64) -> io::Result<PathBuf> {
+    if let Some(home) = home_trash() {
+        // the trash is created on first use, the nearest existing folder tells its file system
+        let existing = home.ancestors().find(|dir| dir.exists());
+        if existing
+            .and_then(|dir| fs::metadata(dir).ok())
+            .is_some_and(|meta| meta.dev() == dev)
+        {
+            return prepare(&home).map(|()| home);
+        }
+    }
+
+    let top = top_dir(path, dev)?;
+    let uid = unsafe { libc::getuid() };
+    // an administrator may provide a shared trash, sticky so users cannot touch each other's
+    let shared = top.join(".Trash");
+    let is_shared =
+        fs::symlink_metadata(&shared).is_ok_and(|meta| meta.is_dir() && meta.permissions().mode() & libc::S_ISVTX != 0);
+    if is_shared {
+        let can = shared.join(uid.to_string());
+        if prepare(&can).is_ok() {
+            return Ok(can);
+        }
+    }
+
+    let can = top.join(format!(".Trash-{}", uid));
+    prepare(&can)?;
+    let meta = fs::symlink_metadata(&can)?;
+    if !meta.is_dir() || meta.uid() != uid {
+        let msg = format!("{} is not a trash of this user", can.display());
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, msg));
+    }
+    Ok(can)
+}
+
+/// `$XDG_DATA_HOME/Trash`, `~/.local/share/Trash` by default
+fn home_trash() -> Option<PathBuf> {
+    let data = std::env::var_os("XDG_DATA_HOME")
+        .filter(|dir| Path::new(dir).is_absolute())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))?;
+    Some(data.join("Trash"))
+}
+
+/// The topmost directory of the file system `dev` above `path`, where it is mounted
+fn top_dir(path: &Path, dev: u64) -> io::Result<PathBuf> {
+    let mut top = fs::canonicalize(
+        path.parent()
+            .filter(|p| !p.as_os_str().is_empty())
+            .unwrap_or(Path::new(".")),
+    )?;
+    while let Some(parent) = top.parent() {
+        if fs::metadata(parent)?.dev() != dev {
+            break;
+        }
+        top = parent.to_path_buf();
+    }
+    Ok(top)
+}
+
+/// Creates the trash `can` with its `files` and `info` directories, only readable by the user
+fn prepare(can: &Path) -> io::Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(true).mode(0o700);
+    builder.create(can.join("files"))?;
+    builder.create(can.join("info"))
+}
+
+/// Writes the `.trashinfo` of `original` under a name no other file of `can` has, and returns
+/// the name along with the path of the info file
+fn write_info(can: &Path, original: &Path) -> io::Result<(OsString, PathBuf)> {
+    let base = original.file_name().unwrap_or_default();
+    let content = format!(
+        "[Trash Info]\nPath={}\nDeletionDate={}\n",
+        escape(original.as_os_str().as_bytes()),
+        deletion_date()
+    );
+    for n in 1.. {
+        let mut name = base.to_owned();
+        if n > 1 {
+            name.push(format!(".{}", n));
+        }
+        if fs::symlink_metadata(can.join("files").join(&name)).is_ok() {
+            continue;
+        }
+
+        let mut file_name = name.clone();
+        file_name.push(".trashinfo");
+        let info = can.join("info").join(file_name);
+        // the info file is created first and exclusively, it is what reserves the name
+        match OpenOptions::new().write(true).create_new(true).mode(0o600).open(&info) {
+            Ok(mut file) => {
+                file.write_all(content.as_bytes())?;
+                return Ok((name, info));
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!("names run out")
+}
+
+/// Percent-encodes a path as in a URL, the way the specification wants `Path`
+fn escape(bytes: &[u8]) -> String {
+    let mut escaped = String::with_capacity(bytes.len());
+    for byte in bytes {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => escaped.push(*byte as char),
+            _ => escaped.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    escaped
+}
+
+/// The local time, as `YYYY-MM-DDThh:mm:ss`
+fn deletion_date() -> String {
+    let now = unsafe { libc::time(std::ptr::null_mut()) };
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&now, &mut tm) };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
@@ -0,0 +1,193 @@
+use crate::cmp::Comparator;
+use crate::config::{HASH_BUF_SIZE, READ_SIZE};
+use crate::file::{resolve_in_sysroot, FileInfo, SymlinkMode};
+use crate::hashing::HashAlgorithm;
+use crate::policy::{PolicyRule, PolicyTable};
+use crate::regions::IgnoreRanges;
+use crate::skipped::{self, Stage};
+use serde_json::Value;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Reads the files of the chosen groups of a report again and updates it with what they hold now.
+///
+/// Groups are given by their position in `same`, from 0. A group which no longer holds is split
+/// in place, its files without a match move to `unique` and unreadable ones to `skipped`. The
+/// files are hashed whole, and compared byte by byte as well with `paranoid`.
+pub fn reverify<P: AsRef<Path>>(path: &P, groups: &[usize], paranoid: bool) -> io::Result<Value> {
+    let mut report = crate::report::check_compat(path)?;
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let same = take_array(&mut report, "same");
+    let mut confidence = take_array(&mut report, "confidence");
+    confidence.resize(same.len(), Value::Null);
+
+    let selected: BTreeSet<usize> = groups.iter().copied().collect();
+    if let Some(out) = selected.iter().find(|g| **g >= same.len()) {
+        let msg = format!("the report has {} groups, there is no group {}", same.len(), out);
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+    }
+
+    let ignore = IgnoreRanges::parse(&ignore_specs(report.get("ignore_ranges"))).map_err(invalid)?;
+    let rules: Vec<PolicyRule> = match report.get("policies") {
+        Some(rules) => serde_json::from_value(rules.clone()).map_err(|e| invalid(e.to_string()))?,
+        None => vec![],
+    };
+    let sysroot = report
+        .pointer("/filters/sysroot")
+        .and_then(|s| s.as_str())
+        .map(PathBuf::from);
+    let mut cmp = Comparator::new(READ_SIZE, HASH_BUF_SIZE, HashAlgorithm::default())
+        .with_rules(ignore, PolicyTable::new(rules)?);
+
+    let mut new_same = vec![];
+    let mut new_confidence = vec![];
+    let mut unique = vec![];
+    for (idx, (group, tag)) in same.into_iter().zip(confidence).enumerate() {
+        if !selected.contains(&idx) {
+            new_same.push(group);
+            new_confidence.push(tag);
+            continue;
+        }
+
+        let entries = match group {
+            Value::Array(entries) => entries,
+            _ => return Err(invalid(format!("group {} is not a list of files", idx))),
+        };
+        let mut files = vec![];
+        let mut kept = vec![];
+        for mut entry in entries {
+            match file_info(&entry, sysroot.as_deref()) {
+                Ok(info) => {
+                    // the files may have changed since the scan, the new digest is filled in below
+                    entry["size"] = Value::from(info.size);
+                    entry["inode"] = Value::from(info.inode);
+                    entry["device"] = Value::from(crate::volume::device_name(info.device));
+                    entry["links"] = Value::from(info.links);
+                    entry["mtime"] = Value::from(info.mtime);
+                    entry["mode"] = Value::from(format!("{:04o}", info.mode));
+                    entry["uid"] = Value::from(info.uid);
+                    entry["gid"] = Value::from(info.gid);
+                    if let Some(entry) = entry.as_object_mut() {
+                        entry.remove("hash");
+                    }
+                    files.push(info);
+                    kept.push(entry);
+                }
+                Err(err) => {
+                    let path = crate::encoding::entry_path(&entry).unwrap_or_default();
+                    if skipped::record(&path, Stage::Compare, &err) {
+                        crate::warn!("Could not read {} again: {}", path.display(), err);
+                    }
+                }
+            }
+        }
+
+        let sep = cmp.verify(&files, paranoid);
+        for (i, hash) in sep.hashes.iter() {
+            kept[*i]["hash"] = Value::from(hash.as_str());
+        }
+        for (group, tag) in sep.same.iter().zip(sep.confidence) {
+            new_same.push(Value::Array(group.iter().map(|i| kept[*i].clone()).collect()));
+            new_confidence.push(serde_json::to_value(tag)?);
+        }
+        unique.extend(sep.unique.iter().map(|i| kept[*i].clone()));
+    }
+
+    let mut old_unique = take_array(&mut report, "unique");
+    old_unique.extend(unique);
+    old_unique.sort_by(|a, b| a["path"].as_str().cmp(&b["path"].as_str()));
+    let mut old_skipped = take_array(&mut report, "skipped");
+    old_skipped.extend(
+        skipped::take()
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?,
+    );
+
+    report["unique"] = Value::Array(old_unique);
+    report["same"] = Value::Array(new_same);
+    report["confidence"] = Value::Array(new_confidence);
+    report["skipped"] = Value::Array(old_skipped);
+    Ok(report)
+}
+
+// ----------
+//  Internal
+// ----------
+
+fn take_array(report: &mut Value, key: &str) -> Vec<Value> {
+    match report.get_mut(key).map(Value::take) {
+        Some(Value::Array(values)) => values,
+        _ => vec![],
+    }
+}
+
+/// The specs `IgnoreRanges` was parsed from, out of its form in the report
+fn ignore_specs(value: Option<&Value>) -> Vec<String> {
+    let Some(value) = value else {
+        return vec![];
+    };
+
+    let mut specs: Vec<String> = value["all"]
+        .as_str()
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .into_iter()
+        .collect();
+    if let Some(by_ext) = value["by_ext"].as_object() {
+        specs.extend(
+            by_ext
+                .iter()
+                .filter_map(|(ext, spec)| Some(format!("{}={}", ext, spec.as_str()?))),
+        );
+    }
+    specs
+}
+
+/// Looks at the file of a report entry as it is now
+fn file_info(entry: &Value, sysroot: Option<&Path>) -> io::Result<FileInfo> {
+    let path = crate::encoding::entry_path(entry)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "a file entry has no path"))?;
+    if entry["symlink"].as_str() == Some("compare-target") {
+        let metadata = fs::symlink_metadata(&path)?;
+        let size = fs::read_link(&path)?.as_os_str().len() as u64;
+        return Ok(FileInfo {
+            inode: metadata.ino(),
+            size,
+            symlink: Some(SymlinkMode::CompareTarget),
+            device: metadata.dev(),
+            links: metadata.nlink(),
+            mtime: crate::file::mtime(&metadata),
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            path,
+            ..Default::default()
+        });
+    }
+
+    // inside a sysroot, links are resolved against the image as during the scan
+    let is_link = fs::symlink_metadata(&path)?.file_type().is_symlink();
+    let source = match (is_link, sysroot) {
+        (true, Some(sysroot)) => Some(resolve_in_sysroot(sysroot, &path)?),
+        _ => None,
+    };
+    let metadata = fs::metadata(source.as_ref().unwrap_or(&path))?;
+    Ok(FileInfo {
+        inode: metadata.ino(),
+        size: metadata.size(),
+        symlink: is_link.then_some(SymlinkMode::Follow),
+        source,
+        device: metadata.dev(),
+        links: metadata.nlink(),
+        mtime: crate::file::mtime(&metadata),
+        mode: metadata.mode() & 0o7777,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        path,
+        ..Default::default()
+    })
+}
@@ -0,0 +1,188 @@
+use crate::file::FileInfo;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// A mounted file system holding some of the scanned files
+#[derive(Debug, Clone, Serialize)]
+pub struct Volume {
+    /// `major:minor` of the device, as reported by `stat`
+    pub device: String,
+    #[serde(serialize_with = "crate::encoding::lossy")]
+    pub mount_point: PathBuf,
+    /// what is mounted, usually a block device like `/dev/sda1`
+    pub source: String,
+    pub fs_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub uuid: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(skip)]
+    pub dev: u64,
+}
+
+impl Volume {
+    /// The most readable way to tell the volume apart: its label, its UUID or its mount point
+    pub fn name(&self) -> String {
+        self.label
+            .clone()
+            .or_else(|| self.uuid.clone())
+            .unwrap_or_else(|| self.mount_point.to_string_lossy().into_owned())
+    }
+}
+
+/// Looks up the volume of every device the files live on.
+///
+/// Mounts are read from `/proc/self/mountinfo` and UUIDs and labels from `/dev/disk`, so the
+/// list is empty on systems without them.
+pub fn detect_volumes(info: &[FileInfo]) -> Vec<Volume> {
+    let devices: BTreeSet<u64> = info.iter().map(|fl| fl.device).collect();
+    let mounts = match fs::read_to_string("/proc/self/mountinfo") {
+        Ok(mounts) => mounts,
+        Err(err) => {
+            eprintln!("Could not read the mounted file systems, volumes are not reported: {}", err);
+            return vec![];
+        }
+    };
+
+    let mut volumes: HashMap<u64, Volume> = HashMap::new();
+    for volume in mounts.lines().filter_map(parse_mount) {
+        if !devices.contains(&volume.dev) {
+            continue;
+        }
+
+        // with bind mounts the same device shows up several times, keep the shortest mount point
+        let keep = volumes
+            .get(&volume.dev)
+            .map(|v| volume.mount_point.as_os_str().len() < v.mount_point.as_os_str().len())
+            .unwrap_or(true);
+        if keep {
+            volumes.insert(volume.dev, volume);
+        }
+    }
+
+    let uuids = disk_names("/dev/disk/by-uuid");
+    let labels = disk_names("/dev/disk/by-label");
+    let mut volumes: Vec<Volume> = volumes.into_values().collect();
+    for volume in volumes.iter_mut() {
+        volume.uuid = find_disk_name(&uuids, volume);
+        volume.label = find_disk_name(&labels, volume);
+    }
+
+    volumes.sort_by(|a, b| a.mount_point.cmp(&b.mount_point));
+    volumes
+}
+
+/// `major:minor` of a device number, as written by `stat` and in `/proc/self/mountinfo`
+pub fn device_name(dev: u64) -> String {
+    let major = ((dev >> 32) & 0xffff_f000) | ((dev >> 8) & 0xfff);
+    let minor = ((dev >> 12) & 0xffff_ff00) | (dev & 0xff);
+    format!("{}:{}", major, minor)
+}
+
+/// Whether the disk behind `dev` spins, as the kernel tells in `/sys/dev/block`.
+///
+/// A partition has no queue of its own, the one of its disk is used. Devices which are not block
+/// devices, like network and memory file systems, tell nothing.
+pub fn is_rotational(dev: u64) -> Option<bool> {
+    let dir = PathBuf::from("/sys/dev/block").join(device_name(dev));
+    [dir.join("queue/rotational"), dir.join("../queue/rotational")]
+        .iter()
+        .find_map(|path| std::fs::read_to_string(path).ok())
+        .map(|flag| flag.trim() == "1")
+}
+
+pub(crate) fn serialize_device<S: serde::Serializer>(dev: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&device_name(*dev))
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// Parses a line like `36 35 98:0 / /mnt rw,noatime master:1 - ext3 /dev/root rw`
+fn parse_mount(line: &str) -> Option<Volume> {
+    let (mount, fs) = line.split_once(" - ")?;
+    let mut mount = mount.split(' ');
+    let device = mount.nth(2)?;
+    let mount_point = mount.nth(1)?;
+    let mut fs = fs.split(' ');
+    let fs_type = fs.next()?;
+    let source = fs.next()?;
+
+    let (major, minor) = device.split_once(':')?;
+    let dev = make_dev(major.parse().ok()?, minor.parse().ok()?);
+    Some(Volume {
+        device: device.to_owned(),
+        mount_point: PathBuf::from(unescape_octal(mount_point)),
+        source: unescape_octal(source),
+        fs_type: fs_type.to_owned(),
+        uuid: None,
+        label: None,
+        dev,
+    })
+}
+
+/// Encodes a device number the way glibc's `makedev` does
+fn make_dev(major: u64, minor: u64) -> u64 {
+    ((major & 0xffff_f000) << 32) | ((major & 0xfff) << 8) | ((minor & 0xffff_ff00) << 12) | (minor & 0xff)
+}
+
+/// Mount info escapes spaces and the like as `\040`
+fn unescape_octal(s: &str) -> String {
+    unescape(s, 8, 3, "")
+}
+
+/// udev escapes the names in `/dev/disk` as `\x20`
+fn unescape_hex(s: &str) -> String {
+    unescape(s, 16, 2, "x")
+}
+
+fn unescape(s: &str, radix: u32, digits: usize, prefix: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = s;
+    while let Some(pos) = rest.find('\\') {
+        bytes.extend_from_slice(&rest.as_bytes()[..pos]);
+        rest = &rest[pos + 1..];
+        let code = rest
+            .strip_prefix(prefix)
+            .and_then(|r| r.get(..digits))
+            .and_then(|d| u8::from_str_radix(d, radix).ok());
+        match code {
+            Some(code) => {
+                bytes.push(code);
+                rest = &rest[prefix.len() + digits..];
+            }
+            None => bytes.push(b'\\'),
+        }
+    }
+    bytes.extend_from_slice(rest.as_bytes());
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Names found in a `/dev/disk` directory, with the device number and node they point to
+fn disk_names(dir: &str) -> Vec<(String, u64, PathBuf)> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return vec![];
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let node = fs::canonicalize(entry.path()).ok()?;
+            let rdev = fs::metadata(&node).ok()?.rdev();
+            Some((unescape_hex(&entry.file_name().to_string_lossy()), rdev, node))
+        })
+        .collect()
+}
+
+fn find_disk_name(names: &[(String, u64, PathBuf)], volume: &Volume) -> Option<String> {
+    // file systems like btrfs report an anonymous device, fall back to the mounted node
+    let source = fs::canonicalize(Path::new(&volume.source)).ok();
+    names
+        .iter()
+        .find(|(_, rdev, node)| *rdev == volume.dev || source.as_ref() == Some(node))
+        .map(|(name, _, _)| name.clone())
+}
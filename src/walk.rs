@@ -0,0 +1,188 @@
+use crate::file::{dir_id, skip_dir, step, unreadable_rest, visit, FileInfo, Step};
+use crate::filter::{Filter, FilterSet};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+
+/// Threads listing the folders of a walk at once
+static THREADS: AtomicUsize = AtomicUsize::new(1);
+
+/// Files listed ahead of the scan, before the walkers wait for it
+const LISTED_AHEAD: usize = 4096;
+
+pub fn set_threads(threads: usize) {
+    THREADS.store(threads.max(1), Ordering::Relaxed);
+}
+
+pub fn threads() -> usize {
+    THREADS.load(Ordering::Relaxed)
+}
+
+/// A walk with several threads listing folders at once, for trees so large or so far away that
+/// listing them takes longer than comparing the files.
+///
+/// The files come in no particular order, `preprocess` sorts them by path before grouping them.
+/// Linked folders are only listed once the real tree is exhausted, as in `PathIter`.
+pub struct ParallelWalk {
+    files: mpsc::IntoIter<FileInfo>,
+}
+
+impl ParallelWalk {
+    pub fn new(root: &Path, filters: &FilterSet, threads: usize) -> Self {
+        let queue = Arc::new(Queue {
+            state: Mutex::new(State {
+                dirs: vec![(root.to_owned(), 0)],
+                links: vec![],
+                busy: 0,
+                visited: dir_id(root).into_iter().collect(),
+                done: false,
+            }),
+            wake: Condvar::new(),
+        });
+
+        // the walkers stop once the scan drops the receiver, like when it is interrupted
+        let (tx, rx) = mpsc::sync_channel(LISTED_AHEAD);
+        for n in 0..threads {
+            let (queue, tx) = (Arc::clone(&queue), tx.clone());
+            let mut filter = Filter::new(filters.clone(), root);
+            let walk = move || {
+                while let Some((dir, depth)) = queue.next() {
+                    match list(&dir, depth, &mut filter, &queue, &tx) {
+                        Some((dirs, links)) => queue.listed(dirs, links),
+                        None => queue.stop(),
+                    }
+                }
+            };
+            std::thread::Builder::new()
+                .name(format!("walker-{}", n + 1))
+                .spawn(walk)
+                .expect("cannot start a walker thread");
+        }
+        Self { files: rx.into_iter() }
+    }
+}
+
+impl Iterator for ParallelWalk {
+    type Item = FileInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.files.next()
+    }
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// Folders waiting to be listed, shared by the walkers
+struct Queue {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+struct State {
+    dirs: Vec<(PathBuf, usize)>,
+    links: Vec<(PathBuf, usize)>,
+    /// walkers listing a folder, which may add more
+    busy: usize,
+    /// (device, inode) of every folder met so far, so links cannot loop or repeat a subtree
+    visited: HashSet<(u64, u64)>,
+    done: bool,
+}
+
+impl Queue {
+    /// The next folder to list and its depth, none once the tree is exhausted
+    fn next(&self) -> Option<(PathBuf, usize)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.done || crate::interrupt::interrupted() {
+                state.done = true;
+                self.wake.notify_all();
+                return None;
+            }
+            if let Some(dir) = state.dirs.pop() {
+                state.busy += 1;
+                return Some(dir);
+            }
+            if state.busy == 0 {
+                let Some((path, depth)) = state.links.pop() else {
+                    state.done = true;
+                    self.wake.notify_all();
+                    return None;
+                };
+                if visit(&mut state.visited, &path) {
+                    state.dirs.push((path, depth));
+                }
+                continue;
+            }
+            state = self.wake.wait(state).unwrap();
+        }
+    }
+
+    /// Whether the folder at `path` is met for the first time
+    fn visit(&self, path: &Path) -> bool {
+        visit(&mut self.state.lock().unwrap().visited, path)
+    }
+
+    /// Queues what a walker found in the folder it listed
+    fn listed(&self, dirs: Vec<(PathBuf, usize)>, links: Vec<(PathBuf, usize)>) {
+        let mut state = self.state.lock().unwrap();
+        state.dirs.extend(dirs);
+        state.links.extend(links);
+        state.busy -= 1;
+        self.wake.notify_all();
+    }
+
+    fn stop(&self) {
+        self.state.lock().unwrap().done = true;
+        self.wake.notify_all();
+    }
+}
+
+/// Folders and linked folders met while listing a folder, with their depth
+type Found = (Vec<(PathBuf, usize)>, Vec<(PathBuf, usize)>);
+
+/// Sends the files of the folder `dir` and returns what else it holds, none when the files are no
+/// longer wanted
+fn list(
+    dir: &Path,
+    depth: usize,
+    filter: &mut Filter,
+    queue: &Queue,
+    tx: &mpsc::SyncSender<FileInfo>,
+) -> Option<Found> {
+    let (mut dirs, mut links) = (vec![], vec![]);
+    let entries = match dir.read_dir() {
+        Ok(entries) => entries,
+        Err(err) => {
+            skip_dir(dir, &err);
+            return Some((dirs, links));
+        }
+    };
+
+    let (mut listed, mut folders, mut complete) = (0, 0, true);
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                unreadable_rest(dir, &err);
+                complete = false;
+                break;
+            }
+        };
+        listed += 1;
+        folders += entry.file_type().map(|t| t.is_dir()).unwrap_or(false) as usize;
+
+        match step(entry.path(), depth + 1, filter) {
+            Step::File(info) => tx.send(info).ok()?,
+            Step::Dir(path) if queue.visit(&path) => dirs.push((path, depth + 1)),
+            Step::Dir(_) | Step::Skip => {}
+            Step::Link(real) => links.push((real, depth + 1)),
+        }
+    }
+    if filter.empty_dirs() && complete && listed == folders {
+        crate::empty::record(dir, folders);
+    }
+    Some((dirs, links))
+}
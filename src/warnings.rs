@@ -0,0 +1,58 @@
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+static WARNINGS: Mutex<Vec<Warning>> = Mutex::new(vec![]);
+
+/// What a warning is about, for automation to pick the ones it cares about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    /// one root is inside the other, its files are listed twice
+    OverlappingRoots,
+    /// a directory could not be listed, the files under it are missing
+    UnreadableDirectory,
+    /// an archive could not be listed, it is only compared as a file
+    UnreadableArchive,
+    /// the filters left out most of the files met by the walk
+    MostlyFiltered,
+    /// the hash cache could not be opened, read or updated
+    Cache,
+    /// the scan was stopped by Ctrl-C, the report only holds what was confirmed before
+    Interrupted,
+}
+
+/// A warning shown on stderr, kept for the report
+#[derive(Debug, Clone, Serialize)]
+pub struct Warning {
+    pub kind: Kind,
+    #[serde(serialize_with = "crate::encoding::lossy_opt", skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path_base64: Option<String>,
+}
+
+/// Records a warning for the report.
+///
+/// Printing it on stderr is up to the caller, in the language of the user, while the report
+/// stays in English.
+pub fn record<M: ToString>(kind: Kind, path: Option<&Path>, message: M) {
+    let warning = Warning {
+        kind,
+        path: path.map(Path::to_path_buf),
+        message: message.to_string(),
+        path_base64: path.and_then(crate::encoding::raw_bytes),
+    };
+    WARNINGS.lock().unwrap().push(warning);
+}
+
+/// Every warning recorded so far, by kind and path.
+///
+/// A warning given again by another pass of the scan is listed once.
+pub fn take() -> Vec<Warning> {
+    let mut warnings = std::mem::take(&mut *WARNINGS.lock().unwrap());
+    warnings.sort_by(|a, b| (a.kind, &a.path).cmp(&(b.kind, &b.path)));
+    warnings.dedup_by(|a, b| a.kind == b.kind && a.path == b.path && a.message == b.message);
+    warnings
+}
@@ -0,0 +1,348 @@
+use crate::cache::HashCache;
+use crate::cmp::{hash_file_prefix, hash_full_file};
+use crate::common::{Preprocessed, Processed};
+use crate::config::{Config, READ_SIZE};
+use crate::file::{FileInfo, Step};
+use crate::filter::Filter;
+use crate::hashing::HashAlgorithm;
+use crate::i18n::{tr, Msg};
+use crate::skipped::{self, Stage};
+use crate::warnings::{self, Kind};
+use crate::{say, warn};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Keeps the hash cache at `cache` up to date with the files under `dir`, until interrupted.
+///
+/// Only files the cache already knows are hashed again, with the kinds of hashes recorded for
+/// them. Kinds read through a mask or a policy cannot be redone here and are dropped instead, as
+/// are the kinds of files whose new content could not be read.
+pub fn watch(dir: &Path, cache: &Path) -> io::Result<()> {
+    if !cache.is_file() {
+        let msg = format!("there is no hash cache at {}, run a scan with --cache first", cache.display());
+        return Err(io::Error::new(io::ErrorKind::NotFound, msg));
+    }
+
+    let cache = HashCache::open(&cache)?;
+    let mut inotify = Inotify::init()?;
+    let mut dirs: HashMap<WatchDescriptor, PathBuf> = HashMap::new();
+    add_tree(&mut inotify, &mut dirs, dir, CACHE_MASK);
+    if !crate::term::is_quiet() {
+        println!("{}", tr(Msg::Watching, &[&dir.display(), &dirs.len().saturating_sub(1)]));
+    }
+
+    let mut buffer = [0; 16 * 1024];
+    loop {
+        let mut changed = BTreeSet::new();
+        let mut new_dirs = vec![];
+        for event in inotify.read_events_blocking(&mut buffer)? {
+            if event.mask.contains(EventMask::IGNORED) {
+                dirs.remove(&event.wd);
+                continue;
+            }
+
+            let (Some(parent), Some(name)) = (dirs.get(&event.wd), event.name) else {
+                continue;
+            };
+            let path = parent.join(name);
+            match event.mask.contains(EventMask::ISDIR) {
+                true => new_dirs.push(path),
+                false => _ = changed.insert(path),
+            }
+        }
+
+        for path in new_dirs {
+            add_tree(&mut inotify, &mut dirs, &path, CACHE_MASK);
+        }
+        for path in changed {
+            refresh(&cache, &path);
+        }
+        // a scan may start at any moment, it must see the updates
+        cache.commit();
+    }
+}
+
+/// The directories of scanned trees, to scan them again once something changes under them
+pub struct TreeWatch {
+    inotify: Inotify,
+    dirs: HashMap<WatchDescriptor, PathBuf>,
+    /// the folders the run writes in and the names it writes there, as a name is only a prefix
+    /// of the files next to it, like `.tmp` or `-wal`; no name for folders of reports
+    own: Vec<(PathBuf, OsString)>,
+}
+
+impl TreeWatch {
+    /// Watches `roots`, leaving out the changes to `own`, the files and report folders the run
+    /// writes itself
+    pub fn new(roots: &[PathBuf], own: &[&Path]) -> io::Result<Self> {
+        let own = own
+            .iter()
+            .filter(|path| path.as_os_str() != "-")
+            .filter_map(|path| match path.is_dir() {
+                true => Some((fs::canonicalize(path).ok()?, OsString::new())),
+                false => {
+                    let parent = path
+                        .parent()
+                        .filter(|p| !p.as_os_str().is_empty())
+                        .unwrap_or(Path::new("."));
+                    Some((fs::canonicalize(parent).ok()?, path.file_name()?.to_owned()))
+                }
+            })
+            .collect();
+        let mut tree = Self { inotify: Inotify::init()?, dirs: HashMap::new(), own };
+        for root in roots {
+            add_tree(&mut tree.inotify, &mut tree.dirs, root, TREE_MASK);
+        }
+        Ok(tree)
+    }
+
+    /// Blocks until files are created, modified, moved or removed under the roots, then gathers
+    /// the changes following within `interval` so a burst of them leads to a single scan
+    pub fn wait(&mut self, interval: Duration) -> io::Result<BTreeSet<PathBuf>> {
+        let mut buffer = [0; 16 * 1024];
+        let mut changed = BTreeSet::new();
+        // the reports just written raise events as well, they do not count
+        while changed.is_empty() {
+            let events: Vec<_> = self
+                .inotify
+                .read_events_blocking(&mut buffer)?
+                .map(|e| (e.wd, e.mask, e.name.map(|n| n.to_owned())))
+                .collect();
+            self.record(events, &mut changed);
+        }
+
+        std::thread::sleep(interval);
+        loop {
+            let events: Vec<_> = match self.inotify.read_events(&mut buffer) {
+                Ok(events) => events.map(|e| (e.wd, e.mask, e.name.map(|n| n.to_owned()))).collect(),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            };
+            if events.is_empty() {
+                break;
+            }
+            self.record(events, &mut changed);
+        }
+
+        if !crate::term::is_quiet() {
+            say!("{}", tr(Msg::Rescanning, &[&changed.len()]));
+        }
+        Ok(changed)
+    }
+
+    fn record(&mut self, events: Vec<(WatchDescriptor, EventMask, Option<OsString>)>, changed: &mut BTreeSet<PathBuf>) {
+        for (wd, mask, name) in events {
+            if mask.contains(EventMask::IGNORED) {
+                self.dirs.remove(&wd);
+                continue;
+            }
+
+            let (Some(parent), Some(name)) = (self.dirs.get(&wd), name) else {
+                continue;
+            };
+            if self.is_own(parent, &name) {
+                continue;
+            }
+            let path = parent.join(name);
+            if mask.contains(EventMask::ISDIR) && mask.intersects(EventMask::CREATE | EventMask::MOVED_TO) {
+                add_tree(&mut self.inotify, &mut self.dirs, &path, TREE_MASK);
+            }
+            changed.insert(path);
+        }
+    }
+
+    /// Whether the run wrote `name` in `parent` itself
+    fn is_own(&self, parent: &Path, name: &OsStr) -> bool {
+        let mut owners = self
+            .own
+            .iter()
+            .filter(|(_, own)| name.as_bytes().starts_with(own.as_bytes()))
+            .peekable();
+        if owners.peek().is_none() {
+            return false;
+        }
+
+        let Ok(parent) = fs::canonicalize(parent) else {
+            return false;
+        };
+        owners.any(|(dir, own)| match own.is_empty() {
+            true => parent.starts_with(dir),
+            false => &parent == dir,
+        })
+    }
+}
+
+/// The files of the scan `res` brought up to date with the `changed` paths, to compare them again
+/// without walking the trees.
+///
+/// Only the changed paths are looked at again, through the filters as the walk would. A folder
+/// created, moved or given other rights, a link, a `.gitignore` or a scan which is more than one
+/// walk give `None`, only a new walk tells what changed then. The files the walk had to skip and
+/// its warnings are recorded again for the report.
+pub fn update(config: &Config, res: &Processed, changed: &BTreeSet<PathBuf>) -> Option<Preprocessed> {
+    let incremental = config.passes == 1
+        && config.resume.is_none()
+        && config.files_from.is_none()
+        && !config.filters.empty_dirs
+        && !res.partial
+        && !crate::source::is_remote(&config.rhs)
+        && res.roots.iter().all(|root| root.is_dir());
+    if !incremental {
+        return None;
+    }
+
+    let affected = |path: &Path| changed.iter().any(|changed| path.starts_with(changed));
+    let mut info: Vec<FileInfo> = res.info.iter().filter(|fl| !affected(&fl.path)).cloned().collect();
+    for path in changed {
+        if config.filters.gitignore && path.file_name().is_some_and(|name| name == ".gitignore") {
+            return None;
+        }
+        match fs::symlink_metadata(path) {
+            Ok(meta) if !meta.is_file() => return None,
+            Ok(_) => {}
+            // removed, the files under it with it
+            Err(_) => continue,
+        }
+
+        for root in res.roots.iter().filter(|root| path.starts_with(root)) {
+            match relist(root, path, &config.filters) {
+                Some(Step::File(fl)) if config.filters.scan_archives => {
+                    info.extend(crate::archive::with_members(fl, &config.filters))
+                }
+                Some(Step::File(fl)) => info.push(fl),
+                Some(Step::Skip) | None => {}
+                Some(Step::Dir(_) | Step::Link(_)) => return None,
+            }
+        }
+    }
+    // the counts only tell about full walks
+    _ = crate::filter::take_counts();
+
+    // whole-content digests are set again by the comparison, for the files still in a group
+    info.iter_mut().for_each(|fl| fl.hash = None);
+    for skip in res
+        .skipped
+        .iter()
+        .filter(|s| s.stage == Stage::Scan && !affected(&s.path))
+    {
+        skipped::record(&skip.path, skip.stage, &skip.reason);
+    }
+    let walked = [
+        Kind::OverlappingRoots,
+        Kind::UnreadableDirectory,
+        Kind::UnreadableArchive,
+        Kind::MostlyFiltered,
+    ];
+    for warning in res.warnings.iter().filter(|w| walked.contains(&w.kind)) {
+        if !warning.path.as_deref().is_some_and(affected) {
+            warnings::record(warning.kind, warning.path.as_deref(), &warning.message);
+        }
+    }
+    Some(crate::common::regroup(res.roots.clone(), &config.filters, &config.policies, info))
+}
+
+// ----------
+//  Internal
+// ----------
+
+/// What changes the hashes of the cache
+const CACHE_MASK: WatchMask = WatchMask::CLOSE_WRITE
+    .union(WatchMask::MOVED_TO)
+    .union(WatchMask::ATTRIB)
+    .union(WatchMask::CREATE)
+    .union(WatchMask::ONLYDIR)
+    .union(WatchMask::DONT_FOLLOW);
+
+/// What changes a report, files leaving the tree as well
+const TREE_MASK: WatchMask = CACHE_MASK.union(WatchMask::DELETE).union(WatchMask::MOVED_FROM);
+
+/// Watches `dir` and the directories under it, without following links
+fn add_tree(inotify: &mut Inotify, dirs: &mut HashMap<WatchDescriptor, PathBuf>, dir: &Path, mask: WatchMask) {
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        match inotify.watches().add(&dir, mask) {
+            Ok(wd) => _ = dirs.insert(wd, dir.clone()),
+            Err(err) => {
+                // most likely the limit of fs.inotify.max_user_watches
+                warn!("Could not watch {}: {}", dir.display(), err);
+                continue;
+            }
+        }
+
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+                pending.push(entry.path());
+            }
+        }
+    }
+}
+
+/// What the walk of `root` does with the file at `path`, `None` when it would not reach it: one
+/// of the folders above it is filtered out or too deep
+fn relist(root: &Path, path: &Path, filters: &crate::filter::FilterSet) -> Option<Step> {
+    let mut filter = Filter::new(filters.clone(), root);
+    let rel = path.strip_prefix(root).ok()?;
+    let depth = rel.components().count();
+    let mut dir = root.to_path_buf();
+    for (level, name) in rel.components().take(depth.saturating_sub(1)).enumerate() {
+        dir.push(name);
+        if !matches!(crate::file::step(dir.clone(), level + 1, &mut filter), Step::Dir(_)) {
+            return None;
+        }
+    }
+    Some(crate::file::step(path.to_path_buf(), depth, &mut filter))
+}
+
+/// Hashes a changed file again with every kind of hash the cache holds for it
+fn refresh(cache: &HashCache, path: &Path) {
+    let Ok(metadata) = fs::symlink_metadata(path) else {
+        return;
+    };
+    if !metadata.is_file() {
+        return;
+    }
+
+    let fl = FileInfo {
+        path: path.to_path_buf(),
+        inode: metadata.ino(),
+        size: metadata.size(),
+        device: metadata.dev(),
+        mtime: crate::file::mtime(&metadata),
+        ..Default::default()
+    };
+    for kind in cache.kinds(fl.device, fl.inode) {
+        let digest = match parse_kind(&kind) {
+            Some((algorithm, Some(size))) => {
+                hash_file_prefix(&fl.path, size, algorithm).map(|k| k.to_be_bytes().to_vec())
+            }
+            Some((algorithm, None)) => hash_full_file(&fl.path, READ_SIZE, algorithm).map(|h| h.digest()),
+            None => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        };
+
+        match digest {
+            Ok(digest) => crate::cache::HashStore::put(cache, &fl, &kind, &digest),
+            Err(_) => cache.forget(fl.device, fl.inode, &kind),
+        }
+    }
+}
+
+/// `prefix-{algorithm}-{size}` or `full-{algorithm}`, the size being `None` for the latter
+fn parse_kind(kind: &str) -> Option<(HashAlgorithm, Option<usize>)> {
+    if let Some(rest) = kind.strip_prefix("prefix-") {
+        let (name, size) = rest.split_once('-')?;
+        return Some((HashAlgorithm::from_name(name)?, Some(size.parse().ok()?)));
+    }
+
+    let name = kind.strip_prefix("full-")?;
+    Some((HashAlgorithm::from_name(name)?, None))
+}